@@ -0,0 +1,437 @@
+//! The admin API, served on the local host for scripts and the `silence`
+//! subcommand, to see into the running service and steer it:
+//!
+//! - `GET /silences` lists the silences in force or still to come;
+//! - `POST /silences` adds one, given as a `NewSilence`;
+//! - `DELETE /silences/<id>` ends one early;
+//! - `GET /circuits` shows the circuit breaker of each destination;
+//! - `GET /deliveries` lists the latest delivery attempts, newest first;
+//! - `GET /routes` shows the routing tree in force;
+//! - `POST /reload` reloads the configuration, as SIGHUP does;
+//! - `GET /consumption` tells whether the queues are being consumed from,
+//!   and `POST /consumption/pause` and `POST /consumption/resume` stop and
+//!   start that.
+//!
+//! Replies are JSON; errors are `{"error": "..."}`. With `admin.token`
+//! every request must carry it as `Authorization: Bearer <token>`.
+
+use crate::config::{ConfigSource, RateLimit, RouteNode};
+use crate::load_config;
+use crate::router::Dispatcher;
+use crate::silence::{self, Silence};
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+/// The largest request body taken.
+const MAX_BODY_BYTES: u64 = 64 * 1024;
+
+/// A silence to add.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NewSilence {
+    pub matchers: BTreeMap<String, String>,
+    /// When it starts; now if not given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub starts_at: Option<DateTime<Utc>>,
+    /// When it ends; one of this and `duration` is needed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ends_at: Option<DateTime<Utc>>,
+    /// How long it lasts from its start, such as `2h`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<String>,
+    pub created_by: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+}
+
+impl NewSilence {
+    fn add(self, dispatcher: &Dispatcher) -> Result<Silence, String> {
+        let starts_at = self.starts_at.unwrap_or_else(Utc::now);
+        let ends_at = match (self.ends_at, self.duration.as_deref()) {
+            (Some(ends_at), None) => ends_at,
+            (None, Some(duration)) => {
+                let duration = silence::parse_duration(duration)
+                    .and_then(|duration| chrono::Duration::from_std(duration).ok())
+                    .ok_or_else(|| format!("invalid duration {}", duration))?;
+                starts_at + duration
+            }
+            _ => return Err("give one of ends_at and duration".to_string()),
+        };
+        dispatcher.silences.add_window(
+            self.matchers,
+            starts_at,
+            ends_at,
+            &self.created_by,
+            self.comment,
+        )
+    }
+}
+
+/// Refused for want of the token.
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// Whether `given` and `expected` are the same, taking as long to tell
+/// wherever they differ.
+fn same(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |differ, (a, b)| differ | (a ^ b))
+            == 0
+}
+
+/// Passes requests carrying `token` as a bearer token, or any if there is
+/// none.
+fn authorized(token: Option<String>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |given: Option<String>| {
+            let token = token.clone();
+            async move {
+                let bearer = given
+                    .as_deref()
+                    .and_then(|given| given.strip_prefix("Bearer "));
+                match (&token, bearer) {
+                    (None, _) => Ok(()),
+                    (Some(token), Some(bearer)) if same(bearer, token) => Ok(()),
+                    _ => Err(warp::reject::custom(Unauthorized)),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+fn limit(limit: &Option<RateLimit>) -> Value {
+    match limit {
+        Some(limit) => json!({ "count": limit.count, "per_secs": limit.per.as_secs() }),
+        None => Value::Null,
+    }
+}
+
+/// `node` and the routes below it, with what is unset left out.
+fn route_tree(node: &RouteNode) -> Value {
+    let mut tree = json!({
+        "matchers": node.matchers.equal,
+        "matchers_regex": node
+            .matchers
+            .regex
+            .iter()
+            .map(|(key, re)| {
+                // As configured, without the anchors added to match wholly.
+                let pattern = re.as_str();
+                let pattern = pattern
+                    .strip_prefix("^(?:")
+                    .and_then(|pattern| pattern.strip_suffix(")$"))
+                    .unwrap_or(pattern);
+                (key.clone(), pattern.to_string())
+            })
+            .collect::<BTreeMap<_, _>>(),
+        "during": node.during,
+        "outside": node.outside,
+        "filter": node.filter.as_ref().map(|filter| filter.text()),
+        "drop": node.drop,
+        "receivers": node.receivers,
+        "ack": node.ack.map(|ack| format!("{:?}", ack).to_lowercase()),
+        "fallback": node.fallback,
+        "template": node.template,
+        "group_by": node.group_by,
+        "group_interval_secs": node.group_interval.map(|interval| interval.as_secs()),
+        "repeat_interval_secs": node.repeat_interval.map(|interval| interval.as_secs()),
+        "per_alert": limit(&node.per_alert),
+        "per_channel": limit(&node.per_channel),
+        "routes": node.routes.iter().map(route_tree).collect::<Vec<_>>(),
+    });
+    if let Some(tree) = tree.as_object_mut() {
+        tree.retain(|_, value| match value {
+            Value::Null => false,
+            Value::Array(values) => !values.is_empty(),
+            Value::Object(values) => !values.is_empty(),
+            _ => true,
+        });
+    }
+    tree
+}
+
+fn reply<T: Serialize>(status: StatusCode, body: &T) -> warp::reply::Response {
+    warp::reply::with_status(warp::reply::json(body), status).into_response()
+}
+
+fn refusal(status: StatusCode, error: impl std::fmt::Display) -> warp::reply::Response {
+    reply(status, &json!({ "error": error.to_string() }))
+}
+
+/// The reply to a request no route took.
+async fn rejected(rejection: Rejection) -> Result<warp::reply::Response, Infallible> {
+    let (status, error) = if rejection.find::<Unauthorized>().is_some() {
+        (StatusCode::UNAUTHORIZED, "unauthorized".to_string())
+    } else if rejection.is_not_found() {
+        (StatusCode::NOT_FOUND, "not found".to_string())
+    } else if let Some(e) = rejection.find::<warp::body::BodyDeserializeError>() {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    } else if rejection.find::<warp::reject::PayloadTooLarge>().is_some() {
+        (StatusCode::PAYLOAD_TOO_LARGE, "too large".to_string())
+    } else if rejection.find::<warp::reject::MethodNotAllowed>().is_some() {
+        (
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method not allowed".to_string(),
+        )
+    } else {
+        (StatusCode::BAD_REQUEST, format!("{:?}", rejection))
+    };
+    Ok(refusal(status, error))
+}
+
+/// Serve the admin API for `dispatcher`, which reloads its configuration
+/// from `source`, on `listen`, to requests bearing `token` if there is one,
+/// until `shutdown` resolves.
+pub async fn serve(
+    dispatcher: Arc<Dispatcher>,
+    source: ConfigSource,
+    listen: SocketAddr,
+    token: Option<String>,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) {
+    let with_dispatcher = warp::any().map(move || dispatcher.clone());
+
+    let list = warp::get()
+        .and(warp::path!("silences"))
+        .and(with_dispatcher.clone())
+        .map(|dispatcher: Arc<Dispatcher>| reply(StatusCode::OK, &dispatcher.silences.unexpired()));
+    let add = warp::post()
+        .and(warp::path!("silences"))
+        .and(warp::body::content_length_limit(MAX_BODY_BYTES))
+        .and(warp::body::json())
+        .and(with_dispatcher.clone())
+        .map(
+            |new: NewSilence, dispatcher: Arc<Dispatcher>| match new.add(&dispatcher) {
+                Ok(silence) => reply(StatusCode::CREATED, &silence),
+                Err(e) => refusal(StatusCode::BAD_REQUEST, e),
+            },
+        );
+    let remove = warp::delete()
+        .and(warp::path!("silences" / u64))
+        .and(with_dispatcher.clone())
+        .map(|id, dispatcher: Arc<Dispatcher>| {
+            match dispatcher.silences.remove(id, "the admin API") {
+                Ok(silence) => reply(StatusCode::OK, &silence),
+                Err(e) => refusal(StatusCode::CONFLICT, e),
+            }
+        });
+    let silences = list.or(add).unify().or(remove).unify();
+
+    let circuits = warp::get()
+        .and(warp::path!("circuits"))
+        .and(with_dispatcher.clone())
+        .map(|dispatcher: Arc<Dispatcher>| {
+            let circuits: Vec<Value> = dispatcher
+                .breaker
+                .circuits()
+                .into_iter()
+                .map(|circuit| {
+                    json!({
+                        "destination": circuit.name,
+                        "state": circuit.state,
+                        "failures": circuit.failures,
+                        "open_secs": circuit.open_for.map(|open_for| open_for.as_secs()),
+                    })
+                })
+                .collect();
+            reply(StatusCode::OK, &circuits)
+        });
+    let deliveries = warp::get()
+        .and(warp::path!("deliveries"))
+        .and(with_dispatcher.clone())
+        .map(|dispatcher: Arc<Dispatcher>| reply(StatusCode::OK, &dispatcher.metrics.recent()));
+    let routing = warp::get()
+        .and(warp::path!("routes"))
+        .and(with_dispatcher.clone())
+        .map(|dispatcher: Arc<Dispatcher>| {
+            let cfg = dispatcher.cfg();
+            let mut receivers: Vec<&String> = cfg.routing.receivers.keys().collect();
+            receivers.sort();
+            let queues: Vec<&str> = cfg.queues.iter().map(|queue| queue.name.as_str()).collect();
+            reply(
+                StatusCode::OK,
+                &json!({
+                    "queues": queues,
+                    "receivers": receivers,
+                    "routes": cfg.routing.root.routes.iter().map(route_tree).collect::<Vec<_>>(),
+                }),
+            )
+        });
+    let reload = warp::post()
+        .and(warp::path!("reload"))
+        .and(with_dispatcher.clone())
+        .and_then(move |dispatcher: Arc<Dispatcher>| {
+            let source = source.clone();
+            async move {
+                let reply = match crate::reload(&source, &dispatcher).await {
+                    Ok(()) => reply(StatusCode::OK, &json!({ "reloaded": source.path })),
+                    Err(e) => refusal(StatusCode::UNPROCESSABLE_ENTITY, e),
+                };
+                Ok::<_, Infallible>(reply)
+            }
+        });
+    let consumption = |dispatcher: &Dispatcher| {
+        reply(
+            StatusCode::OK,
+            &json!({ "paused": dispatcher.pause.paused() }),
+        )
+    };
+    let consuming = warp::get()
+        .and(warp::path!("consumption"))
+        .and(with_dispatcher.clone())
+        .map(move |dispatcher: Arc<Dispatcher>| consumption(&dispatcher));
+    let pause = warp::post()
+        .and(warp::path!("consumption" / "pause"))
+        .and(with_dispatcher.clone())
+        .map(move |dispatcher: Arc<Dispatcher>| {
+            dispatcher.pause.set(true);
+            consumption(&dispatcher)
+        });
+    let resume = warp::post()
+        .and(warp::path!("consumption" / "resume"))
+        .and(with_dispatcher)
+        .map(move |dispatcher: Arc<Dispatcher>| {
+            dispatcher.pause.set(false);
+            consumption(&dispatcher)
+        });
+    let runtime = circuits
+        .or(deliveries)
+        .unify()
+        .or(routing)
+        .unify()
+        .or(reload)
+        .unify()
+        .or(consuming)
+        .unify()
+        .or(pause)
+        .unify()
+        .or(resume)
+        .unify();
+
+    let routes = authorized(token)
+        .and(silences.or(runtime).unify())
+        .recover(rejected);
+
+    match warp::serve(routes).try_bind_with_graceful_shutdown(listen, shutdown) {
+        Ok((addr, server)) => {
+            info!("Serving the admin API on {}", addr);
+            server.await;
+        }
+        Err(e) => error!("Unable to serve the admin API on {}: {}", listen, e),
+    }
+}
+
+/// Talks to the admin API of the service running with the same
+/// configuration.
+pub struct AdminClient {
+    client: reqwest::Client,
+    base: String,
+    token: Option<String>,
+}
+
+impl AdminClient {
+    pub async fn new(source: &ConfigSource) -> Result<AdminClient, String> {
+        let cfg = load_config(source).await?;
+        let admin = cfg
+            .admin
+            .as_ref()
+            .ok_or_else(|| "The admin API is not configured".to_string())?;
+        // Wherever it listens, it is reached on this host.
+        let mut addr = admin.listen;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        let client = reqwest::Client::builder()
+            .no_proxy()
+            .build()
+            .map_err(|e| format!("Unable to build HTTP client: {}", e))?;
+        Ok(AdminClient {
+            client,
+            base: format!("http://{}", addr),
+            token: admin.token.clone(),
+        })
+    }
+
+    async fn send<T: serde::de::DeserializeOwned>(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<T, String> {
+        let request = match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        };
+        let response = request
+            .send()
+            .await
+            .map_err(|e| format!("Unable to reach the admin API at {}: {}", self.base, e))?;
+        let status = response.status();
+        let body = response
+            .bytes()
+            .await
+            .map_err(|e| format!("Unable to read the admin API's reply: {}", e))?;
+        if !status.is_success() {
+            let error = serde_json::from_slice::<serde_json::Value>(&body)
+                .ok()
+                .and_then(|body| body["error"].as_str().map(String::from))
+                .unwrap_or_else(|| status.to_string());
+            return Err(error);
+        }
+        serde_json::from_slice(&body)
+            .map_err(|e| format!("Unable to parse the admin API's reply: {}", e))
+    }
+
+    pub async fn silences(&self) -> Result<Vec<Silence>, String> {
+        self.send(self.client.get(&format!("{}/silences", self.base)))
+            .await
+    }
+
+    pub async fn add_silence(&self, silence: &NewSilence) -> Result<Silence, String> {
+        self.send(
+            self.client
+                .post(&format!("{}/silences", self.base))
+                .json(silence),
+        )
+        .await
+    }
+
+    pub async fn remove_silence(&self, id: u64) -> Result<Silence, String> {
+        self.send(
+            self.client
+                .delete(&format!("{}/silences/{}", self.base, id)),
+        )
+        .await
+    }
+}
+
+/// A silence on one line, for the `silence` subcommand.
+pub fn describe(silence: &Silence) -> String {
+    let mut text = format!(
+        "{} {} from {} until {} (by {})",
+        silence.id,
+        silence.describe(),
+        silence.starts_at.format("%Y-%m-%d %H:%M UTC"),
+        silence.ends_at.format("%Y-%m-%d %H:%M UTC"),
+        silence.created_by
+    );
+    if let Some(comment) = &silence.comment {
+        text.push_str(&format!(": {}", comment));
+    }
+    text
+}
@@ -0,0 +1,246 @@
+use chrono::{DateTime, Utc};
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Ordered from most to least severe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Critical,
+    Warning,
+    Info,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Severity::Critical => "critical",
+            Severity::Warning => "warning",
+            Severity::Info => "info",
+        };
+        f.write_str(name)
+    }
+}
+
+impl std::str::FromStr for Severity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Severity, Self::Err> {
+        match s {
+            "critical" => Ok(Severity::Critical),
+            "warning" => Ok(Severity::Warning),
+            "info" => Ok(Severity::Info),
+            other => Err(format!("Unknown severity {}", other)),
+        }
+    }
+}
+
+/// Read from a plain string rather than as an enum, so that severities can
+/// be map keys in TOML too.
+impl<'de> Deserialize<'de> for Severity {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Severity, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Whether every attribute named in `when` has the given value in `alert`,
+/// the alert in its serialized form so that standard attributes and extra
+/// fields can be matched alike.
+pub fn matches(alert: &Value, when: &BTreeMap<String, String>) -> bool {
+    when.iter().all(|(key, expected)| match &alert[key] {
+        Value::String(s) => s == expected,
+        Value::Null => false,
+        other => expected.parse::<Value>().ok().as_ref() == Some(other),
+    })
+}
+
+/// Whether the check is failing or has recovered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    #[default]
+    #[serde(alias = "failing", alias = "failed")]
+    Firing,
+    #[serde(alias = "recovered", alias = "passed", alias = "ok")]
+    Resolved,
+}
+
+/// An alert as published by 2Steps onto the bus.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Alert {
+    /// Required: an alert without one is rejected as unparseable and
+    /// dead-lettered, rather than guessed at and paging someone.
+    pub severity: Severity,
+    #[serde(default)]
+    pub status: Status,
+    pub title: String,
+    #[serde(default)]
+    pub reason: Option<String>,
+    #[serde(default)]
+    pub source: Option<String>,
+    #[serde(default = "Utc::now")]
+    pub timestamp: DateTime<Utc>,
+    #[serde(default)]
+    pub step: Option<String>,
+    #[serde(default)]
+    pub run_id: Option<String>,
+    /// Identifies the check, overriding the one derived from source, step and
+    /// title.
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+    /// When the check recovered; set on the original alert when its message
+    /// is updated to show the recovery.
+    #[serde(default)]
+    pub resolved_at: Option<DateTime<Utc>>,
+    /// Slack ID of whoever took responsibility for the check, and when; set
+    /// on the original alert when someone clicks its Handle button.
+    #[serde(default)]
+    pub acknowledged_by: Option<String>,
+    #[serde(default)]
+    pub acknowledged_at: Option<DateTime<Utc>>,
+    /// Slack ID of whoever snoozed the check, and until when; set on the
+    /// original alert when someone picks a duration from its Snooze menu.
+    #[serde(default)]
+    pub snoozed_by: Option<String>,
+    #[serde(default)]
+    pub snoozed_until: Option<DateTime<Utc>>,
+    /// Slack ID of whoever escalated the check to PagerDuty, when, and the
+    /// incident raised if we could find it; set on the original alert when
+    /// someone clicks its Escalate button.
+    #[serde(default)]
+    pub escalated_by: Option<String>,
+    #[serde(default)]
+    pub escalated_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub incident_url: Option<String>,
+    /// How many times the check has been reported within the dedup window;
+    /// set on the original alert as repeats are suppressed.
+    #[serde(default)]
+    pub occurrences: Option<u32>,
+    /// Slack channel to post to instead of the configured route.
+    #[serde(default)]
+    pub channel: Option<String>,
+    /// The trace following this alert through the bridge, when tracing is
+    /// enabled; see `trace`.
+    #[serde(default)]
+    pub trace_id: Option<String>,
+    /// Any additional keys in the payload, kept verbatim.
+    #[serde(flatten)]
+    pub fields: BTreeMap<String, Value>,
+}
+
+impl Alert {
+    /// Parse an alert as published. What the bridge records on the alerts
+    /// it has posted is its own to set, so is ignored if a publisher sets it.
+    pub fn from_slice(data: &[u8]) -> Result<Alert, String> {
+        let mut alert: Alert =
+            serde_json::from_slice(data).map_err(|e| format!("Unable to parse alert: {}", e))?;
+        alert.resolved_at = None;
+        alert.acknowledged_by = None;
+        alert.acknowledged_at = None;
+        alert.snoozed_by = None;
+        alert.snoozed_until = None;
+        alert.escalated_by = None;
+        alert.escalated_at = None;
+        alert.incident_url = None;
+        alert.occurrences = None;
+        Ok(alert)
+    }
+
+    /// Identifies the check this alert is about, independent of the
+    /// particular run that raised it.
+    pub fn key(&self) -> String {
+        if let Some(fingerprint) = &self.fingerprint {
+            return fingerprint.clone();
+        }
+        format!(
+            "{}/{}/{}",
+            self.source.as_deref().unwrap_or(""),
+            self.step.as_deref().unwrap_or(""),
+            self.title
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn from_slice_clears_what_the_bridge_records() {
+        let published = json!({
+            "severity": "warning",
+            "status": "failed",
+            "title": "Checkout",
+            "step": "pay",
+            "resolved_at": "2020-05-01T10:00:00Z",
+            "acknowledged_by": "U123",
+            "acknowledged_at": "2020-05-01T10:00:00Z",
+            "snoozed_by": "U123",
+            "snoozed_until": "2020-05-01T11:00:00Z",
+            "escalated_by": "U123",
+            "escalated_at": "2020-05-01T10:00:00Z",
+            "incident_url": "https://example.pagerduty.com/incidents/1",
+            "occurrences": 7,
+            "channel": "#checkout",
+            "env": "prod",
+        });
+        let alert = Alert::from_slice(published.to_string().as_bytes()).unwrap();
+        assert_eq!(alert.severity, Severity::Warning);
+        assert_eq!(alert.status, Status::Firing);
+        assert_eq!(alert.channel.as_deref(), Some("#checkout"));
+        assert_eq!(alert.fields["env"], "prod");
+        assert_eq!(alert.resolved_at, None);
+        assert_eq!(alert.acknowledged_by, None);
+        assert_eq!(alert.acknowledged_at, None);
+        assert_eq!(alert.snoozed_by, None);
+        assert_eq!(alert.snoozed_until, None);
+        assert_eq!(alert.escalated_by, None);
+        assert_eq!(alert.escalated_at, None);
+        assert_eq!(alert.incident_url, None);
+        assert_eq!(alert.occurrences, None);
+    }
+
+    #[test]
+    fn from_slice_needs_a_known_severity() {
+        assert!(Alert::from_slice(br#"{"title": "Checkout"}"#).is_err());
+        assert!(Alert::from_slice(br#"{"title": "Checkout", "severity": "high"}"#).is_err());
+    }
+
+    #[test]
+    fn key_prefers_the_fingerprint() {
+        let alert = |extra: Value| {
+            let mut published = json!({"severity": "info", "title": "Checkout", "source": "web"});
+            published
+                .as_object_mut()
+                .unwrap()
+                .extend(extra.as_object().unwrap().clone());
+            Alert::from_slice(published.to_string().as_bytes()).unwrap()
+        };
+        assert_eq!(alert(json!({})).key(), "web//Checkout");
+        assert_eq!(alert(json!({"step": "pay"})).key(), "web/pay/Checkout");
+        assert_eq!(alert(json!({"fingerprint": "checkout"})).key(), "checkout");
+    }
+
+    #[test]
+    fn matches_attributes_and_extra_fields() {
+        let alert = json!({"severity": "critical", "code": 5, "env": "prod"});
+        let when = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<BTreeMap<_, _>>()
+        };
+        assert!(matches(
+            &alert,
+            &when(&[("severity", "critical"), ("code", "5")])
+        ));
+        assert!(!matches(&alert, &when(&[("env", "dev")])));
+        assert!(!matches(&alert, &when(&[("missing", "")])));
+    }
+}
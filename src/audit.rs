@@ -0,0 +1,221 @@
+//! An append-only record of every alert received and every attempt to
+//! deliver one, so that whether an alert went out, where and when can be
+//! answered long after the fact.
+//!
+//! Records are JSON lines in a file for each day (UTC), named
+//! `audit-YYYY-MM-DD.jsonl`. Files older than the retention period are
+//! removed as each day's file is started; records are never rewritten.
+
+use crate::alert::Alert;
+use crate::config::AuditConfig;
+use crate::sink::{self, DeliveryReceipt, Notification, SinkError};
+use chrono::{DateTime, Duration, NaiveDate, SecondsFormat, Utc};
+use log::{error, info, warn};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+const PREFIX: &str = "audit-";
+const SUFFIX: &str = ".jsonl";
+
+fn timestamp(at: DateTime<Utc>) -> String {
+    at.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// The HTTP status in `error`, for destinations that gave one: sinks
+/// report them as "… returned 503 Service Unavailable".
+fn http_status(error: &str) -> Option<u16> {
+    let (_, rest) = error.split_once(" returned ")?;
+    let code = rest.split(' ').next()?;
+    code.parse().ok().filter(|code| (100..600).contains(code))
+}
+
+/// The audit log, writing to the file for the current day.
+pub struct Audit {
+    dir: PathBuf,
+    retention: Duration,
+    file: Mutex<Option<(NaiveDate, File)>>,
+}
+
+impl Audit {
+    pub fn open(cfg: &AuditConfig) -> Result<Audit, String> {
+        let dir = PathBuf::from(&cfg.dir);
+        fs::create_dir_all(&dir)
+            .map_err(|e| format!("Unable to create audit directory {}: {}", cfg.dir, e))?;
+        let retention = Duration::from_std(cfg.retention)
+            .map_err(|_| "Audit retention is too long".to_string())?;
+        Ok(Audit {
+            dir,
+            retention,
+            file: Mutex::new(None),
+        })
+    }
+
+    /// Remove the files of days before the retention period.
+    fn prune(&self, today: NaiveDate) {
+        let oldest = today - self.retention;
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Unable to list audit files: {}", e);
+                return;
+            }
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let day = name
+                .strip_prefix(PREFIX)
+                .and_then(|name| name.strip_suffix(SUFFIX))
+                .and_then(|day| NaiveDate::parse_from_str(day, "%Y-%m-%d").ok());
+            if day.is_some_and(|day| day < oldest) {
+                match fs::remove_file(entry.path()) {
+                    Ok(()) => info!("Removed expired audit file {}", name),
+                    Err(e) => warn!("Unable to remove audit file {}: {}", name, e),
+                }
+            }
+        }
+    }
+
+    /// Append `record` to today's file. Failing to is logged rather than
+    /// holding up alerts.
+    fn write(&self, record: Value) {
+        let today = Utc::now().date_naive();
+        let mut file = self.file.lock().unwrap();
+        if file.as_ref().map(|(day, _)| *day) != Some(today) {
+            let path = self
+                .dir
+                .join(format!("{}{}{}", PREFIX, today.format("%Y-%m-%d"), SUFFIX));
+            match OpenOptions::new().create(true).append(true).open(&path) {
+                Ok(opened) => *file = Some((today, opened)),
+                Err(e) => {
+                    error!("Unable to open audit file {}: {}", path.display(), e);
+                    *file = None;
+                    return;
+                }
+            }
+            self.prune(today);
+        }
+        if let Some((_, file)) = file.as_mut() {
+            let line = record.to_string() + "\n";
+            if let Err(e) = file.write_all(line.as_bytes()) {
+                error!("Unable to write audit record: {}", e);
+            }
+        }
+    }
+
+    /// Record a message taken off `queue`, and the alert read from it or
+    /// why none could be.
+    pub fn received(
+        &self,
+        queue: &str,
+        delivery: u64,
+        payload: &[u8],
+        alert: &Result<Alert, String>,
+    ) {
+        let mut record = json!({
+            "event": "received",
+            "at": timestamp(Utc::now()),
+            "queue": queue,
+            "delivery": delivery,
+        });
+        match alert {
+            Ok(alert) => {
+                record["fingerprint"] = json!(alert.key());
+                record["alert"] = json!(alert);
+            }
+            Err(e) => {
+                record["error"] = json!(e);
+                record["payload"] = json!(String::from_utf8_lossy(payload));
+            }
+        }
+        self.write(record);
+    }
+
+    /// Record an attempt to deliver `rendered` to `destination` between
+    /// `started` and `finished`.
+    pub fn attempt(
+        &self,
+        destination: &str,
+        rendered: &Notification,
+        result: &Result<DeliveryReceipt, SinkError>,
+        started: DateTime<Utc>,
+        finished: DateTime<Utc>,
+    ) {
+        let fingerprints: Vec<String> = rendered.alerts.iter().map(Alert::key).collect();
+        let mut record = json!({
+            "event": "delivery",
+            "destination": destination,
+            "fingerprints": fingerprints,
+            "outcome": sink::outcome(result),
+            "started": timestamp(started),
+            "finished": timestamp(finished),
+            "payload_sha256": hex::encode(Sha256::digest(rendered.body.to_string().as_bytes())),
+        });
+        if let Some(trace_id) = rendered
+            .alerts
+            .iter()
+            .find_map(|alert| alert.trace_id.as_ref())
+        {
+            record["trace_id"] = json!(trace_id);
+        }
+        match result {
+            Ok(receipt) => {
+                if let Some(id) = &receipt.id {
+                    record["id"] = json!(id);
+                }
+            }
+            Err(e) => {
+                let error = e.to_string();
+                record["status"] = json!(http_status(&error));
+                record["error"] = json!(error);
+            }
+        }
+        self.write(record);
+    }
+}
+
+/// The alerts recorded as received in the audit files in `dir`, oldest
+/// first, each with the queue it came from. Files of days before `since`
+/// are skipped, as nothing received then was raised after it.
+pub fn received(dir: &str, since: Option<NaiveDate>) -> Result<Vec<(String, Alert)>, String> {
+    let entries =
+        fs::read_dir(dir).map_err(|e| format!("Unable to list audit files in {}: {}", dir, e))?;
+    let mut days: Vec<(NaiveDate, PathBuf)> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let day = name
+                .strip_prefix(PREFIX)
+                .and_then(|name| name.strip_suffix(SUFFIX))
+                .and_then(|day| NaiveDate::parse_from_str(day, "%Y-%m-%d").ok())?;
+            Some((day, entry.path()))
+        })
+        .filter(|(day, _)| since.is_none_or(|since| *day >= since))
+        .collect();
+    days.sort();
+
+    let mut alerts = Vec::new();
+    for (_, path) in days {
+        let file =
+            File::open(&path).map_err(|e| format!("Unable to open {}: {}", path.display(), e))?;
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+            // A line cut short by a crash, or a record of something else.
+            let record: Value = match serde_json::from_str(&line) {
+                Ok(record) => record,
+                Err(_) => continue,
+            };
+            if record["event"] != "received" {
+                continue;
+            }
+            let queue = record["queue"].as_str().unwrap_or_default().to_string();
+            if let Ok(alert) = serde_json::from_value(record["alert"].clone()) {
+                alerts.push((queue, alert));
+            }
+        }
+    }
+    Ok(alerts)
+}
@@ -0,0 +1,95 @@
+//! Requests to AWS APIs, signed with Signature Version 4 using the
+//! credentials in `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and
+//! `AWS_SESSION_TOKEN`.
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::{Client, RequestBuilder};
+use sha2::{Digest, Sha256};
+use std::env;
+
+pub fn env_var(name: &str) -> Result<String, String> {
+    env::var(name).map_err(|_| format!("{} is not set", name))
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC takes keys of any length");
+    mac.input(data.as_bytes());
+    mac.result().code().to_vec()
+}
+
+/// The region named by `AWS_REGION` or `AWS_DEFAULT_REGION`.
+pub fn region() -> Result<String, String> {
+    env_var("AWS_REGION").or_else(|_| env_var("AWS_DEFAULT_REGION"))
+}
+
+/// A signed POST of `body` to `service` in `region`, with `headers` (named
+/// in lower case) alongside the ones signing adds.
+pub fn post(
+    client: &Client,
+    service: &str,
+    region: &str,
+    headers: &[(&str, &str)],
+    body: String,
+) -> Result<RequestBuilder, String> {
+    let access_key = env_var("AWS_ACCESS_KEY_ID")?;
+    let secret_key = env_var("AWS_SECRET_ACCESS_KEY")?;
+    let session_token = env::var("AWS_SESSION_TOKEN").ok();
+
+    let host = format!("{}.{}.amazonaws.com", service, region);
+    let now = Utc::now();
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+
+    // Headers in the canonical request must be sorted by name.
+    let mut signed: Vec<(&str, &str)> = headers.to_vec();
+    signed.push(("host", &host));
+    signed.push(("x-amz-date", &amz_date));
+    if let Some(token) = &session_token {
+        signed.push(("x-amz-security-token", token));
+    }
+    signed.sort_by_key(|&(name, _)| name);
+    let canonical_headers: String = signed
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value))
+        .collect();
+    let signed_headers = signed
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "POST\n/\n\n{}\n{}\n{}",
+        canonical_headers,
+        signed_headers,
+        hex::encode(Sha256::digest(body.as_bytes()))
+    );
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+    let key = hmac(format!("AWS4{}", secret_key).as_bytes(), &date);
+    let key = hmac(&key, region);
+    let key = hmac(&key, service);
+    let key = hmac(&key, "aws4_request");
+    let signature = hex::encode(hmac(&key, &string_to_sign));
+
+    let mut req = client.post(&format!("https://{}/", host));
+    for (name, value) in signed {
+        if name != "host" {
+            req = req.header(name, value);
+        }
+    }
+    Ok(req
+        .header(
+            "Authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                access_key, scope, signed_headers, signature
+            ),
+        )
+        .body(body))
+}
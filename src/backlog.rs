@@ -0,0 +1,116 @@
+//! How far behind the bridge is: the number of messages waiting in each
+//! queue, read from the broker every so often, kept as gauges and, past a
+//! threshold, reported so that someone knows before alerts arrive late.
+
+use crate::alert::Severity;
+use crate::router::Dispatcher;
+use crate::sink::Notification;
+use crate::source;
+use lapin::Connection;
+use log::warn;
+use serde_json::json;
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Watches the depth of the queues over whichever connection is current.
+#[derive(Default)]
+pub struct Backlog {
+    conn: RwLock<Option<Connection>>,
+    /// Queues past the threshold when last read.
+    behind: Mutex<BTreeSet<String>>,
+}
+
+impl Backlog {
+    /// Read the queues over a new broker connection from now on.
+    pub fn attach(&self, conn: Connection) {
+        *self.conn.write().unwrap() = Some(conn);
+    }
+
+    /// The queues newly past `threshold` and those newly back under it.
+    fn crossed(&self, depths: &[(String, u32)], threshold: u32) -> (Vec<String>, Vec<String>) {
+        let mut behind = self.behind.lock().unwrap();
+        let (mut over, mut under) = (Vec::new(), Vec::new());
+        for (queue, messages) in depths {
+            if *messages > threshold {
+                if behind.insert(queue.clone()) {
+                    over.push(format!("{} ({} messages)", queue, messages));
+                }
+            } else if behind.remove(queue) {
+                under.push(queue.clone());
+            }
+        }
+        (over, under)
+    }
+
+    /// Read the depth of the queues as the configuration in force says.
+    /// Runs until the process exits.
+    pub async fn watch(self: Arc<Self>, dispatcher: Arc<Dispatcher>) {
+        loop {
+            let cfg = dispatcher.cfg();
+            let queue_depth = match &cfg.queue_depth {
+                Some(queue_depth) => queue_depth,
+                None => return,
+            };
+            tokio::time::delay_for(queue_depth.interval).await;
+            let conn = match &*self.conn.read().unwrap() {
+                Some(conn) if conn.status().connected() => conn.clone(),
+                _ => continue,
+            };
+            let queues: Vec<String> = cfg.queues.iter().map(|queue| queue.name.clone()).collect();
+            let depths = match source::queue_depths(&conn, &queues).await {
+                Ok(depths) => depths,
+                Err(e) => {
+                    warn!("Unable to read the depth of the queues: {:?}", e);
+                    continue;
+                }
+            };
+            for (queue, (messages, consumers)) in queues.iter().zip(depths.iter()) {
+                dispatcher.metrics.queue_depth(queue, *messages, *consumers);
+            }
+
+            let threshold = match queue_depth.threshold {
+                Some(threshold) => threshold,
+                None => continue,
+            };
+            let depths: Vec<(String, u32)> = queues
+                .into_iter()
+                .zip(depths.iter().map(|(messages, _)| *messages))
+                .collect();
+            let (over, under) = self.crossed(&depths, threshold);
+            if !over.is_empty() {
+                warn!("Falling behind on {}", over.join(", "));
+            }
+            let receiver = match &queue_depth.receiver {
+                Some(receiver) => receiver,
+                None => continue,
+            };
+            let reports = [
+                (
+                    over,
+                    Severity::Warning,
+                    ":warning: *2steps-slack-alert* is falling behind on",
+                ),
+                (
+                    under,
+                    Severity::Info,
+                    ":white_check_mark: *2steps-slack-alert* has caught up on",
+                ),
+            ];
+            for (queues, severity, text) in reports.iter() {
+                if queues.is_empty() {
+                    continue;
+                }
+                let text = format!("{} {}", text, queues.join(", "));
+                let rendered = Notification {
+                    alerts: Vec::new(),
+                    severity: *severity,
+                    summary: text.clone(),
+                    body: json!({ "text": text }),
+                };
+                if let Err(e) = dispatcher.send_to(receiver, rendered).await {
+                    warn!("Unable to report queue depth to {}: {}", receiver, e);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,34 @@
+use rand::Rng;
+use std::time::Duration;
+
+/// Exponential backoff with jitter: each delay doubles up to `max`, and the
+/// actual wait is drawn from the upper half of the current window so that a
+/// fleet of restarting bridges doesn't stampede the broker in lockstep.
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Backoff {
+        Backoff {
+            base,
+            max,
+            current: base,
+        }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let window = self.current;
+        self.current = std::cmp::min(self.current * 2, self.max);
+
+        let half = window / 2;
+        let jitter = rand::thread_rng().gen_range(0, half.as_millis() as u64 + 1);
+        half + Duration::from_millis(jitter)
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.base;
+    }
+}
@@ -0,0 +1,37 @@
+//! The `check` subcommand, which validates a configuration file without
+//! connecting to anything, so that changes can be tested before they are
+//! deployed.
+
+use crate::config::{read_config, ConfigSource};
+use crate::render::Templates;
+
+/// Load the configuration as the service would at startup and summarise
+/// it, or describe the first problem found.
+pub fn check(source: &ConfigSource) -> Result<String, String> {
+    let cfg = read_config(source)?;
+    let brokers = cfg.rabbit.uris()?;
+    Templates::load(
+        &cfg.templates,
+        &cfg.routing,
+        &cfg.styles,
+        cfg.pagerduty.is_some(),
+        cfg.email.as_ref(),
+    )?;
+
+    let queues: Vec<&str> = cfg.queues.iter().map(|q| q.name.as_str()).collect();
+    let bindings: usize = cfg.queues.iter().map(|q| q.bindings.len()).sum();
+    let name = match &source.profile {
+        Some(profile) => format!("{} with profile {}", source.path, profile),
+        None => source.path.clone(),
+    };
+    Ok(format!(
+        "{} is valid\n  brokers: {}\n  exchange: {}\n  queues: {} ({} bindings)\n  receivers: {}\n  schedules: {}",
+        name,
+        brokers.len(),
+        cfg.exchange.name,
+        queues.join(", "),
+        bindings,
+        cfg.routing.receivers.len(),
+        cfg.routing.schedules.len(),
+    ))
+}
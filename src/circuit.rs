@@ -0,0 +1,220 @@
+use crate::config::CircuitBreakerConfig;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CircuitState {
+    /// Deliveries go through as normal.
+    Closed,
+    /// The destination is failing; deliveries are not attempted.
+    Open,
+    /// The cool-down has passed and a single trial delivery is in flight.
+    HalfOpen,
+}
+
+impl CircuitState {
+    fn name(self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half-open",
+        }
+    }
+}
+
+struct Circuit {
+    state: CircuitState,
+    failures: u32,
+    opened_at: Instant,
+}
+
+/// How the circuit for one destination stands.
+pub struct CircuitStatus {
+    pub name: String,
+    /// `closed`, `open` or `half-open`.
+    pub state: &'static str,
+    /// Consecutive failed deliveries.
+    pub failures: u32,
+    /// How long it has been open, if it is not closed.
+    pub open_for: Option<Duration>,
+}
+
+/// Per-destination circuit breakers, keyed by a name that is safe to log.
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cool_down: Duration,
+    circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(cfg: &CircuitBreakerConfig) -> CircuitBreaker {
+        CircuitBreaker {
+            failure_threshold: cfg.failure_threshold,
+            cool_down: cfg.cool_down,
+            circuits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn update<T>(&self, name: &str, f: impl FnOnce(&mut Circuit) -> T) -> T {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry(name.to_string()).or_insert_with(|| Circuit {
+            state: CircuitState::Closed,
+            failures: 0,
+            opened_at: Instant::now(),
+        });
+        f(circuit)
+    }
+
+    /// Whether a delivery to `name` may be attempted now. Once the cool-down
+    /// has passed, one caller is let through to probe the destination.
+    pub fn allow(&self, name: &str) -> bool {
+        let cool_down = self.cool_down;
+        self.update(name, |circuit| match circuit.state {
+            CircuitState::Closed => true,
+            CircuitState::Open if circuit.opened_at.elapsed() >= cool_down => {
+                info!("Circuit for {} is half-open, trying a delivery", name);
+                circuit.state = CircuitState::HalfOpen;
+                true
+            }
+            CircuitState::Open | CircuitState::HalfOpen => false,
+        })
+    }
+
+    /// Names of the destinations not currently taking deliveries as normal.
+    pub fn open_circuits(&self) -> Vec<String> {
+        let circuits = self.circuits.lock().unwrap();
+        let mut names: Vec<String> = circuits
+            .iter()
+            .filter(|(_, circuit)| circuit.state != CircuitState::Closed)
+            .map(|(name, _)| name.clone())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Every destination delivered to so far, and how its circuit stands.
+    pub fn circuits(&self) -> Vec<CircuitStatus> {
+        let circuits = self.circuits.lock().unwrap();
+        let mut statuses: Vec<CircuitStatus> = circuits
+            .iter()
+            .map(|(name, circuit)| CircuitStatus {
+                name: name.clone(),
+                state: circuit.state.name(),
+                failures: circuit.failures,
+                open_for: (circuit.state != CircuitState::Closed)
+                    .then(|| circuit.opened_at.elapsed()),
+            })
+            .collect();
+        statuses.sort_by(|a, b| a.name.cmp(&b.name));
+        statuses
+    }
+
+    /// Whether deliveries have been tried and every destination they went
+    /// to is failing.
+    pub fn all_open(&self) -> bool {
+        let circuits = self.circuits.lock().unwrap();
+        !circuits.is_empty()
+            && circuits
+                .values()
+                .all(|circuit| circuit.state != CircuitState::Closed)
+    }
+
+    pub fn record_success(&self, name: &str) {
+        self.update(name, |circuit| {
+            if circuit.state != CircuitState::Closed {
+                info!("Circuit for {} closed", name);
+            }
+            circuit.state = CircuitState::Closed;
+            circuit.failures = 0;
+        })
+    }
+
+    /// Count a failure, returning whether it opened the circuit.
+    pub fn record_failure(&self, name: &str) -> bool {
+        let threshold = self.failure_threshold;
+        let cool_down = self.cool_down;
+        self.update(name, |circuit| {
+            circuit.failures += 1;
+            let trip = match circuit.state {
+                CircuitState::Closed => circuit.failures >= threshold,
+                CircuitState::HalfOpen => true,
+                CircuitState::Open => false,
+            };
+            if trip {
+                warn!(
+                    "Circuit for {} opened after {} consecutive failures, pausing for {:?}",
+                    name, circuit.failures, cool_down
+                );
+                circuit.state = CircuitState::Open;
+                circuit.opened_at = Instant::now();
+            }
+            trip
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(cool_down: Duration) -> CircuitBreaker {
+        CircuitBreaker::new(&CircuitBreakerConfig {
+            failure_threshold: 2,
+            cool_down,
+        })
+    }
+
+    #[test]
+    fn opens_after_the_threshold() {
+        let breaker = breaker(Duration::from_secs(3600));
+        assert!(breaker.allow("slack"));
+        assert!(!breaker.record_failure("slack"));
+        assert!(breaker.allow("slack"));
+        assert!(breaker.record_failure("slack"));
+        assert!(!breaker.allow("slack"));
+        assert!(!breaker.record_failure("slack"));
+        assert_eq!(breaker.open_circuits(), ["slack"]);
+        assert!(breaker.all_open());
+
+        assert!(breaker.allow("email"));
+        breaker.record_success("email");
+        assert!(!breaker.all_open());
+    }
+
+    #[test]
+    fn a_success_resets_the_failures() {
+        let breaker = breaker(Duration::from_secs(3600));
+        breaker.record_failure("slack");
+        breaker.record_success("slack");
+        assert!(!breaker.record_failure("slack"));
+        assert!(breaker.allow("slack"));
+    }
+
+    #[test]
+    fn lets_one_trial_through_after_the_cool_down() {
+        let breaker = breaker(Duration::from_secs(0));
+        breaker.record_failure("slack");
+        breaker.record_failure("slack");
+        assert!(breaker.allow("slack"));
+        assert!(!breaker.allow("slack"));
+        assert_eq!(breaker.circuits()[0].state, "half-open");
+
+        // A failed trial opens it again straight away.
+        assert!(breaker.record_failure("slack"));
+        assert_eq!(breaker.circuits()[0].state, "open");
+
+        assert!(breaker.allow("slack"));
+        breaker.record_success("slack");
+        let status = &breaker.circuits()[0];
+        assert_eq!((status.state, status.failures), ("closed", 0));
+        assert!(status.open_for.is_none());
+        assert!(breaker.open_circuits().is_empty());
+    }
+
+    #[test]
+    fn is_not_all_open_before_any_delivery() {
+        assert!(!breaker(Duration::from_secs(0)).all_open());
+    }
+}
@@ -0,0 +1,114 @@
+//! The `/2steps` slash command, for checking on the bridge and silencing
+//! alerts from Slack.
+
+use crate::router::Dispatcher;
+use crate::silence::{self, Silence};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+const USAGE: &str = "Usage: `/2steps status`, `/2steps silence key=value... <duration>` \
+                     (for example `service=checkout 2h`) or `/2steps silences`";
+
+/// A slash command invocation, as sent by Slack.
+#[derive(Deserialize)]
+pub struct Command {
+    #[serde(default)]
+    pub text: String,
+    pub user_id: String,
+    /// Where replies are posted; valid for half an hour.
+    pub response_url: String,
+}
+
+fn describe(silence: &Silence) -> String {
+    let by = match silence.created_by.as_str() {
+        silence::CONFIG_CREATOR => silence::CONFIG_CREATOR.to_string(),
+        user => format!("<@{}>", user),
+    };
+    let mut text = format!(
+        "#{} {} until {} (by {})",
+        silence.id,
+        silence.describe(),
+        silence.ends_at.format("%Y-%m-%d %H:%M UTC"),
+        by
+    );
+    if let Some(comment) = &silence.comment {
+        text.push_str(&format!(": {}", comment));
+    }
+    text
+}
+
+async fn status(dispatcher: &Dispatcher) -> String {
+    let open = dispatcher.breaker.open_circuits();
+    let mut lines = vec![
+        "*2steps-slack-alert is running*".to_string(),
+        format!(
+            "Open circuits: {}",
+            if open.is_empty() {
+                "none".to_string()
+            } else {
+                open.join(", ")
+            }
+        ),
+    ];
+    if let Some(spool) = &dispatcher.spool {
+        lines.push(format!("Spooled alerts: {}", spool.pending().await.len()));
+    }
+    lines.push(format!(
+        "Active silences: {}",
+        dispatcher.silences.active().len()
+    ));
+    lines.push(format!("Tracked messages: {}", dispatcher.store.count()));
+    let dropped = dispatcher.dropped.lock().unwrap();
+    if !dropped.is_empty() {
+        let counts: Vec<String> = dropped
+            .iter()
+            .map(|(rule, n)| format!("{} {}", n, rule))
+            .collect();
+        lines.push(format!("Dropped alerts: {}", counts.join(", ")));
+    }
+    lines.join("\n")
+}
+
+fn silence(dispatcher: &Dispatcher, args: &[&str], user: &str) -> String {
+    let mut matchers = BTreeMap::new();
+    let mut duration = None;
+    for arg in args {
+        match arg.split_once('=') {
+            Some((key, value)) if !key.is_empty() => {
+                matchers.insert(key.to_string(), value.to_string());
+            }
+            _ => match (silence::parse_duration(arg), duration) {
+                (Some(d), None) => duration = Some(d),
+                _ => return format!("I don't understand `{}`. {}", arg, USAGE),
+            },
+        }
+    }
+    let duration = match (matchers.is_empty(), duration) {
+        (false, Some(duration)) => duration,
+        _ => return USAGE.to_string(),
+    };
+
+    match dispatcher.silences.add(matchers, duration, user) {
+        Ok(silence) => format!("Silenced {}", describe(&silence)),
+        Err(e) => format!("Unable to add silence: {}", e),
+    }
+}
+
+fn silences(dispatcher: &Dispatcher) -> String {
+    let active = dispatcher.silences.active();
+    if active.is_empty() {
+        return "No silences are active.".to_string();
+    }
+    active.iter().map(describe).collect::<Vec<_>>().join("\n")
+}
+
+/// Carry out the command, returning the reply to show the user.
+pub async fn run(dispatcher: &Dispatcher, command: &Command) -> String {
+    let words: Vec<&str> = command.text.split_whitespace().collect();
+    match words.split_first() {
+        Some((&"status", [])) => status(dispatcher).await,
+        Some((&"silence", args)) => silence(dispatcher, args, &command.user_id),
+        Some((&"silences", [])) => silences(dispatcher),
+        _ => USAGE.to_string(),
+    }
+}
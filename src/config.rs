@@ -0,0 +1,4208 @@
+use crate::alert::{self, Severity, Status};
+use crate::expr::Expr;
+use crate::secret;
+use chrono::{DateTime, Datelike, NaiveDate, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+use lapin::uri::{AMQPAuthority, AMQPQueryString, AMQPScheme, AMQPUri, AMQPUserInfo};
+use lapin::ExchangeKind;
+use log::info;
+use regex::Regex;
+use serde::de::{self, DeserializeOwned, Deserializer, MapAccess, Visitor};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// A section that may be given as a bare URL instead of a mapping.
+enum UrlOr<T> {
+    Url(String),
+    Section(T),
+}
+
+/// Deserialize a section by way of its raw form `R`, checking it with
+/// `TryFrom`. The check runs while the section's mapping is still being
+/// read, so that its errors carry the section's path and line.
+fn checked<'de, D, R, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    R: Deserialize<'de>,
+    T: TryFrom<R, Error = String>,
+{
+    struct CheckedVisitor<R, T>(PhantomData<(R, T)>);
+
+    impl<'de, R, T> Visitor<'de> for CheckedVisitor<R, T>
+    where
+        R: Deserialize<'de>,
+        T: TryFrom<R, Error = String>,
+    {
+        type Value = T;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a mapping")
+        }
+
+        fn visit_map<M: MapAccess<'de>>(self, map: M) -> Result<T, M::Error> {
+            let raw = R::deserialize(de::value::MapAccessDeserializer::new(map))?;
+            T::try_from(raw).map_err(de::Error::custom)
+        }
+    }
+
+    deserializer.deserialize_map(CheckedVisitor(PhantomData))
+}
+
+/// As `checked`, for sections that may be given as a bare URL.
+fn checked_url_or<'de, D, R, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    R: Deserialize<'de>,
+    T: TryFrom<UrlOr<R>, Error = String>,
+{
+    struct UrlOrVisitor<R, T>(PhantomData<(R, T)>);
+
+    impl<'de, R, T> Visitor<'de> for UrlOrVisitor<R, T>
+    where
+        R: Deserialize<'de>,
+        T: TryFrom<UrlOr<R>, Error = String>,
+    {
+        type Value = T;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a URL or a mapping")
+        }
+
+        fn visit_str<E: de::Error>(self, url: &str) -> Result<T, E> {
+            T::try_from(UrlOr::Url(url.to_string())).map_err(E::custom)
+        }
+
+        fn visit_map<M: MapAccess<'de>>(self, map: M) -> Result<T, M::Error> {
+            let raw = R::deserialize(de::value::MapAccessDeserializer::new(map))?;
+            T::try_from(UrlOr::Section(raw)).map_err(de::Error::custom)
+        }
+    }
+
+    deserializer.deserialize_any(UrlOrVisitor(PhantomData))
+}
+
+/// Implement `Deserialize` for a section through `checked`, or
+/// `checked_url_or` if it may be given as a bare URL.
+macro_rules! checked {
+    ($ty:ty, url_or $raw:ty) => {
+        impl<'de> Deserialize<'de> for $ty {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<$ty, D::Error> {
+                checked_url_or::<_, $raw, _>(deserializer)
+            }
+        }
+    };
+    ($ty:ty, $raw:ty) => {
+        impl<'de> Deserialize<'de> for $ty {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<$ty, D::Error> {
+                checked::<_, $raw, _>(deserializer)
+            }
+        }
+    };
+}
+
+/// The settings of a section, or its defaults if it was left out.
+fn or_defaults<R, T>(section: Option<T>) -> Result<T, String>
+where
+    R: Default,
+    T: TryFrom<R, Error = String>,
+{
+    section.map_or_else(|| T::try_from(R::default()), Ok)
+}
+
+/// Check that `url` can be posted to, unless it refers to a secret to be
+/// resolved later. The URL is left out of the error, as webhook URLs are
+/// secrets.
+pub fn webhook(url: String) -> Result<String, String> {
+    if secret::is_reference(&url) {
+        return Ok(url);
+    }
+    match reqwest::Url::parse(&url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") && parsed.has_host() => Ok(url),
+        Ok(_) => Err("webhook URLs must be http or https".to_string()),
+        Err(e) => Err(format!("invalid webhook URL: {}", e)),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRoute {
+    channel: Option<String>,
+    url: Option<String>,
+}
+
+/// Where alerts are posted: an incoming webhook, or a channel via the Web
+/// API using the bot token. Given either as a bare webhook URL or as a
+/// mapping with `url` or `channel`.
+#[derive(Clone)]
+pub enum Route {
+    Webhook(String),
+    Channel(String),
+}
+impl TryFrom<UrlOr<RawRoute>> for Route {
+    type Error = String;
+
+    fn try_from(raw: UrlOr<RawRoute>) -> Result<Route, Self::Error> {
+        match raw {
+            UrlOr::Url(url) => Ok(Route::Webhook(webhook(url)?)),
+            UrlOr::Section(RawRoute {
+                channel: Some(channel),
+                ..
+            }) => Ok(Route::Channel(channel)),
+            UrlOr::Section(RawRoute { url: Some(url), .. }) => Ok(Route::Webhook(webhook(url)?)),
+            UrlOr::Section(_) => Err("routes need a webhook URL or a channel".to_string()),
+        }
+    }
+}
+checked!(Route, url_or RawRoute);
+
+/// Check that `arn` names an SNS topic, such as
+/// `arn:aws:sns:eu-west-1:123456789012:alerts`.
+fn sns_topic(arn: &str) -> Result<(), String> {
+    match arn.split(':').collect::<Vec<_>>().as_slice() {
+        ["arn", _, "sns", region, account, topic]
+            if !region.is_empty() && !account.is_empty() && !topic.is_empty() =>
+        {
+            Ok(())
+        }
+        _ => Err(format!("{} is not the ARN of an SNS topic", arn)),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawReceiver {
+    channel: Option<String>,
+    url: Option<String>,
+    teams: Option<String>,
+    discord: Option<String>,
+    google_chat: Option<String>,
+    mattermost: Option<MattermostReceiver>,
+    telegram: Option<TelegramReceiver>,
+    matrix: Option<String>,
+    webex: Option<String>,
+    pushover: Option<PushoverReceiver>,
+    sms: Option<Vec<String>>,
+    sns: Option<String>,
+    pagerduty: Option<PagerDutyReceiver>,
+    opsgenie: Option<OpsgenieReceiver>,
+    jira: Option<JiraReceiver>,
+    servicenow: Option<ServiceNowReceiver>,
+    syslog: Option<SyslogReceiver>,
+    email: Option<EmailReceiver>,
+    webhook: Option<WebhookReceiver>,
+}
+
+/// A named destination for routes: a Slack route, given as for `Route`, or
+/// another kind of destination under a key naming the kind.
+#[derive(Clone)]
+pub enum Receiver {
+    Slack(Route),
+    /// A Microsoft Teams incoming webhook URL.
+    Teams(String),
+    /// A Discord channel webhook URL.
+    Discord(String),
+    /// A Google Chat space webhook URL.
+    GoogleChat(String),
+    Mattermost(MattermostReceiver),
+    Telegram(TelegramReceiver),
+    /// The ID or alias of a Matrix room, posted to through the homeserver
+    /// under `matrix`.
+    Matrix(String),
+    /// The ID of a Webex room, posted to by the bot under `webex`.
+    Webex(String),
+    Pushover(PushoverReceiver),
+    /// Phone numbers texted through the Twilio account under `twilio`.
+    Sms(Vec<String>),
+    /// The ARN of an SNS topic, published to with the credentials in the
+    /// environment.
+    Sns(String),
+    PagerDuty(PagerDutyReceiver),
+    Opsgenie(OpsgenieReceiver),
+    Jira(JiraReceiver),
+    ServiceNow(ServiceNowReceiver),
+    Syslog(SyslogReceiver),
+    Email(EmailReceiver),
+    Webhook(WebhookReceiver),
+}
+impl TryFrom<UrlOr<RawReceiver>> for Receiver {
+    type Error = String;
+
+    fn try_from(raw: UrlOr<RawReceiver>) -> Result<Receiver, Self::Error> {
+        let raw = match raw {
+            UrlOr::Url(url) => return Ok(Receiver::Slack(Route::Webhook(webhook(url)?))),
+            UrlOr::Section(raw) => raw,
+        };
+        let slack = raw.channel.is_some() || raw.url.is_some();
+        let kinds = [
+            slack,
+            raw.teams.is_some(),
+            raw.discord.is_some(),
+            raw.google_chat.is_some(),
+            raw.mattermost.is_some(),
+            raw.telegram.is_some(),
+            raw.matrix.is_some(),
+            raw.webex.is_some(),
+            raw.pushover.is_some(),
+            raw.sms.is_some(),
+            raw.sns.is_some(),
+            raw.pagerduty.is_some(),
+            raw.opsgenie.is_some(),
+            raw.jira.is_some(),
+            raw.servicenow.is_some(),
+            raw.syslog.is_some(),
+            raw.email.is_some(),
+            raw.webhook.is_some(),
+        ];
+        if kinds.iter().filter(|&&kind| kind).count() > 1 {
+            return Err("receivers go to one kind of destination".to_string());
+        }
+
+        if let Some(url) = raw.teams {
+            return Ok(Receiver::Teams(webhook(url)?));
+        }
+        if let Some(url) = raw.discord {
+            return Ok(Receiver::Discord(webhook(url)?));
+        }
+        if let Some(url) = raw.google_chat {
+            return Ok(Receiver::GoogleChat(webhook(url)?));
+        }
+        if let Some(webhook) = raw.mattermost {
+            return Ok(Receiver::Mattermost(webhook));
+        }
+        if let Some(chat) = raw.telegram {
+            return Ok(Receiver::Telegram(chat));
+        }
+        if let Some(room) = raw.matrix {
+            let sigil = room.starts_with('!') || room.starts_with('#');
+            if !sigil || !room.contains(':') {
+                return Err("Matrix rooms are given as !id:server or #alias:server".to_string());
+            }
+            return Ok(Receiver::Matrix(room));
+        }
+        if let Some(room) = raw.webex {
+            if room.is_empty() || room.contains(char::is_whitespace) {
+                return Err("webex takes the ID of a room".to_string());
+            }
+            return Ok(Receiver::Webex(room));
+        }
+        if let Some(user) = raw.pushover {
+            return Ok(Receiver::Pushover(user));
+        }
+        if let Some(numbers) = raw.sms {
+            if numbers.is_empty() {
+                return Err("sms needs at least one number".to_string());
+            }
+            for number in &numbers {
+                phone_number(number)?;
+            }
+            return Ok(Receiver::Sms(numbers));
+        }
+        if let Some(topic) = raw.sns {
+            sns_topic(&topic)?;
+            return Ok(Receiver::Sns(topic));
+        }
+        if let Some(service) = raw.pagerduty {
+            return Ok(Receiver::PagerDuty(service));
+        }
+        if let Some(service) = raw.opsgenie {
+            return Ok(Receiver::Opsgenie(service));
+        }
+        if let Some(project) = raw.jira {
+            return Ok(Receiver::Jira(project));
+        }
+        if let Some(instance) = raw.servicenow {
+            return Ok(Receiver::ServiceNow(instance));
+        }
+        if let Some(server) = raw.syslog {
+            return Ok(Receiver::Syslog(server));
+        }
+        if let Some(recipients) = raw.email {
+            return Ok(Receiver::Email(recipients));
+        }
+        if let Some(webhook) = raw.webhook {
+            return Ok(Receiver::Webhook(webhook));
+        }
+        Route::try_from(UrlOr::Section(RawRoute {
+            channel: raw.channel,
+            url: raw.url,
+        }))
+        .map(Receiver::Slack)
+    }
+}
+checked!(Receiver, url_or RawReceiver);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawSlack {
+    url: Option<String>,
+    channel: Option<String>,
+    token: Option<String>,
+    fallback_url: Option<String>,
+    #[serde(default)]
+    severity: HashMap<Severity, Route>,
+}
+
+#[derive(Clone)]
+pub struct SlackConfig {
+    pub route: Route,
+    /// Bot token for `chat.postMessage`, needed for channel routes and for
+    /// alerts that name their own channel.
+    pub token: Option<String>,
+    /// Secondary webhook used while the circuit for `route` is open.
+    pub fallback_url: Option<String>,
+    /// Routes for particular severities, used instead of `route`.
+    pub severity_routes: HashMap<Severity, Route>,
+}
+impl TryFrom<UrlOr<RawSlack>> for SlackConfig {
+    type Error = String;
+
+    fn try_from(raw: UrlOr<RawSlack>) -> Result<SlackConfig, Self::Error> {
+        let raw = match raw {
+            UrlOr::Url(url) => RawSlack {
+                url: Some(url),
+                channel: None,
+                token: None,
+                fallback_url: None,
+                severity: HashMap::new(),
+            },
+            UrlOr::Section(raw) => raw,
+        };
+        let route = match (raw.channel, raw.url) {
+            (Some(channel), _) => Route::Channel(channel),
+            (None, Some(url)) => Route::Webhook(webhook(url)?),
+            (None, None) => return Err("a webhook URL or a channel is required".to_string()),
+        };
+
+        let uses_channels = std::iter::once(&route)
+            .chain(raw.severity.values())
+            .any(|r| matches!(r, Route::Channel(_)));
+        if uses_channels && raw.token.is_none() {
+            return Err("token is required to post to channels".to_string());
+        }
+
+        Ok(SlackConfig {
+            route,
+            token: raw.token,
+            fallback_url: raw.fallback_url.map(webhook).transpose()?,
+            severity_routes: raw.severity,
+        })
+    }
+}
+checked!(SlackConfig, url_or RawSlack);
+
+impl SlackConfig {
+    /// The route for alerts of the given severity.
+    pub fn route_for(&self, severity: Severity) -> &Route {
+        self.severity_routes.get(&severity).unwrap_or(&self.route)
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConsumer {
+    max_attempts: Option<u32>,
+    prefetch: Option<u16>,
+    concurrency: Option<usize>,
+    ordered: Option<bool>,
+    shutdown_timeout: Option<u64>,
+    retry_delays: Option<Vec<u64>>,
+}
+
+pub struct ConsumerConfig {
+    pub max_attempts: u32,
+    /// Unacked deliveries the broker may push to us at once.
+    pub prefetch: u16,
+    /// Deliveries processed at the same time.
+    pub concurrency: usize,
+    /// Keep alerts about the same check in arrival order, at the cost of
+    /// pinning each check to a single worker.
+    pub ordered: bool,
+    /// How long to wait for in-flight deliveries when stopping.
+    pub shutdown_timeout: Duration,
+    /// Delay before each successive retry of a failed delivery.
+    pub retry_delays: Vec<Duration>,
+}
+impl TryFrom<RawConsumer> for ConsumerConfig {
+    type Error = String;
+
+    fn try_from(raw: RawConsumer) -> Result<ConsumerConfig, Self::Error> {
+        let max_attempts = raw.max_attempts.unwrap_or(5);
+        if max_attempts < 1 {
+            return Err("max_attempts must be at least 1".to_string());
+        }
+        let prefetch = raw.prefetch.unwrap_or(10);
+        if prefetch < 1 {
+            return Err("prefetch must be between 1 and 65535".to_string());
+        }
+        let concurrency = raw.concurrency.unwrap_or(1);
+        if concurrency < 1 {
+            return Err("concurrency must be at least 1".to_string());
+        }
+
+        let retry_delays = raw.retry_delays.unwrap_or_else(|| vec![30, 120, 600]);
+        if retry_delays.contains(&0) {
+            return Err("retry_delays must be a list of positive seconds".to_string());
+        }
+        // Each delay has a queue named after it in seconds.
+        if let Some(delay) = retry_delays
+            .iter()
+            .enumerate()
+            .find_map(|(i, delay)| retry_delays[..i].contains(delay).then_some(delay))
+        {
+            return Err(format!("retry_delays lists {} more than once", delay));
+        }
+
+        Ok(ConsumerConfig {
+            max_attempts,
+            prefetch,
+            concurrency,
+            ordered: raw.ordered.unwrap_or(true),
+            shutdown_timeout: Duration::from_secs(raw.shutdown_timeout.unwrap_or(30)),
+            retry_delays: retry_delays.into_iter().map(Duration::from_secs).collect(),
+        })
+    }
+}
+checked!(ConsumerConfig, RawConsumer);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawProxy {
+    url: String,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+/// Proxy for outgoing HTTP requests. Without one, the usual `HTTP_PROXY`
+/// and `HTTPS_PROXY` environment variables are honoured.
+pub struct ProxyConfig {
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+impl TryFrom<UrlOr<RawProxy>> for ProxyConfig {
+    type Error = String;
+
+    fn try_from(raw: UrlOr<RawProxy>) -> Result<ProxyConfig, Self::Error> {
+        Ok(match raw {
+            UrlOr::Url(url) => ProxyConfig {
+                url,
+                username: None,
+                password: None,
+            },
+            UrlOr::Section(raw) => ProxyConfig {
+                url: raw.url,
+                username: raw.username,
+                password: raw.password,
+            },
+        })
+    }
+}
+checked!(ProxyConfig, url_or RawProxy);
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawHttp {
+    connect_timeout: Option<u64>,
+    request_timeout: Option<u64>,
+    retries: Option<u32>,
+    retry_initial_delay: Option<u64>,
+    retry_max_delay: Option<u64>,
+    proxy: Option<ProxyConfig>,
+    pool_idle_timeout: Option<u64>,
+    pool_max_idle_per_host: Option<usize>,
+    tcp_keepalive: Option<u64>,
+}
+
+/// Settings for the HTTP client used to deliver notifications.
+pub struct HttpConfig {
+    pub connect_timeout: Duration,
+    /// Limit on a whole request, from connecting to reading the response.
+    pub request_timeout: Duration,
+    /// Further attempts made straight away after a network failure or server
+    /// error, before the delivery is handed back to the broker to retry.
+    pub retries: u32,
+    pub retry_initial_delay: Duration,
+    pub retry_max_delay: Duration,
+    pub proxy: Option<ProxyConfig>,
+    /// How long an unused pooled connection is kept open.
+    pub pool_idle_timeout: Duration,
+    pub pool_max_idle_per_host: Option<usize>,
+    /// TCP keep-alive interval, or `None` to leave it off.
+    pub tcp_keepalive: Option<Duration>,
+}
+impl TryFrom<RawHttp> for HttpConfig {
+    type Error = String;
+
+    fn try_from(raw: RawHttp) -> Result<HttpConfig, Self::Error> {
+        let seconds =
+            |value: Option<u64>, default: u64| Duration::from_secs(value.unwrap_or(default));
+        let connect_timeout = seconds(raw.connect_timeout, 10);
+        let request_timeout = seconds(raw.request_timeout, 30);
+        if connect_timeout.as_secs() == 0 || request_timeout.as_secs() == 0 {
+            return Err("timeouts must be at least 1 second".to_string());
+        }
+        let retry_initial_delay = seconds(raw.retry_initial_delay, 1);
+        let retry_max_delay = seconds(raw.retry_max_delay, 10);
+        if retry_max_delay < retry_initial_delay {
+            return Err("retry_max_delay must not be less than retry_initial_delay".to_string());
+        }
+
+        Ok(HttpConfig {
+            connect_timeout,
+            request_timeout,
+            retries: raw.retries.unwrap_or(2),
+            retry_initial_delay,
+            retry_max_delay,
+            proxy: raw.proxy,
+            pool_idle_timeout: seconds(raw.pool_idle_timeout, 90),
+            pool_max_idle_per_host: raw.pool_max_idle_per_host,
+            tcp_keepalive: Some(seconds(raw.tcp_keepalive, 60)).filter(|d| d.as_secs() > 0),
+        })
+    }
+}
+checked!(HttpConfig, RawHttp);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawSpool {
+    path: String,
+    max_bytes: Option<u64>,
+    after: Option<u64>,
+    replay_interval: Option<u64>,
+}
+
+/// On-disk spool for alerts that cannot reach Slack during a long outage.
+pub struct SpoolConfig {
+    pub path: String,
+    /// The spool refuses new alerts once the file reaches this size, leaving
+    /// them with the broker instead.
+    pub max_bytes: u64,
+    /// How long a destination must have been failing before its alerts are
+    /// spooled rather than retried through the broker.
+    pub after: Duration,
+    /// Pause between attempts to replay the spool.
+    pub replay_interval: Duration,
+}
+impl TryFrom<RawSpool> for SpoolConfig {
+    type Error = String;
+
+    fn try_from(raw: RawSpool) -> Result<SpoolConfig, Self::Error> {
+        let max_bytes = raw.max_bytes.unwrap_or(64 * 1024 * 1024);
+        if max_bytes < 1 {
+            return Err("max_bytes must be at least 1".to_string());
+        }
+        let seconds = |key: &str, value: Option<u64>, default: u64| match value {
+            Some(0) => Err(format!("{} must be at least 1", key)),
+            value => Ok(Duration::from_secs(value.unwrap_or(default))),
+        };
+
+        Ok(SpoolConfig {
+            path: raw.path,
+            max_bytes,
+            after: seconds("after", raw.after, 300)?,
+            replay_interval: seconds("replay_interval", raw.replay_interval, 30)?,
+        })
+    }
+}
+checked!(SpoolConfig, RawSpool);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawInteractions {
+    listen: Option<String>,
+    signing_secret: Option<String>,
+    app_token: Option<String>,
+    exchange: Option<String>,
+    routing_key: Option<String>,
+}
+
+/// How Slack interaction payloads, such as button clicks, reach us, and
+/// where the events they produce are published. Payloads arrive over HTTP
+/// when there is a signing secret to check them with, and over a Socket Mode
+/// websocket when there is an app-level token; at least one is required.
+pub struct InteractionsConfig {
+    pub listen: SocketAddr,
+    /// Used to check that HTTP requests really come from Slack.
+    pub signing_secret: Option<String>,
+    /// App-level (`xapp-`) token for opening Socket Mode connections.
+    pub app_token: Option<String>,
+    /// Topic exchange the events are published to, declared if need be.
+    pub exchange: String,
+    pub routing_key: String,
+}
+impl TryFrom<RawInteractions> for InteractionsConfig {
+    type Error = String;
+
+    fn try_from(raw: RawInteractions) -> Result<InteractionsConfig, Self::Error> {
+        let listen = raw.listen.as_deref().unwrap_or("0.0.0.0:3000");
+        let listen = listen
+            .parse()
+            .map_err(|_| format!("Invalid interactions.listen address \"{}\"", listen))?;
+        if raw.signing_secret.is_none() && raw.app_token.is_none() {
+            return Err("a signing_secret or an app_token is required".to_string());
+        }
+
+        Ok(InteractionsConfig {
+            listen,
+            signing_secret: raw.signing_secret,
+            app_token: raw.app_token,
+            exchange: raw.exchange.unwrap_or_else(|| "2steps.events".to_string()),
+            routing_key: raw
+                .routing_key
+                .unwrap_or_else(|| "slack.handled".to_string()),
+        })
+    }
+}
+checked!(InteractionsConfig, RawInteractions);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawMetrics {
+    listen: Option<String>,
+}
+
+/// Where Prometheus metrics are served, at `/metrics`.
+pub struct MetricsConfig {
+    pub listen: SocketAddr,
+}
+impl TryFrom<RawMetrics> for MetricsConfig {
+    type Error = String;
+
+    fn try_from(raw: RawMetrics) -> Result<MetricsConfig, Self::Error> {
+        let listen = raw.listen.as_deref().unwrap_or("0.0.0.0:9090");
+        let listen = listen
+            .parse()
+            .map_err(|_| format!("Invalid metrics.listen address \"{}\"", listen))?;
+        Ok(MetricsConfig { listen })
+    }
+}
+checked!(MetricsConfig, RawMetrics);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawHealth {
+    listen: Option<String>,
+}
+
+/// Where probes are answered, at `/healthz` and `/readyz`.
+pub struct HealthConfig {
+    pub listen: SocketAddr,
+}
+impl TryFrom<RawHealth> for HealthConfig {
+    type Error = String;
+
+    fn try_from(raw: RawHealth) -> Result<HealthConfig, Self::Error> {
+        let listen = raw.listen.as_deref().unwrap_or("0.0.0.0:8080");
+        let listen = listen
+            .parse()
+            .map_err(|_| format!("Invalid health.listen address \"{}\"", listen))?;
+        Ok(HealthConfig { listen })
+    }
+}
+checked!(HealthConfig, RawHealth);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawAdmin {
+    listen: Option<String>,
+    token: Option<String>,
+}
+
+/// Where the admin API is served, for scripts and the `silence`
+/// subcommand on the same host.
+pub struct AdminConfig {
+    pub listen: SocketAddr,
+    /// Bearer token every request must carry, if set. Required unless the
+    /// API listens on a loopback address only.
+    pub token: Option<String>,
+}
+impl TryFrom<RawAdmin> for AdminConfig {
+    type Error = String;
+
+    fn try_from(raw: RawAdmin) -> Result<AdminConfig, Self::Error> {
+        let listen = raw.listen.as_deref().unwrap_or("127.0.0.1:9091");
+        let listen: SocketAddr = listen
+            .parse()
+            .map_err(|_| format!("Invalid admin.listen address \"{}\"", listen))?;
+        if raw.token.as_deref().is_some_and(str::is_empty) {
+            return Err("admin.token must not be empty".to_string());
+        }
+        if raw.token.is_none() && !listen.ip().is_loopback() {
+            return Err(
+                "admin.token is required unless admin.listen is a loopback address".to_string(),
+            );
+        }
+        Ok(AdminConfig {
+            listen,
+            token: raw.token,
+        })
+    }
+}
+checked!(AdminConfig, RawAdmin);
+
+/// The dialect metrics are pushed in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsdFormat {
+    /// Labels and configured tags as DogStatsD tags.
+    #[default]
+    Dogstatsd,
+    /// Plain StatsD, with labels folded into the metric name.
+    Statsd,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawStatsd {
+    address: String,
+    prefix: Option<String>,
+    #[serde(default)]
+    format: StatsdFormat,
+    #[serde(default)]
+    tags: BTreeMap<String, String>,
+    interval: Option<u64>,
+}
+
+/// Where the metrics are pushed over UDP, for monitoring that cannot scrape.
+pub struct StatsdConfig {
+    /// `host:port` of the agent.
+    pub address: String,
+    pub prefix: String,
+    pub format: StatsdFormat,
+    /// Sent with every metric.
+    pub tags: BTreeMap<String, String>,
+    pub interval: Duration,
+}
+impl TryFrom<RawStatsd> for StatsdConfig {
+    type Error = String;
+
+    fn try_from(raw: RawStatsd) -> Result<StatsdConfig, Self::Error> {
+        let port = raw.address.rsplit_once(':').map(|(_, port)| port);
+        if port.and_then(|port| port.parse::<u16>().ok()).is_none() {
+            return Err(format!(
+                "Invalid statsd.address \"{}\", expected host:port",
+                raw.address
+            ));
+        }
+        if raw.format == StatsdFormat::Statsd && !raw.tags.is_empty() {
+            return Err("tags need the dogstatsd format".to_string());
+        }
+        let interval = raw.interval.unwrap_or(10);
+        if interval < 1 {
+            return Err("interval must be at least 1".to_string());
+        }
+        Ok(StatsdConfig {
+            address: raw.address,
+            prefix: raw.prefix.unwrap_or_else(|| "twosteps".to_string()),
+            format: raw.format,
+            tags: raw.tags,
+            interval: Duration::from_secs(interval),
+        })
+    }
+}
+checked!(StatsdConfig, RawStatsd);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawQueueDepth {
+    interval: Option<u64>,
+    threshold: Option<u32>,
+    receiver: Option<String>,
+}
+
+/// How often the queues' depth is checked, and how deep a queue gets
+/// before someone is told the bridge is falling behind.
+pub struct QueueDepthConfig {
+    pub interval: Duration,
+    /// Messages waiting in a queue beyond which it is reported.
+    pub threshold: Option<u32>,
+    /// Told when a queue goes beyond the threshold and when it recovers.
+    pub receiver: Option<String>,
+}
+impl TryFrom<RawQueueDepth> for QueueDepthConfig {
+    type Error = String;
+
+    fn try_from(raw: RawQueueDepth) -> Result<QueueDepthConfig, Self::Error> {
+        let interval = raw.interval.unwrap_or(30);
+        if interval < 1 {
+            return Err("interval must be at least 1".to_string());
+        }
+        if raw.receiver.is_some() && raw.threshold.is_none() {
+            return Err("receiver needs a threshold".to_string());
+        }
+        Ok(QueueDepthConfig {
+            interval: Duration::from_secs(interval),
+            threshold: raw.threshold,
+            receiver: raw.receiver,
+        })
+    }
+}
+checked!(QueueDepthConfig, RawQueueDepth);
+
+/// How log lines are written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Lines for people to read.
+    #[default]
+    Text,
+    /// A JSON object a line, for log pipelines to index.
+    Json,
+}
+
+/// How often the log file is started afresh, besides when it is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotation {
+    #[default]
+    Never,
+    Hourly,
+    Daily,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawLogFile {
+    path: String,
+    max_bytes: Option<u64>,
+    #[serde(default)]
+    rotation: Rotation,
+    keep: Option<usize>,
+}
+
+/// A file logged to as well as stderr.
+pub struct LogFileConfig {
+    pub path: String,
+    /// The file is rotated before it would grow past this size.
+    pub max_bytes: Option<u64>,
+    pub rotation: Rotation,
+    /// Rotated files kept; older ones are removed.
+    pub keep: usize,
+}
+impl TryFrom<RawLogFile> for LogFileConfig {
+    type Error = String;
+
+    fn try_from(raw: RawLogFile) -> Result<LogFileConfig, Self::Error> {
+        if raw.max_bytes == Some(0) {
+            return Err("max_bytes must be at least 1".to_string());
+        }
+        // Without either, the file would grow without bound.
+        let max_bytes = match (raw.max_bytes, raw.rotation) {
+            (None, Rotation::Never) => Some(10 * 1024 * 1024),
+            (max_bytes, _) => max_bytes,
+        };
+        Ok(LogFileConfig {
+            path: raw.path,
+            max_bytes,
+            rotation: raw.rotation,
+            keep: raw.keep.unwrap_or(5),
+        })
+    }
+}
+checked!(LogFileConfig, RawLogFile);
+
+/// The system's own log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SystemLogKind {
+    /// The local syslog daemon (Unix).
+    Syslog,
+    /// The systemd journal (Linux).
+    Journald,
+    /// The Windows Event Log.
+    Eventlog,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawLogging {
+    format: Option<LogFormat>,
+    #[serde(default)]
+    levels: BTreeMap<String, String>,
+    file: Option<LogFileConfig>,
+    system: Option<SystemLogKind>,
+    stderr: Option<bool>,
+}
+
+/// How the bridge logs. Where to and in what format are read once at
+/// startup; the levels are applied again on reload.
+pub struct LoggingConfig {
+    pub format: LogFormat,
+    pub file: Option<LogFileConfig>,
+    pub system: Option<SystemLogKind>,
+    /// Whether lines go to stderr as well, which they do by default.
+    pub stderr: bool,
+    /// The level logged by each module and those within it.
+    pub levels: BTreeMap<String, log::LevelFilter>,
+}
+impl TryFrom<RawLogging> for LoggingConfig {
+    type Error = String;
+
+    fn try_from(raw: RawLogging) -> Result<LoggingConfig, Self::Error> {
+        let levels = raw
+            .levels
+            .into_iter()
+            .map(|(module, level)| match level.parse() {
+                Ok(level) => Ok((module, level)),
+                Err(_) => Err(format!("Invalid log level \"{}\" for {}", level, module)),
+            })
+            .collect::<Result<_, String>>()?;
+        let available = match raw.system {
+            Some(SystemLogKind::Syslog) | Some(SystemLogKind::Journald) => cfg!(unix),
+            Some(SystemLogKind::Eventlog) => cfg!(windows),
+            None => true,
+        };
+        if !available {
+            return Err(format!(
+                "{:?} logging is not available on this system",
+                raw.system.unwrap()
+            ));
+        }
+        Ok(LoggingConfig {
+            format: raw.format.unwrap_or_default(),
+            file: raw.file,
+            system: raw.system,
+            stderr: raw.stderr.unwrap_or(true),
+            levels,
+        })
+    }
+}
+checked!(LoggingConfig, RawLogging);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawTracing {
+    header: Option<String>,
+    otlp_endpoint: Option<String>,
+    service_name: Option<String>,
+}
+
+/// Following each alert through the bridge as a trace. Read once at
+/// startup; reloading does not change it.
+pub struct TracingConfig {
+    /// Message header carrying the trace to continue, falling back on the
+    /// message's correlation ID.
+    pub header: String,
+    /// OTLP/HTTP collector the spans are sent to, if any.
+    pub otlp_endpoint: Option<String>,
+    pub service_name: String,
+}
+impl TryFrom<RawTracing> for TracingConfig {
+    type Error = String;
+
+    fn try_from(raw: RawTracing) -> Result<TracingConfig, Self::Error> {
+        if let Some(endpoint) = &raw.otlp_endpoint {
+            match reqwest::Url::parse(endpoint) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") && url.has_host() => {}
+                _ => return Err("otlp_endpoint must be an http or https URL".to_string()),
+            }
+        }
+        Ok(TracingConfig {
+            header: raw.header.unwrap_or_else(|| "traceparent".to_string()),
+            otlp_endpoint: raw.otlp_endpoint,
+            service_name: raw
+                .service_name
+                .unwrap_or_else(|| "2steps-slack-alert".to_string()),
+        })
+    }
+}
+checked!(TracingConfig, RawTracing);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawAudit {
+    dir: String,
+    retention: Option<u64>,
+}
+
+/// Where every alert received and every delivery attempt is recorded.
+pub struct AuditConfig {
+    /// Directory of the daily JSON lines files.
+    pub dir: String,
+    /// How long the records are kept, to the day.
+    pub retention: Duration,
+}
+impl TryFrom<RawAudit> for AuditConfig {
+    type Error = String;
+
+    fn try_from(raw: RawAudit) -> Result<AuditConfig, Self::Error> {
+        let retention = raw.retention.unwrap_or(90 * 86400);
+        if retention < 86400 {
+            return Err("retention must be at least a day (86400)".to_string());
+        }
+        Ok(AuditConfig {
+            dir: raw.dir,
+            retention: Duration::from_secs(retention),
+        })
+    }
+}
+checked!(AuditConfig, RawAudit);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawHeartbeat {
+    url: Option<String>,
+    receiver: Option<String>,
+    interval: Option<u64>,
+}
+
+/// A sign of life sent while the bridge is connected and consuming, so that
+/// something else notices when it stops.
+pub struct HeartbeatConfig {
+    /// Pinged with a GET, as healthchecks.io and its like expect.
+    pub url: Option<String>,
+    /// Sent a short notification.
+    pub receiver: Option<String>,
+    pub interval: Duration,
+}
+impl TryFrom<RawHeartbeat> for HeartbeatConfig {
+    type Error = String;
+
+    fn try_from(raw: RawHeartbeat) -> Result<HeartbeatConfig, Self::Error> {
+        if raw.url.is_none() && raw.receiver.is_none() {
+            return Err("heartbeat needs a url or a receiver".to_string());
+        }
+        let interval = raw.interval.unwrap_or(60);
+        if interval < 1 {
+            return Err("interval must be at least 1".to_string());
+        }
+        Ok(HeartbeatConfig {
+            url: raw.url.map(webhook).transpose()?,
+            receiver: raw.receiver,
+            interval: Duration::from_secs(interval),
+        })
+    }
+}
+checked!(HeartbeatConfig, RawHeartbeat);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawSelfAlerts {
+    receiver: String,
+    interval: Option<u64>,
+    reconnects: Option<u32>,
+}
+
+/// Where trouble with the bridge itself is reported, and how often.
+pub struct SelfAlertsConfig {
+    pub receiver: String,
+    /// Each kind of trouble is reported at most once an interval.
+    pub interval: Duration,
+    /// Lost connections within an interval that make a storm.
+    pub reconnects: u32,
+}
+impl TryFrom<RawSelfAlerts> for SelfAlertsConfig {
+    type Error = String;
+
+    fn try_from(raw: RawSelfAlerts) -> Result<SelfAlertsConfig, Self::Error> {
+        let interval = raw.interval.unwrap_or(900);
+        if interval < 60 {
+            return Err("interval must be at least 60".to_string());
+        }
+        let reconnects = raw.reconnects.unwrap_or(3);
+        if reconnects < 1 {
+            return Err("reconnects must be at least 1".to_string());
+        }
+        Ok(SelfAlertsConfig {
+            receiver: raw.receiver,
+            interval: Duration::from_secs(interval),
+            reconnects,
+        })
+    }
+}
+checked!(SelfAlertsConfig, RawSelfAlerts);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawSentry {
+    dsn: String,
+    environment: Option<String>,
+}
+
+/// The Sentry project panics and failed deliveries are reported to.
+pub struct SentryConfig {
+    pub dsn: String,
+    pub environment: Option<String>,
+}
+impl TryFrom<RawSentry> for SentryConfig {
+    type Error = String;
+
+    fn try_from(raw: RawSentry) -> Result<SentryConfig, Self::Error> {
+        if !secret::is_reference(&raw.dsn) {
+            crate::sentry::parse_dsn(&raw.dsn)?;
+        }
+        Ok(SentryConfig {
+            dsn: raw.dsn,
+            environment: raw.environment,
+        })
+    }
+}
+checked!(SentryConfig, RawSentry);
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawMessages {
+    path: Option<String>,
+    ttl: Option<u64>,
+}
+
+/// Where Slack messages posted through the Web API are remembered, so that
+/// later alerts about the same check can reply in their thread.
+pub struct MessagesConfig {
+    /// File the messages are kept in across restarts; memory only if unset.
+    pub path: Option<String>,
+    /// Forget a message this long after it was posted.
+    pub ttl: Duration,
+}
+impl TryFrom<RawMessages> for MessagesConfig {
+    type Error = String;
+
+    fn try_from(raw: RawMessages) -> Result<MessagesConfig, Self::Error> {
+        let ttl = raw.ttl.unwrap_or(7 * 24 * 60 * 60);
+        if ttl < 1 {
+            return Err("ttl must be at least 1".to_string());
+        }
+
+        Ok(MessagesConfig {
+            path: raw.path,
+            ttl: Duration::from_secs(ttl),
+        })
+    }
+}
+checked!(MessagesConfig, RawMessages);
+
+fn default_pagerduty_url() -> String {
+    "https://events.pagerduty.com/v2/enqueue".to_string()
+}
+
+/// Where the Escalate button on critical alerts raises incidents.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PagerDutyConfig {
+    /// Integration key of the PagerDuty service.
+    pub routing_key: String,
+    /// Events API v2 endpoint.
+    #[serde(default = "default_pagerduty_url")]
+    pub url: String,
+    /// REST API token used to find the incident so that the Slack message
+    /// can link to it; the message goes without a link if unset.
+    pub api_token: Option<String>,
+}
+
+/// A PagerDuty service that receives events for the alerts routed to it.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PagerDutyReceiver {
+    /// Integration key of the PagerDuty service.
+    pub routing_key: String,
+    /// Events API v2 endpoint.
+    #[serde(default = "default_pagerduty_url")]
+    pub url: String,
+}
+
+fn default_opsgenie_url() -> String {
+    "https://api.opsgenie.com".to_string()
+}
+
+/// An Opsgenie API integration that gets an alert for each check routed to
+/// it, closed again once the check recovers.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OpsgenieReceiver {
+    pub api_key: String,
+    /// Alert API host; https://api.eu.opsgenie.com for the EU instance.
+    #[serde(default = "default_opsgenie_url")]
+    pub url: String,
+    /// Tags for every alert, besides those from the alert's own fields.
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Check that `address` is an email address, optionally with a display
+/// name, such as `Alerts <alerts@example.com>`.
+fn mailbox(address: &str) -> Result<(), String> {
+    address
+        .parse::<lettre::message::Mailbox>()
+        .map(|_| ())
+        .map_err(|e| format!("invalid email address {}: {}", address, e))
+}
+
+fn default_telegram_url() -> String {
+    "https://api.telegram.org".to_string()
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawTelegramReceiver {
+    bot_token: String,
+    chat_id: FieldValue,
+    #[serde(default = "default_telegram_url")]
+    url: String,
+}
+
+/// A Telegram chat that a bot sends alerts to.
+#[derive(Clone)]
+pub struct TelegramReceiver {
+    pub bot_token: String,
+    /// Numeric ID of the chat, or `@name` of a public channel.
+    pub chat_id: String,
+    /// Bot API host.
+    pub url: String,
+}
+impl TryFrom<RawTelegramReceiver> for TelegramReceiver {
+    type Error = String;
+
+    fn try_from(raw: RawTelegramReceiver) -> Result<TelegramReceiver, Self::Error> {
+        let chat_id = match raw.chat_id {
+            FieldValue::Int(id) => id.to_string(),
+            FieldValue::Str(name) if name.starts_with('@') || name.parse::<i64>().is_ok() => name,
+            _ => return Err("chat_id must be a chat's ID or a channel's @name".to_string()),
+        };
+        Ok(TelegramReceiver {
+            bot_token: raw.bot_token,
+            chat_id,
+            url: raw.url,
+        })
+    }
+}
+checked!(TelegramReceiver, RawTelegramReceiver);
+
+fn default_pushover_url() -> String {
+    "https://api.pushover.net".to_string()
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawPushoverReceiver {
+    token: String,
+    user: String,
+    device: Option<String>,
+    sound: Option<String>,
+    #[serde(default)]
+    priority: HashMap<Severity, i8>,
+    retry: Option<u64>,
+    expire: Option<u64>,
+    #[serde(default = "default_pushover_url")]
+    url: String,
+}
+
+/// A Pushover user or group that an application sends alerts to.
+#[derive(Clone)]
+pub struct PushoverReceiver {
+    /// API token of the application.
+    pub token: String,
+    /// Key of the user or group.
+    pub user: String,
+    /// Devices to send to, rather than all of the user's.
+    pub device: Option<String>,
+    pub sound: Option<String>,
+    /// Priority of firing alerts of each severity, from -2 to 2; critical
+    /// alerts are emergencies, repeated until acknowledged, by default.
+    pub priority: HashMap<Severity, i8>,
+    /// How often emergencies are repeated, and for how long at most.
+    pub retry: Duration,
+    pub expire: Duration,
+    /// Message API host.
+    pub url: String,
+}
+impl TryFrom<RawPushoverReceiver> for PushoverReceiver {
+    type Error = String;
+
+    fn try_from(raw: RawPushoverReceiver) -> Result<PushoverReceiver, Self::Error> {
+        let mut priority: HashMap<Severity, i8> = vec![
+            (Severity::Critical, 2),
+            (Severity::Warning, 1),
+            (Severity::Info, 0),
+        ]
+        .into_iter()
+        .collect();
+        for (severity, value) in raw.priority {
+            if !(-2..=2).contains(&value) {
+                return Err(format!(
+                    "priority for {} alerts must be from -2 to 2",
+                    severity
+                ));
+            }
+            priority.insert(severity, value);
+        }
+        let retry = raw.retry.unwrap_or(60);
+        if retry < 30 {
+            return Err("retry must be at least 30 seconds".to_string());
+        }
+        let expire = raw.expire.unwrap_or(60 * 60);
+        if expire == 0 || expire > 3 * 60 * 60 {
+            return Err("expire must be from 1 second to 3 hours".to_string());
+        }
+        Ok(PushoverReceiver {
+            token: raw.token,
+            user: raw.user,
+            device: raw.device,
+            sound: raw.sound,
+            priority,
+            retry: Duration::from_secs(retry),
+            expire: Duration::from_secs(expire),
+            url: raw.url,
+        })
+    }
+}
+checked!(PushoverReceiver, RawPushoverReceiver);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawMattermostReceiver {
+    url: String,
+    channel: Option<String>,
+    username: Option<String>,
+    icon_url: Option<String>,
+}
+
+/// A Mattermost incoming webhook, with the overrides Mattermost honours in
+/// Slack-style payloads.
+#[derive(Clone)]
+pub struct MattermostReceiver {
+    pub url: String,
+    /// Channel to post to instead of the webhook's own, if the webhook
+    /// allows it.
+    pub channel: Option<String>,
+    pub username: Option<String>,
+    pub icon_url: Option<String>,
+}
+impl TryFrom<UrlOr<RawMattermostReceiver>> for MattermostReceiver {
+    type Error = String;
+
+    fn try_from(raw: UrlOr<RawMattermostReceiver>) -> Result<MattermostReceiver, Self::Error> {
+        let raw = match raw {
+            UrlOr::Url(url) => RawMattermostReceiver {
+                url,
+                channel: None,
+                username: None,
+                icon_url: None,
+            },
+            UrlOr::Section(raw) => raw,
+        };
+        Ok(MattermostReceiver {
+            url: webhook(raw.url)?,
+            channel: raw
+                .channel
+                .map(|channel| channel.trim_start_matches('#').to_string()),
+            username: raw.username,
+            icon_url: raw.icon_url,
+        })
+    }
+}
+checked!(MattermostReceiver, url_or RawMattermostReceiver);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawEmailReceiver {
+    to: Vec<String>,
+    #[serde(default)]
+    cc: Vec<String>,
+}
+
+/// The addresses that get an email for each alert routed to a receiver.
+#[derive(Clone)]
+pub struct EmailReceiver {
+    pub to: Vec<String>,
+    pub cc: Vec<String>,
+}
+impl TryFrom<RawEmailReceiver> for EmailReceiver {
+    type Error = String;
+
+    fn try_from(raw: RawEmailReceiver) -> Result<EmailReceiver, Self::Error> {
+        if raw.to.is_empty() {
+            return Err("email receivers need at least one address in to".to_string());
+        }
+        for address in raw.to.iter().chain(&raw.cc) {
+            mailbox(address)?;
+        }
+        Ok(EmailReceiver {
+            to: raw.to,
+            cc: raw.cc,
+        })
+    }
+}
+checked!(EmailReceiver, RawEmailReceiver);
+
+/// How the connection to the mail server is secured.
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// TLS from the start, usually on port 465.
+    Wrapper,
+    /// Plain text upgraded with STARTTLS, which the server must offer;
+    /// usually on port 587.
+    Starttls,
+    /// No encryption at all, for a relay on the same host or network.
+    None,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawEmail {
+    host: String,
+    port: Option<u16>,
+    tls: Option<SmtpTls>,
+    username: Option<String>,
+    password: Option<String>,
+    from: String,
+    subject: Option<String>,
+    text_template: Option<String>,
+    html_template: Option<String>,
+}
+
+/// The mail server email receivers send through, and how their emails
+/// look.
+pub struct EmailConfig {
+    pub host: String,
+    pub port: u16,
+    pub tls: SmtpTls,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    /// Tera template for the subject line, given the same context as the
+    /// body templates.
+    pub subject: Option<String>,
+    /// Tera template files for the plain text and HTML bodies; the built-in
+    /// layouts are used for those not given.
+    pub text_template: Option<String>,
+    pub html_template: Option<String>,
+}
+impl TryFrom<RawEmail> for EmailConfig {
+    type Error = String;
+
+    fn try_from(raw: RawEmail) -> Result<EmailConfig, Self::Error> {
+        if raw.username.is_some() != raw.password.is_some() {
+            return Err("give both username and password, or neither".to_string());
+        }
+        mailbox(&raw.from)?;
+        let tls = raw.tls.unwrap_or(SmtpTls::Starttls);
+        let port = raw.port.unwrap_or(match tls {
+            SmtpTls::Wrapper => 465,
+            SmtpTls::Starttls => 587,
+            SmtpTls::None => 25,
+        });
+
+        Ok(EmailConfig {
+            host: raw.host,
+            port,
+            tls,
+            username: raw.username,
+            password: raw.password,
+            from: raw.from,
+            subject: raw.subject,
+            text_template: raw.text_template,
+            html_template: raw.html_template,
+        })
+    }
+}
+checked!(EmailConfig, RawEmail);
+
+/// Credentials sent with each request to a webhook receiver.
+#[derive(Clone)]
+pub enum WebhookAuth {
+    Bearer(String),
+    Basic { username: String, password: String },
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawWebhookAuth {
+    bearer: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+}
+impl TryFrom<RawWebhookAuth> for WebhookAuth {
+    type Error = String;
+
+    fn try_from(raw: RawWebhookAuth) -> Result<WebhookAuth, Self::Error> {
+        match raw {
+            RawWebhookAuth {
+                bearer: Some(token),
+                username: None,
+                password: None,
+            } => Ok(WebhookAuth::Bearer(token)),
+            RawWebhookAuth {
+                bearer: None,
+                username: Some(username),
+                password: Some(password),
+            } => Ok(WebhookAuth::Basic { username, password }),
+            _ => Err("auth takes a bearer token, or a username and password".to_string()),
+        }
+    }
+}
+checked!(WebhookAuth, RawWebhookAuth);
+
+fn default_webhook_content_type() -> String {
+    "application/json".to_string()
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawWebhookReceiver {
+    url: String,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    auth: Option<WebhookAuth>,
+    template: Option<String>,
+    #[serde(default = "default_webhook_content_type")]
+    content_type: String,
+}
+
+/// An arbitrary HTTP endpoint that alerts are POSTed to, such as internal
+/// incident tooling.
+#[derive(Clone)]
+pub struct WebhookReceiver {
+    pub url: String,
+    /// Sent with each request; values may be secret references.
+    pub headers: BTreeMap<String, String>,
+    pub auth: Option<WebhookAuth>,
+    /// Tera template file for the body. Without one the body is a JSON
+    /// object with the notification's `summary`, `severity` and `alerts`.
+    pub template: Option<String>,
+    pub content_type: String,
+}
+impl TryFrom<RawWebhookReceiver> for WebhookReceiver {
+    type Error = String;
+
+    fn try_from(raw: RawWebhookReceiver) -> Result<WebhookReceiver, Self::Error> {
+        for (name, value) in &raw.headers {
+            reqwest::header::HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("invalid header name {}", name))?;
+            reqwest::header::HeaderValue::from_str(value)
+                .map_err(|_| format!("invalid value for header {}", name))?;
+        }
+        Ok(WebhookReceiver {
+            url: webhook(raw.url)?,
+            headers: raw.headers,
+            auth: raw.auth,
+            template: raw.template,
+            content_type: raw.content_type,
+        })
+    }
+}
+checked!(WebhookReceiver, RawWebhookReceiver);
+
+fn default_jira_issue_type() -> String {
+    "Task".to_string()
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawJiraReceiver {
+    url: String,
+    username: Option<String>,
+    token: String,
+    project: String,
+    #[serde(default = "default_jira_issue_type")]
+    issue_type: String,
+    #[serde(default)]
+    labels: Vec<String>,
+    summary_template: Option<String>,
+    description_template: Option<String>,
+    resolve_transition: Option<String>,
+}
+
+/// A Jira project that gets an issue for each check routed to it. The
+/// issue is commented on, and moved on if a transition is given, once the
+/// check recovers.
+#[derive(Clone)]
+pub struct JiraReceiver {
+    /// Base URL of the Jira site.
+    pub url: String,
+    /// With `token` as an API token for basic authentication, as Jira Cloud
+    /// expects; without one `token` is sent as a bearer token, such as a
+    /// Data Center personal access token.
+    pub username: Option<String>,
+    pub token: String,
+    /// Key of the project issues are opened in.
+    pub project: String,
+    pub issue_type: String,
+    /// Labels for every issue, besides the one identifying its check.
+    pub labels: Vec<String>,
+    /// Tera template files for the summary and description, given the
+    /// context of a single alert. Built-in layouts are used otherwise.
+    pub summary_template: Option<String>,
+    pub description_template: Option<String>,
+    /// Name of the transition that closes an issue, such as `Done`.
+    pub resolve_transition: Option<String>,
+}
+impl TryFrom<RawJiraReceiver> for JiraReceiver {
+    type Error = String;
+
+    fn try_from(raw: RawJiraReceiver) -> Result<JiraReceiver, Self::Error> {
+        match reqwest::Url::parse(&raw.url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") && url.has_host() => {}
+            _ => return Err(format!("{} is not an http or https URL", raw.url)),
+        }
+        let mut key = raw.project.chars();
+        let valid = key.next().is_some_and(|c| c.is_ascii_uppercase())
+            && key.all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_');
+        if !valid {
+            return Err(format!("{} is not a Jira project key", raw.project));
+        }
+        if let Some(label) = raw.labels.iter().find(|label| label.contains(' ')) {
+            return Err(format!("Jira labels cannot contain spaces: {}", label));
+        }
+        Ok(JiraReceiver {
+            url: raw.url,
+            username: raw.username,
+            token: raw.token,
+            project: raw.project,
+            issue_type: raw.issue_type,
+            labels: raw.labels,
+            summary_template: raw.summary_template,
+            description_template: raw.description_template,
+            resolve_transition: raw.resolve_transition,
+        })
+    }
+}
+checked!(JiraReceiver, RawJiraReceiver);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawServiceNowReceiver {
+    instance: String,
+    username: Option<String>,
+    password: Option<String>,
+    token: Option<String>,
+    #[serde(default)]
+    fields: BTreeMap<String, String>,
+    #[serde(default)]
+    defaults: BTreeMap<String, String>,
+    #[serde(default)]
+    resolve: BTreeMap<String, String>,
+}
+
+/// How requests to ServiceNow authenticate.
+#[derive(Clone)]
+pub enum ServiceNowAuth {
+    Basic {
+        username: String,
+        password: String,
+    },
+    /// An OAuth access token.
+    Bearer(String),
+}
+
+/// A ServiceNow instance that gets an incident for each check routed to it,
+/// resolved once the check recovers.
+#[derive(Clone)]
+pub struct ServiceNowReceiver {
+    /// Base URL of the instance, such as https://example.service-now.com.
+    pub instance: String,
+    pub auth: ServiceNowAuth,
+    /// Incident fields set from alert fields, by the name of each.
+    pub fields: BTreeMap<String, String>,
+    /// Incident fields set to the same value for every incident, such as
+    /// `caller_id` or `category`.
+    pub defaults: BTreeMap<String, String>,
+    /// Incident fields set when resolving an incident, over the built-in
+    /// state, close code and close notes.
+    pub resolve: BTreeMap<String, String>,
+}
+impl TryFrom<RawServiceNowReceiver> for ServiceNowReceiver {
+    type Error = String;
+
+    fn try_from(raw: RawServiceNowReceiver) -> Result<ServiceNowReceiver, Self::Error> {
+        match reqwest::Url::parse(&raw.instance) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") && url.has_host() => {}
+            _ => return Err(format!("{} is not an http or https URL", raw.instance)),
+        }
+        let auth = match (raw.username, raw.password, raw.token) {
+            (Some(username), Some(password), None) => ServiceNowAuth::Basic { username, password },
+            (None, None, Some(token)) => ServiceNowAuth::Bearer(token),
+            _ => return Err("ServiceNow takes a username and password, or a token".to_string()),
+        };
+        Ok(ServiceNowReceiver {
+            instance: raw.instance,
+            auth,
+            fields: raw.fields,
+            defaults: raw.defaults,
+            resolve: raw.resolve,
+        })
+    }
+}
+checked!(ServiceNowReceiver, RawServiceNowReceiver);
+
+/// How messages reach a syslog receiver.
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogTransport {
+    /// A datagram for each message, as in RFC 5426; usually port 514.
+    Udp,
+    /// Octet-counted messages on a connection, as in RFC 6587; usually
+    /// port 514 too.
+    Tcp,
+    /// The same over TLS, as in RFC 5425; usually port 6514.
+    Tls,
+}
+
+/// Syslog facilities by name, in the order of their codes.
+const SYSLOG_FACILITIES: [&str; 24] = [
+    "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron", "authpriv",
+    "ftp", "ntp", "audit", "alert", "clock", "local0", "local1", "local2", "local3", "local4",
+    "local5", "local6", "local7",
+];
+
+fn default_syslog_transport() -> SyslogTransport {
+    SyslogTransport::Udp
+}
+
+fn default_syslog_facility() -> String {
+    "local0".to_string()
+}
+
+fn default_syslog_app_name() -> String {
+    "2steps-slack-alert".to_string()
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawSyslogReceiver {
+    host: String,
+    port: Option<u16>,
+    #[serde(default = "default_syslog_transport")]
+    transport: SyslogTransport,
+    tls: Option<TlsConfig>,
+    #[serde(default = "default_syslog_facility")]
+    facility: String,
+    #[serde(default = "default_syslog_app_name")]
+    app_name: String,
+    hostname: Option<String>,
+}
+
+/// A syslog server, such as a SIEM's collector, that gets an RFC 5424
+/// message for each alert routed to it.
+#[derive(Clone)]
+pub struct SyslogReceiver {
+    pub host: String,
+    pub port: u16,
+    pub transport: SyslogTransport,
+    /// Settings for the `tls` transport.
+    pub tls: Option<TlsConfig>,
+    /// Code of the facility messages are logged under.
+    pub facility: u8,
+    pub app_name: String,
+    /// Names this host in messages; the system's host name by default.
+    pub hostname: Option<String>,
+}
+impl TryFrom<RawSyslogReceiver> for SyslogReceiver {
+    type Error = String;
+
+    fn try_from(raw: RawSyslogReceiver) -> Result<SyslogReceiver, Self::Error> {
+        let facility = SYSLOG_FACILITIES
+            .iter()
+            .position(|&name| name == raw.facility)
+            .ok_or_else(|| format!("{} is not a syslog facility", raw.facility))?;
+        if raw.tls.is_some() && raw.transport != SyslogTransport::Tls {
+            return Err("tls settings need the tls transport".to_string());
+        }
+        let printable = |s: &str, max: usize| {
+            !s.is_empty() && s.len() <= max && s.chars().all(|c| c.is_ascii_graphic())
+        };
+        if !printable(&raw.app_name, 48) {
+            return Err("app_name must be up to 48 printable ASCII characters".to_string());
+        }
+        let hostname = raw.hostname.or_else(|| {
+            hostname::get()
+                .ok()
+                .and_then(|name| name.into_string().ok())
+        });
+        if let Some(hostname) = &hostname {
+            if !printable(hostname, 255) {
+                return Err(format!("{} is not a valid syslog host name", hostname));
+            }
+        }
+        let port = raw.port.unwrap_or(match raw.transport {
+            SyslogTransport::Udp | SyslogTransport::Tcp => 514,
+            SyslogTransport::Tls => 6514,
+        });
+        Ok(SyslogReceiver {
+            host: raw.host,
+            port,
+            transport: raw.transport,
+            tls: raw.tls,
+            facility: facility as u8,
+            app_name: raw.app_name,
+            hostname,
+        })
+    }
+}
+checked!(SyslogReceiver, RawSyslogReceiver);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawMatrix {
+    homeserver: String,
+    access_token: String,
+}
+
+/// The homeserver Matrix receivers post through, as the user whose access
+/// token is given. The user must have joined the rooms.
+pub struct MatrixConfig {
+    pub homeserver: String,
+    pub access_token: String,
+}
+impl TryFrom<RawMatrix> for MatrixConfig {
+    type Error = String;
+
+    fn try_from(raw: RawMatrix) -> Result<MatrixConfig, Self::Error> {
+        match reqwest::Url::parse(&raw.homeserver) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") && url.has_host() => {}
+            _ => return Err("homeserver must be an http or https URL".to_string()),
+        }
+        Ok(MatrixConfig {
+            homeserver: raw.homeserver,
+            access_token: raw.access_token,
+        })
+    }
+}
+checked!(MatrixConfig, RawMatrix);
+
+fn default_webex_url() -> String {
+    "https://webexapis.com".to_string()
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawWebex {
+    bot_token: String,
+    #[serde(default = "default_webex_url")]
+    url: String,
+}
+
+/// The Webex bot that Webex receivers post as. The bot must have been
+/// added to the rooms.
+pub struct WebexConfig {
+    pub bot_token: String,
+    /// Messaging API host.
+    pub url: String,
+}
+impl TryFrom<RawWebex> for WebexConfig {
+    type Error = String;
+
+    fn try_from(raw: RawWebex) -> Result<WebexConfig, Self::Error> {
+        match reqwest::Url::parse(&raw.url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") && url.has_host() => {}
+            _ => return Err("url must be an http or https URL".to_string()),
+        }
+        Ok(WebexConfig {
+            bot_token: raw.bot_token,
+            url: raw.url,
+        })
+    }
+}
+checked!(WebexConfig, RawWebex);
+
+/// Check that `number` is in E.164 form, such as `+447700900000`.
+fn phone_number(number: &str) -> Result<(), String> {
+    let digits = number.strip_prefix('+').unwrap_or("");
+    if (8..=15).contains(&digits.len()) && digits.chars().all(|c| c.is_ascii_digit()) {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} is not a phone number in international form, such as +447700900000",
+            number
+        ))
+    }
+}
+
+fn default_twilio_url() -> String {
+    "https://api.twilio.com".to_string()
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawTwilio {
+    account_sid: String,
+    auth_token: String,
+    from: Option<String>,
+    messaging_service_sid: Option<String>,
+    limit: Option<RateLimit>,
+    #[serde(default = "default_twilio_url")]
+    url: String,
+}
+
+/// The Twilio account SMS receivers are texted through.
+pub struct TwilioConfig {
+    pub account_sid: String,
+    pub auth_token: String,
+    /// The number texts come from, unless a messaging service picks one.
+    pub from: Option<String>,
+    pub messaging_service_sid: Option<String>,
+    /// Texts sent beyond this are dropped, to keep the bill in check; by
+    /// default 20 an hour.
+    pub limit: RateLimit,
+    /// API host.
+    pub url: String,
+}
+impl TryFrom<RawTwilio> for TwilioConfig {
+    type Error = String;
+
+    fn try_from(raw: RawTwilio) -> Result<TwilioConfig, Self::Error> {
+        match (&raw.from, &raw.messaging_service_sid) {
+            (Some(from), None) => phone_number(from)?,
+            (None, Some(_)) => {}
+            _ => return Err("give either from or messaging_service_sid".to_string()),
+        }
+        Ok(TwilioConfig {
+            account_sid: raw.account_sid,
+            auth_token: raw.auth_token,
+            from: raw.from,
+            messaging_service_sid: raw.messaging_service_sid,
+            limit: raw.limit.unwrap_or(RateLimit {
+                count: 20,
+                per: Duration::from_secs(60 * 60),
+            }),
+            url: raw.url,
+        })
+    }
+}
+checked!(TwilioConfig, RawTwilio);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawDedup {
+    #[serde(default)]
+    fields: Vec<String>,
+    window: Option<u64>,
+    #[serde(default)]
+    show_count: bool,
+}
+
+/// Suppression of repeats of an alert that has already been posted.
+pub struct DedupConfig {
+    /// Alert attributes whose values identify repeats; the alert's key if
+    /// empty.
+    pub fields: Vec<String>,
+    /// Repeats are suppressed for this long after the alert is posted.
+    pub window: Duration,
+    /// Whether to update the posted message with how often the alert has
+    /// been seen.
+    pub show_count: bool,
+}
+impl TryFrom<RawDedup> for DedupConfig {
+    type Error = String;
+
+    fn try_from(raw: RawDedup) -> Result<DedupConfig, Self::Error> {
+        let window = raw.window.unwrap_or(10 * 60);
+        if window < 1 {
+            return Err("window must be at least 1".to_string());
+        }
+
+        Ok(DedupConfig {
+            fields: raw.fields,
+            window: Duration::from_secs(window),
+            show_count: raw.show_count,
+        })
+    }
+}
+checked!(DedupConfig, RawDedup);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawFlapping {
+    transitions: Option<usize>,
+    window: Option<u64>,
+}
+
+/// Collapsing of notifications about checks that keep failing and passing.
+pub struct FlappingConfig {
+    /// State changes within `window` beyond which a check is flapping.
+    pub transitions: usize,
+    pub window: Duration,
+}
+impl TryFrom<RawFlapping> for FlappingConfig {
+    type Error = String;
+
+    fn try_from(raw: RawFlapping) -> Result<FlappingConfig, Self::Error> {
+        let transitions = raw.transitions.unwrap_or(4);
+        if transitions < 1 {
+            return Err("transitions must be at least 1".to_string());
+        }
+        let window = raw.window.unwrap_or(30 * 60);
+        if window < 1 {
+            return Err("window must be at least 1".to_string());
+        }
+
+        Ok(FlappingConfig {
+            transitions,
+            window: Duration::from_secs(window),
+        })
+    }
+}
+checked!(FlappingConfig, RawFlapping);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRenotify {
+    after: Option<u64>,
+    max: Option<u32>,
+    channel: Option<String>,
+    #[serde(default)]
+    users: Vec<String>,
+    #[serde(default)]
+    groups: Vec<String>,
+}
+
+/// Reminders about critical alerts nobody has acknowledged.
+pub struct RenotifyConfig {
+    /// How long to wait for an acknowledgement before each reminder.
+    pub after: Duration,
+    /// Reminders to send at most for an alert.
+    pub max: u32,
+    /// Channel for reminders instead of the one the alert went to.
+    pub channel: Option<String>,
+    /// Extra people to mention on reminders.
+    pub mentions: MentionsConfig,
+}
+impl TryFrom<RawRenotify> for RenotifyConfig {
+    type Error = String;
+
+    fn try_from(raw: RawRenotify) -> Result<RenotifyConfig, Self::Error> {
+        let after = raw.after.unwrap_or(15 * 60);
+        if after < 1 {
+            return Err("after must be at least 1".to_string());
+        }
+        let max = raw.max.unwrap_or(3);
+        if max < 1 {
+            return Err("max must be at least 1".to_string());
+        }
+
+        Ok(RenotifyConfig {
+            after: Duration::from_secs(after),
+            max,
+            channel: raw.channel,
+            mentions: MentionsConfig {
+                severities: vec![Severity::Critical],
+                rules: vec![MentionRule {
+                    when: BTreeMap::new(),
+                    users: raw.users,
+                    groups: raw.groups,
+                }],
+            },
+        })
+    }
+}
+checked!(RenotifyConfig, RawRenotify);
+
+/// How long alerts are collected for a group unless configured otherwise.
+pub const DEFAULT_GROUP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawGrouping {
+    #[serde(default)]
+    by: Vec<String>,
+    interval: Option<u64>,
+}
+
+/// Batching of firing alerts into digest messages.
+pub struct GroupingConfig {
+    /// Alert attributes whose values alerts must share to be grouped.
+    pub by: Vec<String>,
+    /// How long to collect alerts for a group before posting it.
+    pub interval: Duration,
+}
+impl TryFrom<RawGrouping> for GroupingConfig {
+    type Error = String;
+
+    fn try_from(raw: RawGrouping) -> Result<GroupingConfig, Self::Error> {
+        let interval = match raw.interval {
+            Some(0) => return Err("interval must be at least 1".to_string()),
+            Some(n) => Duration::from_secs(n),
+            None => DEFAULT_GROUP_INTERVAL,
+        };
+
+        Ok(GroupingConfig {
+            by: raw.by,
+            interval,
+        })
+    }
+}
+checked!(GroupingConfig, RawGrouping);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawSilenceWindow {
+    #[serde(default)]
+    matchers: BTreeMap<String, FieldValue>,
+    starts_at: String,
+    ends_at: String,
+    comment: Option<String>,
+}
+
+/// A silence planned ahead in the configuration, such as a maintenance
+/// window.
+pub struct SilenceWindow {
+    pub matchers: BTreeMap<String, String>,
+    pub starts_at: DateTime<Utc>,
+    pub ends_at: DateTime<Utc>,
+    pub comment: Option<String>,
+}
+impl TryFrom<RawSilenceWindow> for SilenceWindow {
+    type Error = String;
+
+    fn try_from(raw: RawSilenceWindow) -> Result<SilenceWindow, Self::Error> {
+        let matchers = conditions(raw.matchers);
+        if matchers.is_empty() {
+            return Err("matchers are required".to_string());
+        }
+        let time = |key: &str, raw: &str| -> Result<DateTime<Utc>, String> {
+            DateTime::parse_from_rfc3339(raw)
+                .map(|t| t.with_timezone(&Utc))
+                .map_err(|e| format!("{} is not an RFC 3339 time: {}", key, e))
+        };
+        let starts_at = time("starts_at", &raw.starts_at)?;
+        let ends_at = time("ends_at", &raw.ends_at)?;
+        if ends_at <= starts_at {
+            return Err("ends_at must be after starts_at".to_string());
+        }
+
+        Ok(SilenceWindow {
+            matchers,
+            starts_at,
+            ends_at,
+            comment: raw.comment,
+        })
+    }
+}
+checked!(SilenceWindow, RawSilenceWindow);
+
+/// Silences planned in the configuration or created at runtime, for example
+/// from Slack.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SilencesConfig {
+    /// File runtime silences are kept in across restarts; memory only if
+    /// unset.
+    pub path: Option<String>,
+    pub windows: Vec<SilenceWindow>,
+    /// Low-noise channel for silenced alerts, which are dropped if unset.
+    pub channel: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawCircuitBreaker {
+    failure_threshold: Option<u32>,
+    cool_down: Option<u64>,
+}
+
+/// When to stop sending to a destination that keeps failing.
+pub struct CircuitBreakerConfig {
+    /// Consecutive failures that open the circuit.
+    pub failure_threshold: u32,
+    /// How long the circuit stays open before a trial delivery is let through.
+    pub cool_down: Duration,
+}
+impl TryFrom<RawCircuitBreaker> for CircuitBreakerConfig {
+    type Error = String;
+
+    fn try_from(raw: RawCircuitBreaker) -> Result<CircuitBreakerConfig, Self::Error> {
+        let failure_threshold = raw.failure_threshold.unwrap_or(5);
+        if failure_threshold < 1 {
+            return Err("failure_threshold must be at least 1".to_string());
+        }
+        let cool_down = raw.cool_down.unwrap_or(60);
+        if cool_down < 1 {
+            return Err("cool_down must be at least 1".to_string());
+        }
+
+        Ok(CircuitBreakerConfig {
+            failure_threshold,
+            cool_down: Duration::from_secs(cool_down),
+        })
+    }
+}
+checked!(CircuitBreakerConfig, RawCircuitBreaker);
+
+/// How an alert is marked out in Slack.
+#[derive(Clone)]
+pub struct Style {
+    /// Colour of the attachment bar, as `#rrggbb`.
+    pub color: String,
+    /// Emoji leading the title, e.g. `:red_circle:`.
+    pub emoji: String,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawStyle {
+    color: Option<String>,
+    emoji: Option<String>,
+}
+
+impl RawStyle {
+    fn or(self, color: &str, emoji: &str) -> Style {
+        Style {
+            color: self.color.unwrap_or_else(|| color.to_string()),
+            emoji: self.emoji.unwrap_or_else(|| emoji.to_string()),
+        }
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawStyles {
+    critical: RawStyle,
+    warning: RawStyle,
+    info: RawStyle,
+    resolved: RawStyle,
+}
+
+/// Styles for each severity, plus one for resolved alerts.
+#[derive(Clone, Deserialize)]
+#[serde(from = "RawStyles")]
+pub struct StylesConfig {
+    pub critical: Style,
+    pub warning: Style,
+    pub info: Style,
+    pub resolved: Style,
+}
+impl From<RawStyles> for StylesConfig {
+    fn from(raw: RawStyles) -> StylesConfig {
+        StylesConfig {
+            critical: raw.critical.or("#e01e5a", ":red_circle:"),
+            warning: raw.warning.or("#ecb22e", ":large_orange_circle:"),
+            info: raw.info.or("#2eb67d", ":large_green_circle:"),
+            resolved: raw.resolved.or("#2eb67d", ":white_check_mark:"),
+        }
+    }
+}
+
+impl StylesConfig {
+    pub fn style_for(&self, severity: Severity, status: Status) -> &Style {
+        match (status, severity) {
+            (Status::Resolved, _) => &self.resolved,
+            (Status::Firing, Severity::Critical) => &self.critical,
+            (Status::Firing, Severity::Warning) => &self.warning,
+            (Status::Firing, Severity::Info) => &self.info,
+        }
+    }
+}
+
+/// A mapping of alert attributes to the values they must equal.
+fn conditions(fields: BTreeMap<String, FieldValue>) -> BTreeMap<String, String> {
+    fields
+        .into_iter()
+        .map(|(k, v)| {
+            let v = match v {
+                FieldValue::Str(s) => s,
+                FieldValue::Int(i) => i.to_string(),
+                FieldValue::Bool(b) => b.to_string(),
+            };
+            (k, v)
+        })
+        .collect()
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawMentionRule {
+    #[serde(default)]
+    when: BTreeMap<String, FieldValue>,
+    #[serde(default)]
+    users: Vec<String>,
+    #[serde(default)]
+    groups: Vec<String>,
+}
+
+/// People to mention on alerts whose attributes all equal the given values.
+#[derive(Deserialize)]
+#[serde(from = "RawMentionRule")]
+pub struct MentionRule {
+    pub when: BTreeMap<String, String>,
+    /// Slack user IDs, or email addresses to look up with the bot token.
+    pub users: Vec<String>,
+    /// Slack usergroup IDs.
+    pub groups: Vec<String>,
+}
+impl From<RawMentionRule> for MentionRule {
+    fn from(raw: RawMentionRule) -> MentionRule {
+        MentionRule {
+            when: conditions(raw.when),
+            users: raw.users,
+            groups: raw.groups,
+        }
+    }
+}
+
+fn default_mention_severities() -> Vec<Severity> {
+    vec![Severity::Critical]
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MentionsConfig {
+    /// Severities whose alerts mention anyone.
+    #[serde(default = "default_mention_severities")]
+    pub severities: Vec<Severity>,
+    #[serde(default)]
+    pub rules: Vec<MentionRule>,
+}
+
+impl Default for MentionsConfig {
+    fn default() -> MentionsConfig {
+        MentionsConfig {
+            severities: default_mention_severities(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawSchedule {
+    timezone: Option<String>,
+    #[serde(default)]
+    days: Vec<String>,
+    #[serde(default)]
+    times: Vec<String>,
+    #[serde(default)]
+    holidays: Vec<String>,
+}
+
+/// Recurring times of the week in a timezone, such as business hours, less
+/// any holidays.
+#[derive(Clone, PartialEq)]
+pub struct Schedule {
+    pub timezone: Tz,
+    pub days: Vec<Weekday>,
+    /// Start and end of each time of day; an end before its start runs
+    /// past midnight.
+    pub times: Vec<(NaiveTime, NaiveTime)>,
+    pub holidays: Vec<NaiveDate>,
+}
+impl TryFrom<RawSchedule> for Schedule {
+    type Error = String;
+
+    fn try_from(raw: RawSchedule) -> Result<Schedule, Self::Error> {
+        let timezone = match raw.timezone {
+            Some(tz) => tz
+                .parse()
+                .map_err(|_| format!("timezone {} is not a known timezone", tz))?,
+            None => Tz::UTC,
+        };
+
+        const WEEK: [Weekday; 7] = [
+            Weekday::Mon,
+            Weekday::Tue,
+            Weekday::Wed,
+            Weekday::Thu,
+            Weekday::Fri,
+            Weekday::Sat,
+            Weekday::Sun,
+        ];
+        let day = |name: &str| -> Result<usize, String> {
+            name.parse::<Weekday>()
+                .map(|day| day.num_days_from_monday() as usize)
+                .map_err(|_| format!("days has unknown day {}", name))
+        };
+        let mut days = Vec::new();
+        for entry in &raw.days {
+            match entry.split_once('-') {
+                // Ranges such as mon-fri, possibly wrapping, as in fri-mon.
+                Some((from, to)) => {
+                    let (from, to) = (day(from)?, day(to)?);
+                    let len = (to + 7 - from) % 7 + 1;
+                    days.extend((0..len).map(|i| WEEK[(from + i) % 7]));
+                }
+                None => days.push(WEEK[day(entry)?]),
+            }
+        }
+        if days.is_empty() {
+            days = WEEK.to_vec();
+        }
+
+        let time = |raw: &str| {
+            NaiveTime::parse_from_str(raw.trim(), "%H:%M")
+                .map_err(|_| format!("times has bad time {}", raw))
+        };
+        let mut times = Vec::new();
+        for entry in &raw.times {
+            let (start, end) = entry
+                .split_once('-')
+                .ok_or_else(|| format!("times entry {} is not HH:MM-HH:MM", entry))?;
+            times.push((time(start)?, time(end)?));
+        }
+
+        let mut holidays = Vec::new();
+        for entry in &raw.holidays {
+            holidays.push(
+                NaiveDate::parse_from_str(entry, "%Y-%m-%d")
+                    .map_err(|_| format!("holidays has bad date {}", entry))?,
+            );
+        }
+
+        Ok(Schedule {
+            timezone,
+            days,
+            times,
+            holidays,
+        })
+    }
+}
+checked!(Schedule, RawSchedule);
+
+impl Schedule {
+    /// Whether `at` falls within the schedule. Days and holidays are judged
+    /// by the local date at `at`, so the hours after midnight of a time that
+    /// runs past it count towards the next day.
+    pub fn contains(&self, at: DateTime<Utc>) -> bool {
+        let local = at.with_timezone(&self.timezone);
+        let (date, time) = (local.date_naive(), local.time());
+        if self.holidays.contains(&date) || !self.days.contains(&date.weekday()) {
+            return false;
+        }
+        self.times.is_empty()
+            || self.times.iter().any(|&(start, end)| match start <= end {
+                true => start <= time && time < end,
+                false => start <= time || time < end,
+            })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawQuietHours {
+    timezone: Option<String>,
+    #[serde(default)]
+    days: Vec<String>,
+    #[serde(default)]
+    times: Vec<String>,
+    #[serde(default)]
+    holidays: Vec<String>,
+    path: Option<String>,
+}
+
+/// When to hold back non-critical alerts for a digest once the quiet hours
+/// end.
+pub struct QuietHoursConfig {
+    pub schedule: Schedule,
+    /// File held alerts are kept in across restarts; memory only if unset.
+    pub path: Option<String>,
+}
+impl TryFrom<RawQuietHours> for QuietHoursConfig {
+    type Error = String;
+
+    fn try_from(raw: RawQuietHours) -> Result<QuietHoursConfig, Self::Error> {
+        let schedule = Schedule::try_from(RawSchedule {
+            timezone: raw.timezone,
+            days: raw.days,
+            times: raw.times,
+            holidays: raw.holidays,
+        })?;
+        if schedule.times.is_empty() {
+            return Err("times are required".to_string());
+        }
+
+        Ok(QuietHoursConfig {
+            schedule,
+            path: raw.path,
+        })
+    }
+}
+checked!(QuietHoursConfig, RawQuietHours);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRateLimit {
+    count: u32,
+    per: Option<u64>,
+}
+
+/// At most `count` notifications every `per`, refilled steadily.
+#[derive(Clone, Copy)]
+pub struct RateLimit {
+    pub count: u32,
+    pub per: Duration,
+}
+impl TryFrom<RawRateLimit> for RateLimit {
+    type Error = String;
+
+    fn try_from(raw: RawRateLimit) -> Result<RateLimit, Self::Error> {
+        if raw.count < 1 {
+            return Err("count must be at least 1".to_string());
+        }
+        let per = raw.per.unwrap_or(60 * 60);
+        if per < 1 {
+            return Err("per must be at least 1".to_string());
+        }
+
+        Ok(RateLimit {
+            count: raw.count,
+            per: Duration::from_secs(per),
+        })
+    }
+}
+checked!(RateLimit, RawRateLimit);
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRateLimits {
+    per_alert: Option<RateLimit>,
+    per_channel: Option<RateLimit>,
+    summary_interval: Option<u64>,
+}
+
+/// Limits on notifications, which routes may override. Notifications over
+/// a limit are counted and summarised every `summary_interval`.
+pub struct RateLimitsConfig {
+    pub per_alert: Option<RateLimit>,
+    pub per_channel: Option<RateLimit>,
+    pub summary_interval: Duration,
+}
+impl TryFrom<RawRateLimits> for RateLimitsConfig {
+    type Error = String;
+
+    fn try_from(raw: RawRateLimits) -> Result<RateLimitsConfig, Self::Error> {
+        let summary_interval = raw.summary_interval.unwrap_or(15 * 60);
+        if summary_interval < 1 {
+            return Err("summary_interval must be at least 1".to_string());
+        }
+
+        Ok(RateLimitsConfig {
+            per_alert: raw.per_alert,
+            per_channel: raw.per_channel,
+            summary_interval: Duration::from_secs(summary_interval),
+        })
+    }
+}
+checked!(RateLimitsConfig, RawRateLimits);
+
+/// Parse an optional filter expression; see `expr`.
+fn read_filter(raw: Option<String>, context: &str) -> Result<Option<Expr>, String> {
+    match raw {
+        Some(raw) => Expr::parse(&raw)
+            .map(Some)
+            .map_err(|e| format!("{} `{}` is invalid: {}", context, raw, e)),
+        None => Ok(None),
+    }
+}
+
+/// Conditions on an alert's attributes.
+#[derive(Clone, Default)]
+pub struct Matchers {
+    /// Attributes that must equal the given values.
+    pub equal: BTreeMap<String, String>,
+    /// Attributes that must wholly match the given patterns.
+    pub regex: Vec<(String, Regex)>,
+}
+
+impl Matchers {
+    /// Build equality matchers from `equal` and patterns from `regex`.
+    fn new(
+        equal: BTreeMap<String, FieldValue>,
+        regex: BTreeMap<String, FieldValue>,
+        context: &str,
+    ) -> Result<Matchers, String> {
+        let mut patterns = Vec::new();
+        for (key, pattern) in conditions(regex) {
+            let re = Regex::new(&format!("^(?:{})$", pattern))
+                .map_err(|e| format!("{}.{} is not a valid regex: {}", context, key, e))?;
+            patterns.push((key, re));
+        }
+
+        Ok(Matchers {
+            equal: conditions(equal),
+            regex: patterns,
+        })
+    }
+
+    /// Whether the alert, given in its serialized form as for
+    /// `alert::matches`, meets every condition.
+    pub fn matches(&self, alert: &Value) -> bool {
+        alert::matches(alert, &self.equal)
+            && self.regex.iter().all(|(key, re)| match &alert[key] {
+                Value::String(s) => re.is_match(s),
+                Value::Null => false,
+                other => re.is_match(&other.to_string()),
+            })
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRouteLimits {
+    per_alert: Option<RateLimit>,
+    per_channel: Option<RateLimit>,
+}
+
+/// When an alert going to several receivers counts as delivered, and its
+/// message is acked on the broker.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AckPolicy {
+    /// Once every receiver has taken it; those that have not are retried
+    /// until they do.
+    #[default]
+    All,
+    /// Once any receiver has taken it; the rest are given up on.
+    Any,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRouteNode {
+    #[serde(default, rename = "match")]
+    equal: BTreeMap<String, FieldValue>,
+    #[serde(default)]
+    match_re: BTreeMap<String, FieldValue>,
+    during: Option<String>,
+    outside: Option<String>,
+    filter: Option<String>,
+    drop: Option<bool>,
+    #[serde(default)]
+    rate_limits: RawRouteLimits,
+    receiver: Option<String>,
+    receivers: Option<Vec<String>>,
+    ack: Option<AckPolicy>,
+    fallback: Option<Vec<String>>,
+    template: Option<String>,
+    group_by: Option<Vec<String>>,
+    group_interval: Option<u64>,
+    repeat_interval: Option<u64>,
+    #[serde(default)]
+    routes: Vec<RouteNode>,
+}
+
+/// A node in the routing tree. Alerts go to the first child matching them,
+/// inheriting whatever that child leaves unset from its parent.
+#[derive(Clone, Default)]
+pub struct RouteNode {
+    pub matchers: Matchers,
+    /// Name of a schedule the route applies only during.
+    pub during: Option<String>,
+    /// Name of a schedule the route applies only outside of.
+    pub outside: Option<String>,
+    /// An expression the alert must satisfy too.
+    pub filter: Option<Expr>,
+    /// Discard alerts taking this route.
+    pub drop: Option<bool>,
+    pub per_alert: Option<RateLimit>,
+    pub per_channel: Option<RateLimit>,
+    /// Names of the receivers to post to. The first is the one threads
+    /// and digests belong to; the rest are sent copies.
+    pub receivers: Option<Vec<String>>,
+    pub ack: Option<AckPolicy>,
+    /// Receivers to try in turn while the circuit for the first of
+    /// `receivers` is open.
+    pub fallback: Option<Vec<String>>,
+    pub template: Option<String>,
+    pub group_by: Option<Vec<String>>,
+    pub group_interval: Option<Duration>,
+    /// Repeats of a posted alert are suppressed for this long.
+    pub repeat_interval: Option<Duration>,
+    pub routes: Vec<RouteNode>,
+}
+impl TryFrom<RawRouteNode> for RouteNode {
+    type Error = String;
+
+    fn try_from(raw: RawRouteNode) -> Result<RouteNode, Self::Error> {
+        let seconds = |key: &str, value: Option<u64>| match value {
+            Some(0) => Err(format!("{} must be at least 1", key)),
+            value => Ok(value.map(Duration::from_secs)),
+        };
+
+        let receivers = match (raw.receiver, raw.receivers) {
+            (Some(_), Some(_)) => return Err("give either receiver or receivers".to_string()),
+            (Some(receiver), None) => Some(vec![receiver]),
+            (None, Some(receivers)) if receivers.is_empty() => {
+                return Err("receivers must name at least one receiver".to_string())
+            }
+            (None, receivers) => receivers,
+        };
+
+        Ok(RouteNode {
+            matchers: Matchers::new(raw.equal, raw.match_re, "match_re")?,
+            during: raw.during,
+            outside: raw.outside,
+            filter: read_filter(raw.filter, "filter")?,
+            drop: raw.drop,
+            per_alert: raw.rate_limits.per_alert,
+            per_channel: raw.rate_limits.per_channel,
+            receivers,
+            ack: raw.ack,
+            fallback: raw.fallback,
+            template: raw.template,
+            group_by: raw.group_by,
+            group_interval: seconds("group_interval", raw.group_interval)?,
+            repeat_interval: seconds("repeat_interval", raw.repeat_interval)?,
+            routes: raw.routes,
+        })
+    }
+}
+checked!(RouteNode, RawRouteNode);
+
+impl RouteNode {
+    /// Every node in the tree below this one.
+    fn descendants(&self) -> Vec<&RouteNode> {
+        self.routes
+            .iter()
+            .flat_map(|node| std::iter::once(node).chain(node.descendants()))
+            .collect()
+    }
+}
+
+/// What the routing tree decides for an alert. Anything left unset falls
+/// back to the queue's route and the global settings.
+#[derive(Default)]
+pub struct Routing<'a> {
+    pub drop: bool,
+    pub receiver: Option<&'a str>,
+    /// Further receivers sent a copy of what goes to `receiver`.
+    pub copies: &'a [String],
+    pub ack: AckPolicy,
+    /// Receivers to try in turn while the circuit for `receiver` is open.
+    pub fallback: &'a [String],
+    pub template: Option<&'a str>,
+    pub group_by: Option<&'a [String]>,
+    pub group_interval: Option<Duration>,
+    pub repeat_interval: Option<Duration>,
+    pub per_alert: Option<RateLimit>,
+    pub per_channel: Option<RateLimit>,
+}
+
+/// Named destinations and schedules, and the tree of routes choosing
+/// between them.
+#[derive(Clone, Default)]
+pub struct RoutingConfig {
+    pub receivers: HashMap<String, Receiver>,
+    pub schedules: HashMap<String, Schedule>,
+    pub root: RouteNode,
+}
+
+impl RoutingConfig {
+    /// Check that the routes only refer to receivers and schedules that
+    /// exist.
+    fn new(
+        receivers: HashMap<String, Receiver>,
+        schedules: HashMap<String, Schedule>,
+        routes: Vec<RouteNode>,
+    ) -> Result<RoutingConfig, String> {
+        let root = RouteNode {
+            routes,
+            ..RouteNode::default()
+        };
+        for node in root.descendants() {
+            for receiver in node.receivers.iter().chain(&node.fallback).flatten() {
+                if !receivers.contains_key(receiver) {
+                    return Err(format!("routes refer to unknown receiver {}", receiver));
+                }
+            }
+            for schedule in node.during.iter().chain(&node.outside) {
+                if !schedules.contains_key(schedule) {
+                    return Err(format!("routes refer to unknown schedule {}", schedule));
+                }
+            }
+        }
+
+        Ok(RoutingConfig {
+            receivers,
+            schedules,
+            root,
+        })
+    }
+
+    /// Whether `node` applies to the alert at `now`.
+    fn applies(&self, node: &RouteNode, alert: &Value, now: DateTime<Utc>) -> bool {
+        let in_schedule = |name: &String| self.schedules[name].contains(now);
+        node.matchers.matches(alert)
+            && node.filter.iter().all(|filter| filter.matches(alert))
+            && node.during.iter().all(in_schedule)
+            && !node.outside.iter().any(in_schedule)
+    }
+
+    /// Walk the tree for the alert, given in its serialized form as for
+    /// `alert::matches`, as it stands now.
+    pub fn resolve(&self, alert: &Value) -> Routing<'_> {
+        let now = Utc::now();
+        let mut routing = Routing::default();
+        let mut node = &self.root;
+        while let Some(child) = node
+            .routes
+            .iter()
+            .find(|child| self.applies(child, alert, now))
+        {
+            routing.drop = child.drop.unwrap_or(routing.drop);
+            if let Some(receivers) = &child.receivers {
+                routing.receiver = receivers.first().map(String::as_str);
+                routing.copies = &receivers[1..];
+            }
+            routing.ack = child.ack.unwrap_or(routing.ack);
+            routing.fallback = child.fallback.as_deref().unwrap_or(routing.fallback);
+            routing.template = child.template.as_deref().or(routing.template);
+            routing.group_by = child.group_by.as_deref().or(routing.group_by);
+            routing.group_interval = child.group_interval.or(routing.group_interval);
+            routing.repeat_interval = child.repeat_interval.or(routing.repeat_interval);
+            routing.per_alert = child.per_alert.or(routing.per_alert);
+            routing.per_channel = child.per_channel.or(routing.per_channel);
+            node = child;
+        }
+        routing
+    }
+
+    /// Templates named anywhere in the tree or by a receiver.
+    pub fn templates(&self) -> Vec<&str> {
+        let receivers = self
+            .receivers
+            .values()
+            .flat_map(|receiver| match receiver {
+                Receiver::Webhook(webhook) => vec![webhook.template.as_deref()],
+                Receiver::Jira(project) => vec![
+                    project.summary_template.as_deref(),
+                    project.description_template.as_deref(),
+                ],
+                _ => vec![],
+            })
+            .flatten();
+        self.root
+            .descendants()
+            .iter()
+            .filter_map(|node| node.template.as_deref())
+            .chain(receivers)
+            .collect()
+    }
+
+    /// Whether any route groups alerts.
+    pub fn groups(&self) -> bool {
+        self.root
+            .descendants()
+            .iter()
+            .any(|node| node.group_by.is_some())
+    }
+
+    /// Whether any route limits notifications.
+    pub fn limits(&self) -> bool {
+        self.root
+            .descendants()
+            .iter()
+            .any(|node| node.per_alert.is_some() || node.per_channel.is_some())
+    }
+
+    /// Whether any route suppresses repeats.
+    pub fn repeats(&self) -> bool {
+        self.root
+            .descendants()
+            .iter()
+            .any(|node| node.repeat_interval.is_some())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawDropRule {
+    name: Option<String>,
+    #[serde(default, rename = "match")]
+    equal: BTreeMap<String, FieldValue>,
+    #[serde(default)]
+    match_re: BTreeMap<String, FieldValue>,
+    filter: Option<String>,
+}
+
+/// Discards alerts known to be noise.
+pub struct DropRule {
+    /// Counts are reported under this name.
+    pub name: String,
+    pub matchers: Matchers,
+    pub filter: Option<Expr>,
+}
+
+/// Rules left unnamed are named after their place in the list.
+fn read_drop_rules(raw: Vec<RawDropRule>) -> Result<Vec<DropRule>, String> {
+    let mut rules = Vec::new();
+    for (i, rule) in raw.into_iter().enumerate() {
+        let context = format!("drop_rules[{}]", i);
+        let matchers = Matchers::new(rule.equal, rule.match_re, &format!("{}.match_re", context))?;
+        let filter = read_filter(rule.filter, &format!("{}.filter", context))?;
+        if matchers.equal.is_empty() && matchers.regex.is_empty() && filter.is_none() {
+            return Err(format!("{} needs matchers or a filter", context));
+        }
+        rules.push(DropRule {
+            name: rule.name.unwrap_or_else(|| format!("drop rule {}", i + 1)),
+            matchers,
+            filter,
+        });
+    }
+    Ok(rules)
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawInhibitRule {
+    #[serde(default)]
+    source_match: BTreeMap<String, FieldValue>,
+    #[serde(default)]
+    source_match_re: BTreeMap<String, FieldValue>,
+    #[serde(default)]
+    target_match: BTreeMap<String, FieldValue>,
+    #[serde(default)]
+    target_match_re: BTreeMap<String, FieldValue>,
+    #[serde(default)]
+    equal: Vec<String>,
+}
+
+/// Suppresses `target` alerts while a `source` alert is firing that has the
+/// same values for the `equal` attributes.
+pub struct InhibitRule {
+    pub source: Matchers,
+    pub target: Matchers,
+    pub equal: Vec<String>,
+}
+impl TryFrom<RawInhibitRule> for InhibitRule {
+    type Error = String;
+
+    fn try_from(raw: RawInhibitRule) -> Result<InhibitRule, Self::Error> {
+        let source = Matchers::new(raw.source_match, raw.source_match_re, "source_match_re")?;
+        let target = Matchers::new(raw.target_match, raw.target_match_re, "target_match_re")?;
+        if source.equal.is_empty() && source.regex.is_empty() {
+            return Err("source_match or source_match_re is required".to_string());
+        }
+
+        Ok(InhibitRule {
+            source,
+            target,
+            equal: raw.equal,
+        })
+    }
+}
+checked!(InhibitRule, RawInhibitRule);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawTemplateRule {
+    #[serde(default)]
+    when: BTreeMap<String, FieldValue>,
+    template: String,
+}
+
+/// Picks a template for alerts whose attributes all equal the given values.
+#[derive(Deserialize)]
+#[serde(from = "RawTemplateRule")]
+pub struct TemplateRule {
+    pub when: BTreeMap<String, String>,
+    pub template: String,
+}
+impl From<RawTemplateRule> for TemplateRule {
+    fn from(raw: RawTemplateRule) -> TemplateRule {
+        TemplateRule {
+            when: conditions(raw.when),
+            template: raw.template,
+        }
+    }
+}
+
+/// Tera templates for Slack message bodies. Alerts matching no rule use
+/// `default`, or the built-in layout if that is not set either.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TemplateConfig {
+    pub default: Option<String>,
+    pub select: Vec<TemplateRule>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum XMatch {
+    All,
+    Any,
+}
+
+impl XMatch {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            XMatch::All => "all",
+            XMatch::Any => "any",
+        }
+    }
+}
+
+/// A scalar AMQP field value, as used in binding headers and queue arguments.
+#[derive(Clone)]
+pub enum FieldValue {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+}
+
+impl<'de> Deserialize<'de> for FieldValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<FieldValue, D::Error> {
+        struct FieldValueVisitor;
+
+        impl<'de> Visitor<'de> for FieldValueVisitor {
+            type Value = FieldValue;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a string, integer or boolean")
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<FieldValue, E> {
+                Ok(FieldValue::Str(s.to_string()))
+            }
+
+            fn visit_i64<E: de::Error>(self, i: i64) -> Result<FieldValue, E> {
+                Ok(FieldValue::Int(i))
+            }
+
+            fn visit_u64<E: de::Error>(self, u: u64) -> Result<FieldValue, E> {
+                i64::try_from(u)
+                    .map(FieldValue::Int)
+                    .map_err(|_| E::custom(format!("{} is too large", u)))
+            }
+
+            fn visit_bool<E: de::Error>(self, b: bool) -> Result<FieldValue, E> {
+                Ok(FieldValue::Bool(b))
+            }
+        }
+
+        deserializer.deserialize_any(FieldValueVisitor)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawBinding {
+    #[serde(default)]
+    routing_key: String,
+    #[serde(rename = "match")]
+    x_match: Option<XMatch>,
+    #[serde(default)]
+    headers: BTreeMap<String, FieldValue>,
+}
+
+/// One binding between the exchange and a queue. Headers exchanges use
+/// `x_match` and `headers`; topic and direct exchanges use `routing_key`.
+#[derive(Clone, Deserialize)]
+#[serde(from = "RawBinding")]
+pub struct BindingConfig {
+    pub routing_key: String,
+    pub x_match: XMatch,
+    pub headers: BTreeMap<String, FieldValue>,
+}
+impl From<RawBinding> for BindingConfig {
+    fn from(raw: RawBinding) -> BindingConfig {
+        BindingConfig {
+            routing_key: raw.routing_key,
+            x_match: raw.x_match.unwrap_or(XMatch::All),
+            headers: raw.headers,
+        }
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawExchange {
+    name: Option<String>,
+    kind: Option<String>,
+    #[serde(default)]
+    durable: bool,
+    #[serde(default)]
+    auto_delete: bool,
+}
+
+pub struct ExchangeConfig {
+    pub name: String,
+    pub kind: ExchangeKind,
+    pub durable: bool,
+    pub auto_delete: bool,
+}
+impl TryFrom<RawExchange> for ExchangeConfig {
+    type Error = String;
+
+    fn try_from(raw: RawExchange) -> Result<ExchangeConfig, Self::Error> {
+        let kind = match raw.kind.as_deref() {
+            None | Some("headers") => ExchangeKind::Headers,
+            Some("topic") => ExchangeKind::Topic,
+            Some("direct") => ExchangeKind::Direct,
+            Some("fanout") => ExchangeKind::Fanout,
+            Some(other) => return Err(format!("Unknown exchange.kind \"{}\"", other)),
+        };
+
+        Ok(ExchangeConfig {
+            name: raw.name.unwrap_or_else(|| "2steps".to_string()),
+            kind,
+            durable: raw.durable,
+            auto_delete: raw.auto_delete,
+        })
+    }
+}
+checked!(ExchangeConfig, RawExchange);
+
+pub struct QueueConfig {
+    pub name: String,
+    pub durable: bool,
+    pub auto_delete: bool,
+    /// Extra `x-` arguments such as `x-queue-type` or `x-message-ttl`.
+    pub arguments: BTreeMap<String, FieldValue>,
+    pub bindings: Vec<BindingConfig>,
+    pub slack: SlackConfig,
+}
+
+impl QueueConfig {
+    /// Names where an alert of `severity` from this queue goes, in a form
+    /// that is safe to log. `channel` is the alert's own choice of channel
+    /// and `receiver` the one chosen by the routing tree.
+    pub fn destination(
+        &self,
+        severity: Severity,
+        channel: Option<&str>,
+        receiver: Option<&str>,
+    ) -> String {
+        match (channel, receiver) {
+            (Some(channel), _) => format!("{}/{}", self.name, channel),
+            (None, Some(receiver)) => format!("{}/{}", self.name, receiver),
+            (None, None) if self.slack.severity_routes.contains_key(&severity) => {
+                format!("{}/{}", self.name, severity)
+            }
+            (None, None) => self.name.clone(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawQueue {
+    name: String,
+    #[serde(default)]
+    durable: bool,
+    #[serde(default)]
+    auto_delete: bool,
+    #[serde(default)]
+    arguments: BTreeMap<String, FieldValue>,
+    bindings: Option<Vec<BindingConfig>>,
+    slack: Option<SlackConfig>,
+}
+
+/// With no explicit bindings, receive everything: an empty x-match=all
+/// binding on a headers exchange, or `#` on a topic exchange.
+fn default_bindings() -> Vec<BindingConfig> {
+    vec![BindingConfig {
+        routing_key: "#".to_string(),
+        x_match: XMatch::All,
+        headers: BTreeMap::new(),
+    }]
+}
+
+/// Build the queues from the `queues` list. Each queue may carry its own
+/// `slack` section and otherwise falls back to the top-level one. Without a
+/// `queues` list we consume from a single `slack_alerts` queue using the
+/// top-level `bindings`.
+fn read_queues(
+    queues: Option<Vec<RawQueue>>,
+    slack: Option<SlackConfig>,
+    bindings: Option<Vec<BindingConfig>>,
+) -> Result<Vec<QueueConfig>, String> {
+    let list = match queues {
+        Some(list) => list,
+        None => {
+            return Ok(vec![QueueConfig {
+                name: "slack_alerts".to_string(),
+                durable: false,
+                auto_delete: false,
+                arguments: BTreeMap::new(),
+                bindings: bindings.unwrap_or_else(default_bindings),
+                slack: slack.ok_or("Configuration missing required Slack URL or channel")?,
+            }])
+        }
+    };
+
+    let mut queues = Vec::with_capacity(list.len());
+    for item in list {
+        let slack = match item.slack {
+            Some(slack) => slack,
+            None => slack
+                .clone()
+                .ok_or_else(|| format!("Queue {} has no Slack URL", item.name))?,
+        };
+        queues.push(QueueConfig {
+            name: item.name,
+            durable: item.durable,
+            auto_delete: item.auto_delete,
+            arguments: item.arguments,
+            bindings: item.bindings.unwrap_or_else(default_bindings),
+            slack,
+        });
+    }
+
+    if queues.is_empty() {
+        return Err("queues must list at least one queue".to_string());
+    }
+    Ok(queues)
+}
+
+/// Check the bindings of each queue against the kind of exchange they bind
+/// to: headers only mean something to a headers exchange, `x-` headers are
+/// reserved for the broker, and topic wildcards must be whole words.
+fn check_bindings(exchange: &ExchangeConfig, queues: &[QueueConfig]) -> Result<(), String> {
+    for queue in queues {
+        for binding in &queue.bindings {
+            if let Some(name) = binding.headers.keys().find(|k| k.starts_with("x-")) {
+                return Err(format!(
+                    "Queue {} binds on header {}, which is reserved",
+                    queue.name, name
+                ));
+            }
+            match exchange.kind {
+                ExchangeKind::Headers | ExchangeKind::Fanout => {}
+                _ if !binding.headers.is_empty() => {
+                    return Err(format!(
+                        "Queue {} binds on headers, which needs a headers exchange",
+                        queue.name
+                    ))
+                }
+                ExchangeKind::Topic => {
+                    let wildcard = |w: &str| w != "*" && w != "#" && w.contains(['*', '#']);
+                    if binding.routing_key.split('.').any(wildcard) {
+                        return Err(format!(
+                            "Queue {} has routing key \"{}\"; * and # must stand for whole words",
+                            queue.name, binding.routing_key
+                        ));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawTls {
+    ca_file: Option<String>,
+    cert_file: Option<String>,
+    key_file: Option<String>,
+    pkcs12_file: Option<String>,
+    #[serde(default)]
+    pkcs12_password: String,
+    server_name: Option<String>,
+}
+
+/// Client-side TLS settings for `amqps://` brokers and syslog receivers.
+#[derive(Clone)]
+pub struct TlsConfig {
+    /// PEM bundle of CAs trusted in addition to the system store.
+    pub ca_file: Option<String>,
+    /// PEM client certificate and PKCS#8 key for mutual TLS.
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>,
+    /// Alternatively, a PKCS#12 archive holding both.
+    pub pkcs12_file: Option<String>,
+    pub pkcs12_password: String,
+    /// Name presented via SNI and checked against the broker certificate,
+    /// when it differs from the host we connect to.
+    pub server_name: Option<String>,
+}
+impl TryFrom<RawTls> for TlsConfig {
+    type Error = String;
+
+    fn try_from(raw: RawTls) -> Result<TlsConfig, Self::Error> {
+        if raw.cert_file.is_some() != raw.key_file.is_some() {
+            return Err("cert_file and key_file must be given together".to_string());
+        }
+        if raw.cert_file.is_some() && raw.pkcs12_file.is_some() {
+            return Err(
+                "either cert_file/key_file or pkcs12_file may be given, not both".to_string(),
+            );
+        }
+
+        Ok(TlsConfig {
+            ca_file: raw.ca_file,
+            cert_file: raw.cert_file,
+            key_file: raw.key_file,
+            pkcs12_file: raw.pkcs12_file,
+            pkcs12_password: raw.pkcs12_password,
+            server_name: raw.server_name,
+        })
+    }
+}
+checked!(TlsConfig, RawTls);
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRetry {
+    initial_delay: Option<u64>,
+    max_delay: Option<u64>,
+    startup_attempts: Option<u32>,
+}
+
+/// How hard to try when the broker can't be reached.
+pub struct RetryConfig {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Give up starting after this many failed connection attempts; `None`
+    /// keeps trying forever.
+    pub startup_attempts: Option<u32>,
+}
+impl TryFrom<RawRetry> for RetryConfig {
+    type Error = String;
+
+    fn try_from(raw: RawRetry) -> Result<RetryConfig, Self::Error> {
+        let seconds = |key: &str, value: Option<u64>, default: u64| match value {
+            Some(0) => Err(format!("{} must be at least 1", key)),
+            value => Ok(Duration::from_secs(value.unwrap_or(default))),
+        };
+
+        let initial_delay = seconds("initial_delay", raw.initial_delay, 1)?;
+        let max_delay = seconds("max_delay", raw.max_delay, 60)?;
+        if max_delay < initial_delay {
+            return Err("max_delay must not be less than initial_delay".to_string());
+        }
+
+        Ok(RetryConfig {
+            initial_delay,
+            max_delay,
+            startup_attempts: raw.startup_attempts.filter(|&n| n > 0),
+        })
+    }
+}
+checked!(RetryConfig, RawRetry);
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRabbit {
+    urls: Option<Vec<String>>,
+    url: Option<String>,
+    hosts: Option<Vec<String>>,
+    host: Option<String>,
+    port: Option<u16>,
+    vhost: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    heartbeat: Option<u16>,
+    connection_timeout: Option<u64>,
+    failover: Option<String>,
+    retry: Option<RetryConfig>,
+    tls: Option<TlsConfig>,
+    declare: Option<bool>,
+}
+
+/// Broker connection settings. Each value may be overridden by an `AMQP_*`
+/// environment variable, and `AMQP_ADDR` (or `rabbit.urls`) replaces the
+/// hosts, port, vhost and credentials with complete URLs. Lists in
+/// environment variables are comma separated.
+pub struct RabbitConfig {
+    pub urls: Vec<String>,
+    /// Cluster nodes as `host` or `host:port`.
+    pub hosts: Vec<String>,
+    pub port: Option<u16>,
+    pub vhost: String,
+    pub username: String,
+    pub password: String,
+    /// Heartbeat interval in seconds, or the broker's choice if unset.
+    pub heartbeat: Option<u16>,
+    pub connection_timeout: Duration,
+    /// Rotate through the brokers on each reconnect rather than always
+    /// preferring the first one listed.
+    pub round_robin: bool,
+    pub retry: RetryConfig,
+    pub tls: Option<TlsConfig>,
+    /// Declare the exchanges, queues and bindings on connecting. Otherwise
+    /// they are only checked, for credentials that may consume but not
+    /// configure, with `setup` having declared them.
+    pub declare: bool,
+}
+impl TryFrom<RawRabbit> for RabbitConfig {
+    type Error = String;
+
+    fn try_from(raw: RawRabbit) -> Result<RabbitConfig, Self::Error> {
+        let setting = |value: Option<String>, var: &str, default: &str| {
+            env::var(var)
+                .ok()
+                .or(value)
+                .unwrap_or_else(|| default.to_string())
+        };
+        let list = |items: Option<Vec<String>>, item: Option<String>, var: &str| match env::var(var)
+        {
+            Ok(v) => v.split(',').map(|s| s.trim().to_string()).collect(),
+            Err(_) => items.or_else(|| item.map(|i| vec![i])).unwrap_or_default(),
+        };
+        fn number<T: FromStr>(value: Option<T>, var: &str) -> Result<Option<T>, String> {
+            match env::var(var) {
+                Ok(v) => v
+                    .parse()
+                    .map(Some)
+                    .map_err(|_| format!("{} must be a valid number", var)),
+                Err(_) => Ok(value),
+            }
+        }
+
+        let port = number(raw.port, "AMQP_PORT")?;
+        if port == Some(0) {
+            return Err("port must be between 1 and 65535".to_string());
+        }
+        let heartbeat = number(raw.heartbeat, "AMQP_HEARTBEAT")?;
+        let connection_timeout =
+            number(raw.connection_timeout, "AMQP_CONNECTION_TIMEOUT")?.unwrap_or(30);
+        if connection_timeout < 1 {
+            return Err("connection_timeout must be at least 1".to_string());
+        }
+        let round_robin = match raw.failover.as_deref() {
+            None | Some("in_order") => false,
+            Some("round_robin") => true,
+            Some(other) => {
+                return Err(format!(
+                    "failover must be \"in_order\" or \"round_robin\", not \"{}\"",
+                    other
+                ))
+            }
+        };
+
+        let declare = match env::var("AMQP_DECLARE") {
+            Ok(v) => v
+                .parse()
+                .map_err(|_| "AMQP_DECLARE must be true or false".to_string())?,
+            Err(_) => raw.declare.unwrap_or(true),
+        };
+
+        let mut hosts = list(raw.hosts, raw.host, "AMQP_HOST");
+        if hosts.is_empty() {
+            hosts.push("127.0.0.1".to_string());
+        }
+
+        Ok(RabbitConfig {
+            urls: list(raw.urls, raw.url, "AMQP_ADDR"),
+            hosts,
+            port,
+            vhost: setting(raw.vhost, "AMQP_VHOST", "/"),
+            username: setting(raw.username, "AMQP_USERNAME", "guest"),
+            password: setting(raw.password, "AMQP_PASSWORD", "guest"),
+            heartbeat,
+            connection_timeout: Duration::from_secs(connection_timeout),
+            round_robin,
+            retry: or_defaults::<RawRetry, _>(raw.retry)?,
+            tls: raw.tls,
+            declare,
+        })
+    }
+}
+checked!(RabbitConfig, RawRabbit);
+
+impl RabbitConfig {
+    /// Every broker we may connect to, in order of preference. A `tls`
+    /// section implies `amqps`.
+    pub fn uris(&self) -> Result<Vec<AMQPUri>, String> {
+        let mut uris = if !self.urls.is_empty() {
+            self.urls
+                .iter()
+                .map(|url| {
+                    url.parse::<AMQPUri>()
+                        .map_err(|e| format!("Invalid broker URL: {}", e))
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        } else {
+            let scheme = if self.tls.is_some() {
+                AMQPScheme::AMQPS
+            } else {
+                AMQPScheme::AMQP
+            };
+            let default_port = self.port.unwrap_or_else(|| scheme.default_port());
+            self.hosts
+                .iter()
+                .map(|host| {
+                    let (host, port) = match host.rsplit_once(':') {
+                        Some((host, port)) => (
+                            host,
+                            port.parse()
+                                .map_err(|_| format!("Invalid port in broker {}", host))?,
+                        ),
+                        None => (host.as_str(), default_port),
+                    };
+                    Ok(AMQPUri {
+                        scheme: scheme.clone(),
+                        authority: AMQPAuthority {
+                            userinfo: AMQPUserInfo {
+                                username: self.username.clone(),
+                                password: self.password.clone(),
+                            },
+                            host: host.to_string(),
+                            port,
+                        },
+                        vhost: self.vhost.clone(),
+                        query: AMQPQueryString::default(),
+                    })
+                })
+                .collect::<Result<Vec<_>, String>>()?
+        };
+
+        for uri in &mut uris {
+            if uri.query.heartbeat.is_none() {
+                uri.query.heartbeat = self.heartbeat;
+            }
+        }
+        Ok(uris)
+    }
+}
+
+/// The configuration file as written. Sections are checked as they are
+/// read, so that errors point at the offending key and line; what needs
+/// several sections is checked when building the `Config`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    rabbit: Option<RabbitConfig>,
+    consumer: Option<ConsumerConfig>,
+    http: Option<HttpConfig>,
+    spool: Option<SpoolConfig>,
+    interactions: Option<InteractionsConfig>,
+    metrics: Option<MetricsConfig>,
+    health: Option<HealthConfig>,
+    admin: Option<AdminConfig>,
+    statsd: Option<StatsdConfig>,
+    queue_depth: Option<QueueDepthConfig>,
+    logging: Option<LoggingConfig>,
+    tracing: Option<TracingConfig>,
+    audit: Option<AuditConfig>,
+    heartbeat: Option<HeartbeatConfig>,
+    self_alerts: Option<SelfAlertsConfig>,
+    sentry: Option<SentryConfig>,
+    pagerduty: Option<PagerDutyConfig>,
+    email: Option<EmailConfig>,
+    matrix: Option<MatrixConfig>,
+    webex: Option<WebexConfig>,
+    twilio: Option<TwilioConfig>,
+    dedup: Option<DedupConfig>,
+    grouping: Option<GroupingConfig>,
+    flapping: Option<FlappingConfig>,
+    renotify: Option<RenotifyConfig>,
+    quiet_hours: Option<QuietHoursConfig>,
+    circuit_breaker: Option<CircuitBreakerConfig>,
+    rate_limits: Option<RateLimitsConfig>,
+    messages: Option<MessagesConfig>,
+    silences: Option<SilencesConfig>,
+    #[serde(default)]
+    receivers: HashMap<String, Receiver>,
+    #[serde(default)]
+    schedules: HashMap<String, Schedule>,
+    #[serde(default)]
+    routes: Vec<RouteNode>,
+    #[serde(default)]
+    inhibit_rules: Vec<InhibitRule>,
+    #[serde(default)]
+    drop_rules: Vec<RawDropRule>,
+    styles: Option<StylesConfig>,
+    mentions: Option<MentionsConfig>,
+    templates: Option<TemplateConfig>,
+    exchange: Option<ExchangeConfig>,
+    slack: Option<SlackConfig>,
+    bindings: Option<Vec<BindingConfig>>,
+    queues: Option<Vec<RawQueue>>,
+    /// Settings for particular environments, applied by `read_config` when
+    /// one is chosen and otherwise ignored.
+    #[serde(default, rename = "profiles")]
+    _profiles: de::IgnoredAny,
+}
+
+pub struct Config {
+    pub rabbit: RabbitConfig,
+    pub consumer: ConsumerConfig,
+    pub http: HttpConfig,
+    pub spool: Option<SpoolConfig>,
+    pub interactions: Option<InteractionsConfig>,
+    pub metrics: Option<MetricsConfig>,
+    pub health: Option<HealthConfig>,
+    pub admin: Option<AdminConfig>,
+    pub statsd: Option<StatsdConfig>,
+    pub queue_depth: Option<QueueDepthConfig>,
+    pub logging: LoggingConfig,
+    pub tracing: Option<TracingConfig>,
+    pub audit: Option<AuditConfig>,
+    pub heartbeat: Option<HeartbeatConfig>,
+    pub self_alerts: Option<SelfAlertsConfig>,
+    pub sentry: Option<SentryConfig>,
+    pub pagerduty: Option<PagerDutyConfig>,
+    pub email: Option<EmailConfig>,
+    pub matrix: Option<MatrixConfig>,
+    pub webex: Option<WebexConfig>,
+    pub twilio: Option<TwilioConfig>,
+    pub dedup: Option<DedupConfig>,
+    pub grouping: Option<GroupingConfig>,
+    pub flapping: Option<FlappingConfig>,
+    pub renotify: Option<RenotifyConfig>,
+    pub quiet_hours: Option<QuietHoursConfig>,
+    pub circuit_breaker: CircuitBreakerConfig,
+    pub rate_limits: RateLimitsConfig,
+    pub messages: MessagesConfig,
+    pub silences: SilencesConfig,
+    pub routing: RoutingConfig,
+    pub inhibit_rules: Vec<InhibitRule>,
+    pub drop_rules: Vec<DropRule>,
+    pub styles: StylesConfig,
+    pub mentions: MentionsConfig,
+    pub templates: TemplateConfig,
+    pub exchange: ExchangeConfig,
+    pub queues: Vec<QueueConfig>,
+}
+impl TryFrom<RawConfig> for Config {
+    type Error = String;
+
+    fn try_from(raw: RawConfig) -> Result<Config, Self::Error> {
+        let silences = raw.silences.unwrap_or_default();
+        let routing = RoutingConfig::new(raw.receivers, raw.schedules, raw.routes)?;
+        let queues = read_queues(raw.queues, raw.slack, raw.bindings)?;
+        let tokenless = queues.iter().any(|q| q.slack.token.is_none());
+        if silences.channel.is_some() && tokenless {
+            return Err("silences.channel needs a slack.token for every queue".to_string());
+        }
+        let uses = |kind: fn(&Receiver) -> bool| routing.receivers.values().any(kind);
+        if uses(|r| matches!(r, Receiver::Slack(Route::Channel(_)))) && tokenless {
+            return Err("channel receivers need a slack.token for every queue".to_string());
+        }
+        if uses(|r| matches!(r, Receiver::Email(_))) && raw.email.is_none() {
+            return Err("email receivers need an email section".to_string());
+        }
+        if uses(|r| matches!(r, Receiver::Matrix(_))) && raw.matrix.is_none() {
+            return Err("Matrix receivers need a matrix section".to_string());
+        }
+        if uses(|r| matches!(r, Receiver::Webex(_))) && raw.webex.is_none() {
+            return Err("Webex receivers need a webex section".to_string());
+        }
+        if uses(|r| matches!(r, Receiver::Sms(_))) && raw.twilio.is_none() {
+            return Err("SMS receivers need a twilio section".to_string());
+        }
+        if let Some(receiver) = raw.heartbeat.as_ref().and_then(|h| h.receiver.as_ref()) {
+            if !routing.receivers.contains_key(receiver) {
+                return Err(format!("heartbeat: unknown receiver {}", receiver));
+            }
+        }
+        if let Some(receiver) = raw.queue_depth.as_ref().and_then(|q| q.receiver.as_ref()) {
+            if !routing.receivers.contains_key(receiver) {
+                return Err(format!("queue_depth: unknown receiver {}", receiver));
+            }
+        }
+        if let Some(self_alerts) = &raw.self_alerts {
+            if !routing.receivers.contains_key(&self_alerts.receiver) {
+                return Err(format!(
+                    "self_alerts: unknown receiver {}",
+                    self_alerts.receiver
+                ));
+            }
+        }
+
+        let exchange = or_defaults::<RawExchange, _>(raw.exchange)?;
+        check_bindings(&exchange, &queues)?;
+
+        Ok(Config {
+            rabbit: or_defaults::<RawRabbit, _>(raw.rabbit)?,
+            consumer: or_defaults::<RawConsumer, _>(raw.consumer)?,
+            http: or_defaults::<RawHttp, _>(raw.http)?,
+            spool: raw.spool,
+            interactions: raw.interactions,
+            metrics: raw.metrics,
+            health: raw.health,
+            admin: raw.admin,
+            statsd: raw.statsd,
+            queue_depth: raw.queue_depth,
+            logging: or_defaults::<RawLogging, _>(raw.logging)?,
+            tracing: raw.tracing,
+            audit: raw.audit,
+            heartbeat: raw.heartbeat,
+            self_alerts: raw.self_alerts,
+            sentry: raw.sentry,
+            pagerduty: raw.pagerduty,
+            email: raw.email,
+            matrix: raw.matrix,
+            webex: raw.webex,
+            twilio: raw.twilio,
+            dedup: raw.dedup,
+            grouping: raw.grouping,
+            flapping: raw.flapping,
+            renotify: raw.renotify,
+            quiet_hours: raw.quiet_hours,
+            circuit_breaker: or_defaults::<RawCircuitBreaker, _>(raw.circuit_breaker)?,
+            rate_limits: or_defaults::<RawRateLimits, _>(raw.rate_limits)?,
+            messages: or_defaults::<RawMessages, _>(raw.messages)?,
+            silences,
+            routing,
+            inhibit_rules: raw.inhibit_rules,
+            drop_rules: read_drop_rules(raw.drop_rules)?,
+            styles: raw
+                .styles
+                .unwrap_or_else(|| StylesConfig::from(RawStyles::default())),
+            mentions: raw.mentions.unwrap_or_default(),
+            templates: raw.templates.unwrap_or_default(),
+            exchange,
+            queues,
+        })
+    }
+}
+
+impl Config {
+    /// Turn off whatever keeps state that the real service would share, or
+    /// acts other than by delivering notifications, so that a dry run can
+    /// go alongside it: state is kept in memory only, and there is no
+    /// spool, audit log, heartbeat, admin API or handling of interactions.
+    pub fn dry_run(&mut self) {
+        self.messages.path = None;
+        self.silences.path = None;
+        if let Some(quiet_hours) = &mut self.quiet_hours {
+            quiet_hours.path = None;
+        }
+        self.spool = None;
+        self.audit = None;
+        self.heartbeat = None;
+        self.interactions = None;
+        self.admin = None;
+    }
+}
+
+/// Replace `${NAME}` with the value of the environment variable `NAME`, or
+/// with `default` for `${NAME:-default}` if it is unset or empty, so that
+/// secrets can be left out of the file. `$${` stands for a literal `${`.
+/// Lines that are wholly comments are left alone. Values that may hold
+/// characters special to YAML should be quoted.
+fn interpolate(raw: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(raw.len());
+    for (n, line) in raw.split_inclusive('\n').enumerate() {
+        if line.trim_start().starts_with('#') {
+            out.push_str(line);
+            continue;
+        }
+        let mut rest = line;
+        while let Some(i) = rest.find('$') {
+            out.push_str(&rest[..i]);
+            rest = &rest[i..];
+            if rest.starts_with("$${") {
+                out.push_str("${");
+                rest = &rest[3..];
+                continue;
+            }
+            if !rest.starts_with("${") {
+                out.push('$');
+                rest = &rest[1..];
+                continue;
+            }
+            let end = rest
+                .find('}')
+                .ok_or_else(|| format!("Unterminated ${{ on line {}", n + 1))?;
+            let (name, default) = match rest[2..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&rest[2..end], None),
+            };
+            let valid = name.chars().next().is_some_and(|c| !c.is_ascii_digit())
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !valid {
+                return Err(format!(
+                    "Invalid variable name \"{}\" on line {}",
+                    name,
+                    n + 1
+                ));
+            }
+            match (env::var(name), default) {
+                (Ok(value), Some(default)) if value.is_empty() => out.push_str(default),
+                (Ok(value), _) => out.push_str(&value),
+                (Err(_), Some(default)) => out.push_str(default),
+                (Err(_), None) => {
+                    return Err(format!(
+                        "Environment variable {} on line {} is not set",
+                        name,
+                        n + 1
+                    ))
+                }
+            }
+            rest = &rest[end + 1..];
+        }
+        out.push_str(rest);
+    }
+    Ok(out)
+}
+
+/// The language a configuration file is written in.
+#[derive(Clone, Copy)]
+pub enum Format {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl Format {
+    /// The format of the file at `path`, going by its extension. Anything
+    /// but `.toml` and `.json` is taken to be YAML.
+    fn of(path: &Path) -> Format {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Format::Toml,
+            Some("json") => Format::Json,
+            _ => Format::Yaml,
+        }
+    }
+
+    fn parse<T: DeserializeOwned>(self, text: &str) -> Result<T, String> {
+        match self {
+            Format::Yaml => serde_yaml::from_str(text).map_err(|e| e.to_string()),
+            Format::Toml => toml::from_str(text).map_err(|e| e.to_string()),
+            Format::Json => serde_json::from_str(text).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, Self::Err> {
+        match s {
+            "yaml" => Ok(Format::Yaml),
+            "toml" => Ok(Format::Toml),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("Unknown configuration format \"{}\"", s)),
+        }
+    }
+}
+
+/// Where the configuration comes from, as given on the command line.
+#[derive(Clone)]
+pub struct ConfigSource {
+    pub path: String,
+    /// The format of the file, if not to go by its extension.
+    pub format: Option<Format>,
+    /// The entry of the file's `profiles` to apply over the rest of it.
+    pub profile: Option<String>,
+    /// `key.path=value` settings applied in order over the file.
+    pub overrides: Vec<String>,
+}
+
+/// The directory of fragments merged over the configuration at `path`.
+fn fragment_dir(path: &str) -> PathBuf {
+    Path::new(path)
+        .parent()
+        .unwrap_or_else(|| Path::new("."))
+        .join("conf.d")
+}
+
+/// The YAML, TOML and JSON files in `dir`, in order of name. A missing
+/// directory has none.
+fn fragments(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Unable to read {}: {}", dir.display(), e)),
+    };
+    let mut paths = Vec::new();
+    for entry in entries {
+        let path = entry
+            .map_err(|e| format!("Unable to read {}: {}", dir.display(), e))?
+            .path();
+        if matches!(
+            path.extension().and_then(|ext| ext.to_str()),
+            Some("yaml") | Some("yml") | Some("toml") | Some("json")
+        ) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Merge `overlay` over `base`: mappings key by key, lists by appending
+/// the overlay's items if `append` is set, and anything else by replacing
+/// it.
+fn merge(base: &mut serde_yaml::Value, overlay: serde_yaml::Value, append: bool) {
+    use serde_yaml::Value as Yaml;
+
+    match (base, overlay) {
+        (Yaml::Mapping(base), Yaml::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value, append),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (Yaml::Sequence(base), Yaml::Sequence(overlay)) if append => base.extend(overlay),
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Apply one `key.path=value` override to the configuration, creating
+/// mappings along the path where needed. Numbers in the path index lists,
+/// and the value is read as YAML, so that `true` and `5` keep their types.
+fn apply_override(doc: &mut serde_yaml::Value, setting: &str) -> Result<(), String> {
+    use serde_yaml::{Mapping, Value as Yaml};
+
+    let (path, value) = setting
+        .split_once('=')
+        .ok_or_else(|| format!("Override \"{}\" must be key.path=value", setting))?;
+    let mut node = doc;
+    for key in path.split('.') {
+        if key.is_empty() {
+            return Err(format!("Override \"{}\" has an empty key", setting));
+        }
+        if !matches!(node, Yaml::Mapping(_) | Yaml::Sequence(_)) {
+            *node = Yaml::Mapping(Mapping::new());
+        }
+        node = match node {
+            Yaml::Sequence(items) => key
+                .parse::<usize>()
+                .ok()
+                .and_then(move |i| items.get_mut(i))
+                .ok_or_else(|| format!("Override \"{}\": no list item {}", setting, key))?,
+            Yaml::Mapping(map) => {
+                let key = Yaml::String(key.to_string());
+                if !map.contains_key(&key) {
+                    map.insert(key.clone(), Yaml::Null);
+                }
+                map.get_mut(&key).expect("inserted above")
+            }
+            _ => unreachable!(),
+        };
+    }
+    // A value such as "#ops" would otherwise be read as a comment.
+    *node = match value.starts_with('#') {
+        true => Yaml::String(value.to_string()),
+        false => serde_yaml::from_str(value).unwrap_or_else(|_| Yaml::String(value.to_string())),
+    };
+    Ok(())
+}
+
+/// Read the file at `path` with environment variables interpolated.
+fn read_interpolated(path: &Path) -> Result<String, String> {
+    let raw = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read configuration {}: {}", path.display(), e))?;
+    interpolate(&raw).map_err(|e| format!("Invalid configuration in {}: {}", path.display(), e))
+}
+
+/// Read the configuration at `source.path`, with the files in the `conf.d`
+/// directory beside it merged over it in order of name, so that parts of it
+/// such as routes and receivers can be kept in separate files, then the
+/// chosen profile and then the overrides from the command line. Fragments
+/// may be in any format.
+pub fn read_config(source: &ConfigSource) -> Result<Config, String> {
+    let path = source.path.as_str();
+    info!("Reading configuration from {}", path);
+
+    let format = source.format.unwrap_or_else(|| Format::of(Path::new(path)));
+    let text = read_interpolated(Path::new(path))?;
+    let dir = fragment_dir(path);
+    let fragments = fragments(&dir)?;
+    let raw: RawConfig = if fragments.is_empty()
+        && source.profile.is_none()
+        && source.overrides.is_empty()
+    {
+        format
+            .parse(&text)
+            .map_err(|e| format!("Invalid configuration in {}: {}", path, e))?
+    } else {
+        // Fragments are merged as YAML, which can hold anything the other
+        // formats can.
+        let mut merged: serde_yaml::Value = format
+            .parse(&text)
+            .map_err(|e| format!("Invalid configuration in {}: {}", path, e))?;
+        for fragment in &fragments {
+            info!("Merging configuration from {}", fragment.display());
+            let text = read_interpolated(fragment)?;
+            // An empty file leaves the configuration alone.
+            if text.trim().is_empty() {
+                continue;
+            }
+            let overlay = Format::of(fragment)
+                .parse(&text)
+                .map_err(|e| format!("Invalid configuration in {}: {}", fragment.display(), e))?;
+            merge(&mut merged, overlay, true);
+        }
+        let profiles = match &mut merged {
+            serde_yaml::Value::Mapping(map) => map.remove(&"profiles".into()),
+            _ => None,
+        };
+        if let Some(name) = &source.profile {
+            info!("Applying profile {}", name);
+            let profile = profiles
+                .as_ref()
+                .and_then(|profiles| profiles.get(name.as_str()))
+                .ok_or_else(|| format!("Invalid configuration in {}: no profile {}", path, name))?;
+            // A profile replaces lists, such as the broker hosts, outright.
+            merge(&mut merged, profile.clone(), false);
+        }
+        for setting in &source.overrides {
+            info!("Overriding configuration with {}", setting);
+            apply_override(&mut merged, setting)?;
+        }
+
+        let mut origin = path.to_string();
+        if !fragments.is_empty() {
+            origin += &format!(" merged with {}", dir.display());
+        }
+        if let Some(name) = &source.profile {
+            origin += &format!(" with profile {}", name);
+        }
+        if !source.overrides.is_empty() {
+            origin += " and --set overrides";
+        }
+        // Going through text again, rather than from_value, keeps the path to
+        // any error; its line numbers are of the merged document, though.
+        let merged = serde_yaml::to_string(&merged)
+            .map_err(|e| format!("Unable to merge configuration: {}", e))?;
+        serde_yaml::from_str(&merged)
+            .map_err(|e| format!("Invalid configuration in {}: {}", origin, e))?
+    };
+    Config::try_from(raw).map_err(|e| format!("Invalid configuration in {}: {}", path, e))
+}
+
+/// Parse a YAML configuration held in memory, such as the sample.
+pub fn parse_config(text: &str) -> Result<Config, String> {
+    let raw: RawConfig = serde_yaml::from_str(&interpolate(text)?).map_err(|e| e.to_string())?;
+    Config::try_from(raw)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn yaml(text: &str) -> serde_yaml::Value {
+        serde_yaml::from_str(text).unwrap()
+    }
+
+    fn consumer(text: &str) -> Result<ConsumerConfig, String> {
+        ConsumerConfig::try_from(serde_yaml::from_str::<RawConsumer>(text).unwrap())
+    }
+
+    #[test]
+    fn interpolate_substitutes_variables() {
+        env::set_var("INTERPOLATE_SET", "secret");
+        env::set_var("INTERPOLATE_EMPTY", "");
+        env::remove_var("INTERPOLATE_UNSET");
+        let raw = "a: ${INTERPOLATE_SET}\n\
+                   b: ${INTERPOLATE_EMPTY:-fallback}\n\
+                   c: ${INTERPOLATE_UNSET:-}\n\
+                   d: $${INTERPOLATE_SET} costs $5\n\
+                   # ${INTERPOLATE_UNSET}\n";
+        assert_eq!(
+            interpolate(raw).unwrap(),
+            "a: secret\n\
+             b: fallback\n\
+             c: \n\
+             d: ${INTERPOLATE_SET} costs $5\n\
+             # ${INTERPOLATE_UNSET}\n"
+        );
+    }
+
+    #[test]
+    fn interpolate_rejects_unset_and_malformed_variables() {
+        env::remove_var("INTERPOLATE_MISSING");
+        assert_eq!(
+            interpolate("a: 1\nb: ${INTERPOLATE_MISSING}\n").unwrap_err(),
+            "Environment variable INTERPOLATE_MISSING on line 2 is not set"
+        );
+        assert!(interpolate("a: ${UNTERMINATED\n").is_err());
+        assert!(interpolate("a: ${1ST}\n").is_err());
+        assert!(interpolate("a: ${A-B}\n").is_err());
+    }
+
+    #[test]
+    fn merge_combines_mappings_and_lists() {
+        let mut base = yaml("a: {b: 1, c: 2}\nlist: [1]\nscalar: x");
+        merge(
+            &mut base,
+            yaml("a: {c: 3, d: 4}\nlist: [2]\nscalar: y"),
+            true,
+        );
+        assert_eq!(base, yaml("a: {b: 1, c: 3, d: 4}\nlist: [1, 2]\nscalar: y"));
+
+        merge(&mut base, yaml("list: [3]"), false);
+        assert_eq!(base["list"], yaml("[3]"));
+    }
+
+    #[test]
+    fn apply_override_sets_typed_values_along_the_path() {
+        let mut doc = yaml("consumer: {prefetch: 10}\nhosts: [a, b]\nslack: x");
+        apply_override(&mut doc, "consumer.prefetch=5").unwrap();
+        apply_override(&mut doc, "consumer.ordered=false").unwrap();
+        apply_override(&mut doc, "hosts.1=c").unwrap();
+        apply_override(&mut doc, "slack.channel=#ops").unwrap();
+        apply_override(&mut doc, "new.section=a=b").unwrap();
+        assert_eq!(
+            doc,
+            yaml(
+                "consumer: {prefetch: 5, ordered: false}\n\
+                 hosts: [a, c]\n\
+                 slack: {channel: '#ops'}\n\
+                 new: {section: a=b}"
+            )
+        );
+    }
+
+    #[test]
+    fn apply_override_rejects_malformed_settings() {
+        let mut doc = yaml("hosts: [a]");
+        assert!(apply_override(&mut doc, "consumer.prefetch").is_err());
+        assert!(apply_override(&mut doc, "consumer..prefetch=5").is_err());
+        assert!(apply_override(&mut doc, "hosts.1=b").is_err());
+        assert!(apply_override(&mut doc, "hosts.first=b").is_err());
+    }
+
+    #[test]
+    fn read_config_applies_the_profile_then_the_overrides() {
+        let dir = env::temp_dir().join(format!("profile-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.yml");
+        fs::write(
+            &path,
+            "rabbit: {hosts: [a, b]}\n\
+             slack: {url: 'https://hooks.slack.com/x'}\n\
+             profiles:\n  \
+               staging: {rabbit: {hosts: [c]}, consumer: {prefetch: 5}}\n",
+        )
+        .unwrap();
+        let source = |profile: Option<&str>| ConfigSource {
+            path: path.to_str().unwrap().to_string(),
+            format: None,
+            profile: profile.map(str::to_string),
+            overrides: vec!["consumer.concurrency=3".to_string()],
+        };
+
+        let cfg = read_config(&source(None)).unwrap();
+        assert_eq!(cfg.rabbit.hosts, ["a", "b"]);
+        assert_eq!(cfg.consumer.prefetch, 10);
+        assert_eq!(cfg.consumer.concurrency, 3);
+
+        let cfg = read_config(&source(Some("staging"))).unwrap();
+        assert_eq!(cfg.rabbit.hosts, ["c"]);
+        assert_eq!(cfg.consumer.prefetch, 5);
+        assert_eq!(cfg.consumer.concurrency, 3);
+
+        assert!(read_config(&source(Some("production"))).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn consumer_defaults() {
+        let consumer = consumer("{}").unwrap();
+        assert_eq!(consumer.max_attempts, 5);
+        assert_eq!(consumer.prefetch, 10);
+        assert_eq!(consumer.concurrency, 1);
+        assert!(consumer.ordered);
+        assert_eq!(
+            consumer.retry_delays,
+            [30, 120, 600]
+                .iter()
+                .copied()
+                .map(Duration::from_secs)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn consumer_rejects_invalid_settings() {
+        assert!(consumer("max_attempts: 0").is_err());
+        assert!(consumer("prefetch: 0").is_err());
+        assert!(consumer("concurrency: 0").is_err());
+        assert!(consumer("retry_delays: [30, 0]").is_err());
+        assert_eq!(
+            consumer("retry_delays: [30, 60, 30]").err().unwrap(),
+            "retry_delays lists 30 more than once"
+        );
+    }
+}
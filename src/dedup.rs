@@ -0,0 +1,156 @@
+use crate::alert::Alert;
+use crate::config::DedupConfig;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct Seen {
+    /// Until when repeats are suppressed.
+    until: Instant,
+    count: u32,
+}
+
+/// Recognises repeats of alerts posted within the last `window`, or within
+/// the repeat interval of their route.
+pub struct Dedup {
+    fields: Vec<String>,
+    /// None if only routes suppress repeats.
+    window: Option<Duration>,
+    pub show_count: bool,
+    seen: Mutex<HashMap<String, Seen>>,
+}
+
+impl Dedup {
+    pub fn new(cfg: Option<&DedupConfig>) -> Dedup {
+        Dedup {
+            fields: cfg.map(|cfg| cfg.fields.clone()).unwrap_or_default(),
+            window: cfg.map(|cfg| cfg.window),
+            show_count: cfg.is_some_and(|cfg| cfg.show_count),
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Identifies repeats of `alert`: the values of the configured fields,
+    /// or the alert's key if there are none.
+    fn fingerprint(&self, alert: &Alert) -> String {
+        if self.fields.is_empty() {
+            return alert.key();
+        }
+        let value = serde_json::to_value(alert).unwrap_or(Value::Null);
+        self.fields
+            .iter()
+            .map(|field| match &value[field] {
+                Value::String(s) => s.clone(),
+                Value::Null => String::new(),
+                other => other.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// If `alert` repeats one posted within the window, count it and return
+    /// how many times it has now been seen.
+    pub fn repeat(&self, alert: &Alert) -> Option<u32> {
+        let fingerprint = self.fingerprint(alert);
+        let mut seen = self.seen.lock().unwrap();
+        match seen.get_mut(&fingerprint) {
+            Some(seen) if Instant::now() < seen.until => {
+                seen.count += 1;
+                Some(seen.count)
+            }
+            _ => None,
+        }
+    }
+
+    /// Start a new window for `alert`, which has just been posted. `repeat`
+    /// is its route's repeat interval, which overrides the window.
+    pub fn posted(&self, alert: &Alert, repeat: Option<Duration>) {
+        let window = match repeat.or(self.window) {
+            Some(window) => window,
+            None => return,
+        };
+        let fingerprint = self.fingerprint(alert);
+        let mut seen = self.seen.lock().unwrap();
+        let now = Instant::now();
+        seen.retain(|_, s| now < s.until);
+        seen.insert(
+            fingerprint,
+            Seen {
+                until: now + window,
+                count: 1,
+            },
+        );
+    }
+
+    /// Forget `alert`, whose check has recovered, so that it is posted
+    /// again should it fail again.
+    pub fn resolved(&self, alert: &Alert) {
+        self.seen.lock().unwrap().remove(&self.fingerprint(alert));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alert(title: &str, env: &str) -> Alert {
+        let published = serde_json::json!({"severity": "critical", "title": title, "env": env});
+        Alert::from_slice(published.to_string().as_bytes()).unwrap()
+    }
+
+    fn dedup(fields: &[&str]) -> Dedup {
+        Dedup::new(Some(&DedupConfig {
+            fields: fields.iter().map(|f| f.to_string()).collect(),
+            window: Duration::from_secs(3600),
+            show_count: true,
+        }))
+    }
+
+    #[test]
+    fn counts_repeats_within_the_window() {
+        let dedup = dedup(&[]);
+        let first = alert("Checkout", "prod");
+        assert_eq!(dedup.repeat(&first), None);
+        dedup.posted(&first, None);
+        assert_eq!(dedup.repeat(&first), Some(2));
+        assert_eq!(dedup.repeat(&first), Some(3));
+        assert_eq!(dedup.repeat(&alert("Login", "prod")), None);
+    }
+
+    #[test]
+    fn fingerprints_by_the_configured_fields() {
+        let dedup = dedup(&["env"]);
+        dedup.posted(&alert("Checkout", "prod"), None);
+        assert_eq!(dedup.repeat(&alert("Login", "prod")), Some(2));
+        assert_eq!(dedup.repeat(&alert("Checkout", "dev")), None);
+    }
+
+    #[test]
+    fn forgets_resolved_checks() {
+        let dedup = dedup(&[]);
+        let first = alert("Checkout", "prod");
+        dedup.posted(&first, None);
+        dedup.resolved(&first);
+        assert_eq!(dedup.repeat(&first), None);
+    }
+
+    #[test]
+    fn the_route_repeat_interval_overrides_the_window() {
+        let dedup = dedup(&[]);
+        let first = alert("Checkout", "prod");
+        dedup.posted(&first, Some(Duration::from_secs(0)));
+        assert_eq!(dedup.repeat(&first), None);
+    }
+
+    #[test]
+    fn without_a_window_only_routes_suppress_repeats() {
+        let dedup = Dedup::new(None);
+        let first = alert("Checkout", "prod");
+        dedup.posted(&first, None);
+        assert_eq!(dedup.repeat(&first), None);
+        dedup.posted(&first, Some(Duration::from_secs(3600)));
+        assert_eq!(dedup.repeat(&first), Some(2));
+        assert!(!dedup.show_count);
+    }
+}
@@ -0,0 +1,373 @@
+//! A small expression language for filtering alerts, such as
+//! `severity == "critical" && env != "sandbox" && duration_ms > 30000`.
+//!
+//! Names refer to the alert's attributes in its serialized form, with dots
+//! reaching into objects. Comparisons are `==`, `!=`, `<`, `<=`, `>`, `>=`,
+//! and `=~` or `!~` against a regex literal; they combine with `&&`, `||`,
+//! `!` and parentheses. Literals are strings in double quotes, numbers,
+//! `true`, `false` and `null`. An attribute the alert lacks is `null`.
+
+use regex::Regex;
+use serde_json::Value;
+use std::cmp::Ordering;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Name(String),
+    Str(String),
+    Num(f64),
+    Op(&'static str),
+    Open,
+    Close,
+}
+
+const OPS: [&str; 12] = [
+    "&&", "||", "==", "!=", "<=", ">=", "=~", "!~", "<", ">", "!", "-",
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '(' || c == ')' {
+            tokens.push(if c == '(' { Token::Open } else { Token::Close });
+            i += 1;
+        } else if c == '"' {
+            let mut s = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err("unterminated string".to_string()),
+                    Some('"') => break,
+                    Some('\\') => {
+                        match chars.get(i + 1) {
+                            Some('n') => s.push('\n'),
+                            Some(&c) => s.push(c),
+                            None => return Err("unterminated string".to_string()),
+                        }
+                        i += 2;
+                    }
+                    Some(&c) => {
+                        s.push(c);
+                        i += 1;
+                    }
+                }
+            }
+            tokens.push(Token::Str(s));
+            i += 1;
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let raw: String = chars[start..i].iter().collect();
+            let n = raw.parse().map_err(|_| format!("bad number {}", raw))?;
+            tokens.push(Token::Num(n));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len()
+                && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.')
+            {
+                i += 1;
+            }
+            tokens.push(Token::Name(chars[start..i].iter().collect()));
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            let op = OPS
+                .iter()
+                .find(|op| rest.starts_with(*op))
+                .ok_or_else(|| format!("unexpected {}", c))?;
+            tokens.push(Token::Op(op));
+            i += op.len();
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Literal(Value),
+    Attribute(Vec<String>),
+    Not(Box<Node>),
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    Compare(&'static str, Box<Node>, Box<Node>),
+    Matches(bool, Box<Node>, Box<Regex>),
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn eat(&mut self, op: &str) -> bool {
+        match self.peek() {
+            Some(Token::Op(o)) if *o == op => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn or(&mut self) -> Result<Node, String> {
+        let mut node = self.and()?;
+        while self.eat("||") {
+            node = Node::Or(Box::new(node), Box::new(self.and()?));
+        }
+        Ok(node)
+    }
+
+    fn and(&mut self) -> Result<Node, String> {
+        let mut node = self.not()?;
+        while self.eat("&&") {
+            node = Node::And(Box::new(node), Box::new(self.not()?));
+        }
+        Ok(node)
+    }
+
+    fn not(&mut self) -> Result<Node, String> {
+        if self.eat("!") {
+            return Ok(Node::Not(Box::new(self.not()?)));
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Node, String> {
+        let left = self.operand()?;
+        for op in &["=~", "!~"] {
+            if self.eat(op) {
+                let pattern = match self.tokens.get(self.pos) {
+                    Some(Token::Str(pattern)) => pattern.clone(),
+                    _ => return Err(format!("{} needs a regex in quotes", op)),
+                };
+                self.pos += 1;
+                let re = Regex::new(&pattern).map_err(|e| format!("bad regex: {}", e))?;
+                return Ok(Node::Matches(*op == "=~", Box::new(left), Box::new(re)));
+            }
+        }
+        for op in &["==", "!=", "<=", ">=", "<", ">"] {
+            if self.eat(op) {
+                return Ok(Node::Compare(op, Box::new(left), Box::new(self.operand()?)));
+            }
+        }
+        Ok(left)
+    }
+
+    fn operand(&mut self) -> Result<Node, String> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        Ok(match token {
+            Some(Token::Open) => {
+                let node = self.or()?;
+                if self.peek() != Some(&Token::Close) {
+                    return Err("missing )".to_string());
+                }
+                self.pos += 1;
+                node
+            }
+            Some(Token::Op("-")) => match self.tokens.get(self.pos) {
+                Some(Token::Num(n)) => {
+                    self.pos += 1;
+                    Node::Literal(number(-n))
+                }
+                _ => return Err("- must precede a number".to_string()),
+            },
+            Some(Token::Num(n)) => Node::Literal(number(n)),
+            Some(Token::Str(s)) => Node::Literal(Value::String(s)),
+            Some(Token::Name(name)) => match name.as_str() {
+                "true" => Node::Literal(Value::Bool(true)),
+                "false" => Node::Literal(Value::Bool(false)),
+                "null" => Node::Literal(Value::Null),
+                _ => Node::Attribute(name.split('.').map(String::from).collect()),
+            },
+            Some(token) => return Err(format!("unexpected {:?}", token)),
+            None => return Err("unexpected end of expression".to_string()),
+        })
+    }
+}
+
+fn number(n: f64) -> Value {
+    serde_json::Number::from_f64(n)
+        .map(Value::Number)
+        .unwrap_or(Value::Null)
+}
+
+/// Order two values of the same kind; values of different kinds, and
+/// anything but numbers and strings, are unordered.
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+fn equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(_), Value::Number(_)) => compare(a, b) == Some(Ordering::Equal),
+        _ => a == b,
+    }
+}
+
+impl Node {
+    fn eval(&self, alert: &Value) -> Value {
+        match self {
+            Node::Literal(value) => value.clone(),
+            Node::Attribute(path) => path
+                .iter()
+                .fold(alert, |value, key| &value[key.as_str()])
+                .clone(),
+            Node::Not(node) => Value::Bool(!node.test(alert)),
+            Node::And(a, b) => Value::Bool(a.test(alert) && b.test(alert)),
+            Node::Or(a, b) => Value::Bool(a.test(alert) || b.test(alert)),
+            Node::Compare(op, a, b) => {
+                let (a, b) = (a.eval(alert), b.eval(alert));
+                Value::Bool(match *op {
+                    "==" => equal(&a, &b),
+                    "!=" => !equal(&a, &b),
+                    "<" => compare(&a, &b) == Some(Ordering::Less),
+                    "<=" => matches!(compare(&a, &b), Some(Ordering::Less | Ordering::Equal)),
+                    ">" => compare(&a, &b) == Some(Ordering::Greater),
+                    _ => matches!(compare(&a, &b), Some(Ordering::Greater | Ordering::Equal)),
+                })
+            }
+            Node::Matches(want, node, re) => Value::Bool(match node.eval(alert) {
+                Value::String(s) => re.is_match(&s) == *want,
+                Value::Null => !want,
+                other => re.is_match(&other.to_string()) == *want,
+            }),
+        }
+    }
+
+    fn test(&self, alert: &Value) -> bool {
+        self.eval(alert) == Value::Bool(true)
+    }
+}
+
+/// A parsed filter expression.
+#[derive(Debug, Clone)]
+pub struct Expr {
+    root: Node,
+    /// As written.
+    text: String,
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, String> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            pos: 0,
+        };
+        let root = parser.or()?;
+        match parser.peek() {
+            None => Ok(Expr {
+                root,
+                text: text.to_string(),
+            }),
+            Some(token) => Err(format!("unexpected {:?}", token)),
+        }
+    }
+
+    /// Whether the alert, given in its serialized form as for
+    /// `alert::matches`, satisfies the expression. Anything but `true`
+    /// counts as not.
+    pub fn matches(&self, alert: &Value) -> bool {
+        self.root.test(alert)
+    }
+
+    /// The expression as written.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn matches(text: &str, alert: &Value) -> bool {
+        Expr::parse(text).unwrap().matches(alert)
+    }
+
+    #[test]
+    fn compares_attributes_with_literals() {
+        let alert = json!({
+            "severity": "critical",
+            "duration_ms": 45000,
+            "ratio": -0.5,
+            "muted": false,
+            "labels": {"env": "prod"},
+        });
+        assert!(matches(r#"severity == "critical""#, &alert));
+        assert!(matches(
+            "duration_ms > 30000 && duration_ms <= 45000.0",
+            &alert
+        ));
+        assert!(matches("ratio < 0 && ratio >= -0.5", &alert));
+        assert!(matches("muted == false", &alert));
+        assert!(matches(r#"labels.env != "dev""#, &alert));
+        assert!(matches("missing == null", &alert));
+        assert!(!matches(r#"duration_ms > "1""#, &alert));
+        assert!(!matches("severity < 5", &alert));
+    }
+
+    #[test]
+    fn combines_conditions_with_precedence() {
+        let alert = json!({"a": 1, "b": 2});
+        assert!(matches("a == 2 || b == 2 && a == 1", &alert));
+        assert!(!matches("(a == 2 || b == 2) && a == 2", &alert));
+        assert!(matches("!(a == 2) && !!(b == 2)", &alert));
+    }
+
+    #[test]
+    fn matches_regexes() {
+        let alert = json!({"host": "web-01", "code": 503});
+        assert!(matches(r#"host =~ "^web-\\d+$""#, &alert));
+        assert!(matches(r#"host !~ "^db""#, &alert));
+        assert!(matches(r#"code =~ "^5""#, &alert));
+        assert!(matches(r#"missing !~ "x""#, &alert));
+        assert!(!matches(r#"missing =~ ".*""#, &alert));
+    }
+
+    #[test]
+    fn an_attribute_alone_must_be_true() {
+        assert!(matches("flag", &json!({"flag": true})));
+        assert!(!matches("flag", &json!({"flag": "yes"})));
+        assert!(!matches("flag", &json!({})));
+    }
+
+    #[test]
+    fn rejects_malformed_expressions() {
+        for text in &[
+            "",
+            r#"a == "unterminated"#,
+            "a == 1 &&",
+            "(a == 1",
+            "a == 1)",
+            "a = 1",
+            "a =~ b",
+            r#"a =~ "(""#,
+            "a == - b",
+            "a == 1.2.3",
+        ] {
+            assert!(Expr::parse(text).is_err(), "{} parsed", text);
+        }
+    }
+
+    #[test]
+    fn keeps_the_text_as_written() {
+        let text = r#"env == "prod"  &&  code > 2"#;
+        assert_eq!(Expr::parse(text).unwrap().text(), text);
+    }
+}
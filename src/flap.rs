@@ -0,0 +1,84 @@
+use crate::alert::{Alert, Status};
+use crate::config::FlappingConfig;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// What to do with an alert, given how its check has been behaving.
+pub enum Flapping {
+    /// The check is steady; notify as usual.
+    No,
+    /// The check has just started flapping; say so, once.
+    Started { transitions: usize },
+    /// The check is still flapping; stay quiet.
+    Ongoing,
+}
+
+struct History {
+    status: Status,
+    /// When the check changed state, within the window.
+    transitions: VecDeque<Instant>,
+    flapping: bool,
+}
+
+/// Watches each check for flipping between failing and passing more than
+/// `transitions` times within `window`. A flapping check settles once it
+/// has gone a whole window without changing state.
+pub struct Flaps {
+    transitions: usize,
+    window: Duration,
+    history: Mutex<HashMap<String, History>>,
+}
+
+impl Flaps {
+    pub fn new(cfg: &FlappingConfig) -> Flaps {
+        Flaps {
+            transitions: cfg.transitions,
+            window: cfg.window,
+            history: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record `alert` and tell whether its check is flapping.
+    pub fn observe(&self, alert: &Alert) -> Flapping {
+        let now = Instant::now();
+        let window = self.window;
+        let mut history = self.history.lock().unwrap();
+        history.retain(|_, h| {
+            h.transitions
+                .back()
+                .is_some_and(|t| now.duration_since(*t) < window)
+                || h.status == Status::Firing
+        });
+        let h = history.entry(alert.key()).or_insert_with(|| History {
+            status: alert.status,
+            transitions: VecDeque::new(),
+            flapping: false,
+        });
+
+        if h.status != alert.status {
+            h.status = alert.status;
+            h.transitions.push_back(now);
+        }
+        while h
+            .transitions
+            .front()
+            .is_some_and(|t| now.duration_since(*t) >= window)
+        {
+            h.transitions.pop_front();
+        }
+
+        match (h.flapping, h.transitions.len()) {
+            (false, n) if n > self.transitions => {
+                h.flapping = true;
+                Flapping::Started { transitions: n }
+            }
+            (false, _) => Flapping::No,
+            (true, 0) => {
+                h.flapping = false;
+                Flapping::No
+            }
+            (true, _) => Flapping::Ongoing,
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use crate::alert::Alert;
+use crate::source::Settler;
+use lapin::message::Delivery;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A buffered alert, with what is needed to settle its delivery once the
+/// digest it ends up in has been posted.
+pub struct Pending {
+    pub settler: Settler,
+    pub delivery: Delivery,
+    pub alert: Alert,
+}
+
+/// Alerts waiting to be posted together.
+pub struct Group {
+    /// The configured queue the alerts arrived on.
+    pub queue: usize,
+    /// The receiver chosen for them by the routing tree, if any.
+    pub receiver: Option<String>,
+    /// The values the alerts share, for showing in the digest.
+    pub label: String,
+    pub pending: Vec<Pending>,
+    started: Instant,
+    interval: Duration,
+}
+
+/// How to group an alert: by the values of which attributes, and for how
+/// long.
+pub struct Grouping<'a> {
+    pub by: &'a [String],
+    pub interval: Duration,
+}
+
+/// Queue, receiver and label of a group.
+type GroupKey = (usize, Option<String>, String);
+
+/// Collects firing alerts into groups by queue, receiver and the values of
+/// the grouping attributes.
+#[derive(Default)]
+pub struct Grouper {
+    groups: Mutex<HashMap<GroupKey, Group>>,
+}
+
+impl Grouper {
+    /// The non-empty values of the grouping attributes, joined with
+    /// slashes.
+    fn label(alert: &Alert, by: &[String]) -> String {
+        let value = serde_json::to_value(alert).unwrap_or(Value::Null);
+        by.iter()
+            .filter_map(|field| match &value[field] {
+                Value::String(s) if !s.is_empty() => Some(s.clone()),
+                Value::Null | Value::String(_) => None,
+                other => Some(other.to_string()),
+            })
+            .collect::<Vec<_>>()
+            .join(" / ")
+    }
+
+    pub fn add(
+        &self,
+        queue: usize,
+        receiver: Option<&str>,
+        grouping: Grouping<'_>,
+        pending: Pending,
+    ) {
+        let label = Grouper::label(&pending.alert, grouping.by);
+        let receiver = receiver.map(String::from);
+        let mut groups = self.groups.lock().unwrap();
+        groups
+            .entry((queue, receiver.clone(), label.clone()))
+            .or_insert_with(|| Group {
+                queue,
+                receiver,
+                label,
+                pending: Vec::new(),
+                started: Instant::now(),
+                interval: grouping.interval,
+            })
+            .pending
+            .push(pending);
+    }
+
+    /// Take the alerts about the check with `key` out of the groups for the
+    /// `queue`th queue, as when the check recovers before they are posted.
+    pub fn cancel(&self, queue: usize, key: &str) -> Vec<Pending> {
+        let mut groups = self.groups.lock().unwrap();
+        let mut cancelled = Vec::new();
+        for group in groups.values_mut().filter(|g| g.queue == queue) {
+            let (matching, rest): (Vec<Pending>, Vec<Pending>) =
+                group.pending.drain(..).partition(|p| p.alert.key() == key);
+            group.pending = rest;
+            cancelled.extend(matching);
+        }
+        groups.retain(|_, group| !group.pending.is_empty());
+        cancelled
+    }
+
+    /// Forget every buffered alert, returning how many there were, as when
+    /// the connection they came on is lost and the broker will redeliver
+    /// them.
+    pub fn abandon(&self) -> usize {
+        let mut groups = self.groups.lock().unwrap();
+        let abandoned = groups.values().map(|group| group.pending.len()).sum();
+        groups.clear();
+        abandoned
+    }
+
+    /// Take the groups that have been collecting for the whole interval.
+    pub fn due(&self) -> Vec<Group> {
+        let mut groups = self.groups.lock().unwrap();
+        let due: Vec<GroupKey> = groups
+            .iter()
+            .filter(|(_, group)| group.started.elapsed() >= group.interval)
+            .map(|(key, _)| key.clone())
+            .collect();
+        due.iter().filter_map(|key| groups.remove(key)).collect()
+    }
+}
@@ -0,0 +1,91 @@
+//! Liveness and readiness probes, for Kubernetes and load balancers:
+//! `/healthz` answers while the process runs, and `/readyz` only while the
+//! bridge can take alerts off the broker and deliver them somewhere.
+
+use crate::router::Dispatcher;
+use crate::systemd;
+use log::{error, info};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use warp::http::StatusCode;
+use warp::Filter;
+
+/// What readiness depends on, updated by `run` as it goes.
+#[derive(Default)]
+pub struct Health {
+    connected: AtomicBool,
+    /// Set once the configuration is loaded and everything is set up.
+    dispatcher: Mutex<Option<Arc<Dispatcher>>>,
+}
+
+impl Health {
+    pub fn set_connected(&self, connected: bool) {
+        self.connected.store(connected, Ordering::Relaxed);
+    }
+
+    pub fn connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    pub fn set_dispatcher(&self, dispatcher: Arc<Dispatcher>) {
+        *self.dispatcher.lock().unwrap() = Some(dispatcher);
+    }
+
+    /// Why the bridge is not ready, if it is not.
+    pub fn unready(&self) -> Vec<&'static str> {
+        let mut reasons = Vec::new();
+        match &*self.dispatcher.lock().unwrap() {
+            None => reasons.push("configuration not loaded"),
+            Some(dispatcher) if dispatcher.breaker.all_open() => {
+                reasons.push("no destination reachable")
+            }
+            Some(_) => {}
+        }
+        if !self.connected() {
+            reasons.push("not connected to rabbit");
+        }
+        reasons
+    }
+}
+
+/// Answer probes at `/healthz` and `/readyz` until `shutdown` resolves.
+pub async fn serve(
+    health: Arc<Health>,
+    listen: SocketAddr,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) {
+    let healthz = warp::path!("healthz").map(|| "ok");
+    let readyz = warp::path!("readyz").map(move || {
+        let reasons = health.unready();
+        if reasons.is_empty() {
+            warp::reply::with_status("ok".to_string(), StatusCode::OK)
+        } else {
+            warp::reply::with_status(reasons.join("\n"), StatusCode::SERVICE_UNAVAILABLE)
+        }
+    });
+    let route = warp::get().and(healthz.or(readyz));
+
+    if let Some(listener) = systemd::listener("health") {
+        match systemd::incoming(listener) {
+            Ok(incoming) => {
+                warp::serve(route)
+                    .serve_incoming_with_graceful_shutdown(incoming, shutdown)
+                    .await
+            }
+            Err(e) => error!(
+                "Unable to answer health probes on the socket systemd passed: {}",
+                e
+            ),
+        }
+        return;
+    }
+    match warp::serve(route).try_bind_with_graceful_shutdown(listen, shutdown) {
+        Ok((addr, server)) => {
+            info!("Answering health probes on {}", addr);
+            server.await;
+        }
+        Err(e) => error!("Unable to answer health probes on {}: {}", listen, e),
+    }
+}
@@ -0,0 +1,52 @@
+//! A dead man's switch: a ping to a monitoring URL, or a short notification
+//! to a receiver, sent every so often while the bridge is ready. If the
+//! bridge stops consuming, the heartbeats stop and whatever watches for
+//! them raises the alarm.
+
+use crate::alert::Severity;
+use crate::health::Health;
+use crate::router::Dispatcher;
+use crate::sink::Notification;
+use log::{debug, warn};
+use reqwest::Client;
+use serde_json::json;
+use std::sync::Arc;
+
+const TEXT: &str = "2steps-slack-alert is running";
+
+/// Send heartbeats as the configuration in force says, while `health`
+/// finds the bridge ready. Runs until the process exits.
+pub async fn beat(dispatcher: Arc<Dispatcher>, health: Arc<Health>, client: Client) {
+    loop {
+        let cfg = dispatcher.cfg();
+        let heartbeat = match &cfg.heartbeat {
+            Some(heartbeat) => heartbeat,
+            None => return,
+        };
+        tokio::time::delay_for(heartbeat.interval).await;
+        let unready = health.unready();
+        if !unready.is_empty() {
+            warn!("Skipping heartbeat: {}", unready.join(", "));
+            continue;
+        }
+
+        if let Some(url) = &heartbeat.url {
+            match client.get(url).send().await {
+                Ok(res) if res.status().is_success() => debug!("Heartbeat sent"),
+                Ok(res) => warn!("Heartbeat URL returned {}", res.status()),
+                Err(e) => warn!("Unable to send heartbeat: {}", e),
+            }
+        }
+        if let Some(receiver) = &heartbeat.receiver {
+            let rendered = Notification {
+                alerts: Vec::new(),
+                severity: Severity::Info,
+                summary: TEXT.to_string(),
+                body: json!({ "text": TEXT }),
+            };
+            if let Err(e) = dispatcher.send_to(receiver, rendered).await {
+                warn!("Unable to send heartbeat to {}: {}", receiver, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,28 @@
+use crate::config::HttpConfig;
+use reqwest::{Client, Proxy};
+
+/// Build the HTTP client shared by every delivery, so that connections to
+/// the same destination are pooled and reused.
+pub fn build_client(cfg: &HttpConfig) -> Result<Client, String> {
+    let mut builder = Client::builder()
+        .connect_timeout(cfg.connect_timeout)
+        .timeout(cfg.request_timeout)
+        .pool_idle_timeout(cfg.pool_idle_timeout)
+        .tcp_keepalive(cfg.tcp_keepalive);
+
+    if let Some(max) = cfg.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max);
+    }
+
+    if let Some(proxy) = &cfg.proxy {
+        let mut p = Proxy::all(&proxy.url).map_err(|e| format!("Invalid http.proxy.url: {}", e))?;
+        if let Some(username) = &proxy.username {
+            p = p.basic_auth(username, proxy.password.as_deref().unwrap_or(""));
+        }
+        builder = builder.proxy(p);
+    }
+
+    builder
+        .build()
+        .map_err(|e| format!("Unable to create HTTP client: {}", e))
+}
@@ -0,0 +1,137 @@
+use crate::alert::Status;
+use crate::config::InhibitRule;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct Source {
+    alert: Value,
+    seen: Instant,
+}
+
+/// Tracks firing alerts that could inhibit others under the configured
+/// rules. A source is forgotten when it resolves, or once it has not been
+/// reported for `ttl`.
+pub struct Inhibitor {
+    ttl: Duration,
+    sources: Mutex<HashMap<String, Source>>,
+}
+
+impl Inhibitor {
+    pub fn new(ttl: Duration) -> Inhibitor {
+        Inhibitor {
+            ttl,
+            sources: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Note the state of the alert with `key`, given in its serialized form
+    /// as for `alert::matches`.
+    pub fn observe(&self, rules: &[InhibitRule], key: &str, status: Status, alert: &Value) {
+        let mut sources = self.sources.lock().unwrap();
+        let ttl = self.ttl;
+        sources.retain(|_, s| s.seen.elapsed() < ttl);
+        match status {
+            Status::Firing if rules.iter().any(|r| r.source.matches(alert)) => {
+                sources.insert(
+                    key.to_string(),
+                    Source {
+                        alert: alert.clone(),
+                        seen: Instant::now(),
+                    },
+                );
+            }
+            _ => {
+                sources.remove(key);
+            }
+        }
+    }
+
+    /// The key of a firing alert inhibiting the one with `key`, if any.
+    pub fn inhibiting(&self, rules: &[InhibitRule], key: &str, alert: &Value) -> Option<String> {
+        let sources = self.sources.lock().unwrap();
+        rules
+            .iter()
+            .filter(|rule| rule.target.matches(alert))
+            .find_map(|rule| {
+                sources
+                    .iter()
+                    .find(|(source_key, source)| {
+                        source_key.as_str() != key
+                            && source.seen.elapsed() < self.ttl
+                            && rule.source.matches(&source.alert)
+                            && rule
+                                .equal
+                                .iter()
+                                .all(|field| source.alert[field] == alert[field])
+                    })
+                    .map(|(source_key, _)| source_key.clone())
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rules() -> Vec<InhibitRule> {
+        vec![serde_yaml::from_str(
+            "source_match: {check: site-down}\n\
+             target_match: {severity: warning}\n\
+             equal: [site]",
+        )
+        .unwrap()]
+    }
+
+    fn down(site: &str) -> Value {
+        json!({"check": "site-down", "severity": "critical", "site": site})
+    }
+
+    fn slow(site: &str) -> Value {
+        json!({"check": "slow-page", "severity": "warning", "site": site})
+    }
+
+    #[test]
+    fn a_firing_source_inhibits_matching_targets() {
+        let (rules, inhibitor) = (rules(), Inhibitor::new(Duration::from_secs(3600)));
+        assert_eq!(inhibitor.inhibiting(&rules, "slow", &slow("a")), None);
+
+        inhibitor.observe(&rules, "down-a", Status::Firing, &down("a"));
+        assert_eq!(
+            inhibitor.inhibiting(&rules, "slow", &slow("a")),
+            Some("down-a".to_string())
+        );
+        // Only targets with the same site, and never the source itself.
+        assert_eq!(inhibitor.inhibiting(&rules, "slow", &slow("b")), None);
+        let mut critical = slow("a");
+        critical["severity"] = json!("critical");
+        assert_eq!(inhibitor.inhibiting(&rules, "slow", &critical), None);
+        assert_eq!(inhibitor.inhibiting(&rules, "down-a", &down("a")), None);
+    }
+
+    #[test]
+    fn a_resolved_source_stops_inhibiting() {
+        let (rules, inhibitor) = (rules(), Inhibitor::new(Duration::from_secs(3600)));
+        inhibitor.observe(&rules, "down-a", Status::Firing, &down("a"));
+        inhibitor.observe(&rules, "down-a", Status::Resolved, &down("a"));
+        assert_eq!(inhibitor.inhibiting(&rules, "slow", &slow("a")), None);
+    }
+
+    #[test]
+    fn a_source_is_forgotten_after_the_ttl() {
+        let (rules, inhibitor) = (rules(), Inhibitor::new(Duration::from_secs(0)));
+        inhibitor.observe(&rules, "down-a", Status::Firing, &down("a"));
+        assert_eq!(inhibitor.inhibiting(&rules, "slow", &slow("a")), None);
+    }
+
+    #[test]
+    fn alerts_matching_no_source_are_not_tracked() {
+        let (rules, inhibitor) = (rules(), Inhibitor::new(Duration::from_secs(3600)));
+        inhibitor.observe(&rules, "slow-a", Status::Firing, &slow("a"));
+        let mut other = slow("a");
+        other["check"] = json!("other");
+        assert_eq!(inhibitor.inhibiting(&rules, "other", &other), None);
+    }
+}
@@ -0,0 +1,63 @@
+//! The `init` subcommand, which writes out an annotated sample
+//! configuration.
+
+use crate::config::parse_config;
+use std::fs::OpenOptions;
+use std::io::Write;
+
+/// Every section with its settings. Lines marked `#:` are optional and are
+/// written out commented; they are parsed all the same when the sample is
+/// checked, so that it cannot fall out of step with the configuration.
+const SAMPLE: &str = include_str!("sample.yml");
+
+/// The sample as it is written out, once checked against the configuration
+/// model with every optional setting switched on.
+pub fn sample() -> Result<String, String> {
+    let everything: String = SAMPLE
+        .split_inclusive('\n')
+        .map(|line| line.strip_prefix("#:").unwrap_or(line))
+        .collect();
+    parse_config(&everything)
+        .map_err(|e| format!("The sample configuration is out of date: {}", e))?;
+
+    Ok(SAMPLE
+        .split_inclusive('\n')
+        .map(|line| match line.strip_prefix("#:") {
+            Some(rest) => format!("# {}", rest),
+            None => line.to_string(),
+        })
+        .collect())
+}
+
+/// Write the sample to `path`, which must not exist yet unless `force` is
+/// given, or to stdout.
+pub fn init(path: Option<&str>, force: bool) -> Result<(), String> {
+    let sample = sample()?;
+    match path {
+        Some(path) => {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .create_new(!force)
+                .open(path)
+                .map_err(|e| format!("Unable to create {}: {}", path, e))?;
+            file.write_all(sample.as_bytes())
+                .map_err(|e| format!("Unable to write {}: {}", path, e))
+        }
+        None => {
+            print!("{}", sample);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_matches_the_configuration() {
+        assert_eq!(sample().err(), None);
+    }
+}
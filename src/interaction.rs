@@ -0,0 +1,579 @@
+//! Receives Slack interaction payloads, such as clicks on an alert's
+//! "Handle" button, and publishes what happened back onto the bus so that
+//! 2Steps knows the alert was picked up, along with `/2steps` slash
+//! commands. Payloads arrive either as HTTP requests from Slack or over a
+//! Socket Mode websocket we open ourselves.
+
+use crate::backoff::Backoff;
+use crate::command::{self, Command};
+use crate::config::InteractionsConfig;
+use crate::router::Dispatcher;
+use crate::silence;
+use crate::sink::slack::Target;
+use crate::sink::SinkError;
+use crate::source::Publisher;
+use chrono::{DateTime, Utc};
+use futures::{SinkExt, StreamExt};
+use hmac::{Hmac, Mac};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::Message;
+use warp::http::{HeaderMap, StatusCode};
+use warp::Filter;
+
+/// `action_id` of the button on each alert.
+pub const HANDLE_ACTION: &str = "handle";
+/// `action_id` of the Escalate button on critical alerts.
+pub const ESCALATE_ACTION: &str = "escalate";
+/// `action_id` of the Snooze menu on each alert, whose block ID is the
+/// alert's key and whose options are durations.
+pub const SNOOZE_ACTION: &str = "snooze";
+/// Requests signed longer ago than this are refused, so that a captured
+/// request cannot be replayed.
+const MAX_AGE_SECS: i64 = 5 * 60;
+/// Slack caps interaction payloads well below this.
+const MAX_BODY_BYTES: u64 = 64 * 1024;
+/// Bounds on the pause before reopening a failed Socket Mode connection.
+const RECONNECT_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize)]
+struct Form {
+    payload: String,
+}
+
+/// Socket Mode's wrapper around each payload. Every envelope with an ID
+/// must be acknowledged.
+#[derive(Deserialize)]
+struct Envelope {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    envelope_id: Option<String>,
+    #[serde(default)]
+    payload: Value,
+}
+
+#[derive(Deserialize)]
+struct Payload {
+    #[serde(rename = "type")]
+    kind: String,
+    user: User,
+    #[serde(default)]
+    channel: Option<Channel>,
+    #[serde(default)]
+    message: Option<MessageRef>,
+    #[serde(default)]
+    actions: Vec<Action>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct User {
+    id: String,
+    #[serde(default, alias = "username", skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Channel {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct MessageRef {
+    ts: String,
+}
+
+#[derive(Deserialize)]
+struct Action {
+    action_id: String,
+    #[serde(default)]
+    block_id: Option<String>,
+    #[serde(default)]
+    value: Option<String>,
+    #[serde(default)]
+    selected_option: Option<SelectedOption>,
+}
+
+#[derive(Deserialize)]
+struct SelectedOption {
+    value: String,
+}
+
+/// Published when someone takes responsibility for an alert.
+#[derive(Serialize)]
+struct Handled<'a> {
+    event: &'static str,
+    /// The alert's key; see `Alert::key`.
+    fingerprint: &'a str,
+    user: &'a User,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    channel: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message_ts: Option<&'a str>,
+    timestamp: DateTime<Utc>,
+}
+
+/// Check Slack's signature: `v0=` and the hex HMAC-SHA256, keyed by the
+/// signing secret, of `v0:<timestamp>:<body>`.
+fn verify(secret: &str, headers: &HeaderMap, body: &[u8]) -> Result<(), &'static str> {
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    let timestamp = header("x-slack-request-timestamp").ok_or("missing timestamp")?;
+    let signature = header("x-slack-signature").ok_or("missing signature")?;
+
+    let signed_at: i64 = timestamp.parse().map_err(|_| "invalid timestamp")?;
+    if (Utc::now().timestamp() - signed_at).abs() > MAX_AGE_SECS {
+        return Err("stale timestamp");
+    }
+    let signature = signature
+        .strip_prefix("v0=")
+        .and_then(|hex| hex::decode(hex).ok())
+        .ok_or("malformed signature")?;
+
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).map_err(|_| "invalid secret")?;
+    mac.input(b"v0:");
+    mac.input(timestamp.as_bytes());
+    mac.input(b":");
+    mac.input(body);
+    mac.verify(&signature).map_err(|_| "signature mismatch")
+}
+
+/// Turns interaction payloads into events on the bus and updates to the
+/// messages they came from.
+pub struct Interactions {
+    dispatcher: Arc<Dispatcher>,
+    exchange: String,
+    routing_key: String,
+    /// Replaced each time we reconnect to the broker; empty until the first
+    /// connection.
+    publisher: RwLock<Option<Publisher>>,
+}
+
+impl Interactions {
+    pub fn new(cfg: &InteractionsConfig, dispatcher: Arc<Dispatcher>) -> Interactions {
+        Interactions {
+            dispatcher,
+            exchange: cfg.exchange.clone(),
+            routing_key: cfg.routing_key.clone(),
+            publisher: RwLock::new(None),
+        }
+    }
+
+    /// Publish through a new broker connection from now on.
+    pub fn attach(&self, publisher: Publisher) {
+        *self.publisher.write().unwrap() = Some(publisher);
+    }
+
+    /// Act on a payload, however it reached us.
+    async fn handle_payload(&self, payload: &Payload) -> Result<(), String> {
+        if payload.kind != "block_actions" {
+            debug!("Ignoring {} interaction", payload.kind);
+            return Ok(());
+        }
+
+        for action in &payload.actions {
+            match action.action_id.as_str() {
+                HANDLE_ACTION => self.handle(payload, action).await?,
+                SNOOZE_ACTION => self.snooze(payload, action).await?,
+                ESCALATE_ACTION => self.escalate(payload, action).await?,
+                other => debug!("Ignoring action {}", other),
+            }
+        }
+        Ok(())
+    }
+
+    /// Someone has clicked an alert's Handle button.
+    async fn handle(&self, payload: &Payload, action: &Action) -> Result<(), String> {
+        let fingerprint = match &action.value {
+            Some(value) => value,
+            None => {
+                warn!("Handle action without an alert key");
+                return Ok(());
+            }
+        };
+
+        let now = Utc::now();
+        let event = Handled {
+            event: "handled",
+            fingerprint,
+            user: &payload.user,
+            channel: payload.channel.as_ref().map(|c| c.id.as_str()),
+            message_ts: payload.message.as_ref().map(|m| m.ts.as_str()),
+            timestamp: now,
+        };
+        let data =
+            serde_json::to_vec(&event).map_err(|e| format!("Unable to encode event: {}", e))?;
+        self.publish(data).await?;
+        self.dispatcher
+            .acknowledge(fingerprint, &payload.user.id, now)
+            .await;
+        Ok(())
+    }
+
+    /// Someone has clicked a critical alert's Escalate button.
+    async fn escalate(&self, payload: &Payload, action: &Action) -> Result<(), String> {
+        match &action.value {
+            Some(fingerprint) => {
+                self.dispatcher
+                    .escalate(fingerprint, &payload.user.id)
+                    .await
+            }
+            None => {
+                warn!("Escalate action without an alert key");
+                Ok(())
+            }
+        }
+    }
+
+    /// Someone has picked a duration from an alert's Snooze menu.
+    async fn snooze(&self, payload: &Payload, action: &Action) -> Result<(), String> {
+        let (fingerprint, choice) = match (&action.block_id, &action.selected_option) {
+            (Some(fingerprint), Some(choice)) => (fingerprint, &choice.value),
+            _ => {
+                warn!("Snooze action without an alert key or duration");
+                return Ok(());
+            }
+        };
+        let duration = silence::parse_duration(choice)
+            .ok_or_else(|| format!("Invalid snooze duration {}", choice))?;
+        self.dispatcher
+            .snooze(fingerprint, &payload.user.id, duration)
+            .await
+    }
+
+    async fn publish(&self, data: Vec<u8>) -> Result<(), String> {
+        let publisher = self
+            .publisher
+            .read()
+            .unwrap()
+            .clone()
+            .ok_or("not connected to rabbit")?;
+        publisher
+            .publish(&self.exchange, &self.routing_key, data)
+            .await
+            .map_err(|e| format!("Unable to publish event: {:?}", e))
+    }
+
+    /// Run a slash command and post the reply, visible only to whoever ran
+    /// it, to its response URL.
+    async fn handle_command(&self, command: Command) {
+        let reply = command::run(&self.dispatcher, &command).await;
+        let body = json!({ "response_type": "ephemeral", "text": reply });
+        let target = Target::Webhook(&command.response_url);
+        if let Err(e) = self.dispatcher.sinks.slack.post(target, &body).await {
+            warn!("Unable to reply to slash command: {}", e);
+        }
+    }
+
+    /// Act on the payload of a Socket Mode envelope. Only interactions and
+    /// slash commands are of interest; anything else Slack sends is
+    /// acknowledged and ignored.
+    async fn handle_envelope(&self, envelope: Envelope) -> Result<(), String> {
+        match envelope.kind.as_str() {
+            "interactive" => {
+                let payload = serde_json::from_value(envelope.payload)
+                    .map_err(|e| format!("Unreadable interaction payload: {}", e))?;
+                self.handle_payload(&payload).await
+            }
+            "slash_commands" => {
+                let command = serde_json::from_value(envelope.payload)
+                    .map_err(|e| format!("Unreadable slash command: {}", e))?;
+                self.handle_command(command).await;
+                Ok(())
+            }
+            other => {
+                debug!("Ignoring {} envelope", other);
+                Ok(())
+            }
+        }
+    }
+
+    /// Handle one HTTP request from Slack. The payload is acted on in the
+    /// background, as Slack wants an answer within three seconds and the
+    /// message is updated to show what came of it anyway.
+    fn receive(
+        self: Arc<Self>,
+        signing_secret: &str,
+        headers: HeaderMap,
+        body: &[u8],
+    ) -> StatusCode {
+        if let Err(e) = verify(signing_secret, &headers, body) {
+            warn!("Refusing interaction request: {}", e);
+            return StatusCode::UNAUTHORIZED;
+        }
+
+        let payload = serde_urlencoded::from_bytes::<Form>(body)
+            .map_err(|e| e.to_string())
+            .and_then(|form| {
+                serde_json::from_str::<Payload>(&form.payload).map_err(|e| e.to_string())
+            });
+        let payload = match payload {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!("Unreadable interaction payload: {}", e);
+                return StatusCode::BAD_REQUEST;
+            }
+        };
+
+        tokio::spawn(async move {
+            if let Err(e) = self.handle_payload(&payload).await {
+                error!("Failed to handle interaction: {}", e);
+            }
+        });
+        StatusCode::OK
+    }
+
+    /// Handle one slash command request from Slack. The command is run in
+    /// the background, as Slack wants an answer within three seconds and
+    /// the reply goes to the response URL anyway.
+    fn receive_command(
+        self: Arc<Self>,
+        signing_secret: &str,
+        headers: HeaderMap,
+        body: &[u8],
+    ) -> StatusCode {
+        if let Err(e) = verify(signing_secret, &headers, body) {
+            warn!("Refusing slash command request: {}", e);
+            return StatusCode::UNAUTHORIZED;
+        }
+        let command = match serde_urlencoded::from_bytes::<Command>(body) {
+            Ok(command) => command,
+            Err(e) => {
+                warn!("Unreadable slash command: {}", e);
+                return StatusCode::BAD_REQUEST;
+            }
+        };
+
+        tokio::spawn(async move { self.handle_command(command).await });
+        StatusCode::OK
+    }
+}
+
+/// Serve Slack's interactivity request URL, `/slack/interactions`, and
+/// slash command URL, `/slack/commands`, until `shutdown` resolves.
+pub async fn serve(
+    interactions: Arc<Interactions>,
+    listen: SocketAddr,
+    signing_secret: String,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) {
+    let route = warp::post()
+        .and(warp::path!("slack" / String))
+        .and(warp::header::headers_cloned())
+        .and(warp::body::content_length_limit(MAX_BODY_BYTES))
+        .and(warp::body::bytes())
+        .and_then(
+            move |endpoint: String, headers, body: warp::hyper::body::Bytes| {
+                let interactions = interactions.clone();
+                let signing_secret = signing_secret.clone();
+                async move {
+                    let status = match endpoint.as_str() {
+                        "interactions" => interactions.receive(&signing_secret, headers, &body),
+                        "commands" => interactions.receive_command(&signing_secret, headers, &body),
+                        _ => StatusCode::NOT_FOUND,
+                    };
+                    Ok::<_, warp::Rejection>(warp::reply::with_status(warp::reply(), status))
+                }
+            },
+        );
+
+    match warp::serve(route).try_bind_with_graceful_shutdown(listen, shutdown) {
+        Ok((addr, server)) => {
+            info!("Listening for Slack interactions on {}", addr);
+            server.await;
+        }
+        Err(e) => error!(
+            "Unable to listen for Slack interactions on {}: {}",
+            listen, e
+        ),
+    }
+}
+
+fn socket_error(e: impl std::fmt::Display) -> SinkError {
+    SinkError::Transient(format!("Socket Mode connection failed: {}", e))
+}
+
+/// Receive envelopes over one Socket Mode connection until Slack closes it
+/// or asks us to reconnect.
+async fn socket_connection(
+    interactions: &Arc<Interactions>,
+    app_token: &str,
+) -> Result<(), SinkError> {
+    let url = interactions
+        .dispatcher
+        .sinks
+        .slack
+        .open_socket(app_token)
+        .await?;
+    let (mut socket, _) = tokio_tungstenite::connect_async(url.as_str())
+        .await
+        .map_err(socket_error)?;
+
+    while let Some(message) = socket.next().await {
+        let text = match message.map_err(socket_error)? {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let envelope: Envelope = match serde_json::from_str(&text) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                warn!("Unreadable Socket Mode message: {}", e);
+                continue;
+            }
+        };
+
+        // Slack redelivers anything not acknowledged within a few seconds,
+        // so acknowledge before acting on it.
+        if let Some(id) = &envelope.envelope_id {
+            let ack = json!({ "envelope_id": id }).to_string();
+            socket
+                .send(Message::Text(ack))
+                .await
+                .map_err(socket_error)?;
+        }
+        match envelope.kind.as_str() {
+            "hello" => info!("Connected to Slack in Socket Mode"),
+            "disconnect" => {
+                debug!("Slack asked us to reconnect");
+                break;
+            }
+            // In the background, so that one slow to act on holds up
+            // neither the envelopes after it nor Slack's pings.
+            _ => {
+                let interactions = interactions.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = interactions.handle_envelope(envelope).await {
+                        error!("Failed to handle interaction: {}", e);
+                    }
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Keep a Socket Mode connection open until `shutdown` resolves, reopening
+/// it whenever it closes. Gives up only if Slack refuses the app token.
+pub async fn socket_mode(
+    interactions: Arc<Interactions>,
+    app_token: String,
+    shutdown: impl Future<Output = ()> + Clone,
+) {
+    let mut backoff = Backoff::new(RECONNECT_INITIAL_DELAY, RECONNECT_MAX_DELAY);
+    loop {
+        let outcome = tokio::select! {
+            outcome = socket_connection(&interactions, &app_token) => outcome,
+            _ = shutdown.clone() => return,
+        };
+        let delay = match outcome {
+            Ok(()) => {
+                backoff.reset();
+                continue;
+            }
+            Err(SinkError::Rejected(e)) => {
+                error!("Unable to use Socket Mode: {}", e);
+                return;
+            }
+            Err(SinkError::Transient(e)) => {
+                let delay = backoff.next_delay();
+                warn!("{}; reconnecting in {:?}", e, delay);
+                delay
+            }
+        };
+        tokio::select! {
+            _ = tokio::time::delay_for(delay) => {}
+            _ = shutdown.clone() => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "8f742231b10e8888abcd99yyyzzz85a5";
+    const BODY: &[u8] = b"payload=%7B%22type%22%3A%22block_actions%22%7D";
+
+    fn sign(secret: &str, timestamp: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).unwrap();
+        mac.input(format!("v0:{}:", timestamp).as_bytes());
+        mac.input(body);
+        format!("v0={}", hex::encode(mac.result().code()))
+    }
+
+    fn headers(timestamp: &str, signature: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-slack-request-timestamp", timestamp.parse().unwrap());
+        headers.insert("x-slack-signature", signature.parse().unwrap());
+        headers
+    }
+
+    fn signed_at(age_secs: i64) -> HeaderMap {
+        let timestamp = (Utc::now().timestamp() - age_secs).to_string();
+        headers(&timestamp, &sign(SECRET, &timestamp, BODY))
+    }
+
+    #[test]
+    fn accepts_a_fresh_signature() {
+        assert_eq!(verify(SECRET, &signed_at(0), BODY), Ok(()));
+        assert_eq!(verify(SECRET, &signed_at(MAX_AGE_SECS - 10), BODY), Ok(()));
+        assert_eq!(verify(SECRET, &signed_at(10 - MAX_AGE_SECS), BODY), Ok(()));
+    }
+
+    #[test]
+    fn rejects_timestamps_outside_five_minutes() {
+        assert_eq!(
+            verify(SECRET, &signed_at(MAX_AGE_SECS + 10), BODY),
+            Err("stale timestamp")
+        );
+        assert_eq!(
+            verify(SECRET, &signed_at(-MAX_AGE_SECS - 10), BODY),
+            Err("stale timestamp")
+        );
+    }
+
+    #[test]
+    fn rejects_a_signature_that_does_not_match() {
+        let headers = signed_at(0);
+        assert_eq!(
+            verify(SECRET, &headers, b"payload=%7B%7D"),
+            Err("signature mismatch")
+        );
+        assert_eq!(
+            verify("another secret", &headers, BODY),
+            Err("signature mismatch")
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        let timestamp = Utc::now().timestamp().to_string();
+        let signature = sign(SECRET, &timestamp, BODY);
+        assert_eq!(
+            verify(SECRET, &HeaderMap::new(), BODY),
+            Err("missing timestamp")
+        );
+        let mut unsigned = HeaderMap::new();
+        unsigned.insert("x-slack-request-timestamp", timestamp.parse().unwrap());
+        assert_eq!(verify(SECRET, &unsigned, BODY), Err("missing signature"));
+        assert_eq!(
+            verify(SECRET, &headers("soon", &signature), BODY),
+            Err("invalid timestamp")
+        );
+        assert_eq!(
+            verify(SECRET, &headers(&timestamp, &signature[3..]), BODY),
+            Err("malformed signature")
+        );
+        assert_eq!(
+            verify(SECRET, &headers(&timestamp, "v0=not hex"), BODY),
+            Err("malformed signature")
+        );
+    }
+}
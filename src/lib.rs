@@ -0,0 +1,324 @@
+//! A bridge from 2 Steps alerts on RabbitMQ to Slack and other
+//! destinations.
+//!
+//! Alerts are consumed by `source`, routed, deduplicated and otherwise
+//! processed by `router`, rendered by `render` and delivered through `sink`,
+//! all as set out in `config`. `run` puts these together the way the
+//! `2steps-slack-alert` binary does.
+
+pub mod admin;
+pub mod alert;
+pub mod audit;
+pub mod aws;
+pub mod backlog;
+pub mod backoff;
+pub mod check;
+pub mod circuit;
+pub mod command;
+pub mod config;
+pub mod dedup;
+pub mod expr;
+pub mod flap;
+pub mod group;
+pub mod health;
+pub mod heartbeat;
+pub mod http;
+pub mod inhibit;
+pub mod init;
+pub mod interaction;
+pub mod logfile;
+pub mod logging;
+pub mod mention;
+pub mod metrics;
+pub mod quiet;
+pub mod ratelimit;
+pub mod render;
+pub mod replay;
+pub mod router;
+pub mod secret;
+pub mod selfalert;
+pub mod sendtest;
+pub mod sentry;
+pub mod silence;
+pub mod sink;
+pub mod source;
+pub mod spool;
+pub mod statsd;
+pub mod store;
+pub mod systemd;
+pub mod systemlog;
+pub mod tail;
+pub mod trace;
+#[cfg(windows)]
+pub mod winservice;
+
+pub use alert::{Alert, Severity, Status};
+pub use config::{read_config, Config, ConfigSource};
+pub use render::Templates;
+pub use router::Dispatcher;
+
+use backlog::Backlog;
+use backoff::Backoff;
+use futures::future::{FutureExt, Shared};
+use health::Health;
+use interaction::Interactions;
+use log::{debug, error, info, warn};
+use sentry::Sentry;
+use silence::Silences;
+use sink::pagerduty::PagerDuty;
+use sink::Sinks;
+use source::Brokers;
+use spool::Spool;
+use std::future::Future;
+use std::sync::Arc;
+use store::MessageStore;
+
+/// Resolves once shutdown has been requested; cloned into every consumer.
+pub type ShutdownSignal = Shared<futures::future::BoxFuture<'static, ()>>;
+
+/// Read the configuration and resolve the secrets it refers to.
+pub async fn load_config(source: &ConfigSource) -> Result<Config, String> {
+    let mut cfg = read_config(source)?;
+    secret::resolve(&mut cfg).await?;
+    Ok(cfg)
+}
+
+/// Declare the broker topology the configuration from `source` needs, and
+/// return without consuming anything.
+pub async fn setup(source: &ConfigSource) -> Result<(), String> {
+    let cfg = load_config(source).await?;
+    source::setup(&cfg).await
+}
+
+/// Re-read the configuration from `source` and hand it to `dispatcher`. A
+/// configuration that fails to load leaves the current one in place.
+pub async fn reload(source: &ConfigSource, dispatcher: &Dispatcher) -> Result<(), String> {
+    systemd::notify("RELOADING=1");
+    let reloaded = load_config(source)
+        .await
+        .map(|mut cfg| {
+            if dispatcher.dry_run {
+                cfg.dry_run();
+            }
+            cfg
+        })
+        .and_then(|cfg| dispatcher.reload(cfg));
+    match &reloaded {
+        Ok(()) => {
+            logging::reload(&dispatcher.cfg().logging);
+            info!("Reloaded configuration from {}", source.path)
+        }
+        Err(e) => error!("Keeping the current configuration: {}", e),
+    }
+    systemd::notify("READY=1");
+    reloaded
+}
+
+/// Reload the configuration from `source` on each SIGHUP.
+#[cfg(unix)]
+async fn reload_on_hangup(source: ConfigSource, dispatcher: Arc<Dispatcher>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!("Unable to listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        let _ = reload(&source, &dispatcher).await;
+    }
+}
+
+/// Run the bridge with the configuration from `source` until `shutdown`
+/// resolves, reconnecting to the broker whenever the connection is lost.
+/// On Unix the configuration is reloaded on SIGHUP. A `dry_run` consumes
+/// copies of what arrives and logs what it would deliver; see
+/// `Config::dry_run`.
+pub async fn run(
+    source: ConfigSource,
+    dry_run: bool,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<(), String> {
+    let mut cfg = load_config(&source).await?;
+    if dry_run {
+        info!("Dry run: logging notifications instead of delivering them");
+        cfg.dry_run();
+    }
+    let cfg = Arc::new(cfg);
+    let templates = Templates::load(
+        &cfg.templates,
+        &cfg.routing,
+        &cfg.styles,
+        cfg.pagerduty.is_some(),
+        cfg.email.as_ref(),
+    )?;
+    let store = MessageStore::open(&cfg.messages)?;
+    let silences = Silences::open(&cfg.silences)?;
+    let mut brokers = Brokers::new(&cfg.rabbit)?;
+    let shutdown: ShutdownSignal = shutdown.boxed().shared();
+    let health = Arc::new(Health::default());
+    if let Some(health_cfg) = &cfg.health {
+        tokio::spawn(health::serve(
+            health.clone(),
+            health_cfg.listen,
+            shutdown.clone(),
+        ));
+    }
+
+    let retry = &cfg.rabbit.retry;
+    let mut backoff = Backoff::new(retry.initial_delay, retry.max_delay);
+    let mut rabbit = tokio::select! {
+        rabbit = source::connect_with_retry(&cfg, &mut brokers, &mut backoff, retry.startup_attempts, dry_run) => rabbit?,
+        _ = shutdown.clone() => return Ok(()),
+    };
+
+    let client = http::build_client(&cfg.http)?;
+    let sentry = match &cfg.sentry {
+        Some(sentry_cfg) => Some(Arc::new(Sentry::new(sentry_cfg, client.clone())?)),
+        None => None,
+    };
+    if let Some(sentry) = &sentry {
+        sentry.clone().install_panic_hook();
+    }
+    let pagerduty = cfg
+        .pagerduty
+        .as_ref()
+        .map(|pagerduty_cfg| PagerDuty::new(client.clone(), pagerduty_cfg));
+    let sinks = Sinks::new(client.clone(), &cfg.http);
+    let spool = match &cfg.spool {
+        Some(spool_cfg) => Some(Arc::new(Spool::open(spool_cfg)?)),
+        None => None,
+    };
+    let mut dispatcher = Dispatcher::new(
+        cfg.clone(),
+        sinks,
+        spool.clone(),
+        templates,
+        store,
+        silences,
+        pagerduty,
+    )?;
+    dispatcher.sentry = sentry;
+    dispatcher.dry_run = dry_run;
+    let dispatcher = Arc::new(dispatcher);
+    health.set_dispatcher(dispatcher.clone());
+    if let (Some(spool), Some(spool_cfg)) = (spool, &cfg.spool) {
+        tokio::spawn(spool::replay(
+            spool,
+            dispatcher.clone(),
+            spool_cfg.replay_interval,
+        ));
+    }
+    if let Some(admin_cfg) = &cfg.admin {
+        tokio::spawn(admin::serve(
+            dispatcher.clone(),
+            source.clone(),
+            admin_cfg.listen,
+            admin_cfg.token.clone(),
+            shutdown.clone(),
+        ));
+    }
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(source, dispatcher.clone()));
+    if cfg.grouping.is_some() || cfg.routing.groups() {
+        let dispatcher = dispatcher.clone();
+        tokio::spawn(async move { dispatcher.flush_groups().await });
+    }
+    let limits = &cfg.rate_limits;
+    if limits.per_alert.is_some() || limits.per_channel.is_some() || cfg.routing.limits() {
+        let dispatcher = dispatcher.clone();
+        tokio::spawn(async move { dispatcher.summarize_suppressed().await });
+    }
+    if cfg.quiet_hours.is_some() {
+        let dispatcher = dispatcher.clone();
+        tokio::spawn(async move { dispatcher.wake().await });
+    }
+    if cfg.renotify.is_some() {
+        let dispatcher = dispatcher.clone();
+        tokio::spawn(async move { dispatcher.renotify().await });
+    }
+    if cfg.self_alerts.is_some() {
+        let dispatcher = dispatcher.clone();
+        tokio::spawn(async move { dispatcher.report_self().await });
+    }
+    if cfg.heartbeat.is_some() {
+        tokio::spawn(heartbeat::beat(dispatcher.clone(), health.clone(), client));
+    }
+    if let Some(metrics_cfg) = &cfg.metrics {
+        tokio::spawn(metrics::serve(
+            dispatcher.clone(),
+            metrics_cfg.listen,
+            shutdown.clone(),
+        ));
+    }
+    let backlog = cfg.queue_depth.as_ref().map(|_| {
+        let backlog = Arc::new(Backlog::default());
+        backlog.attach(rabbit.conn.clone());
+        tokio::spawn(backlog.clone().watch(dispatcher.clone()));
+        backlog
+    });
+    if cfg.statsd.is_some() {
+        tokio::spawn(statsd::push(dispatcher.clone()));
+    }
+    let interactions = cfg.interactions.as_ref().map(|interactions_cfg| {
+        let interactions = Arc::new(Interactions::new(interactions_cfg, dispatcher.clone()));
+        interactions.attach(rabbit.publisher());
+        if let Some(signing_secret) = &interactions_cfg.signing_secret {
+            tokio::spawn(interaction::serve(
+                interactions.clone(),
+                interactions_cfg.listen,
+                signing_secret.clone(),
+                shutdown.clone(),
+            ));
+        }
+        if let Some(app_token) = &interactions_cfg.app_token {
+            tokio::spawn(interaction::socket_mode(
+                interactions.clone(),
+                app_token.clone(),
+                shutdown.clone(),
+            ));
+        }
+        interactions
+    });
+
+    tokio::spawn(systemd::watchdog());
+    tokio::spawn(systemd::report_status(dispatcher.clone(), health.clone()));
+    systemd::notify("READY=1");
+    loop {
+        health.set_connected(true);
+        source::consume(&mut rabbit, &cfg, &dispatcher, &shutdown).await;
+        health.set_connected(false);
+        if shutdown.peek().is_some() {
+            break;
+        }
+        warn!("Lost connection to rabbit, reconnecting");
+        dispatcher.connection_lost();
+        dispatcher.metrics.reconnected();
+        if let Some(self_alerts) = &dispatcher.cfg().self_alerts {
+            dispatcher.self_alerts.reconnected(self_alerts);
+        }
+        if let Err(e) = rabbit.shutdown().await {
+            debug!("Error closing previous connection: {:?}", e);
+        }
+
+        rabbit = tokio::select! {
+            rabbit = source::connect_with_retry(&cfg, &mut brokers, &mut backoff, None, dry_run) => rabbit?,
+            _ = shutdown.clone() => return Ok(()),
+        };
+        if let Some(interactions) = &interactions {
+            interactions.attach(rabbit.publisher());
+        }
+        if let Some(backlog) = &backlog {
+            backlog.attach(rabbit.conn.clone());
+        }
+    }
+
+    systemd::notify("STOPPING=1");
+    if let Err(e) = rabbit.shutdown().await {
+        warn!("Unclean shutdown: {:?}", e);
+    }
+    info!("Shut down");
+    Ok(())
+}
@@ -0,0 +1,145 @@
+//! A log file for systems whose supervisor does not keep stderr. The file
+//! is rotated once it grows past a size or a day or hour passes, whichever
+//! comes first; rotated files are named for when they were rotated, and
+//! only the newest few are kept.
+
+use crate::config::{LogFileConfig, Rotation};
+use chrono::{DateTime, Utc};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing_subscriber::fmt::MakeWriter;
+
+struct Current {
+    file: File,
+    size: u64,
+    /// When the file was started, as far as the period goes.
+    opened: DateTime<Utc>,
+}
+
+/// The log file, rotated as its configuration says.
+pub struct LogFile {
+    path: PathBuf,
+    max_bytes: Option<u64>,
+    rotation: Rotation,
+    keep: usize,
+    current: Mutex<Current>,
+}
+
+fn open(path: &Path) -> io::Result<Current> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let metadata = file.metadata()?;
+    let opened = metadata
+        .modified()
+        .map(DateTime::<Utc>::from)
+        .unwrap_or_else(|_| Utc::now());
+    Ok(Current {
+        file,
+        size: metadata.len(),
+        opened,
+    })
+}
+
+impl LogFile {
+    pub fn open(cfg: &LogFileConfig) -> Result<LogFile, String> {
+        let path = PathBuf::from(&cfg.path);
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)
+                .map_err(|e| format!("Unable to create log directory {}: {}", dir.display(), e))?;
+        }
+        let current =
+            open(&path).map_err(|e| format!("Unable to open log file {}: {}", cfg.path, e))?;
+        Ok(LogFile {
+            path,
+            max_bytes: cfg.max_bytes,
+            rotation: cfg.rotation,
+            keep: cfg.keep,
+            current: Mutex::new(current),
+        })
+    }
+
+    /// Whether `current` is due to be rotated before `len` more bytes are
+    /// written to it.
+    fn due(&self, current: &Current, len: usize, now: DateTime<Utc>) -> bool {
+        let full = self
+            .max_bytes
+            .is_some_and(|max_bytes| current.size > 0 && current.size + len as u64 > max_bytes);
+        let format = match self.rotation {
+            Rotation::Never => return full,
+            Rotation::Hourly => "%Y-%m-%d %H",
+            Rotation::Daily => "%Y-%m-%d",
+        };
+        full || current.opened.format(format).to_string() != now.format(format).to_string()
+    }
+
+    /// Move the file aside and start another, removing rotated files beyond
+    /// those kept.
+    fn rotate(&self, current: &mut Current, now: DateTime<Utc>) -> io::Result<()> {
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(now.format(".%Y%m%dT%H%M%S%.3f").to_string());
+        fs::rename(&self.path, &rotated)?;
+        *current = open(&self.path)?;
+
+        let (dir, name) = match (self.path.parent(), self.path.file_name()) {
+            (Some(dir), Some(name)) => (dir, name.to_string_lossy().to_string() + "."),
+            _ => return Ok(()),
+        };
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        let mut old: Vec<PathBuf> = fs::read_dir(dir)?
+            .flatten()
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(&name))
+            .map(|entry| entry.path())
+            .collect();
+        // The timestamps sort in the order the files were rotated.
+        old.sort();
+        let excess = old.len().saturating_sub(self.keep);
+        for path in &old[..excess] {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<()> {
+        let mut current = self.current.lock().unwrap();
+        let now = Utc::now();
+        if self.due(&current, buf.len(), now) {
+            // Carry on in the same file rather than lose lines, trying
+            // again once it is next due.
+            if let Err(e) = self.rotate(&mut current, now) {
+                eprintln!("Unable to rotate {}: {}", self.path.display(), e);
+                current.size = 0;
+                current.opened = now;
+            }
+        }
+        current.file.write_all(buf)?;
+        current.size += buf.len() as u64;
+        Ok(())
+    }
+}
+
+/// Writes a line, or whatever else it is given, to the log file.
+pub struct LogFileWriter<'a>(&'a LogFile);
+
+impl Write for LogFileWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.current.lock().unwrap().file.flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogFile {
+    type Writer = LogFileWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        LogFileWriter(self)
+    }
+}
@@ -0,0 +1,163 @@
+//! Log output: lines for people to read, or a JSON object a line, written
+//! through tracing-subscriber.
+//!
+//! Everything here logs through `log`; tracing-subscriber picks those
+//! records up along with the span each alert is processed in, which carries
+//! its fingerprint and delivery tag, and follows traces when enabled.
+//!
+//! What is logged starts from errors only, or the level `-v` and `-q` ask
+//! for, with `logging.levels` for particular modules and `RUST_LOG` over
+//! both. Levels are applied again when the configuration is reloaded.
+//! Lines go to stderr unless turned off, and to `logging.file` and the
+//! system's log as well if configured.
+
+use crate::config::{Config, LogFormat, LoggingConfig};
+use crate::logfile::LogFile;
+use crate::systemlog::SystemLog;
+use crate::trace::{self, TraceLayer};
+use std::sync::OnceLock;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::{Layered, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, Layer, Registry};
+
+/// How modules are named in `logging.levels`, and how they are known to
+/// `log`.
+const CRATE: (&str, &str) = ("2steps_slack_alert", "twosteps_slack_alert");
+
+type Inner = Layered<Option<TraceLayer>, Registry>;
+type Handle = reload::Handle<Targets, Inner>;
+
+/// The filter of each output, and the verbosity given on the command line.
+static FILTERS: OnceLock<(Vec<Handle>, i32)> = OnceLock::new();
+
+/// The level logged by default at `verbosity`: errors only, nothing for
+/// `-q`, then info, debug and trace for each `-v`.
+fn default_level(verbosity: i32) -> LevelFilter {
+    match verbosity {
+        i32::MIN..=-1 => LevelFilter::OFF,
+        0 => LevelFilter::ERROR,
+        1 => LevelFilter::INFO,
+        2 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    }
+}
+
+fn level(level: log::LevelFilter) -> LevelFilter {
+    match level {
+        log::LevelFilter::Off => LevelFilter::OFF,
+        log::LevelFilter::Error => LevelFilter::ERROR,
+        log::LevelFilter::Warn => LevelFilter::WARN,
+        log::LevelFilter::Info => LevelFilter::INFO,
+        log::LevelFilter::Debug => LevelFilter::DEBUG,
+        log::LevelFilter::Trace => LevelFilter::TRACE,
+    }
+}
+
+/// What to log at `verbosity` with the levels `logging` gives, and those
+/// `RUST_LOG` gives over them.
+fn targets(verbosity: i32, logging: Option<&LoggingConfig>) -> Targets {
+    let mut targets = Targets::new().with_default(default_level(verbosity));
+    for (module, module_level) in logging.into_iter().flat_map(|logging| &logging.levels) {
+        let module = match module.strip_prefix(CRATE.0) {
+            Some(rest) => format!("{}{}", CRATE.1, rest),
+            None => module.clone(),
+        };
+        targets = targets.with_target(module, level(*module_level));
+    }
+    let env = std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|directives| directives.parse::<Targets>().ok());
+    if let Some(env) = env {
+        if let Some(env_default) = env.default_level() {
+            targets = targets.with_default(env_default);
+        }
+        targets = targets.with_targets(env);
+    }
+    targets
+}
+
+/// Start logging, and tracing, as `cfg` says, or with the defaults if the
+/// configuration could not be read, at the `verbosity` of `-v` (positive)
+/// or `-q` (negative). Only the first call has any effect. Must be called
+/// within the runtime.
+pub fn init(cfg: Option<&Config>, verbosity: i32) {
+    let format = cfg.map_or(LogFormat::default(), |cfg| cfg.logging.format);
+    let traces = cfg.and_then(|cfg| cfg.tracing.as_ref()).map(trace::layer);
+    let logging = cfg.map(|cfg| &cfg.logging);
+    let mut outputs = Vec::new();
+    if logging.is_none_or(|logging| logging.stderr) {
+        outputs.push(output(format, std::io::stderr, Dest::Stderr));
+    }
+    let file = logging.and_then(|logging| logging.file.as_ref());
+    match file.map(LogFile::open) {
+        Some(Ok(file)) => outputs.push(output(format, file, Dest::File)),
+        Some(Err(e)) => eprintln!("{}", e),
+        None => {}
+    }
+    let system = logging.and_then(|logging| logging.system);
+    match system.map(SystemLog::open) {
+        Some(Ok(system)) => outputs.push(output(format, system, Dest::System)),
+        Some(Err(e)) => eprintln!("{}", e),
+        None => {}
+    }
+    let mut handles = Vec::new();
+    let outputs: Vec<_> = outputs
+        .into_iter()
+        .map(|output| {
+            let (filter, handle) = reload::Layer::new(targets(verbosity, logging));
+            handles.push(handle);
+            output.with_filter(filter)
+        })
+        .collect();
+    let initialized = tracing_subscriber::registry()
+        .with(traces)
+        .with(outputs)
+        .try_init();
+    if initialized.is_ok() {
+        // `log` would otherwise drop records finer than the level in force
+        // at startup before the filter saw them.
+        log::set_max_level(log::LevelFilter::Trace);
+        let _ = FILTERS.set((handles, verbosity));
+    }
+}
+
+/// Where lines go, which decides how they are dressed.
+enum Dest {
+    Stderr,
+    File,
+    /// A log that keeps its own time and level.
+    System,
+}
+
+/// Lines in `format` to `writer`.
+fn output<W>(format: LogFormat, writer: W, dest: Dest) -> Box<dyn Layer<Inner> + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(matches!(dest, Dest::Stderr));
+    match (format, dest) {
+        (LogFormat::Text, Dest::System) => layer.without_time().with_level(false).boxed(),
+        (LogFormat::Text, _) => layer.boxed(),
+        (LogFormat::Json, _) => layer
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+    }
+}
+
+/// Apply the levels of a reloaded configuration.
+pub fn reload(logging: &LoggingConfig) {
+    if let Some((handles, verbosity)) = FILTERS.get() {
+        for handle in handles {
+            if let Err(e) = handle.reload(targets(*verbosity, Some(logging))) {
+                log::error!("Unable to change log levels: {}", e);
+            }
+        }
+    }
+}
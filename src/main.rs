@@ -1,174 +1,526 @@
-use clap::{App, Arg};
-use futures::executor::block_on;
-use lapin::{options::*, types::FieldTable, Connection, ConnectionProperties, ExchangeKind};
-use log::{debug, error, info};
-use reqwest::Client;
-use serde_json::json;
-use std::convert::TryFrom;
-use std::fs;
-use yaml_rust::YamlLoader;
-
-struct Rabbit {
-    conn: Connection,
-    chan: lapin::Channel,
-    q: lapin::Queue,
-    consumer: lapin::Consumer,
+use clap::{App, Arg, ArgMatches, SubCommand};
+use log::{error, info};
+use twosteps_slack_alert::admin::{self, AdminClient, NewSilence};
+use twosteps_slack_alert::replay::{self, ReplayFilter};
+#[cfg(windows)]
+use twosteps_slack_alert::winservice;
+use twosteps_slack_alert::{
+    check, init, logging, read_config, run, sendtest, setup, tail, ConfigSource,
+};
+
+fn get_args() -> ArgMatches<'static> {
+    let app = App::new("2steps-slack-alert")
+        .version("1.0")
+        .author("Andrew Newlands")
+        .about("Publish 2 Steps alerts to slack")
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .help("set path to configuration file")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .help("set the configuration format, instead of going by its extension")
+                .possible_values(&["yaml", "toml", "json"])
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .value_name("NAME")
+                .help("apply the named profile from the configuration file")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("set")
+                .long("set")
+                .value_name("KEY=VALUE")
+                .help("override a configuration setting, such as slack.channel=#test")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .help("log more: info, then debug (-vv) and trace (-vvv)")
+                .multiple(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .short("q")
+                .long("quiet")
+                .help("log nothing, not even errors")
+                .conflicts_with("verbose")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("log or print what would be delivered, instead of delivering it; the service consumes copies of the alerts")
+                .global(true),
+        )
+        .subcommand(
+            SubCommand::with_name("check").about("validate the configuration file and exit"),
+        )
+        .subcommand(
+            SubCommand::with_name("setup")
+                .about("declare the exchanges, queues and bindings on the broker and exit"),
+        )
+        .subcommand(
+            SubCommand::with_name("send-test")
+                .about("send an alert through routing and templates to its destinations")
+                .arg(
+                    Arg::with_name("alert")
+                        .help("JSON file holding the alert, instead of a sample one")
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("queue")
+                        .long("queue")
+                        .value_name("NAME")
+                        .help("route the alert as the named queue would, instead of the first")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("replay")
+                .about("send alerts again from the dead-letter queues or the audit log")
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .help("where to take the alerts from")
+                        .possible_values(&["dlq", "audit"])
+                        .default_value("dlq"),
+                )
+                .arg(
+                    Arg::with_name("queue")
+                        .long("queue")
+                        .value_name("NAME")
+                        .help("replay only alerts from the named queue")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("since")
+                        .long("since")
+                        .value_name("TIME")
+                        .help("replay only alerts raised at or after an RFC 3339 time")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("until")
+                        .long("until")
+                        .value_name("TIME")
+                        .help("replay only alerts raised before an RFC 3339 time")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("fingerprint")
+                        .long("fingerprint")
+                        .value_name("FINGERPRINT")
+                        .help("replay only alerts with this fingerprint; may be repeated")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("silence")
+                .about("add, list or remove silences in the running service, through its admin API")
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("silence alerts matching every key=value given")
+                        .arg(
+                            Arg::with_name("matchers")
+                                .value_name("KEY=VALUE")
+                                .help("an attribute the alerts must have, such as service=checkout")
+                                .required(true)
+                                .multiple(true),
+                        )
+                        .arg(
+                            Arg::with_name("duration")
+                                .long("duration")
+                                .value_name("DURATION")
+                                .help("how long the silence lasts, such as 90m or 2h")
+                                .takes_value(true)
+                                .required_unless("until")
+                                .conflicts_with("until"),
+                        )
+                        .arg(
+                            Arg::with_name("until")
+                                .long("until")
+                                .value_name("TIME")
+                                .help("when the silence ends, as an RFC 3339 time")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("start")
+                                .long("start")
+                                .value_name("TIME")
+                                .help("when the silence starts, as an RFC 3339 time, instead of now")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("comment")
+                                .long("comment")
+                                .value_name("TEXT")
+                                .help("why, for the record")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("by")
+                                .long("by")
+                                .value_name("NAME")
+                                .help("who is silencing, instead of the current user")
+                                .takes_value(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("list").about("list silences in force or to come"),
+                )
+                .subcommand(
+                    SubCommand::with_name("remove")
+                        .about("end a silence early")
+                        .arg(
+                            Arg::with_name("id")
+                                .help("the silence's number, as listed")
+                                .required(true)
+                                .index(1),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("tail")
+                .about("print alerts as they are published, without delivering them")
+                .arg(
+                    Arg::with_name("queue")
+                        .long("queue")
+                        .value_name("NAME")
+                        .help("print only alerts the named queue would take, instead of any")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("filter")
+                        .long("filter")
+                        .value_name("EXPR")
+                        .help("print only alerts matching an expression, such as 'severity == \"critical\"'; may be repeated")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("init")
+                .about("write an annotated sample configuration")
+                .arg(
+                    Arg::with_name("path")
+                        .help("file to write, instead of stdout")
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("overwrite the file if it exists"),
+                ),
+        );
+    #[cfg(windows)]
+    let app = app.subcommand(
+        SubCommand::with_name("service")
+            .about("run unattended as a Windows service")
+            .subcommand(
+                SubCommand::with_name("install")
+                    .about("register the service to start with Windows, with these options"),
+            )
+            .subcommand(SubCommand::with_name("uninstall").about("stop and remove the service"))
+            .subcommand(
+                SubCommand::with_name("run")
+                    .about("run as the service; for the service manager to start"),
+            ),
+    );
+    app.get_matches()
 }
 
-impl Drop for Rabbit {
-    fn drop(&mut self) {
-        block_on(self.chan.close(200, "client shut down")).unwrap();
-        block_on(self.conn.close(200, "client shut down")).unwrap();
-        info!("Shut down");
+/// `matches` and those of the subcommands given under it, innermost
+/// first.
+fn levels<'a>(matches: &'a ArgMatches<'a>) -> Vec<&'a ArgMatches<'a>> {
+    let mut levels = vec![matches];
+    while let Some(sub) = levels[levels.len() - 1].subcommand().1 {
+        levels.push(sub);
     }
+    levels.reverse();
+    levels
 }
 
-async fn rabbit_connect(ex: &str, q: &str) -> lapin::Result<Rabbit> {
-    let addr = std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://127.0.0.1:5672/%2f".into());
-
-    let conn = Connection::connect(&addr, ConnectionProperties::default()).await?;
-    let chan = conn.create_channel().await?;
-
-    chan.exchange_declare(
-        ex,
-        ExchangeKind::Headers,
-        ExchangeDeclareOptions::default(),
-        FieldTable::default(),
-    )
-    .await?;
-
-    let queue = chan
-        .queue_declare(q, QueueDeclareOptions::default(), FieldTable::default())
-        .await?;
-
-    let consumer = chan
-        .clone()
-        .basic_consume(
-            q,
-            "my tag",
-            BasicConsumeOptions::default(),
-            FieldTable::default(),
-        )
-        .await?;
+/// The value of a global option, which may come before or after the
+/// subcommand.
+fn global_value<'a>(matches: &'a ArgMatches<'a>, name: &str) -> Option<&'a str> {
+    levels(matches)
+        .into_iter()
+        .find_map(|level| level.value_of(name))
+}
 
-    info!("Completed rabbit bus initialization");
+/// The values of a global option that may be repeated.
+fn global_values(matches: &ArgMatches, name: &str) -> Vec<String> {
+    levels(matches)
+        .into_iter()
+        .find_map(|level| level.values_of(name))
+        .map_or_else(Vec::new, |values| values.map(String::from).collect())
+}
 
-    Ok(Rabbit {
-        conn,
-        chan,
-        q: queue,
-        consumer,
-    })
+/// How many times a global flag was given, before or after the subcommand.
+fn global_occurrences(matches: &ArgMatches, name: &str) -> u64 {
+    levels(matches)
+        .iter()
+        .map(|level| level.occurrences_of(name))
+        .sum()
 }
 
-fn get_config_path() -> String {
+fn get_config_path(matches: &ArgMatches) -> String {
     let default_config = match cfg!(windows) {
         true => "./2steps-slack-alert.conf",
         false => "/etc/opt/remasys/2steps/2steps-slack-alert.conf",
     };
 
-    let matches = App::new("2steps-slack-alert")
-        .version("1.0")
-        .author("Andrew Newlands")
-        .about("Publish 2 Steps alerts to slack")
-        .arg(
-            Arg::with_name("config")
-                .short("c")
-                .long("config")
-                .help("set path to configuration file")
-                .takes_value(true),
-        )
-        .get_matches();
-    let config = matches.value_of("config").unwrap_or(default_config);
-
-    return String::from(config);
-}
+    let config = global_value(matches, "config").unwrap_or(default_config);
 
-struct SlackConfig {
-    url: String,
+    String::from(config)
 }
-impl TryFrom<yaml_rust::Yaml> for SlackConfig {
-    type Error = &'static str;
-
-    fn try_from(yaml: yaml_rust::Yaml) -> Result<SlackConfig, Self::Error> {
-        let url = (&yaml["slack"]["url"])
-            .as_str()
-            .ok_or("Configuration missing required Slack URL")?
-            .to_string();
 
-        Ok(SlackConfig { url })
+fn get_config_source(matches: &ArgMatches) -> ConfigSource {
+    ConfigSource {
+        path: get_config_path(matches),
+        // clap has already checked it is one of the possible values.
+        format: global_value(matches, "format").and_then(|format| format.parse().ok()),
+        profile: global_value(matches, "profile").map(String::from),
+        overrides: global_values(matches, "set"),
     }
 }
 
-struct Config {
-    slack: SlackConfig,
+/// Replay as the `replay` subcommand's arguments say.
+async fn replay_matches(
+    source: &ConfigSource,
+    matches: &ArgMatches<'_>,
+    dry_run: bool,
+) -> Result<(), String> {
+    // clap has already checked it is one of the possible values.
+    let from = matches.value_of("from").unwrap_or("dlq").parse()?;
+    let time = |name| {
+        matches
+            .value_of(name)
+            .map(ReplayFilter::parse_time)
+            .transpose()
+    };
+    let filter = ReplayFilter {
+        since: time("since")?,
+        until: time("until")?,
+        fingerprints: matches
+            .values_of("fingerprint")
+            .map_or_else(Vec::new, |values| values.map(String::from).collect()),
+    };
+    replay::replay(source, from, matches.value_of("queue"), &filter, dry_run).await
 }
 
-fn read_config(path: &str) -> Result<Config, String> {
-    info!("Reading configuration from {}", path);
+/// Carry out the `silence` subcommand through the service's admin API.
+async fn silence_matches(source: &ConfigSource, matches: &ArgMatches<'_>) -> Result<(), String> {
+    let client = AdminClient::new(source).await?;
+    match matches.subcommand() {
+        ("add", Some(add)) => {
+            let mut matchers = std::collections::BTreeMap::new();
+            for matcher in add.values_of("matchers").into_iter().flatten() {
+                match matcher.split_once('=') {
+                    Some((key, value)) if !key.is_empty() => {
+                        matchers.insert(key.to_string(), value.to_string());
+                    }
+                    _ => return Err(format!("Expected KEY=VALUE, not {}", matcher)),
+                }
+            }
+            let time = |name| add.value_of(name).map(ReplayFilter::parse_time).transpose();
+            let created_by = match add.value_of("by") {
+                Some(by) => by.to_string(),
+                None => std::env::var("USER")
+                    .or_else(|_| std::env::var("USERNAME"))
+                    .unwrap_or_else(|_| "the command line".to_string()),
+            };
+            let silence = client
+                .add_silence(&NewSilence {
+                    matchers,
+                    starts_at: time("start")?,
+                    ends_at: time("until")?,
+                    duration: add.value_of("duration").map(String::from),
+                    created_by,
+                    comment: add.value_of("comment").map(String::from),
+                })
+                .await?;
+            println!("Added {}", admin::describe(&silence));
+        }
+        ("list", _) => {
+            for silence in client.silences().await? {
+                println!("{}", admin::describe(&silence));
+            }
+        }
+        ("remove", Some(remove)) => {
+            let id = remove.value_of("id").unwrap_or_default();
+            let id = id
+                .parse()
+                .map_err(|_| format!("Expected a silence number, not {}", id))?;
+            let silence = client.remove_silence(id).await?;
+            println!("Removed {}", admin::describe(&silence));
+        }
+        _ => return Err("Expected add, list or remove".to_string()),
+    }
+    Ok(())
+}
 
-    let raw =
-        fs::read_to_string(path).map_err(|e| format!("Unable to read configuration: {}", e))?;
-    let docs = YamlLoader::load_from_str(&raw)
-        .map_err(|e| format!("Unable to parse configuration: {}", e))?;
+/// Wait for SIGINT, or SIGTERM where there is such a thing.
+async fn shutdown_requested() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
 
-    let slack = SlackConfig::try_from(docs[0].clone())?;
-    Ok(Config { slack })
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = term.recv() => {}
+                }
+            }
+            Err(e) => {
+                error!("Unable to listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+    info!("Shutdown requested");
 }
 
 #[tokio::main]
 async fn main() -> Result<(), String> {
-    env_logger::init();
-
-    let cfg = read_config(&get_config_path())?;
+    let args = get_args();
+    if let Some(init) = args.subcommand_matches("init") {
+        if let Err(e) = init::init(init.value_of("path"), init.is_present("force")) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    let source = get_config_source(&args);
+    // The configuration says how to log, so it is read before anything is
+    // logged. Any error in it comes up again below.
+    let verbosity = if global_occurrences(&args, "quiet") > 0 {
+        -1
+    } else {
+        global_occurrences(&args, "verbose") as i32
+    };
+    logging::init(read_config(&source).ok().as_ref(), verbosity);
+    if args.subcommand_matches("check").is_some() {
+        match check::check(&source) {
+            Ok(report) => {
+                println!("{}", report);
+                return Ok(());
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
 
-    let rabbit = rabbit_connect("2steps", "slack_alerts")
-        .await
-        .map_err(|e| format!("Failed to initialize rabbit: {:?}", e))?;
+    if args.subcommand_matches("setup").is_some() {
+        match setup(&source).await {
+            Ok(()) => {
+                println!("Declared the broker topology");
+                return Ok(());
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
 
-    let body = json!({
-        "blocks": [
-            {
-                "type": "section",
-                "text": {
-                    "type": "mrkdwn",
-                    "text": "*Foo Failed*"
-                }
-            },
-            {
-                "type":"divider"
-            },
-            {
-                "type": "section",
-                "text": {
-                    "type": "mrkdwn",
-                    "text": ">Reason: the fleem is flocked"
-                },
-                "accessory": {
-                    "type": "button",
-                    "text": {
-                        "type": "plain_text",
-                        "emoji": true,
-                        "text": "Handle"
-                    },
-                    "value": "handled something"
-                }
+    #[cfg(windows)]
+    if let Some(service) = args.subcommand_matches("service") {
+        let done = match service.subcommand_name() {
+            Some("install") => winservice::install(&source, verbosity),
+            Some("uninstall") => winservice::uninstall(),
+            Some("run") => {
+                let source = source.clone();
+                tokio::task::spawn_blocking(move || winservice::dispatch(source))
+                    .await
+                    .unwrap_or_else(|e| Err(e.to_string()))
             }
-        ]
-    });
-
-    let client = Client::new();
-    let res = client
-        .post(&cfg.slack.url)
-        .json(&body)
-        .send()
-        .await
-        .map_err(|e| format!("failed sending to slack: {:?}", e))?;
-
-    match res.status() {
-        reqwest::StatusCode::OK => debug!("Message acknowledged by Slack"),
-        _ => error!("Slack returned {}", res.status()),
-    };
+            _ => Err("Expected install, uninstall or run".to_string()),
+        };
+        if let Err(e) = done {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
 
-    Ok(())
+    let dry_run = global_occurrences(&args, "dry-run") > 0;
+    if let Some(send_test) = args.subcommand_matches("send-test") {
+        let sent = sendtest::send_test(
+            &source,
+            send_test.value_of("alert"),
+            send_test.value_of("queue"),
+            dry_run,
+        )
+        .await;
+        if let Err(e) = sent {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Some(replay) = args.subcommand_matches("replay") {
+        let replayed = replay_matches(&source, replay, dry_run).await;
+        if let Err(e) = replayed {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Some(silence) = args.subcommand_matches("silence") {
+        if let Err(e) = silence_matches(&source, silence).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Some(tail) = args.subcommand_matches("tail") {
+        let filters: Vec<String> = tail
+            .values_of("filter")
+            .map_or_else(Vec::new, |values| values.map(String::from).collect());
+        let tailed = tail::tail(
+            &source,
+            tail.value_of("queue"),
+            &filters,
+            shutdown_requested(),
+        )
+        .await;
+        if let Err(e) = tailed {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    run(source, dry_run, shutdown_requested()).await
 }
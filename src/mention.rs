@@ -0,0 +1,50 @@
+use crate::alert::{self, Alert, Status};
+use crate::config::MentionsConfig;
+use crate::sink::slack::Slack;
+use log::warn;
+
+/// The `<@user>` and `<!subteam^group>` mentions due on an alert, separated
+/// by spaces. Users given by email are looked up with `token`; any that
+/// cannot be found are left out.
+pub async fn mentions(
+    cfg: &MentionsConfig,
+    slack: &Slack,
+    token: Option<&str>,
+    alert: &Alert,
+) -> String {
+    if alert.status == Status::Resolved || !cfg.severities.contains(&alert.severity) {
+        return String::new();
+    }
+    let value = match serde_json::to_value(alert) {
+        Ok(value) => value,
+        Err(_) => return String::new(),
+    };
+
+    let mut mentions: Vec<String> = Vec::new();
+    for rule in cfg.rules.iter().filter(|r| alert::matches(&value, &r.when)) {
+        for user in &rule.users {
+            let id = match (user.contains('@'), token) {
+                (false, _) => user.clone(),
+                (true, Some(token)) => match slack.lookup_user(token, user).await {
+                    Ok(id) => id,
+                    Err(e) => {
+                        warn!("Unable to look up Slack user {}: {}", user, e);
+                        continue;
+                    }
+                },
+                (true, None) => {
+                    warn!("Cannot look up Slack user {} without slack.token", user);
+                    continue;
+                }
+            };
+            mentions.push(format!("<@{}>", id));
+        }
+        for group in &rule.groups {
+            mentions.push(format!("<!subteam^{}>", group));
+        }
+    }
+
+    let mut seen = std::collections::HashSet::new();
+    mentions.retain(|m| seen.insert(m.clone()));
+    mentions.join(" ")
+}
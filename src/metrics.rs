@@ -0,0 +1,498 @@
+//! Counters and histograms about what we consume and deliver, served in
+//! the Prometheus text format so that the bridge itself can be alerted on,
+//! or taken as samples to be pushed elsewhere.
+
+use crate::router::Dispatcher;
+use crate::sink::{self, DeliveryReceipt, SinkError};
+use crate::systemd;
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use warp::Filter;
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+/// Upper bounds of the delivery latency buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+/// Delivery timings kept for collection at most; later ones are dropped.
+const MAX_TIMINGS: usize = 10_000;
+/// Deliveries kept for `recent`; older ones are forgotten.
+const MAX_RECENT: usize = 100;
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations no greater than each of `LATENCY_BUCKETS`.
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS.iter()) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+/// Counts of what has happened since startup.
+#[derive(Default)]
+pub struct Metrics {
+    consumed: AtomicU64,
+    parsed: AtomicU64,
+    parse_failed: AtomicU64,
+    /// Alerts routed to each receiver, or to the queue's own route under "".
+    routed: Mutex<BTreeMap<String, u64>>,
+    /// Deliveries to each destination by outcome.
+    deliveries: Mutex<BTreeMap<(String, &'static str), u64>>,
+    latency: Mutex<BTreeMap<String, Histogram>>,
+    retries: AtomicU64,
+    dead_letters: AtomicU64,
+    rate_limited: AtomicU64,
+    reconnects: AtomicU64,
+    /// Messages waiting in and consumers of each queue, when last asked.
+    queues: Mutex<BTreeMap<String, (u32, u32)>>,
+    /// How long each delivery took, by destination, while something is
+    /// collecting them.
+    timings: Mutex<Option<Vec<(String, Duration)>>>,
+    recent: Mutex<VecDeque<RecentDelivery>>,
+}
+
+/// A delivery attempt, as the admin API shows it.
+#[derive(Clone, Serialize)]
+pub struct RecentDelivery {
+    pub at: DateTime<Utc>,
+    pub destination: String,
+    /// The keys of the alerts it was about; see `Alert::key`.
+    pub alerts: Vec<String>,
+    /// `delivered`, `failed` or `rejected`.
+    pub outcome: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub millis: u128,
+}
+
+/// A counter's value as it stands, with the labels that tell it apart
+/// from others of the same name.
+pub struct Sample {
+    pub name: &'static str,
+    pub labels: Vec<(&'static str, String)>,
+    pub value: u64,
+}
+
+impl Sample {
+    fn new(name: &'static str, labels: Vec<(&'static str, String)>, value: u64) -> Sample {
+        Sample {
+            name,
+            labels,
+            value,
+        }
+    }
+}
+
+impl Metrics {
+    /// Count a message taken off a queue, and whether it held an alert.
+    pub fn consumed(&self, parsed: bool) {
+        self.consumed.fetch_add(1, Ordering::Relaxed);
+        let counter = if parsed {
+            &self.parsed
+        } else {
+            &self.parse_failed
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an alert routed to `receiver`, or to the queue's own route.
+    pub fn routed(&self, receiver: Option<&str>) {
+        *self
+            .routed
+            .lock()
+            .unwrap()
+            .entry(receiver.unwrap_or_default().to_string())
+            .or_default() += 1;
+    }
+
+    /// Count a delivery to the destination `name` that took `elapsed` and
+    /// came to `result`.
+    pub fn delivered(
+        &self,
+        name: &str,
+        alerts: Vec<String>,
+        result: &Result<DeliveryReceipt, SinkError>,
+        started_at: DateTime<Utc>,
+        elapsed: Duration,
+    ) {
+        *self
+            .deliveries
+            .lock()
+            .unwrap()
+            .entry((name.to_string(), sink::outcome(result)))
+            .or_default() += 1;
+        self.latency
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+        if let Some(timings) = self.timings.lock().unwrap().as_mut() {
+            if timings.len() < MAX_TIMINGS {
+                timings.push((name.to_string(), elapsed));
+            }
+        }
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == MAX_RECENT {
+            recent.pop_front();
+        }
+        recent.push_back(RecentDelivery {
+            at: started_at,
+            destination: name.to_string(),
+            alerts,
+            outcome: sink::outcome(result),
+            error: result.as_ref().err().map(|e| e.to_string()),
+            millis: elapsed.as_millis(),
+        });
+    }
+
+    /// The latest deliveries attempted, newest first.
+    pub fn recent(&self) -> Vec<RecentDelivery> {
+        self.recent.lock().unwrap().iter().rev().cloned().collect()
+    }
+
+    /// Note how many messages wait in `queue` and how many consumers it has.
+    pub fn queue_depth(&self, queue: &str, messages: u32, consumers: u32) {
+        self.queues
+            .lock()
+            .unwrap()
+            .insert(queue.to_string(), (messages, consumers));
+    }
+
+    /// Messages waiting in and consumers of each queue, when last asked.
+    pub fn queue_depths(&self) -> BTreeMap<String, (u32, u32)> {
+        self.queues.lock().unwrap().clone()
+    }
+
+    /// Keep the time taken by each delivery from now on, for `take_timings`.
+    pub fn collect_timings(&self) {
+        self.timings.lock().unwrap().get_or_insert_with(Vec::new);
+    }
+
+    /// The deliveries timed since last asked.
+    pub fn take_timings(&self) -> Vec<(String, Duration)> {
+        self.timings
+            .lock()
+            .unwrap()
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Messages consumed, and deliveries made and not made, since startup.
+    pub fn totals(&self) -> (u64, u64, u64) {
+        let (mut delivered, mut failed) = (0, 0);
+        for ((_, outcome), count) in self.deliveries.lock().unwrap().iter() {
+            match *outcome {
+                "delivered" => delivered += count,
+                _ => failed += count,
+            }
+        }
+        (self.consumed.load(Ordering::Relaxed), delivered, failed)
+    }
+
+    /// Every counter, as it stands.
+    pub fn samples(&self, dropped: &BTreeMap<String, u64>) -> Vec<Sample> {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let mut samples = vec![
+            Sample::new("messages.consumed", vec![], load(&self.consumed)),
+            Sample::new("messages.parsed", vec![], load(&self.parsed)),
+            Sample::new("messages.parse_failed", vec![], load(&self.parse_failed)),
+            Sample::new("retries", vec![], load(&self.retries)),
+            Sample::new("dead_letters", vec![], load(&self.dead_letters)),
+            Sample::new("rate_limited", vec![], load(&self.rate_limited)),
+            Sample::new("reconnects", vec![], load(&self.reconnects)),
+        ];
+        for (receiver, count) in self.routed.lock().unwrap().iter() {
+            samples.push(Sample::new(
+                "alerts.routed",
+                vec![("receiver", receiver.clone())],
+                *count,
+            ));
+        }
+        for (rule, count) in dropped {
+            samples.push(Sample::new(
+                "alerts.dropped",
+                vec![("rule", rule.clone())],
+                *count,
+            ));
+        }
+        for ((destination, outcome), count) in self.deliveries.lock().unwrap().iter() {
+            samples.push(Sample::new(
+                "deliveries",
+                vec![
+                    ("destination", destination.clone()),
+                    ("outcome", outcome.to_string()),
+                ],
+                *count,
+            ));
+        }
+        samples
+    }
+
+    pub fn retried(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dead_lettered(&self) {
+        self.dead_letters.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a notification held back by rate limits.
+    pub fn rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a reconnection to the broker after losing the connection.
+    pub fn reconnected(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// `value` escaped for use as a label value.
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: &AtomicU64) {
+    family(out, name, "counter", help);
+    let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+}
+
+/// Everything `dispatcher` has counted, in the Prometheus text format.
+pub async fn exposition(dispatcher: &Dispatcher) -> String {
+    let metrics = &dispatcher.metrics;
+    let mut out = String::new();
+    counter(
+        &mut out,
+        "twosteps_messages_consumed_total",
+        "Messages taken off the queues.",
+        &metrics.consumed,
+    );
+    counter(
+        &mut out,
+        "twosteps_messages_parsed_total",
+        "Messages holding an alert.",
+        &metrics.parsed,
+    );
+    counter(
+        &mut out,
+        "twosteps_messages_parse_failed_total",
+        "Messages that could not be read as an alert.",
+        &metrics.parse_failed,
+    );
+
+    family(
+        &mut out,
+        "twosteps_alerts_routed_total",
+        "counter",
+        "Alerts routed, by receiver.",
+    );
+    for (receiver, count) in metrics.routed.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "twosteps_alerts_routed_total{{receiver=\"{}\"}} {}",
+            label(receiver),
+            count
+        );
+    }
+    family(
+        &mut out,
+        "twosteps_alerts_dropped_total",
+        "counter",
+        "Alerts discarded, by drop rule.",
+    );
+    for (rule, count) in dispatcher.dropped.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "twosteps_alerts_dropped_total{{rule=\"{}\"}} {}",
+            label(rule),
+            count
+        );
+    }
+
+    family(
+        &mut out,
+        "twosteps_deliveries_total",
+        "counter",
+        "Deliveries to each destination, by outcome.",
+    );
+    for ((destination, outcome), count) in metrics.deliveries.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "twosteps_deliveries_total{{destination=\"{}\",outcome=\"{}\"}} {}",
+            label(destination),
+            outcome,
+            count
+        );
+    }
+    family(
+        &mut out,
+        "twosteps_delivery_duration_seconds",
+        "histogram",
+        "Time taken by deliveries to each destination, retries included.",
+    );
+    for (destination, histogram) in metrics.latency.lock().unwrap().iter() {
+        let destination = label(destination);
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+            let _ = writeln!(
+                out,
+                "twosteps_delivery_duration_seconds_bucket{{destination=\"{}\",le=\"{}\"}} {}",
+                destination, bound, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "twosteps_delivery_duration_seconds_bucket{{destination=\"{}\",le=\"+Inf\"}} {}",
+            destination, histogram.count
+        );
+        let _ = writeln!(
+            out,
+            "twosteps_delivery_duration_seconds_sum{{destination=\"{}\"}} {}",
+            destination, histogram.sum
+        );
+        let _ = writeln!(
+            out,
+            "twosteps_delivery_duration_seconds_count{{destination=\"{}\"}} {}",
+            destination, histogram.count
+        );
+    }
+
+    counter(
+        &mut out,
+        "twosteps_retries_total",
+        "Messages sent back to be tried again.",
+        &metrics.retries,
+    );
+    counter(
+        &mut out,
+        "twosteps_dead_letters_total",
+        "Messages given up on and dead-lettered.",
+        &metrics.dead_letters,
+    );
+    counter(
+        &mut out,
+        "twosteps_rate_limited_total",
+        "Notifications held back by rate limits.",
+        &metrics.rate_limited,
+    );
+    counter(
+        &mut out,
+        "twosteps_reconnects_total",
+        "Reconnections to the broker after losing the connection.",
+        &metrics.reconnects,
+    );
+
+    let queues = metrics.queue_depths();
+    if !queues.is_empty() {
+        family(
+            &mut out,
+            "twosteps_queue_messages",
+            "gauge",
+            "Messages waiting in each queue.",
+        );
+        for (queue, (messages, _)) in &queues {
+            let _ = writeln!(
+                out,
+                "twosteps_queue_messages{{queue=\"{}\"}} {}",
+                label(queue),
+                messages
+            );
+        }
+        family(
+            &mut out,
+            "twosteps_queue_consumers",
+            "gauge",
+            "Consumers of each queue.",
+        );
+        for (queue, (_, consumers)) in &queues {
+            let _ = writeln!(
+                out,
+                "twosteps_queue_consumers{{queue=\"{}\"}} {}",
+                label(queue),
+                consumers
+            );
+        }
+    }
+
+    if let Some(spool) = &dispatcher.spool {
+        let (entries, bytes) = spool.depth().await;
+        family(
+            &mut out,
+            "twosteps_spool_entries",
+            "gauge",
+            "Notifications waiting in the spool.",
+        );
+        let _ = writeln!(out, "twosteps_spool_entries {}", entries);
+        family(
+            &mut out,
+            "twosteps_spool_bytes",
+            "gauge",
+            "Size of the spool file.",
+        );
+        let _ = writeln!(out, "twosteps_spool_bytes {}", bytes);
+    }
+    out
+}
+
+/// Serve `dispatcher`'s metrics at `/metrics` until `shutdown` resolves.
+pub async fn serve(
+    dispatcher: Arc<Dispatcher>,
+    listen: SocketAddr,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) {
+    let route = warp::get().and(warp::path!("metrics")).and_then(move || {
+        let dispatcher = dispatcher.clone();
+        async move {
+            let body = exposition(&dispatcher).await;
+            Ok::<_, warp::Rejection>(warp::reply::with_header(body, "content-type", CONTENT_TYPE))
+        }
+    });
+
+    if let Some(listener) = systemd::listener("metrics") {
+        match systemd::incoming(listener) {
+            Ok(incoming) => {
+                warp::serve(route)
+                    .serve_incoming_with_graceful_shutdown(incoming, shutdown)
+                    .await
+            }
+            Err(e) => error!(
+                "Unable to serve metrics on the socket systemd passed: {}",
+                e
+            ),
+        }
+        return;
+    }
+    match warp::serve(route).try_bind_with_graceful_shutdown(listen, shutdown) {
+        Ok((addr, server)) => {
+            info!("Serving metrics on {}", addr);
+            server.await;
+        }
+        Err(e) => error!("Unable to serve metrics on {}: {}", listen, e),
+    }
+}
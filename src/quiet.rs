@@ -0,0 +1,180 @@
+use crate::alert::Alert;
+use crate::config::{QuietHoursConfig, Schedule};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// An alert held back during quiet hours.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Held {
+    /// The queue the alert arrived on.
+    pub queue: String,
+    pub alert: Alert,
+}
+
+/// Non-critical alerts held during quiet hours, optionally saved to a JSON
+/// file so that they outlive a restart.
+pub struct QuietHours {
+    schedule: Schedule,
+    path: Option<PathBuf>,
+    held: Mutex<Vec<Held>>,
+}
+
+impl QuietHours {
+    pub fn open(cfg: &QuietHoursConfig) -> Result<QuietHours, String> {
+        let path = cfg.path.as_ref().map(PathBuf::from);
+        let held: Vec<Held> = match &path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(raw) => serde_json::from_str(&raw)
+                    .map_err(|e| format!("Unable to parse {}: {}", path.display(), e))?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+                Err(e) => return Err(format!("Unable to read {}: {}", path.display(), e)),
+            },
+            None => Vec::new(),
+        };
+        if !held.is_empty() {
+            info!("Loaded {} alerts held for quiet hours", held.len());
+        }
+
+        Ok(QuietHours {
+            schedule: cfg.schedule.clone(),
+            path,
+            held: Mutex::new(held),
+        })
+    }
+
+    pub fn is_quiet(&self, at: DateTime<Utc>) -> bool {
+        self.schedule.contains(at)
+    }
+
+    /// Hold `alert` in place of any earlier one about the same check.
+    pub fn hold(&self, queue: &str, alert: Alert) {
+        let mut held = self.held.lock().unwrap();
+        let key = alert.key();
+        held.retain(|h| h.queue != queue || h.alert.key() != key);
+        held.push(Held {
+            queue: queue.to_string(),
+            alert,
+        });
+        self.save(&held);
+    }
+
+    /// Let go of the held alerts about the check with `key`, as when it
+    /// recovers. Returns whether there were any.
+    pub fn release(&self, queue: &str, key: &str) -> bool {
+        let mut held = self.held.lock().unwrap();
+        let before = held.len();
+        held.retain(|h| h.queue != queue || h.alert.key() != key);
+        if held.len() == before {
+            return false;
+        }
+        self.save(&held);
+        true
+    }
+
+    /// Take every held alert.
+    pub fn take(&self) -> Vec<Held> {
+        let mut held = self.held.lock().unwrap();
+        let taken = std::mem::take(&mut *held);
+        if !taken.is_empty() {
+            self.save(&held);
+        }
+        taken
+    }
+
+    /// Best effort: losing the file only means held alerts are lost at a
+    /// restart.
+    fn save(&self, held: &[Held]) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let tmp = path.with_extension("tmp");
+        let result = serde_json::to_string(held)
+            .map_err(|e| e.to_string())
+            .and_then(|raw| fs::write(&tmp, raw).map_err(|e| e.to_string()))
+            .and_then(|_| fs::rename(&tmp, path).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            warn!("Unable to save held alerts to {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alert::Status;
+    use chrono::TimeZone;
+
+    fn quiet_hours(path: Option<&str>) -> QuietHours {
+        let mut cfg: QuietHoursConfig = serde_yaml::from_str(
+            "timezone: Europe/London\n\
+             days: [mon-fri]\n\
+             times: ['22:00-07:00']\n\
+             holidays: ['2020-12-25']",
+        )
+        .unwrap();
+        cfg.path = path.map(str::to_string);
+        QuietHours::open(&cfg).unwrap()
+    }
+
+    fn alert(title: &str, status: &str) -> Alert {
+        let published =
+            serde_json::json!({"severity": "warning", "title": title, "status": status});
+        Alert::from_slice(published.to_string().as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn is_quiet_within_the_schedule() {
+        let quiet = quiet_hours(None);
+        let at = |y, m, d, h| Utc.with_ymd_and_hms(y, m, d, h, 0, 0).unwrap();
+        // Wednesday 1 July 2020, on British Summer Time.
+        assert!(quiet.is_quiet(at(2020, 7, 1, 21)));
+        assert!(quiet.is_quiet(at(2020, 7, 1, 5)));
+        assert!(!quiet.is_quiet(at(2020, 7, 1, 6)));
+        assert!(!quiet.is_quiet(at(2020, 7, 1, 12)));
+        // A Saturday and a holiday.
+        assert!(!quiet.is_quiet(at(2020, 7, 4, 23)));
+        assert!(!quiet.is_quiet(at(2020, 12, 25, 23)));
+    }
+
+    #[test]
+    fn holds_the_latest_alert_per_check() {
+        let quiet = quiet_hours(None);
+        quiet.hold("alerts", alert("Checkout", "firing"));
+        quiet.hold("alerts", alert("Login", "firing"));
+        quiet.hold("alerts", alert("Checkout", "resolved"));
+        quiet.hold("other", alert("Checkout", "firing"));
+        assert!(quiet.release("alerts", "//Login"));
+        assert!(!quiet.release("alerts", "//Login"));
+
+        let held = quiet.take();
+        let held: Vec<_> = held
+            .iter()
+            .map(|h| (h.queue.as_str(), h.alert.title.as_str(), h.alert.status))
+            .collect();
+        assert_eq!(
+            held,
+            [
+                ("alerts", "Checkout", Status::Resolved),
+                ("other", "Checkout", Status::Firing)
+            ]
+        );
+        assert!(quiet.take().is_empty());
+    }
+
+    #[test]
+    fn keeps_held_alerts_across_a_restart() {
+        let path = std::env::temp_dir().join(format!("quiet-test-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        quiet_hours(Some(path)).hold("alerts", alert("Checkout", "firing"));
+
+        let reopened = quiet_hours(Some(path));
+        assert_eq!(reopened.take()[0].alert.title, "Checkout");
+        assert!(quiet_hours(Some(path)).take().is_empty());
+        fs::remove_file(path).unwrap();
+    }
+}
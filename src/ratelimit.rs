@@ -0,0 +1,164 @@
+use crate::alert::Severity;
+use crate::config::RateLimit;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Titles listed individually in a summary of suppressed notifications.
+const MAX_TITLES: usize = 10;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    limit: RateLimit,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let rate = f64::from(self.limit.count) / self.limit.per.as_secs_f64();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(f64::from(self.limit.count));
+        self.updated = now;
+    }
+}
+
+/// Notifications held back from one destination since the last summary.
+pub struct Suppressed {
+    pub queue: usize,
+    pub severity: Severity,
+    pub channel: Option<String>,
+    pub receiver: Option<String>,
+    pub count: u64,
+    /// The first few distinct alert titles.
+    pub titles: Vec<String>,
+}
+
+/// Token buckets for notifications per alert and per destination, and a
+/// tally of the notifications they turned away.
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<String, Bucket>>,
+    suppressed: Mutex<HashMap<String, Suppressed>>,
+}
+
+impl RateLimiter {
+    /// Take a token from each of the buckets named in `limits`, or from none
+    /// of them if any is empty.
+    pub fn admit(&self, limits: &[(String, RateLimit)]) -> bool {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        // A bucket that has had time to refill completely is as good as new.
+        buckets.retain(|_, b| now.duration_since(b.updated) < b.limit.per);
+        for (key, limit) in limits {
+            let bucket = buckets.entry(key.clone()).or_insert_with(|| Bucket {
+                tokens: f64::from(limit.count),
+                updated: now,
+                limit: *limit,
+            });
+            bucket.limit = *limit;
+            bucket.refill(now);
+        }
+        if limits.iter().any(|(key, _)| buckets[key].tokens < 1.0) {
+            return false;
+        }
+        for (key, _) in limits {
+            if let Some(bucket) = buckets.get_mut(key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        true
+    }
+
+    /// Count a notification about `title` turned away from the destination
+    /// `name`.
+    pub fn suppress(&self, name: &str, title: &str, destination: impl FnOnce() -> Suppressed) {
+        let mut suppressed = self.suppressed.lock().unwrap();
+        let entry = suppressed
+            .entry(name.to_string())
+            .or_insert_with(destination);
+        entry.count += 1;
+        if entry.titles.len() < MAX_TITLES && !entry.titles.iter().any(|t| t == title) {
+            entry.titles.push(title.to_string());
+        }
+    }
+
+    /// Take the tally of suppressed notifications for each destination.
+    pub fn take_suppressed(&self) -> Vec<(String, Suppressed)> {
+        self.suppressed.lock().unwrap().drain().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn limit(key: &str, count: u32) -> (String, RateLimit) {
+        let per = Duration::from_secs(3600);
+        (key.to_string(), RateLimit { count, per })
+    }
+
+    fn destination() -> Suppressed {
+        Suppressed {
+            queue: 0,
+            severity: Severity::Warning,
+            channel: Some("#ops".to_string()),
+            receiver: None,
+            count: 0,
+            titles: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn admits_up_to_the_count() {
+        let limiter = RateLimiter::default();
+        let limits = [limit("alert", 2)];
+        assert!(limiter.admit(&limits));
+        assert!(limiter.admit(&limits));
+        assert!(!limiter.admit(&limits));
+        assert!(limiter.admit(&[limit("other", 2)]));
+    }
+
+    #[test]
+    fn takes_from_every_bucket_or_none() {
+        let limiter = RateLimiter::default();
+        assert!(limiter.admit(&[limit("alert", 1)]));
+        // The channel's bucket is left full, as the alert's is empty.
+        assert!(!limiter.admit(&[limit("alert", 1), limit("channel", 1)]));
+        assert!(limiter.admit(&[limit("channel", 1)]));
+        assert!(!limiter.admit(&[limit("channel", 1)]));
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let limiter = RateLimiter::default();
+        let limits = [(
+            "alert".to_string(),
+            RateLimit {
+                count: 1,
+                per: Duration::from_millis(20),
+            },
+        )];
+        assert!(limiter.admit(&limits));
+        assert!(!limiter.admit(&limits));
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(limiter.admit(&limits));
+    }
+
+    #[test]
+    fn tallies_suppressed_notifications() {
+        let limiter = RateLimiter::default();
+        for n in 0..15 {
+            limiter.suppress("#ops", &format!("title {}", n), destination);
+            limiter.suppress("#ops", &format!("title {}", n), destination);
+        }
+        let suppressed = limiter.take_suppressed();
+        assert_eq!(suppressed.len(), 1);
+        let (name, tally) = &suppressed[0];
+        assert_eq!(name, "#ops");
+        assert_eq!(tally.count, 30);
+        assert_eq!(tally.titles.len(), MAX_TITLES);
+        assert_eq!(tally.titles[1], "title 1");
+        assert!(limiter.take_suppressed().is_empty());
+    }
+}
@@ -0,0 +1,593 @@
+use crate::alert::{self, Alert, Severity, Status};
+use crate::config::{
+    EmailConfig, JiraReceiver, RoutingConfig, Style, StylesConfig, TemplateConfig,
+};
+use crate::interaction;
+use crate::sink::slack::blocks::{
+    Accessory, Actions, Block, Button, Context, Element, Message, Mrkdwn, Overflow, Section,
+    MAX_BLOCK_ID_LEN,
+};
+use crate::sink::Notification;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use tera::Tera;
+
+/// Labels and values of the choices in the Snooze menu.
+const SNOOZE_OPTIONS: [(&str, &str); 3] = [
+    ("Snooze for 30 minutes", "30m"),
+    ("Snooze for 2 hours", "2h"),
+    ("Snooze for 24 hours", "24h"),
+];
+
+/// Most alerts listed individually in a digest, keeping well within
+/// Slack's limit on blocks.
+const MAX_DIGEST_ALERTS: usize = 40;
+
+/// The built-in Slack Block Kit layout, used when no template applies.
+fn render_alert(alert: &Alert, style: &Style, mentions: &str, escalate: bool) -> Value {
+    let title = match alert.status {
+        Status::Firing => format!("{} *{}*", style.emoji, alert.title),
+        Status::Resolved => format!("{} *~{}~*", style.emoji, alert.title),
+    };
+    let reason = format!(
+        ">Reason: {}",
+        alert.reason.as_deref().unwrap_or("not given")
+    );
+    let mut message = Message::new()
+        .text(alert.title.clone())
+        .block(Section::new(Mrkdwn::new(title)));
+    if !mentions.is_empty() {
+        message = message.block(Section::new(Mrkdwn::new(mentions)));
+    }
+    // Once someone has picked the alert up there is nothing left to handle.
+    let mut reason = Section::new(Mrkdwn::new(reason));
+    if alert.acknowledged_by.is_none() {
+        reason = reason.accessory(Accessory::Button(
+            Button::new("Handle")
+                .action_id(interaction::HANDLE_ACTION)
+                .value(alert.key()),
+        ));
+    }
+    message = message.block(Block::Divider).block(reason);
+
+    if alert.status == Status::Firing {
+        let key = alert.key();
+        let mut actions = Actions::default();
+        if escalate && alert.severity == Severity::Critical && alert.escalated_by.is_none() {
+            actions = actions.element(Element::Button(
+                Button::new("Escalate")
+                    .action_id(interaction::ESCALATE_ACTION)
+                    .value(key.clone()),
+            ));
+        }
+        // The menu's block ID tells us which alert to snooze; alerts whose
+        // key is too long for one go without.
+        if key.len() <= MAX_BLOCK_ID_LEN {
+            let snooze = SNOOZE_OPTIONS.iter().fold(
+                Overflow::new(interaction::SNOOZE_ACTION),
+                |menu, (text, value)| menu.option(*text, *value),
+            );
+            actions = actions.element(Element::Overflow(snooze)).block_id(key);
+        }
+        if !actions.elements.is_empty() {
+            message = message.block(actions);
+        }
+    }
+
+    if !alert.fields.is_empty() {
+        let section = alert
+            .fields
+            .iter()
+            .fold(Section::default(), |section, (k, v)| {
+                let v = match v {
+                    Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                };
+                section.field(Mrkdwn::new(format!("*{}*\n{}", k, v)))
+            });
+        message = message.block(section);
+    }
+
+    let mut context = vec![format!("Severity: {}", alert.severity)];
+    if let Some(source) = &alert.source {
+        context.push(format!("Source: {}", source));
+    }
+    if let Some(step) = &alert.step {
+        context.push(format!("Step: {}", step));
+    }
+    if let Some(run_id) = &alert.run_id {
+        context.push(format!("Run: {}", run_id));
+    }
+    context.push(alert.timestamp.to_rfc2822());
+    if let (Some(by), Some(at)) = (&alert.acknowledged_by, alert.acknowledged_at) {
+        context.push(format!(
+            "Acknowledged by <@{}> at {}",
+            by,
+            at.format("%H:%M UTC")
+        ));
+    }
+    if let (Some(by), Some(until)) = (&alert.snoozed_by, alert.snoozed_until) {
+        context.push(format!(
+            "Snoozed by <@{}> until {}",
+            by,
+            until.format("%H:%M UTC")
+        ));
+    }
+    if let Some(n) = alert.occurrences.filter(|n| *n > 1) {
+        context.push(format!("Seen {}×", n));
+    }
+    if let (Some(by), Some(at)) = (&alert.escalated_by, alert.escalated_at) {
+        let escalated = format!(
+            "Escalated to PagerDuty by <@{}> at {}",
+            by,
+            at.format("%H:%M UTC")
+        );
+        context.push(match &alert.incident_url {
+            Some(url) => format!("{} (<{}|incident>)", escalated, url),
+            None => escalated,
+        });
+    }
+    if alert.status == Status::Resolved {
+        let at = alert.resolved_at.unwrap_or(alert.timestamp);
+        context.push(format!("Resolved at {}", at.format("%H:%M UTC")));
+    }
+    if let Some(trace_id) = &alert.trace_id {
+        context.push(format!("Trace: {}", trace_id));
+    }
+
+    message
+        .block(Context::new(Mrkdwn::new(context.join(" | "))))
+        .color(style.color.clone())
+        .to_value()
+}
+
+/// Names under which the email templates are kept, apart from the files
+/// of Slack templates. Tera escapes templates named `*.html`.
+const EMAIL_SUBJECT: &str = "<email subject>";
+const EMAIL_TEXT: &str = "<email text>";
+const EMAIL_HTML: &str = "<email>.html";
+
+/// An email rendered from a notification.
+pub struct EmailBody {
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+/// Replace the characters special to HTML in `text`.
+pub fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// What goes in an email or ticket about `alert` besides its title and
+/// reason.
+pub fn alert_details(alert: &Alert) -> Vec<(String, String)> {
+    let status = match alert.status {
+        Status::Firing => "firing",
+        Status::Resolved => "resolved",
+    };
+    let mut details = vec![
+        ("Severity".to_string(), alert.severity.to_string()),
+        ("Status".to_string(), status.to_string()),
+    ];
+    for (name, value) in [
+        ("Source", &alert.source),
+        ("Step", &alert.step),
+        ("Run", &alert.run_id),
+    ] {
+        if let Some(value) = value {
+            details.push((name.to_string(), value.clone()));
+        }
+    }
+    for (key, value) in &alert.fields {
+        let value = match value {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        details.push((key.clone(), value));
+    }
+    details.push((
+        "Time".to_string(),
+        alert.timestamp.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+    ));
+    details
+}
+
+/// What templates for destinations other than Slack are given: the
+/// notification's summary as `summary`, its severity as `severity`, its
+/// alerts as `alerts` and, when there is just one, that alert as `alert`.
+/// Head the message `body`, in Slack's format, with `note`.
+pub fn add_note(body: &mut Value, note: &str) -> Result<(), String> {
+    let heading = serde_json::to_value(Block::from(Section::new(Mrkdwn::new(note.to_string()))))
+        .map_err(|e| format!("Unable to serialize note: {}", e))?;
+    if let Some(blocks) = body["blocks"].as_array_mut() {
+        blocks.insert(0, heading);
+    }
+    if let Some(text) = body["text"].as_str() {
+        body["text"] = Value::String(format!("{} {}", note, text));
+    }
+    Ok(())
+}
+
+fn notification_context(rendered: &Notification) -> tera::Context {
+    let mut context = tera::Context::new();
+    context.insert("summary", &rendered.summary);
+    context.insert("severity", &rendered.severity.to_string());
+    if let [alert] = rendered.alerts.as_slice() {
+        context.insert("alert", alert);
+    }
+    context.insert("alerts", &rendered.alerts);
+    context
+}
+
+/// The built-in subject line: the summary, marked with the severity or as
+/// resolved.
+fn email_subject(rendered: &Notification) -> String {
+    match rendered.alerts.as_slice() {
+        [alert] if alert.status == Status::Resolved => format!("[RESOLVED] {}", rendered.summary),
+        _ => format!(
+            "[{}] {}",
+            rendered.severity.to_string().to_uppercase(),
+            rendered.summary
+        ),
+    }
+}
+
+/// The built-in plain text body.
+fn email_text(rendered: &Notification) -> String {
+    let mut text = format!("{}\n", rendered.summary);
+    for alert in &rendered.alerts {
+        text.push_str(&format!("\n{}\n", alert.title));
+        if let Some(reason) = &alert.reason {
+            text.push_str(&format!("Reason: {}\n", reason));
+        }
+        for (name, value) in alert_details(alert) {
+            text.push_str(&format!("{}: {}\n", name, value));
+        }
+    }
+    text
+}
+
+/// The built-in HTML body, with a table of details for each alert.
+fn email_html(rendered: &Notification, styles: &StylesConfig) -> String {
+    let mut html = format!(
+        "<html><body style=\"font-family: sans-serif\">\n<h2>{}</h2>\n",
+        escape_html(&rendered.summary)
+    );
+    for alert in &rendered.alerts {
+        let style = styles.style_for(alert.severity, alert.status);
+        html.push_str(&format!(
+            "<h3 style=\"border-left: 4px solid {}; padding-left: 8px\">{}</h3>\n",
+            escape_html(&style.color),
+            escape_html(&alert.title)
+        ));
+        if let Some(reason) = &alert.reason {
+            html.push_str(&format!("<p>{}</p>\n", escape_html(reason)));
+        }
+        html.push_str("<table>\n");
+        for (name, value) in alert_details(alert) {
+            html.push_str(&format!(
+                "<tr><th align=\"left\">{}</th><td>{}</td></tr>\n",
+                escape_html(&name),
+                escape_html(&value)
+            ));
+        }
+        html.push_str("</table>\n");
+    }
+    html.push_str("</body></html>\n");
+    html
+}
+
+/// Jira's limit on the length of an issue summary.
+const MAX_JIRA_SUMMARY: usize = 255;
+
+/// A Jira issue rendered from an alert.
+pub struct JiraIssue {
+    pub summary: String,
+    pub description: String,
+}
+
+/// The built-in issue description, in Jira's wiki markup.
+fn jira_description(alert: &Alert) -> String {
+    let mut description = String::new();
+    if let Some(reason) = &alert.reason {
+        description.push_str(&format!("{}\n\n", reason));
+    }
+    for (name, value) in alert_details(alert) {
+        description.push_str(&format!("*{}:* {}\n", name, value));
+    }
+    description
+}
+
+/// Message templates loaded from the files named in the config. Each
+/// template renders to the JSON body posted to Slack, with the alert as
+/// `alert`, its key (the Handle button's value) as `key`, its extra fields
+/// as `fields`, its colour and emoji as `style`, anyone to notify as
+/// `mentions` and whether to offer an Escalate button as `escalate`.
+pub struct Templates {
+    tera: Tera,
+    styles: StylesConfig,
+    /// Whether alerts can be escalated to PagerDuty.
+    escalate: bool,
+    default: Option<String>,
+    select: Vec<(BTreeMap<String, String>, String)>,
+    /// Consulted before `select`, as routes may name templates too.
+    routing: RoutingConfig,
+    /// Which of the subject and bodies of emails have templates.
+    email_subject: bool,
+    email_text: bool,
+    email_html: bool,
+}
+
+impl Templates {
+    pub fn load(
+        cfg: &TemplateConfig,
+        routing: &RoutingConfig,
+        styles: &StylesConfig,
+        escalate: bool,
+        email: Option<&EmailConfig>,
+    ) -> Result<Templates, String> {
+        let mut tera = Tera::default();
+        let paths = cfg
+            .default
+            .iter()
+            .chain(cfg.select.iter().map(|rule| &rule.template))
+            .map(String::as_str)
+            .chain(routing.templates());
+        for path in paths {
+            tera.add_template_file(path, Some(path))
+                .map_err(|e| format!("Unable to load template {}: {}", path, e))?;
+        }
+        let subject = email.and_then(|email| email.subject.as_deref());
+        if let Some(subject) = subject {
+            tera.add_raw_template(EMAIL_SUBJECT, subject)
+                .map_err(|e| format!("Unable to load email subject template: {}", e))?;
+        }
+        let text = email.and_then(|email| email.text_template.as_deref());
+        let html = email.and_then(|email| email.html_template.as_deref());
+        for (name, path) in [(EMAIL_TEXT, text), (EMAIL_HTML, html)] {
+            if let Some(path) = path {
+                tera.add_template_file(path, Some(name))
+                    .map_err(|e| format!("Unable to load template {}: {}", path, e))?;
+            }
+        }
+
+        Ok(Templates {
+            tera,
+            styles: styles.clone(),
+            escalate,
+            default: cfg.default.clone(),
+            select: cfg
+                .select
+                .iter()
+                .map(|rule| (rule.when.clone(), rule.template.clone()))
+                .collect(),
+            routing: routing.clone(),
+            email_subject: subject.is_some(),
+            email_text: text.is_some(),
+            email_html: html.is_some(),
+        })
+    }
+
+    /// The message for an alert, headed by `note`, such as a reminder that
+    /// nobody has acknowledged it. Works for templates too, provided they
+    /// produce a `blocks` list.
+    pub fn render_with_note(
+        &self,
+        alert: &Alert,
+        mentions: &str,
+        note: &str,
+    ) -> Result<Value, String> {
+        let mut body = self.render(alert, mentions)?;
+        add_note(&mut body, note)?;
+        Ok(body)
+    }
+
+    /// A note that `count` notifications, about the alerts with `titles`
+    /// among others, were held back by rate limits during the last
+    /// `minutes`.
+    pub fn render_suppressed(&self, count: u64, titles: &[String], minutes: u64) -> Value {
+        let style = self.styles.style_for(Severity::Info, Status::Firing);
+        let summary = format!(
+            "{} notifications suppressed by rate limits in the last {} minutes",
+            count, minutes
+        );
+        let mut message = Message::new()
+            .text(summary.clone())
+            .block(Section::new(Mrkdwn::new(format!(
+                "{} *{}*",
+                style.emoji, summary
+            ))));
+        if !titles.is_empty() {
+            let list: Vec<String> = titles.iter().map(|t| format!("• {}", t)).collect();
+            message = message.block(Section::new(Mrkdwn::new(list.join("\n"))));
+        }
+        message.color(style.color.clone()).to_value()
+    }
+
+    /// A digest of several firing alerts headed by `summary`, in the
+    /// built-in layout and the style of the most severe of them.
+    pub fn render_digest(&self, summary: &str, alerts: &[Alert], mentions: &str) -> Value {
+        let severity = alerts
+            .iter()
+            .map(|alert| alert.severity)
+            .min()
+            .unwrap_or(Severity::Critical);
+        let style = self.styles.style_for(severity, Status::Firing);
+
+        let mut message =
+            Message::new()
+                .text(summary.to_string())
+                .block(Section::new(Mrkdwn::new(format!(
+                    "{} *{}*",
+                    style.emoji, summary
+                ))));
+        if !mentions.is_empty() {
+            message = message.block(Section::new(Mrkdwn::new(mentions)));
+        }
+        message = message.block(Block::Divider);
+
+        for alert in alerts.iter().take(MAX_DIGEST_ALERTS) {
+            let mut text = format!("*{}*", alert.title);
+            if let Some(reason) = &alert.reason {
+                text.push_str(&format!("\n>{}", reason));
+            }
+            let mut context = vec![format!("Severity: {}", alert.severity)];
+            if let Some(source) = &alert.source {
+                context.push(format!("Source: {}", source));
+            }
+            if let Some(step) = &alert.step {
+                context.push(format!("Step: {}", step));
+            }
+            text.push_str(&format!("\n{}", context.join(" | ")));
+            message = message.block(Section::new(Mrkdwn::new(text)));
+        }
+        if alerts.len() > MAX_DIGEST_ALERTS {
+            message = message.block(Context::new(Mrkdwn::new(format!(
+                "…and {} more",
+                alerts.len() - MAX_DIGEST_ALERTS
+            ))));
+        }
+
+        message.color(style.color.clone()).to_value()
+    }
+
+    /// The first template whose conditions all hold for the alert.
+    fn choose(&self, alert: &Value) -> Option<&str> {
+        if let Some(template) = self.routing.resolve(alert).template {
+            return Some(template);
+        }
+        self.select
+            .iter()
+            .find(|(when, _)| alert::matches(alert, when))
+            .map(|(_, template)| template.as_str())
+            .or(self.default.as_deref())
+    }
+
+    pub fn render(&self, alert: &Alert, mentions: &str) -> Result<Value, String> {
+        let value =
+            serde_json::to_value(alert).map_err(|e| format!("Unable to serialize alert: {}", e))?;
+        let style = self.styles.style_for(alert.severity, alert.status);
+        let template = match self.choose(&value) {
+            Some(template) => template,
+            None => return Ok(render_alert(alert, style, mentions, self.escalate)),
+        };
+
+        let mut context = tera::Context::new();
+        context.insert("alert", &value);
+        context.insert("key", &alert.key());
+        context.insert("fields", &alert.fields);
+        context.insert("mentions", mentions);
+        context.insert("escalate", &self.escalate);
+        context.insert(
+            "style",
+            &json!({ "color": style.color, "emoji": style.emoji }),
+        );
+        let body = self
+            .tera
+            .render(template, &context)
+            .map_err(|e| format!("Unable to render template {}: {:?}", template, e))?;
+        serde_json::from_str(&body)
+            .map_err(|e| format!("Template {} did not produce valid JSON: {}", template, e))
+    }
+
+    /// The email for `rendered`, from templates given the context of
+    /// `notification_context`. Values are escaped in the HTML template.
+    pub fn render_email(&self, rendered: &Notification) -> Result<EmailBody, String> {
+        let context = notification_context(rendered);
+        let render = |name: &str| {
+            self.tera
+                .render(name, &context)
+                .map_err(|e| format!("Unable to render {} template: {:?}", name, e))
+        };
+
+        let subject = if self.email_subject {
+            render(EMAIL_SUBJECT)?.trim().to_string()
+        } else {
+            email_subject(rendered)
+        };
+        let text = if self.email_text {
+            render(EMAIL_TEXT)?
+        } else {
+            email_text(rendered)
+        };
+        let html = if self.email_html {
+            render(EMAIL_HTML)?
+        } else {
+            email_html(rendered, &self.styles)
+        };
+        Ok(EmailBody {
+            subject,
+            text,
+            html,
+        })
+    }
+
+    /// The issue for `alert` in `project`, from its templates given the
+    /// context of `notification_context` for the alert on its own.
+    pub fn render_jira(&self, project: &JiraReceiver, alert: &Alert) -> Result<JiraIssue, String> {
+        let context = notification_context(&Notification {
+            alerts: vec![alert.clone()],
+            severity: alert.severity,
+            summary: alert.title.clone(),
+            body: Value::Null,
+        });
+        let render = |template: &str| {
+            self.tera
+                .render(template, &context)
+                .map_err(|e| format!("Unable to render template {}: {:?}", template, e))
+        };
+
+        let summary = match &project.summary_template {
+            Some(template) => render(template)?,
+            None => format!(
+                "[{}] {}",
+                alert.severity.to_string().to_uppercase(),
+                alert.title
+            ),
+        };
+        // Summaries are a single line.
+        let summary = summary.split_whitespace().collect::<Vec<_>>().join(" ");
+        let description = match &project.description_template {
+            Some(template) => render(template)?,
+            None => jira_description(alert),
+        };
+        Ok(JiraIssue {
+            summary: summary.chars().take(MAX_JIRA_SUMMARY).collect(),
+            description,
+        })
+    }
+
+    /// The body of a request to a webhook receiver: `template` rendered
+    /// with the context of `notification_context`, or a JSON object with
+    /// the notification's summary, severity and alerts.
+    pub fn render_webhook(
+        &self,
+        template: Option<&str>,
+        rendered: &Notification,
+    ) -> Result<String, String> {
+        match template {
+            Some(template) => self
+                .tera
+                .render(template, &notification_context(rendered))
+                .map_err(|e| format!("Unable to render template {}: {:?}", template, e)),
+            None => Ok(json!({
+                "summary": rendered.summary,
+                "severity": rendered.severity.to_string(),
+                "alerts": rendered.alerts,
+            })
+            .to_string()),
+        }
+    }
+}
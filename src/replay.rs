@@ -0,0 +1,204 @@
+//! The `replay` subcommand, which sends alerts again from the dead-letter
+//! queues or the audit log, so that those dropped while a destination was
+//! misconfigured can be recovered once it is put right.
+
+use crate::alert::Alert;
+use crate::audit;
+use crate::config::{Config, ConfigSource};
+use crate::load_config;
+use crate::router::Dispatcher;
+use crate::sendtest;
+use crate::source::{self, DeadLetters};
+use chrono::{DateTime, Utc};
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Where alerts are replayed from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayFrom {
+    /// The dead-letter queue of each queue. Alerts replayed to every
+    /// destination are removed from it; the rest are left there.
+    DeadLetters,
+    /// The alerts the audit log records as received.
+    Audit,
+}
+
+impl FromStr for ReplayFrom {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<ReplayFrom, String> {
+        match value {
+            "dlq" => Ok(ReplayFrom::DeadLetters),
+            "audit" => Ok(ReplayFrom::Audit),
+            _ => Err(format!(
+                "Unable to replay from {}: expected dlq or audit",
+                value
+            )),
+        }
+    }
+}
+
+/// Which alerts to replay: those raised in a time range, and with one of
+/// the fingerprints if any are given.
+#[derive(Debug, Clone, Default)]
+pub struct ReplayFilter {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub fingerprints: Vec<String>,
+}
+
+impl ReplayFilter {
+    /// An RFC 3339 time, such as 2020-06-01T09:30:00Z.
+    pub fn parse_time(value: &str) -> Result<DateTime<Utc>, String> {
+        DateTime::parse_from_rfc3339(value)
+            .map(|time| time.with_timezone(&Utc))
+            .map_err(|e| format!("Unable to parse time {}: {}", value, e))
+    }
+
+    fn matches(&self, alert: &Alert) -> bool {
+        self.since.is_none_or(|since| alert.timestamp >= since)
+            && self.until.is_none_or(|until| alert.timestamp < until)
+            && (self.fingerprints.is_empty() || self.fingerprints.contains(&alert.key()))
+    }
+}
+
+/// Send `alert` as the `queue`th queue would, to each destination but those
+/// `delivered` already, printing how each went. Returns whether all took it.
+async fn send(
+    dispatcher: &Dispatcher,
+    queue: usize,
+    alert: &Alert,
+    delivered: &[String],
+    dry_run: bool,
+) -> bool {
+    let cfg = dispatcher.cfg();
+    println!(
+        "{} raised {} on {}:",
+        alert.key(),
+        alert.timestamp.to_rfc3339(),
+        cfg.queues[queue].name
+    );
+    let results = match dispatcher.deliver_now(queue, alert, delivered).await {
+        Ok(results) => results,
+        Err(e) => {
+            println!("  Unable to replay: {}", e);
+            return false;
+        }
+    };
+    let mut sent = true;
+    for (destination, _, result) in results {
+        match result {
+            Ok(_) if dry_run => println!("  Not delivered to {}: dry run", destination),
+            Ok(_) => println!("  Delivered to {}", destination),
+            Err(e) => {
+                println!("  Unable to deliver to {}: {}", destination, e);
+                sent = false;
+            }
+        }
+    }
+    sent
+}
+
+/// The queues to replay for: the one named `queue`, or all of them.
+fn queues(cfg: &Config, queue: Option<&str>) -> Result<Vec<usize>, String> {
+    match queue {
+        Some(_) => Ok(vec![sendtest::queue_index(cfg, queue)?]),
+        None => Ok((0..cfg.queues.len()).collect()),
+    }
+}
+
+/// Replay the dead letters of `queues` that `filter` matches. Returns how
+/// many were replayed and how many of those failed.
+async fn replay_dead_letters(
+    dispatcher: &Dispatcher,
+    letters: &DeadLetters,
+    queues: &[usize],
+    filter: &ReplayFilter,
+    dry_run: bool,
+) -> Result<(usize, usize), String> {
+    let cfg = dispatcher.cfg();
+    let (mut replayed, mut failed) = (0, 0);
+    for &queue in queues {
+        // Messages taken stay with us until removed or the channel is
+        // closed, so each is seen once.
+        while let Some(delivery) = letters.next(&cfg.queues[queue].name).await? {
+            let alert = match Alert::from_slice(&delivery.data) {
+                Ok(alert) => alert,
+                Err(e) => {
+                    println!("Leaving dead letter {}: {}", delivery.delivery_tag, e);
+                    continue;
+                }
+            };
+            if !filter.matches(&alert) {
+                continue;
+            }
+            replayed += 1;
+            let delivered = source::delivered(&delivery);
+            if !send(dispatcher, queue, &alert, &delivered, dry_run).await {
+                failed += 1;
+            } else if !dry_run {
+                letters.remove(&delivery).await?;
+            }
+        }
+    }
+    Ok((replayed, failed))
+}
+
+/// Send again the alerts in the dead-letter queues or the audit log that
+/// `filter` matches, from the queue named `queue` or all of them, printing
+/// what became of each. Fails if any was not delivered everywhere. A
+/// `dry_run` prints where each would go without sending anything or
+/// removing any dead letter.
+pub async fn replay(
+    source: &ConfigSource,
+    from: ReplayFrom,
+    queue: Option<&str>,
+    filter: &ReplayFilter,
+    dry_run: bool,
+) -> Result<(), String> {
+    let cfg = Arc::new(load_config(source).await?);
+    let queues = queues(&cfg, queue)?;
+    let dispatcher = sendtest::dispatcher(&cfg, dry_run)?;
+
+    let (replayed, failed) = match from {
+        ReplayFrom::DeadLetters => {
+            let letters = DeadLetters::open(&cfg).await?;
+            let replayed =
+                replay_dead_letters(&dispatcher, &letters, &queues, filter, dry_run).await;
+            // Those not removed go back to the dead-letter queue.
+            letters.close().await;
+            replayed?
+        }
+        ReplayFrom::Audit => {
+            let audit = cfg
+                .audit
+                .as_ref()
+                .ok_or_else(|| "No audit log is configured".to_string())?;
+            let since = filter.since.map(|since| since.date_naive());
+            let (mut replayed, mut failed) = (0, 0);
+            // An alert is received again each time it is retried.
+            let mut seen = HashSet::new();
+            for (name, alert) in audit::received(&audit.dir, since)? {
+                let queue = match queues.iter().find(|&&queue| cfg.queues[queue].name == name) {
+                    Some(&queue) => queue,
+                    None => continue,
+                };
+                if !filter.matches(&alert) || !seen.insert(serde_json::json!(alert).to_string()) {
+                    continue;
+                }
+                replayed += 1;
+                if !send(&dispatcher, queue, &alert, &[], dry_run).await {
+                    failed += 1;
+                }
+            }
+            (replayed, failed)
+        }
+    };
+
+    println!("Replayed {} alert(s)", replayed);
+    match failed {
+        0 => Ok(()),
+        n => Err(format!("{} alert(s) not delivered everywhere", n)),
+    }
+}
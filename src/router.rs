@@ -0,0 +1,1796 @@
+use crate::alert::{Alert, Severity, Status};
+use crate::audit::Audit;
+use crate::circuit::CircuitBreaker;
+use crate::config::{AckPolicy, Config, RateLimit, Receiver, Routing, DEFAULT_GROUP_INTERVAL};
+use crate::dedup::Dedup;
+use crate::flap::{Flapping, Flaps};
+use crate::group::{Group, Grouper, Grouping, Pending};
+use crate::inhibit::Inhibitor;
+use crate::mention;
+use crate::metrics::Metrics;
+use crate::quiet::{Held, QuietHours};
+use crate::ratelimit::{RateLimiter, Suppressed};
+use crate::render::{self, Templates};
+use crate::selfalert::{self, SelfAlerts};
+use crate::sentry::Sentry;
+use crate::silence::{Silence, Silences};
+use crate::sink::discord::DiscordSink;
+use crate::sink::dryrun::DryRunSink;
+use crate::sink::email::EmailSink;
+use crate::sink::google_chat::GoogleChatSink;
+use crate::sink::jira::JiraSink;
+use crate::sink::matrix::MatrixSink;
+use crate::sink::mattermost::MattermostSink;
+use crate::sink::opsgenie::OpsgenieSink;
+use crate::sink::pagerduty::PagerDuty;
+use crate::sink::pagerduty::PagerDutySink;
+use crate::sink::pushover::PushoverSink;
+use crate::sink::servicenow::ServiceNowSink;
+use crate::sink::slack::{SlackSink, Target};
+use crate::sink::sns::SnsSink;
+use crate::sink::syslog::SyslogSink;
+use crate::sink::teams::TeamsSink;
+use crate::sink::telegram::TelegramSink;
+use crate::sink::twilio::SmsSink;
+use crate::sink::webex::WebexSink;
+use crate::sink::webhook::WebhookSink;
+use crate::sink::{self, DeliveryReceipt, Notification, Sink, SinkError, Sinks};
+use crate::source::{self, Pause, Settler};
+use crate::spool::{Entry, Spool};
+use crate::store::{MessageStore, StoredMessage};
+use chrono::{DateTime, Utc};
+use futures::future::join_all;
+use lapin::message::Delivery;
+use log::{debug, error, info, warn};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{field, info_span, Instrument};
+
+/// Attribute under which silences see an alert's key; see `Alert::key`.
+const KEY_ATTRIBUTE: &str = "key";
+/// How often grouped alerts are checked for being due.
+const GROUP_TICK: Duration = Duration::from_secs(1);
+/// How often quiet hours are checked for having ended.
+const QUIET_TICK: Duration = Duration::from_secs(60);
+/// How often posted alerts are checked for being due a reminder.
+const RENOTIFY_TICK: Duration = Duration::from_secs(30);
+/// How often trouble with the bridge itself is checked for being due a
+/// report.
+const SELF_ALERT_TICK: Duration = Duration::from_secs(10);
+
+/// Write an alert to the spool, reporting whether that worked.
+async fn spool_alert(spool: &Spool, tag: u64, entry: Entry) -> bool {
+    let destination = entry.destination.clone();
+    match spool.push(entry).await {
+        Ok(()) => {
+            warn!(
+                "Spooled delivery {} for {} until it recovers",
+                tag, destination
+            );
+            true
+        }
+        Err(e) => {
+            warn!("Unable to spool delivery {}: {}", tag, e);
+            false
+        }
+    }
+}
+
+/// `rendered` with `note` heading its Slack message and ahead of the
+/// reason of each of its alerts, for other destinations.
+fn with_note(mut rendered: Notification, note: &str) -> Notification {
+    if let Err(e) = render::add_note(&mut rendered.body, note) {
+        warn!("{}", e);
+    }
+    for alert in &mut rendered.alerts {
+        alert.reason = Some(match alert.reason.take() {
+            Some(reason) => format!("{}\n\n{}", note, reason),
+            None => note.to_string(),
+        });
+    }
+    rendered
+}
+
+/// One of the destinations a notification fans out to: the one its route
+/// picks, or a further receiver sent a copy.
+struct Leg<'a> {
+    channel: Option<&'a str>,
+    receiver: Option<&'a str>,
+    /// See `QueueConfig::destination`.
+    name: String,
+    /// Names the leg in `source::DELIVERED_HEADER`.
+    key: String,
+    /// Whether this is the destination threads and digests belong to.
+    main: bool,
+    /// Receivers to try while the circuit for this one is open.
+    fallback: &'a [String],
+    rendered: Notification,
+}
+
+impl Leg<'_> {
+    /// The leg as a spool entry for the queue named `queue`.
+    fn entry(&self, queue: &str) -> Entry {
+        Entry {
+            destination: self.name.clone(),
+            queue: queue.to_string(),
+            severity: self.rendered.severity,
+            channel: self.channel.map(String::from),
+            receiver: self.receiver.map(String::from),
+            body: self.rendered.body.clone(),
+            alerts: self.rendered.alerts.clone(),
+            summary: self.rendered.summary.clone(),
+        }
+    }
+}
+
+/// What came of fanning a notification out.
+#[derive(Default)]
+struct Outcome {
+    /// The main leg's receipt, if it took the notification just now.
+    receipt: Option<DeliveryReceipt>,
+    /// Keys of the legs that have taken it, now or on an earlier attempt,
+    /// or that have it spooled.
+    delivered: Vec<String>,
+    /// Keys of the legs that have not, as spool entries, and why.
+    failed: Vec<(String, Entry, SinkError)>,
+}
+
+/// Everything needed to deliver an alert and settle its message, shared by
+/// the workers of every queue.
+pub struct Dispatcher {
+    /// Replaced as a whole when the configuration is reloaded.
+    cfg: RwLock<Arc<Config>>,
+    pub sinks: Sinks,
+    pub spool: Option<Arc<Spool>>,
+    pub silences: Silences,
+    pub store: MessageStore,
+    pub breaker: CircuitBreaker,
+    templates: RwLock<Arc<Templates>>,
+    pagerduty: Option<PagerDuty>,
+    dedup: Option<Dedup>,
+    grouper: Option<Grouper>,
+    flaps: Option<Flaps>,
+    inhibitor: Option<Inhibitor>,
+    /// Alerts discarded by each drop rule, or by a route that drops them.
+    pub dropped: Mutex<BTreeMap<String, u64>>,
+    limiter: RateLimiter,
+    quiet: Option<QuietHours>,
+    pub metrics: Metrics,
+    pub audit: Option<Audit>,
+    pub self_alerts: SelfAlerts,
+    /// Where failed deliveries are reported, if anywhere.
+    pub sentry: Option<Arc<Sentry>>,
+    /// Whether notifications are logged rather than delivered.
+    pub dry_run: bool,
+    /// Whether the queues are being consumed from.
+    pub pause: Pause,
+}
+
+/// The settings that differ between `old` and `new` but are acted on only
+/// at startup, whether by building the dispatcher's state for them or by
+/// spawning the task that serves them. The queues must stay the same, as
+/// alerts refer to them by position.
+fn restart_needed(old: &Config, new: &Config) -> Vec<&'static str> {
+    let queues = |cfg: &Config| {
+        cfg.queues
+            .iter()
+            .map(|q| q.name.clone())
+            .collect::<Vec<_>>()
+    };
+    let dedup = |cfg: &Config| {
+        let settings = cfg
+            .dedup
+            .as_ref()
+            .map(|d| (d.fields.clone(), d.window, d.show_count));
+        (cfg.dedup.is_some() || cfg.routing.repeats(), settings)
+    };
+    let grouping = |cfg: &Config| cfg.grouping.is_some() || cfg.routing.groups();
+    let flapping = |cfg: &Config| cfg.flapping.as_ref().map(|f| (f.transitions, f.window));
+    let quiet_hours = |cfg: &Config| {
+        cfg.quiet_hours
+            .as_ref()
+            .map(|q| (q.schedule.clone(), q.path.clone()))
+    };
+    let rate_limits = |cfg: &Config| {
+        let limits = &cfg.rate_limits;
+        limits.per_alert.is_some() || limits.per_channel.is_some() || cfg.routing.limits()
+    };
+    let breaker = |cfg: &Config| {
+        let breaker = &cfg.circuit_breaker;
+        (breaker.failure_threshold, breaker.cool_down)
+    };
+    let checks = [
+        ("queues", queues(old) != queues(new)),
+        ("dedup and repeat_interval", dedup(old) != dedup(new)),
+        ("grouping and group_by", grouping(old) != grouping(new)),
+        ("flapping", flapping(old) != flapping(new)),
+        ("quiet_hours", quiet_hours(old) != quiet_hours(new)),
+        (
+            "inhibit_rules",
+            old.inhibit_rules.is_empty() != new.inhibit_rules.is_empty(),
+        ),
+        (
+            "messages",
+            (&old.messages.path, old.messages.ttl) != (&new.messages.path, new.messages.ttl),
+        ),
+        ("rate_limits", rate_limits(old) != rate_limits(new)),
+        ("circuit_breaker", breaker(old) != breaker(new)),
+        ("renotify", old.renotify.is_some() != new.renotify.is_some()),
+        (
+            "self_alerts",
+            old.self_alerts.is_some() != new.self_alerts.is_some(),
+        ),
+        (
+            "heartbeat",
+            old.heartbeat.is_some() != new.heartbeat.is_some(),
+        ),
+        ("statsd", old.statsd.is_some() != new.statsd.is_some()),
+    ];
+    checks
+        .iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+impl Dispatcher {
+    pub fn new(
+        cfg: Arc<Config>,
+        sinks: Sinks,
+        spool: Option<Arc<Spool>>,
+        templates: Templates,
+        store: MessageStore,
+        silences: Silences,
+        pagerduty: Option<PagerDuty>,
+    ) -> Result<Dispatcher, String> {
+        let breaker = CircuitBreaker::new(&cfg.circuit_breaker);
+        let dedup =
+            (cfg.dedup.is_some() || cfg.routing.repeats()).then(|| Dedup::new(cfg.dedup.as_ref()));
+        let grouper = (cfg.grouping.is_some() || cfg.routing.groups()).then(Grouper::default);
+        let flaps = cfg.flapping.as_ref().map(Flaps::new);
+        let quiet = match &cfg.quiet_hours {
+            Some(quiet_hours) => Some(QuietHours::open(quiet_hours)?),
+            None => None,
+        };
+        let inhibitor = (!cfg.inhibit_rules.is_empty()).then(|| Inhibitor::new(cfg.messages.ttl));
+        let audit = match &cfg.audit {
+            Some(audit) => Some(Audit::open(audit)?),
+            None => None,
+        };
+        Ok(Dispatcher {
+            cfg: RwLock::new(cfg),
+            sinks,
+            spool,
+            silences,
+            store,
+            breaker,
+            templates: RwLock::new(Arc::new(templates)),
+            pagerduty,
+            dedup,
+            grouper,
+            flaps,
+            inhibitor,
+            dropped: Mutex::new(BTreeMap::new()),
+            limiter: RateLimiter::default(),
+            quiet,
+            metrics: Metrics::default(),
+            audit,
+            self_alerts: SelfAlerts::default(),
+            sentry: None,
+            dry_run: false,
+            pause: Pause::default(),
+        })
+    }
+
+    /// The configuration in force.
+    pub fn cfg(&self) -> Arc<Config> {
+        self.cfg.read().unwrap().clone()
+    }
+
+    fn templates(&self) -> Arc<Templates> {
+        self.templates.read().unwrap().clone()
+    }
+
+    /// Switch to `cfg` for whatever is processed from now on. Routes,
+    /// receivers, rules, templates, styles, mentions, planned silences and
+    /// Slack destinations change straight away, as do the settings of the
+    /// background tasks already running. Changes to what `restart_needed`
+    /// lists are refused: the state and tasks behind them are set up once,
+    /// at startup. The broker, consumer, HTTP client, listeners, spool, audit
+    /// and the other connections are likewise kept until a restart.
+    pub fn reload(&self, cfg: Config) -> Result<(), String> {
+        let changed = restart_needed(&self.cfg(), &cfg);
+        if !changed.is_empty() {
+            return Err(format!(
+                "{} cannot be changed without a restart",
+                changed.join(", ")
+            ));
+        }
+        let templates = Templates::load(
+            &cfg.templates,
+            &cfg.routing,
+            &cfg.styles,
+            self.pagerduty.is_some(),
+            cfg.email.as_ref(),
+        )?;
+        self.silences.plan(&cfg.silences);
+        *self.templates.write().unwrap() = Arc::new(templates);
+        *self.cfg.write().unwrap() = Arc::new(cfg);
+        Ok(())
+    }
+
+    /// What the routing tree in `cfg` decides for `alert`.
+    fn routing<'a>(cfg: &'a Config, alert: &Alert) -> Routing<'a> {
+        match serde_json::to_value(alert) {
+            Ok(value) => cfg.routing.resolve(&value),
+            Err(_) => Routing::default(),
+        }
+    }
+
+    /// The sink for the `queue`th queue's route for `severity`, or for
+    /// `channel` or the named `receiver`; see `Target::resolve`. An alert
+    /// naming a channel of its own stays on Slack whatever the receiver.
+    pub fn sink<'a>(
+        &'a self,
+        cfg: &'a Config,
+        queue: usize,
+        severity: Severity,
+        channel: Option<&'a str>,
+        receiver: Option<&str>,
+        name: String,
+    ) -> Box<dyn Sink + 'a> {
+        if self.dry_run {
+            return Box::new(DryRunSink::new(name));
+        }
+        let route = match receiver.and_then(|name| cfg.routing.receivers.get(name)) {
+            Some(Receiver::Teams(url)) if channel.is_none() => {
+                return Box::new(TeamsSink::new(&self.sinks.teams, url, name))
+            }
+            Some(Receiver::Discord(url)) if channel.is_none() => {
+                return Box::new(DiscordSink::new(&self.sinks.discord, url, name))
+            }
+            Some(Receiver::GoogleChat(url)) if channel.is_none() => {
+                return Box::new(GoogleChatSink::new(&self.sinks.google_chat, url, name))
+            }
+            Some(Receiver::Mattermost(receiver)) if channel.is_none() => {
+                return Box::new(MattermostSink::new(&self.sinks.mattermost, receiver, name))
+            }
+            Some(Receiver::Telegram(chat)) if channel.is_none() => {
+                return Box::new(TelegramSink::new(&self.sinks.telegram, chat, name))
+            }
+            Some(Receiver::Matrix(room)) if channel.is_none() => {
+                let server = cfg
+                    .matrix
+                    .as_ref()
+                    .expect("config requires a matrix section for Matrix receivers");
+                return Box::new(MatrixSink::new(&self.sinks.matrix, server, room, name));
+            }
+            Some(Receiver::Webex(room)) if channel.is_none() => {
+                let bot = cfg
+                    .webex
+                    .as_ref()
+                    .expect("config requires a webex section for Webex receivers");
+                return Box::new(WebexSink::new(&self.sinks.webex, bot, room, name));
+            }
+            Some(Receiver::Pushover(user)) if channel.is_none() => {
+                return Box::new(PushoverSink::new(&self.sinks.pushover, user, name))
+            }
+            Some(Receiver::Sms(numbers)) if channel.is_none() => {
+                let account = cfg
+                    .twilio
+                    .as_ref()
+                    .expect("config requires a twilio section for SMS receivers");
+                return Box::new(SmsSink::new(&self.sinks.twilio, account, numbers, name));
+            }
+            Some(Receiver::Sns(topic)) if channel.is_none() => {
+                return Box::new(SnsSink::new(&self.sinks.sns, topic, name))
+            }
+            Some(Receiver::PagerDuty(service)) if channel.is_none() => {
+                return Box::new(PagerDutySink::new(&self.sinks.pagerduty, service, name))
+            }
+            Some(Receiver::Opsgenie(service)) if channel.is_none() => {
+                return Box::new(OpsgenieSink::new(&self.sinks.opsgenie, service, name))
+            }
+            Some(Receiver::Jira(project)) if channel.is_none() => {
+                return Box::new(JiraSink::new(
+                    &self.sinks.jira,
+                    project,
+                    self.templates(),
+                    name,
+                ))
+            }
+            Some(Receiver::ServiceNow(instance)) if channel.is_none() => {
+                return Box::new(ServiceNowSink::new(&self.sinks.servicenow, instance, name))
+            }
+            Some(Receiver::Syslog(server)) if channel.is_none() => {
+                return Box::new(SyslogSink::new(&self.sinks.syslog, server, name))
+            }
+            Some(Receiver::Email(recipients)) if channel.is_none() => {
+                let server = cfg
+                    .email
+                    .as_ref()
+                    .expect("config requires an email section for email receivers");
+                let templates = self.templates();
+                return Box::new(EmailSink::new(
+                    &self.sinks.email,
+                    server,
+                    recipients,
+                    templates,
+                    name,
+                ));
+            }
+            Some(Receiver::Webhook(receiver)) if channel.is_none() => {
+                return Box::new(WebhookSink::new(
+                    &self.sinks.webhook,
+                    receiver,
+                    self.templates(),
+                    name,
+                ))
+            }
+            Some(Receiver::Slack(route)) => Some(route),
+            _ => None,
+        };
+        let target = Target::resolve(&cfg.queues[queue].slack, severity, channel, route);
+        Box::new(SlackSink::new(&self.sinks.slack, target, name))
+    }
+
+    /// Deliver to the queue's route for the notification's severity, or to
+    /// `channel` or the named `receiver`, unless the circuit for `name` is
+    /// open. Then the `fallback` receivers whose circuits are closed are
+    /// tried in turn, then the queue's fallback webhook if there is one, with
+    /// a note that the notification was meant for `name`.
+    async fn post(
+        &self,
+        queue: usize,
+        channel: Option<&str>,
+        receiver: Option<&str>,
+        name: &str,
+        fallback: &[String],
+        rendered: Notification,
+    ) -> Result<DeliveryReceipt, SinkError> {
+        let cfg = self.cfg();
+        if self.breaker.allow(name) {
+            return self
+                .post_to(&cfg, queue, channel, receiver, name, rendered)
+                .await;
+        }
+
+        let rendered = with_note(
+            rendered,
+            &format!("Delivered via fallback: {} is unavailable.", name),
+        );
+        for fallback in fallback {
+            let fallback_name =
+                cfg.queues[queue].destination(rendered.severity, None, Some(fallback));
+            if !self.breaker.allow(&fallback_name) {
+                continue;
+            }
+            let result = self
+                .post_to(
+                    &cfg,
+                    queue,
+                    None,
+                    Some(fallback),
+                    &fallback_name,
+                    rendered.clone(),
+                )
+                .await;
+            match result {
+                Ok(receipt) => {
+                    info!("Delivered to {} in place of {}", fallback_name, name);
+                    return Ok(receipt);
+                }
+                Err(e) => warn!("Unable to deliver to fallback {}: {}", fallback_name, e),
+            }
+        }
+        match &cfg.queues[queue].slack.fallback_url {
+            Some(url) => {
+                let fallback = format!("{} fallback", name);
+                SlackSink::new(&self.sinks.slack, Target::Webhook(url), fallback)
+                    .deliver(rendered)
+                    .await
+            }
+            None => Err(SinkError::Transient(format!(
+                "circuit for {} is open",
+                name
+            ))),
+        }
+    }
+
+    /// Deliver to the destination `name`, recording how that went against
+    /// its circuit.
+    async fn post_to(
+        &self,
+        cfg: &Config,
+        queue: usize,
+        channel: Option<&str>,
+        receiver: Option<&str>,
+        name: &str,
+        rendered: Notification,
+    ) -> Result<DeliveryReceipt, SinkError> {
+        let sink = self.sink(
+            cfg,
+            queue,
+            rendered.severity,
+            channel,
+            receiver,
+            name.to_string(),
+        );
+        let span = info_span!("deliver", destination = name, error = field::Empty);
+        let audited = self.audit.as_ref().map(|audit| (audit, rendered.clone()));
+        let reported = self.sentry.as_ref().map(|sentry| {
+            let alerts: Vec<Value> = rendered
+                .alerts
+                .iter()
+                .map(|alert| {
+                    json!({
+                        "fingerprint": alert.key(),
+                        "title": alert.title,
+                        "trace_id": alert.trace_id,
+                    })
+                })
+                .collect();
+            let context = json!({
+                "severity": rendered.severity.to_string(),
+                "summary": rendered.summary,
+                "alerts": alerts,
+            });
+            (sentry, context)
+        });
+        let alerts = rendered.alerts.iter().map(Alert::key).collect();
+        let started = Instant::now();
+        let started_at = Utc::now();
+        let result = sink.deliver(rendered).instrument(span.clone()).await;
+        self.metrics
+            .delivered(name, alerts, &result, started_at, started.elapsed());
+        if let Some((audit, rendered)) = audited {
+            audit.attempt(name, &rendered, &result, started_at, Utc::now());
+        }
+        if let (Some((sentry, context)), Err(e)) = (reported, &result) {
+            sentry.delivery_failed(name, sink::outcome(&result), &e.to_string(), context);
+        }
+        if let Err(e) = &result {
+            span.record("error", field::display(e));
+        }
+        match &result {
+            Err(SinkError::Transient(e)) => {
+                if self.breaker.record_failure(name) {
+                    self.self_alerts.report(
+                        selfalert::CIRCUIT,
+                        format!(
+                            "Stopped delivering to {} after repeated failures: {}",
+                            name, e
+                        ),
+                    );
+                }
+            }
+            // A rejection says nothing about the health of the destination.
+            _ => self.breaker.record_success(name),
+        }
+        result
+    }
+
+    /// `main`, followed by a leg for each of the further `receivers` its
+    /// route names, getting `copy`.
+    fn legs<'a>(
+        cfg: &Config,
+        queue: usize,
+        main: Leg<'a>,
+        receivers: &'a [String],
+        copy: &Notification,
+    ) -> Vec<Leg<'a>> {
+        let mut legs = vec![main];
+        for receiver in receivers {
+            let name = cfg.queues[queue].destination(copy.severity, None, Some(receiver));
+            legs.push(Leg {
+                channel: None,
+                receiver: Some(receiver),
+                key: name.clone(),
+                name,
+                main: false,
+                fallback: &[],
+                rendered: copy.clone(),
+            });
+        }
+        legs
+    }
+
+    /// Deliver to each of `legs` at once, skipping those `delivered` names
+    /// as having taken the notification on an earlier attempt, and spooling
+    /// it for those whose destination has a backlog in the spool.
+    async fn fan_out(
+        &self,
+        queue: usize,
+        legs: Vec<Leg<'_>>,
+        delivered: &[String],
+        tag: u64,
+    ) -> Outcome {
+        let queue_name = self.cfg().queues[queue].name.clone();
+        let spool = self.spool.as_deref();
+        let mut outcome = Outcome::default();
+        let mut pending = Vec::new();
+        for leg in legs {
+            if delivered.contains(&leg.key) {
+                debug!(
+                    "Skipping {} for delivery {}: delivered before",
+                    leg.name, tag
+                );
+                outcome.delivered.push(leg.key);
+                continue;
+            }
+            // While earlier notifications for a destination sit in the spool,
+            // new ones queue up behind them.
+            if let Some(spool) = spool {
+                if spool.is_backlogged(&leg.name).await
+                    && spool_alert(spool, tag, leg.entry(&queue_name)).await
+                {
+                    outcome.delivered.push(leg.key);
+                    continue;
+                }
+            }
+            pending.push(leg);
+        }
+
+        let results = join_all(pending.iter().map(|leg| {
+            self.post(
+                queue,
+                leg.channel,
+                leg.receiver,
+                &leg.name,
+                leg.fallback,
+                leg.rendered.clone(),
+            )
+        }))
+        .await;
+        for (leg, result) in pending.into_iter().zip(results) {
+            match result {
+                Ok(receipt) => {
+                    debug!("Delivery {} went to {}", tag, leg.name);
+                    if let Some(spool) = spool {
+                        spool.record_success(&leg.name).await;
+                    }
+                    if leg.main {
+                        outcome.receipt = Some(receipt);
+                    }
+                    outcome.delivered.push(leg.key);
+                }
+                Err(e) => {
+                    let entry = leg.entry(&queue_name);
+                    outcome.failed.push((leg.key, entry, e));
+                }
+            }
+        }
+        outcome
+    }
+
+    /// The alert in serialized form, with its key as `key`, for matching
+    /// silences and inhibition rules against.
+    fn attributes(alert: &Alert) -> Option<Value> {
+        let mut value = serde_json::to_value(alert).ok()?;
+        value[KEY_ATTRIBUTE] = Value::String(alert.key());
+        Some(value)
+    }
+
+    /// The silence suppressing `alert`, if any. Silences can match the
+    /// alert's key as `key` as well as its attributes. A recovery still goes
+    /// through when there is a message to update with it.
+    fn silencing(&self, alert: &Alert, posted: bool) -> Option<Silence> {
+        if alert.status == Status::Resolved && posted {
+            return None;
+        }
+        self.silences.silencing(&Dispatcher::attributes(alert)?)
+    }
+
+    /// Take a token for a notification to the destination `name`, and about
+    /// the check with `fingerprint` if given, under the rate limits for the
+    /// route it takes.
+    fn admit(&self, fingerprint: Option<&str>, routing: &Routing<'_>, name: &str) -> bool {
+        let cfg = self.cfg();
+        let limits = &cfg.rate_limits;
+        let mut buckets: Vec<(String, RateLimit)> = Vec::new();
+        if let (Some(fingerprint), Some(limit)) =
+            (fingerprint, routing.per_alert.or(limits.per_alert))
+        {
+            buckets.push((format!("alert:{}", fingerprint), limit));
+        }
+        if let Some(limit) = routing.per_channel.or(limits.per_channel) {
+            buckets.push((format!("destination:{}", name), limit));
+        }
+        buckets.is_empty() || self.limiter.admit(&buckets)
+    }
+
+    /// The name of the drop rule or route discarding `alert`, if any.
+    fn dropping(&self, alert: &Alert) -> Option<String> {
+        let cfg = self.cfg();
+        let value = serde_json::to_value(alert).ok()?;
+        let rule = cfg.drop_rules.iter().find(|rule| {
+            rule.matchers.matches(&value) && rule.filter.iter().all(|f| f.matches(&value))
+        });
+        match rule {
+            Some(rule) => Some(rule.name.clone()),
+            None if cfg.routing.resolve(&value).drop => Some("routes".to_string()),
+            None => None,
+        }
+    }
+
+    /// Note whether `alert` is firing, and return the key of another alert
+    /// inhibiting it, if any. As with silences, a recovery still goes
+    /// through when there is a message to update with it.
+    fn inhibiting(&self, alert: &Alert, posted: bool) -> Option<String> {
+        let cfg = self.cfg();
+        let inhibitor = self.inhibitor.as_ref()?;
+        let rules = &cfg.inhibit_rules;
+        let value = Dispatcher::attributes(alert)?;
+        let key = alert.key();
+        inhibitor.observe(rules, &key, alert.status, &value);
+        if alert.status == Status::Resolved && posted {
+            return None;
+        }
+        inhibitor.inhibiting(rules, &key, &value)
+    }
+
+    /// Re-render the message first posted about a check to show that it has
+    /// recovered. Best effort, as the recovery is also posted in its thread.
+    async fn show_resolved(&self, queue: usize, message: &StoredMessage, resolved: &Alert) {
+        let cfg = self.cfg();
+        let token = match &cfg.queues[queue].slack.token {
+            Some(token) => token,
+            None => return,
+        };
+        let mut original = message.alert.clone();
+        original.status = Status::Resolved;
+        original.resolved_at = Some(resolved.timestamp);
+
+        let body = match self.templates().render(&original, "") {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Unable to render resolved message: {}", e);
+                return;
+            }
+        };
+        let target = Target::Update {
+            token,
+            channel: &message.channel,
+            ts: &message.ts,
+        };
+        if let Err(e) = self.sinks.slack.post(target, &body).await {
+            warn!(
+                "Unable to update message {} in {}: {}",
+                message.ts, message.channel, e
+            );
+        }
+    }
+
+    /// Apply `change` to the alert behind the message posted about
+    /// `fingerprint`, then re-render the message to match. `change` returns
+    /// false to leave the message as it is.
+    async fn update_message(&self, fingerprint: &str, change: impl FnOnce(&mut Alert) -> bool) {
+        let mut message = match self.store.get(fingerprint) {
+            Some(message) => message,
+            None => {
+                debug!("No message to update for {}", fingerprint);
+                return;
+            }
+        };
+        let cfg = self.cfg();
+        let token = cfg
+            .queues
+            .iter()
+            .find(|q| q.name == message.queue)
+            .and_then(|q| q.slack.token.as_deref());
+        let token = match token {
+            Some(token) => token,
+            None => return,
+        };
+        if !change(&mut message.alert) {
+            return;
+        }
+        self.store.insert(fingerprint, message.clone());
+
+        let body = match self.templates().render(&message.alert, "") {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Unable to render updated message: {}", e);
+                return;
+            }
+        };
+        let target = Target::Update {
+            token,
+            channel: &message.channel,
+            ts: &message.ts,
+        };
+        if let Err(e) = self.sinks.slack.post(target, &body).await {
+            warn!(
+                "Unable to update message {} in {}: {}",
+                message.ts, message.channel, e
+            );
+        }
+    }
+
+    /// Record that the Slack user `user` has taken responsibility for the
+    /// check with `fingerprint`, and show it on the message posted about it.
+    pub async fn acknowledge(&self, fingerprint: &str, user: &str, at: DateTime<Utc>) {
+        info!(target: "audit", "{} acknowledged {} at {}", user, fingerprint, at.to_rfc3339());
+
+        self.update_message(fingerprint, |alert| {
+            if alert.acknowledged_by.is_some() {
+                debug!("{} is already acknowledged", fingerprint);
+                return false;
+            }
+            alert.acknowledged_by = Some(user.to_string());
+            alert.acknowledged_at = Some(at);
+            true
+        })
+        .await;
+        self.acknowledge_incidents(fingerprint).await
+    }
+
+    /// Acknowledge the incidents raised for the check with `fingerprint` on
+    /// the PagerDuty and Opsgenie receivers its route names, once someone has
+    /// taken responsibility for it in Slack.
+    async fn acknowledge_incidents(&self, fingerprint: &str) {
+        let message = match self.store.get(fingerprint) {
+            Some(message) => message,
+            None => return,
+        };
+        let cfg = self.cfg();
+        let queue = match cfg.queues.iter().position(|q| q.name == message.queue) {
+            Some(queue) => queue,
+            None => return,
+        };
+        let alert = message.alert;
+        let routing = Dispatcher::routing(&cfg, &alert);
+        let receivers = routing
+            .receiver
+            .into_iter()
+            .chain(routing.copies.iter().map(String::as_str))
+            .filter(|name| {
+                matches!(
+                    cfg.routing.receivers.get(*name),
+                    Some(Receiver::PagerDuty(_)) | Some(Receiver::Opsgenie(_))
+                )
+            });
+        let rendered = Notification {
+            severity: alert.severity,
+            summary: alert.title.clone(),
+            body: Value::Null,
+            alerts: vec![alert],
+        };
+        for receiver in receivers {
+            let name = cfg.queues[queue].destination(rendered.severity, None, Some(receiver));
+            let sink = self.sink(&cfg, queue, rendered.severity, None, Some(receiver), name);
+            if let Err(e) = sink.deliver(rendered.clone()).await {
+                warn!(
+                    "Unable to acknowledge {} on {}: {}",
+                    fingerprint,
+                    sink.name(),
+                    e
+                );
+            }
+        }
+    }
+
+    /// Silence the check with `fingerprint` for `duration` on behalf of the
+    /// Slack user `user`, and show it on the message posted about it.
+    pub async fn snooze(
+        &self,
+        fingerprint: &str,
+        user: &str,
+        duration: Duration,
+    ) -> Result<(), String> {
+        let mut matchers = BTreeMap::new();
+        matchers.insert(KEY_ATTRIBUTE.to_string(), fingerprint.to_string());
+        let silence = self.silences.add(matchers, duration, user)?;
+
+        self.update_message(fingerprint, |alert| {
+            alert.snoozed_by = Some(user.to_string());
+            alert.snoozed_until = Some(silence.ends_at);
+            true
+        })
+        .await;
+        Ok(())
+    }
+
+    /// Raise a PagerDuty incident for the check with `fingerprint` on behalf
+    /// of the Slack user `user`, and link to it from the message posted
+    /// about the check.
+    pub async fn escalate(&self, fingerprint: &str, user: &str) -> Result<(), String> {
+        let pagerduty = self
+            .pagerduty
+            .as_ref()
+            .ok_or("PagerDuty is not configured")?;
+        let alert = self.store.get(fingerprint).map(|message| message.alert);
+        if alert
+            .as_ref()
+            .is_some_and(|alert| alert.escalated_by.is_some())
+        {
+            debug!("{} is already escalated", fingerprint);
+            return Ok(());
+        }
+
+        let incident_url = pagerduty.trigger(fingerprint, alert.as_ref(), user).await?;
+        let now = Utc::now();
+        info!(target: "audit", "{} escalated {} to PagerDuty at {}", user, fingerprint, now.to_rfc3339());
+
+        self.update_message(fingerprint, |alert| {
+            alert.escalated_by = Some(user.to_string());
+            alert.escalated_at = Some(now);
+            alert.incident_url = incident_url;
+            true
+        })
+        .await;
+        Ok(())
+    }
+
+    /// Resolve the PagerDuty incident raised for a check that has recovered.
+    /// Best effort, as the incident can still be resolved by hand.
+    async fn resolve_incident(&self, fingerprint: &str) {
+        let pagerduty = match &self.pagerduty {
+            Some(pagerduty) => pagerduty,
+            None => return,
+        };
+        match pagerduty.resolve(fingerprint).await {
+            Ok(()) => info!("Resolved PagerDuty incident for {}", fingerprint),
+            Err(e) => warn!(
+                "Unable to resolve PagerDuty incident for {}: {}",
+                fingerprint, e
+            ),
+        }
+    }
+
+    /// Settle deliveries once their notification has fanned out. Legs that
+    /// failed are spooled if their destination has been failing for long
+    /// enough. The deliveries are acked once the legs `policy` asks for have
+    /// taken the notification; otherwise each is retried for the legs still
+    /// to take it, or dead-lettered once out of attempts. Returns whether
+    /// the notification counts as delivered.
+    async fn settle(
+        &self,
+        deliveries: &[(&Settler, &Delivery)],
+        policy: AckPolicy,
+        outcome: Outcome,
+    ) -> bool {
+        let cfg = self.cfg();
+        let tag = deliveries.first().map_or(0, |(_, d)| d.delivery_tag);
+        let mut delivered = outcome.delivered;
+        let mut failed = Vec::new();
+        for (key, entry, e) in outcome.failed {
+            let spooled = match (self.spool.as_deref(), &e) {
+                (Some(spool), SinkError::Transient(_)) => {
+                    spool.record_failure(&entry.destination).await
+                        && spool_alert(spool, tag, entry.clone()).await
+                }
+                _ => false,
+            };
+            if spooled {
+                delivered.push(key);
+            } else {
+                failed.push(format!("{}: {}", entry.destination, e));
+            }
+        }
+        let reason = failed.join("; ");
+        let done = failed.is_empty() || (policy == AckPolicy::Any && !delivered.is_empty());
+        if done && !failed.is_empty() {
+            warn!("Giving up on part of delivery {}: {}", tag, reason);
+        }
+
+        let max_attempts = cfg.consumer.max_attempts;
+        for (settler, delivery) in deliveries {
+            let tag = delivery.delivery_tag;
+            let attempts = source::attempts(delivery) + 1;
+            let outcome = if done {
+                settler.ack(delivery).await
+            } else if attempts >= max_attempts {
+                self.metrics.dead_lettered();
+                settler.dead_letter(delivery, attempts, &reason).await
+            } else {
+                self.metrics.retried();
+                error!(
+                    "Delivery {} failed (attempt {} of {}): {}",
+                    tag, attempts, max_attempts, reason
+                );
+                settler.retry(delivery, attempts, &delivered).await
+            };
+            if let Err(e) = outcome {
+                error!("Failed to settle delivery {}: {:?}", tag, e);
+            }
+        }
+        done
+    }
+
+    /// Check a single parsed alert received on the `queue`th configured
+    /// queue against drop rules, inhibition rules, silences, flapping, dedup
+    /// and grouping, then deliver it unless one of them holds it back.
+    pub async fn process(&self, settler: &Settler, queue: usize, delivery: Delivery, alert: Alert) {
+        let cfg = self.cfg();
+        let tag = delivery.delivery_tag;
+        if let Some(rule) = self.dropping(&alert) {
+            debug!("Dropping delivery {} for {}: {}", tag, alert.key(), rule);
+            *self.dropped.lock().unwrap().entry(rule).or_default() += 1;
+            if let Err(e) = settler.ack(&delivery).await {
+                error!("Failed to settle delivery {}: {:?}", tag, e);
+            }
+            return;
+        }
+        let token = cfg.queues[queue].slack.token.as_deref();
+        let fingerprint = alert.key();
+        // Follow-ups about a check we have already posted about go in that
+        // message's thread.
+        let thread = match token {
+            Some(_) => self.store.get(&fingerprint),
+            None => None,
+        };
+        // A check that recovers while its failure waits to be grouped is
+        // not worth mentioning at all.
+        if let (Some(grouper), Status::Resolved, None) = (&self.grouper, alert.status, &thread) {
+            let cancelled = grouper.cancel(queue, &fingerprint);
+            if !cancelled.is_empty() {
+                info!(
+                    "Dropping delivery {} for {}: recovered before it was posted",
+                    tag, fingerprint
+                );
+                let held = cancelled.iter().map(|p| (&p.settler, &p.delivery));
+                for (settler, delivery) in held.chain(std::iter::once((settler, &delivery))) {
+                    if let Err(e) = settler.ack(delivery).await {
+                        error!(
+                            "Failed to settle delivery {}: {:?}",
+                            delivery.delivery_tag, e
+                        );
+                    }
+                }
+                return;
+            }
+        }
+        // Checked before silences, so that silenced alerts still inhibit
+        // others.
+        if let Some(source) = self.inhibiting(&alert, thread.is_some()) {
+            info!(
+                "Dropping delivery {} for {}: inhibited by {}",
+                tag, fingerprint, source
+            );
+            if let Err(e) = settler.ack(&delivery).await {
+                error!("Failed to settle delivery {}: {:?}", tag, e);
+            }
+            return;
+        }
+        if let Some(silence) = self.silencing(&alert, thread.is_some()) {
+            // Silenced alerts skip dedup and grouping on the way to the
+            // low-noise channel, and are not threaded under earlier alerts.
+            if let Some(channel) = &cfg.silences.channel {
+                info!(
+                    "Routing delivery {} for {} to {}: silenced by silence {}",
+                    tag, fingerprint, channel, silence.id
+                );
+                let mut alert = alert;
+                alert.channel = Some(channel.clone());
+                return self
+                    .deliver(settler, queue, delivery, alert, None, None)
+                    .await;
+            }
+            info!(
+                "Dropping delivery {} for {}: silenced by silence {}",
+                tag, fingerprint, silence.id
+            );
+            if let Err(e) = settler.ack(&delivery).await {
+                error!("Failed to settle delivery {}: {:?}", tag, e);
+            }
+            return;
+        }
+        match self.flaps.as_ref().map(|flaps| flaps.observe(&alert)) {
+            Some(Flapping::Started { transitions }) => {
+                info!("{} is flapping", fingerprint);
+                let minutes = cfg.flapping.as_ref().map_or(0, |f| f.window.as_secs() / 60);
+                let note = format!(
+                    ":repeat: *{} is flapping*: it changed state {} times in {} minutes. \
+                     Further changes are not posted until it settles.",
+                    alert.title, transitions, minutes
+                );
+                return self
+                    .deliver(settler, queue, delivery, alert, thread, Some(&note))
+                    .await;
+            }
+            Some(Flapping::Ongoing) => {
+                debug!("Suppressing delivery {} for {}: flapping", tag, fingerprint);
+                if let Err(e) = settler.ack(&delivery).await {
+                    error!("Failed to settle delivery {}: {:?}", tag, e);
+                }
+                return;
+            }
+            Some(Flapping::No) | None => {}
+        }
+        if let Some(dedup) = self
+            .dedup
+            .as_ref()
+            .filter(|_| alert.status == Status::Firing)
+        {
+            if let Some(count) = dedup.repeat(&alert) {
+                debug!(
+                    "Suppressing delivery {} for {}: seen {} times",
+                    tag, fingerprint, count
+                );
+                if dedup.show_count {
+                    self.update_message(&fingerprint, |alert| {
+                        alert.occurrences = Some(count);
+                        true
+                    })
+                    .await;
+                }
+                if let Err(e) = settler.ack(&delivery).await {
+                    error!("Failed to settle delivery {}: {:?}", tag, e);
+                }
+                return;
+            }
+        }
+
+        // Non-critical alerts for the configured route wait out quiet hours,
+        // as do recoveries of checks whose failure is still waiting.
+        if let Some(quiet) = &self.quiet {
+            if alert.severity != Severity::Critical
+                && thread.is_none()
+                && alert.channel.is_none()
+                && quiet.is_quiet(Utc::now())
+            {
+                let name = &cfg.queues[queue].name;
+                let held = match alert.status {
+                    Status::Firing => {
+                        quiet.hold(name, alert.clone());
+                        true
+                    }
+                    Status::Resolved => quiet.release(name, &fingerprint),
+                };
+                if held {
+                    debug!(
+                        "Holding delivery {} for {} until quiet hours end",
+                        tag, fingerprint
+                    );
+                    if let Err(e) = settler.ack(&delivery).await {
+                        error!("Failed to settle delivery {}: {:?}", tag, e);
+                    }
+                    return;
+                }
+            }
+        }
+
+        // New firing alerts for the configured route wait to be posted
+        // together; their deliveries are settled when the digest is.
+        let routing = Dispatcher::routing(&cfg, &alert);
+        self.metrics.routed(routing.receiver);
+        let grouping = match (routing.group_by, &cfg.grouping) {
+            (Some(by), global) => Some(Grouping {
+                by,
+                interval: routing
+                    .group_interval
+                    .or_else(|| global.as_ref().map(|g| g.interval))
+                    .unwrap_or(DEFAULT_GROUP_INTERVAL),
+            }),
+            (None, Some(global)) => Some(Grouping {
+                by: &global.by,
+                interval: routing.group_interval.unwrap_or(global.interval),
+            }),
+            (None, None) => None,
+        };
+        if let (Some(grouper), Some(grouping)) = (&self.grouper, grouping) {
+            if alert.status == Status::Firing && thread.is_none() && alert.channel.is_none() {
+                debug!("Holding delivery {} for grouping", tag);
+                grouper.add(
+                    queue,
+                    routing.receiver,
+                    grouping,
+                    Pending {
+                        settler: settler.clone(),
+                        delivery,
+                        alert,
+                    },
+                );
+                return;
+            }
+        }
+
+        self.deliver(settler, queue, delivery, alert, thread, None)
+            .await
+    }
+
+    /// Render, deliver and settle a single alert, replying in `thread` if
+    /// there is one and heading the message with `note` if given.
+    async fn deliver(
+        &self,
+        settler: &Settler,
+        queue: usize,
+        delivery: Delivery,
+        alert: Alert,
+        thread: Option<StoredMessage>,
+        note: Option<&str>,
+    ) {
+        let cfg = self.cfg();
+        let tag = delivery.delivery_tag;
+        let attempts = source::attempts(&delivery) + 1;
+
+        let token = cfg.queues[queue].slack.token.as_deref();
+        let fingerprint = alert.key();
+        let channel = match &thread {
+            Some(thread) => Some(thread.channel.as_str()),
+            None => alert.channel.as_deref().filter(|_| token.is_some()),
+        };
+        let routing = Dispatcher::routing(&cfg, &alert);
+        let receiver = routing.receiver;
+        let name = cfg.queues[queue].destination(alert.severity, channel, receiver);
+        if alert.status == Status::Firing && !self.admit(Some(&fingerprint), &routing, &name) {
+            info!(
+                "Suppressing delivery {} for {}: rate limited",
+                tag, fingerprint
+            );
+            self.metrics.rate_limited();
+            self.limiter.suppress(&name, &alert.title, || Suppressed {
+                queue,
+                severity: alert.severity,
+                channel: channel.map(String::from),
+                receiver: receiver.map(String::from),
+                count: 0,
+                titles: Vec::new(),
+            });
+            if let Err(e) = settler.ack(&delivery).await {
+                error!("Failed to settle delivery {}: {:?}", tag, e);
+            }
+            return;
+        }
+        let mentions = mention::mentions(&cfg.mentions, &self.sinks.slack, token, &alert).await;
+        let rendered = info_span!("render").in_scope(|| match note {
+            Some(note) => self.templates().render_with_note(&alert, &mentions, note),
+            None => self.templates().render(&alert, &mentions),
+        });
+        let mut body = match rendered {
+            Ok(body) => body,
+            Err(e) => {
+                // A broken template will not fix itself on redelivery.
+                error!("Unable to render delivery {}: {}", tag, e);
+                self.metrics.dead_lettered();
+                self.self_alerts.report(
+                    selfalert::RENDER,
+                    format!("Unable to render an alert: {}", e),
+                );
+                if let Err(e) = settler.dead_letter(&delivery, attempts, &e).await {
+                    error!("Failed to dead-letter delivery {}: {:?}", tag, e);
+                }
+                return;
+            }
+        };
+        // Copies go elsewhere, so they are not threaded.
+        let copy = Notification {
+            alerts: vec![alert.clone()],
+            severity: alert.severity,
+            summary: alert.title.clone(),
+            body: body.clone(),
+        };
+        if let (Some(thread), Some(body)) = (&thread, body.as_object_mut()) {
+            body.insert("thread_ts".to_string(), Value::String(thread.ts.clone()));
+        }
+        let main = Leg {
+            channel,
+            receiver,
+            name: name.clone(),
+            // As the destination was before any message about the alert
+            // could be replied to, so that a retry does not post again in
+            // the thread of its own earlier attempt.
+            key: cfg.queues[queue].destination(
+                alert.severity,
+                alert.channel.as_deref().filter(|_| token.is_some()),
+                receiver,
+            ),
+            main: true,
+            fallback: routing.fallback,
+            rendered: Notification {
+                body,
+                ..copy.clone()
+            },
+        };
+        let legs = Dispatcher::legs(&cfg, queue, main, routing.copies, &copy);
+        let mut outcome = self
+            .fan_out(queue, legs, &source::delivered(&delivery), tag)
+            .await;
+
+        // Only once the main destination takes it: a retry for the others
+        // finds this done.
+        if let Some(receipt) = outcome.receipt.take() {
+            match (alert.status, receipt.channel, receipt.id) {
+                (Status::Resolved, _, _) => {
+                    self.store.remove(&fingerprint);
+                    if let Some(thread) = &thread {
+                        self.show_resolved(queue, thread, &alert).await;
+                        if thread.alert.escalated_by.is_some() {
+                            self.resolve_incident(&fingerprint).await;
+                        }
+                    }
+                }
+                // A silenced alert sent to the low-noise channel leaves any
+                // message already posted about the check in place.
+                (Status::Firing, Some(channel), Some(ts))
+                    if thread.is_none() && self.store.get(&fingerprint).is_none() =>
+                {
+                    debug!("Delivery {} posted to {} as {}", tag, channel, ts);
+                    self.store.insert(
+                        &fingerprint,
+                        StoredMessage {
+                            queue: cfg.queues[queue].name.clone(),
+                            channel,
+                            ts,
+                            posted_at: Utc::now(),
+                            alert: alert.clone(),
+                            reminders: 0,
+                        },
+                    );
+                }
+                _ => {}
+            }
+        }
+        if !self
+            .settle(&[(settler, &delivery)], routing.ack, outcome)
+            .await
+        {
+            return;
+        }
+        if let Some(dedup) = &self.dedup {
+            match alert.status {
+                Status::Firing => dedup.posted(&alert, routing.repeat_interval),
+                Status::Resolved => dedup.resolved(&alert),
+            }
+        }
+    }
+
+    /// Post one digest for a group of alerts, or the alert itself if it is
+    /// alone, and settle their deliveries.
+    async fn deliver_group(&self, group: Group) {
+        let mut pending = group.pending;
+        // Those that came on a lost connection are redelivered on the new
+        // one, so posting them now would post them twice.
+        pending.retain(|p| p.settler.is_open());
+        if pending.is_empty() {
+            return;
+        }
+        if pending.len() == 1 {
+            let Pending {
+                settler,
+                delivery,
+                alert,
+            } = pending.remove(0);
+            return self
+                .deliver(&settler, group.queue, delivery, alert, None, None)
+                .await;
+        }
+
+        let cfg = self.cfg();
+        let queue = group.queue;
+        let token = cfg.queues[queue].slack.token.as_deref();
+        let alerts: Vec<Alert> = pending.iter().map(|p| p.alert.clone()).collect();
+        let deliveries: Vec<(&Settler, &Delivery)> =
+            pending.iter().map(|p| (&p.settler, &p.delivery)).collect();
+
+        let mut mentions: Vec<String> = Vec::new();
+        for alert in &alerts {
+            for mention in mention::mentions(&cfg.mentions, &self.sinks.slack, token, alert)
+                .await
+                .split_whitespace()
+            {
+                if !mentions.iter().any(|m| m == mention) {
+                    mentions.push(mention.to_string());
+                }
+            }
+        }
+        let summary = match group.label.as_str() {
+            "" => format!("{} checks failing", alerts.len()),
+            label => format!("{} checks failing in {}", alerts.len(), label),
+        };
+        let body = self
+            .templates()
+            .render_digest(&summary, &alerts, &mentions.join(" "));
+
+        let severity = alerts
+            .iter()
+            .map(|alert| alert.severity)
+            .min()
+            .unwrap_or(Severity::Critical);
+        let receiver = group.receiver.as_deref();
+        let name = cfg.queues[queue].destination(severity, None, receiver);
+        let routing = Dispatcher::routing(&cfg, &alerts[0]);
+        if !self.admit(None, &routing, &name) {
+            info!(
+                "Suppressing digest of {} alerts for {}: rate limited",
+                alerts.len(),
+                name
+            );
+            self.metrics.rate_limited();
+            for alert in &alerts {
+                self.limiter.suppress(&name, &alert.title, || Suppressed {
+                    queue,
+                    severity,
+                    channel: None,
+                    receiver: receiver.map(String::from),
+                    count: 0,
+                    titles: Vec::new(),
+                });
+            }
+            for (settler, delivery) in deliveries {
+                if let Err(e) = settler.ack(delivery).await {
+                    error!(
+                        "Failed to settle delivery {}: {:?}",
+                        delivery.delivery_tag, e
+                    );
+                }
+            }
+            return;
+        }
+        let tag = deliveries[0].1.delivery_tag;
+        info!(
+            "Posting digest of {} alerts for {}",
+            alerts.len(),
+            if group.label.is_empty() {
+                &name
+            } else {
+                &group.label
+            }
+        );
+
+        let rendered = Notification {
+            alerts: alerts.clone(),
+            severity,
+            summary,
+            body,
+        };
+        let main = Leg {
+            channel: None,
+            receiver,
+            key: name.clone(),
+            name,
+            main: true,
+            fallback: routing.fallback,
+            rendered: rendered.clone(),
+        };
+        let legs = Dispatcher::legs(&cfg, queue, main, routing.copies, &rendered);
+        // A leg is only skipped if every alert in the digest went there on
+        // an earlier attempt.
+        let mut delivered = source::delivered(deliveries[0].1);
+        for (_, delivery) in &deliveries[1..] {
+            let also = source::delivered(delivery);
+            delivered.retain(|key| also.contains(key));
+        }
+        let outcome = self.fan_out(queue, legs, &delivered, tag).await;
+        if self.settle(&deliveries, routing.ack, outcome).await {
+            if let Some(dedup) = &self.dedup {
+                for alert in &alerts {
+                    dedup.posted(alert, Dispatcher::routing(&cfg, alert).repeat_interval);
+                }
+            }
+        }
+    }
+
+    /// Post a reminder about the unacknowledged alert behind `message`,
+    /// then count it against the alert.
+    async fn remind(&self, fingerprint: &str, mut message: StoredMessage) {
+        let cfg = self.cfg();
+        let renotify = match &cfg.renotify {
+            Some(renotify) => renotify,
+            None => return,
+        };
+        let queue = match cfg.queues.iter().position(|q| q.name == message.queue) {
+            Some(queue) => queue,
+            None => return,
+        };
+        let token = cfg.queues[queue].slack.token.as_deref();
+        let alert = &message.alert;
+
+        let mut mentions = mention::mentions(&cfg.mentions, &self.sinks.slack, token, alert).await;
+        let extra = mention::mentions(&renotify.mentions, &self.sinks.slack, token, alert).await;
+        for mention in extra.split_whitespace() {
+            if !mentions.split_whitespace().any(|m| m == mention) {
+                if !mentions.is_empty() {
+                    mentions.push(' ');
+                }
+                mentions.push_str(mention);
+            }
+        }
+        let reminder = message.reminders + 1;
+        let minutes = (Utc::now() - message.posted_at).num_minutes();
+        let note = format!(
+            ":rotating_light: *Still unacknowledged after {} minutes* (reminder {} of {})",
+            minutes, reminder, renotify.max
+        );
+        let body = match self.templates().render_with_note(alert, &mentions, &note) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Unable to render reminder for {}: {}", fingerprint, e);
+                self.self_alerts.report(
+                    selfalert::RENDER,
+                    format!("Unable to render a reminder: {}", e),
+                );
+                return;
+            }
+        };
+
+        let channel = renotify.channel.as_deref().unwrap_or(&message.channel);
+        let name = cfg.queues[queue].destination(alert.severity, Some(channel), None);
+        let rendered = Notification {
+            alerts: vec![alert.clone()],
+            severity: alert.severity,
+            summary: alert.title.clone(),
+            body,
+        };
+        match self
+            .post(queue, Some(channel), None, &name, &[], rendered)
+            .await
+        {
+            Ok(_) => info!(
+                "Sent reminder {} of {} about {} to {}",
+                reminder, renotify.max, fingerprint, name
+            ),
+            // Tried again at the next tick.
+            Err(e) => {
+                warn!("Unable to send reminder about {}: {}", fingerprint, e);
+                return;
+            }
+        }
+        message.reminders = reminder;
+        self.store.insert(fingerprint, message);
+    }
+
+    /// Remind people of critical alerts nobody has acknowledged, escalated
+    /// or snoozed, every `renotify.after` up to `renotify.max` times. Runs
+    /// until the process exits.
+    pub async fn renotify(&self) {
+        loop {
+            tokio::time::delay_for(RENOTIFY_TICK).await;
+            // Read afresh each time in case the configuration is reloaded.
+            let cfg = self.cfg();
+            let renotify = match &cfg.renotify {
+                Some(renotify) => renotify,
+                None => continue,
+            };
+            let after = chrono::Duration::seconds(renotify.after.as_secs() as i64);
+            let now = Utc::now();
+            for (fingerprint, message) in self.store.messages() {
+                let alert = &message.alert;
+                let due = message.posted_at + after * (message.reminders as i32 + 1);
+                if alert.status != Status::Firing
+                    || alert.severity != Severity::Critical
+                    || alert.acknowledged_by.is_some()
+                    || alert.escalated_by.is_some()
+                    || message.reminders >= renotify.max
+                    || now < due
+                    || self.silencing(alert, false).is_some()
+                {
+                    continue;
+                }
+                self.remind(&fingerprint, message).await;
+            }
+        }
+    }
+
+    /// Once quiet hours end, post the alerts held during them as one digest
+    /// per queue, without mentions. Runs until the process exits.
+    pub async fn wake(&self) {
+        let quiet = match &self.quiet {
+            Some(quiet) => quiet,
+            None => return,
+        };
+        loop {
+            tokio::time::delay_for(QUIET_TICK).await;
+            if quiet.is_quiet(Utc::now()) {
+                continue;
+            }
+            let cfg = self.cfg();
+            let mut by_queue: BTreeMap<String, Vec<Alert>> = BTreeMap::new();
+            for Held { queue, alert } in quiet.take() {
+                by_queue.entry(queue).or_default().push(alert);
+            }
+
+            for (queue_name, alerts) in by_queue {
+                let queue = match cfg.queues.iter().position(|q| q.name == queue_name) {
+                    Some(queue) => queue,
+                    None => {
+                        warn!(
+                            "Dropping {} held alerts for unknown queue {}",
+                            alerts.len(),
+                            queue_name
+                        );
+                        continue;
+                    }
+                };
+                let severity = alerts
+                    .iter()
+                    .map(|alert| alert.severity)
+                    .min()
+                    .unwrap_or(Severity::Critical);
+                let name = cfg.queues[queue].destination(severity, None, None);
+                let summary = format!("{} alerts held during quiet hours", alerts.len());
+                let rendered = Notification {
+                    body: self.templates().render_digest(&summary, &alerts, ""),
+                    alerts: alerts.clone(),
+                    severity,
+                    summary,
+                };
+                match self.post(queue, None, None, &name, &[], rendered).await {
+                    Ok(_) => info!(
+                        "Posted {} alerts held during quiet hours to {}",
+                        alerts.len(),
+                        name
+                    ),
+                    Err(e) => {
+                        warn!(
+                            "Unable to post alerts held during quiet hours to {}: {}",
+                            name, e
+                        );
+                        for alert in alerts {
+                            quiet.hold(&queue_name, alert);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Tell each destination how many notifications rate limits held back
+    /// from it, every `rate_limits.summary_interval`. Runs until the process
+    /// exits.
+    pub async fn summarize_suppressed(&self) {
+        loop {
+            let interval = self.cfg().rate_limits.summary_interval;
+            tokio::time::delay_for(interval).await;
+            for (name, suppressed) in self.limiter.take_suppressed() {
+                let rendered = Notification {
+                    alerts: Vec::new(),
+                    severity: suppressed.severity,
+                    summary: format!(
+                        "{} notifications suppressed by rate limits",
+                        suppressed.count
+                    ),
+                    body: self.templates().render_suppressed(
+                        suppressed.count,
+                        &suppressed.titles,
+                        interval.as_secs() / 60,
+                    ),
+                };
+                let result = self
+                    .post(
+                        suppressed.queue,
+                        suppressed.channel.as_deref(),
+                        suppressed.receiver.as_deref(),
+                        &name,
+                        &[],
+                        rendered,
+                    )
+                    .await;
+                match result {
+                    Ok(_) => info!(
+                        "{} notifications to {} were suppressed",
+                        suppressed.count, name
+                    ),
+                    Err(e) => warn!(
+                        "Unable to report {} suppressed notifications to {}: {}",
+                        suppressed.count, name, e
+                    ),
+                }
+            }
+        }
+    }
+
+    /// Send `rendered` to the named `receiver` as the first queue would,
+    /// apart from any alert's route.
+    pub async fn send_to(
+        &self,
+        receiver: &str,
+        rendered: Notification,
+    ) -> Result<DeliveryReceipt, SinkError> {
+        let name = self.cfg().queues[0].destination(rendered.severity, None, Some(receiver));
+        self.post(0, None, Some(receiver), &name, &[], rendered)
+            .await
+    }
+
+    /// Route and render `alert` as if it had arrived on the `queue`th queue,
+    /// and deliver it to each destination its route names but those
+    /// `delivered` already, whatever silences, rate limits and circuits say,
+    /// for `send-test` and `replay`. Returns each destination with what it
+    /// was sent and how that went.
+    pub async fn deliver_now(
+        &self,
+        queue: usize,
+        alert: &Alert,
+        delivered: &[String],
+    ) -> Result<Vec<(String, Value, Result<DeliveryReceipt, SinkError>)>, String> {
+        let cfg = self.cfg();
+        let token = cfg.queues[queue].slack.token.as_deref();
+        let channel = alert.channel.as_deref().filter(|_| token.is_some());
+        let routing = Dispatcher::routing(&cfg, alert);
+        let mentions = mention::mentions(&cfg.mentions, &self.sinks.slack, token, alert).await;
+        let body = self.templates().render(alert, &mentions)?;
+        let name = cfg.queues[queue].destination(alert.severity, channel, routing.receiver);
+        let copy = Notification {
+            alerts: vec![alert.clone()],
+            severity: alert.severity,
+            summary: alert.title.clone(),
+            body,
+        };
+        let main = Leg {
+            channel,
+            receiver: routing.receiver,
+            key: name.clone(),
+            name,
+            main: true,
+            fallback: &[],
+            rendered: copy.clone(),
+        };
+        let mut results = Vec::new();
+        for leg in Dispatcher::legs(&cfg, queue, main, routing.copies, &copy) {
+            if delivered.contains(&leg.key) {
+                continue;
+            }
+            let sink = self.sink(
+                &cfg,
+                queue,
+                alert.severity,
+                leg.channel,
+                leg.receiver,
+                leg.name.clone(),
+            );
+            let result = sink.deliver(leg.rendered.clone()).await;
+            results.push((leg.name, leg.rendered.body, result));
+        }
+        Ok(results)
+    }
+
+    /// Report trouble with the bridge itself to the receiver configured for
+    /// it, as each kind falls due. Runs until the process exits.
+    pub async fn report_self(&self) {
+        loop {
+            tokio::time::delay_for(SELF_ALERT_TICK).await;
+            let cfg = self.cfg();
+            let self_alerts = match &cfg.self_alerts {
+                Some(self_alerts) => self_alerts,
+                None => return,
+            };
+            for (kind, trouble) in self.self_alerts.take_due(self_alerts) {
+                let text = match trouble.count {
+                    1 => trouble.message.clone(),
+                    n => format!("{} ({} times since the last report)", trouble.message, n),
+                };
+                let rendered = Notification {
+                    alerts: Vec::new(),
+                    severity: Severity::Warning,
+                    summary: format!("2steps-slack-alert: {}", text),
+                    body: json!({ "text": format!(":warning: *2steps-slack-alert*: {}", text) }),
+                };
+                if let Err(e) = self.send_to(&self_alerts.receiver, rendered).await {
+                    error!("Unable to report {} trouble: {}", kind, e);
+                }
+            }
+        }
+    }
+
+    /// Drop the grouped alerts taken over a connection that has been lost,
+    /// as they can no longer be acked and the broker redelivers them.
+    pub fn connection_lost(&self) {
+        if let Some(grouper) = &self.grouper {
+            let abandoned = grouper.abandon();
+            if abandoned > 0 {
+                info!(
+                    "Dropped {} grouped alert(s) until the broker redelivers them",
+                    abandoned
+                );
+            }
+        }
+    }
+
+    /// Post grouped alerts as their groups fall due. Runs until the process
+    /// exits.
+    pub async fn flush_groups(&self) {
+        let grouper = match &self.grouper {
+            Some(grouper) => grouper,
+            None => return,
+        };
+        loop {
+            tokio::time::delay_for(GROUP_TICK).await;
+            for group in grouper.due() {
+                self.deliver_group(group).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,271 @@
+//! Secrets kept out of the configuration file. Webhook URLs, the Slack bot
+//! token, the keys, tokens, headers and credentials of other receivers, the
+//! SMTP password, the Matrix access token, the Webex bot token, the Twilio
+//! auth token, the PagerDuty routing key and API token used for escalation,
+//! the Slack signing secret and app token for interactions, the heartbeat
+//! URL, the Sentry DSN, the admin API token and the broker password may be
+//! given as references instead:
+//!
+//! - `vault:<mount>/<path>#<key>` reads `key` from a HashiCorp Vault KV
+//!   version 2 secret, using `VAULT_ADDR` and `VAULT_TOKEN`;
+//! - `aws-sm:<secret id>` reads an AWS Secrets Manager secret, or with
+//!   `#<key>` one field of a secret holding a JSON object, using
+//!   `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`, `AWS_SESSION_TOKEN` and
+//!   `AWS_REGION`.
+//!
+//! References are resolved each time the configuration is loaded.
+
+use crate::aws::{self, env_var};
+use crate::config::{self, Config, Receiver, Route, ServiceNowAuth, WebhookAuth};
+use crate::http;
+use log::debug;
+use reqwest::Client;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+const VAULT_PREFIX: &str = "vault:";
+const AWS_PREFIX: &str = "aws-sm:";
+const AWS_TARGET: &str = "secretsmanager.GetSecretValue";
+const AWS_CONTENT_TYPE: &str = "application/x-amz-json-1.1";
+
+/// Whether `value` refers to a secret rather than being one.
+pub fn is_reference(value: &str) -> bool {
+    value.starts_with(VAULT_PREFIX) || value.starts_with(AWS_PREFIX)
+}
+
+/// Looks up references, fetching each secret once per load.
+struct Resolver {
+    client: Client,
+    vault: HashMap<String, Map<String, Value>>,
+    aws: HashMap<String, String>,
+}
+
+impl Resolver {
+    /// Replace `value` with the secret it refers to, if it is a reference.
+    async fn resolve(&mut self, value: &mut String) -> Result<(), String> {
+        if !is_reference(value) {
+            return Ok(());
+        }
+        let secret = if let Some(reference) = value.strip_prefix(VAULT_PREFIX) {
+            self.vault(reference).await
+        } else {
+            self.aws(&value[AWS_PREFIX.len()..]).await
+        };
+        *value = secret.map_err(|e| format!("Unable to resolve secret {}: {}", value, e))?;
+        Ok(())
+    }
+
+    /// Resolve a webhook URL, which must still look like one.
+    async fn resolve_webhook(&mut self, url: &mut String) -> Result<(), String> {
+        if is_reference(url) {
+            let reference = url.clone();
+            self.resolve(url).await?;
+            config::webhook(url.clone()).map_err(|e| format!("Secret {}: {}", reference, e))?;
+        }
+        Ok(())
+    }
+
+    async fn resolve_route(&mut self, route: &mut Route) -> Result<(), String> {
+        match route {
+            Route::Webhook(url) => self.resolve_webhook(url).await,
+            Route::Channel(_) => Ok(()),
+        }
+    }
+
+    async fn vault(&mut self, reference: &str) -> Result<String, String> {
+        let (path, key) = reference
+            .split_once('#')
+            .ok_or("Vault references need a #key")?;
+        if !self.vault.contains_key(path) {
+            let (mount, rest) = path
+                .split_once('/')
+                .ok_or("Vault references need a mount and a path")?;
+            let url = format!(
+                "{}/v1/{}/data/{}",
+                env_var("VAULT_ADDR")?.trim_end_matches('/'),
+                mount,
+                rest
+            );
+            let res = self
+                .client
+                .get(&url)
+                .header("X-Vault-Token", env_var("VAULT_TOKEN")?)
+                .send()
+                .await
+                .map_err(|e| format!("failed reaching Vault: {}", e))?;
+            if !res.status().is_success() {
+                return Err(format!("Vault returned {}", res.status()));
+            }
+            let mut body: Value = res
+                .json()
+                .await
+                .map_err(|e| format!("unreadable response from Vault: {}", e))?;
+            let data = match body["data"]["data"].take() {
+                Value::Object(data) => data,
+                _ => return Err("Vault returned no data".to_string()),
+            };
+            debug!("Read secret {} from Vault", path);
+            self.vault.insert(path.to_string(), data);
+        }
+        match self.vault[path].get(key) {
+            Some(Value::String(secret)) => Ok(secret.clone()),
+            Some(_) => Err(format!("{} is not a string", key)),
+            None => Err(format!("no such key {}", key)),
+        }
+    }
+
+    async fn aws(&mut self, reference: &str) -> Result<String, String> {
+        let (id, key) = match reference.split_once('#') {
+            Some((id, key)) => (id, Some(key)),
+            None => (reference, None),
+        };
+        if !self.aws.contains_key(id) {
+            let secret = self.aws_fetch(id).await?;
+            debug!("Read secret {} from AWS Secrets Manager", id);
+            self.aws.insert(id.to_string(), secret);
+        }
+        let secret = &self.aws[id];
+        match key {
+            None => Ok(secret.clone()),
+            Some(key) => {
+                let fields: Map<String, Value> = serde_json::from_str(secret)
+                    .map_err(|_| "the secret is not a JSON object".to_string())?;
+                match fields.get(key) {
+                    Some(Value::String(secret)) => Ok(secret.clone()),
+                    Some(_) => Err(format!("{} is not a string", key)),
+                    None => Err(format!("no such key {}", key)),
+                }
+            }
+        }
+    }
+
+    /// Call GetSecretValue, signed with Signature Version 4.
+    async fn aws_fetch(&self, id: &str) -> Result<String, String> {
+        let region = aws::region()?;
+        let body = json!({ "SecretId": id }).to_string();
+        let req = aws::post(
+            &self.client,
+            "secretsmanager",
+            &region,
+            &[
+                ("content-type", AWS_CONTENT_TYPE),
+                ("x-amz-target", AWS_TARGET),
+            ],
+            body,
+        )?;
+        let res = req
+            .send()
+            .await
+            .map_err(|e| format!("failed reaching AWS Secrets Manager: {}", e))?;
+        if !res.status().is_success() {
+            return Err(format!("AWS Secrets Manager returned {}", res.status()));
+        }
+        let mut body: Value = res
+            .json()
+            .await
+            .map_err(|e| format!("unreadable response from AWS Secrets Manager: {}", e))?;
+        match body["SecretString"].take() {
+            Value::String(secret) => Ok(secret),
+            _ => Err("the secret has no SecretString".to_string()),
+        }
+    }
+}
+
+/// Replace the secret references in `cfg` with the secrets themselves.
+pub async fn resolve(cfg: &mut Config) -> Result<(), String> {
+    let mut resolver = Resolver {
+        client: http::build_client(&cfg.http)?,
+        vault: HashMap::new(),
+        aws: HashMap::new(),
+    };
+
+    resolver.resolve(&mut cfg.rabbit.password).await?;
+    for queue in &mut cfg.queues {
+        let slack = &mut queue.slack;
+        resolver.resolve_route(&mut slack.route).await?;
+        for route in slack.severity_routes.values_mut() {
+            resolver.resolve_route(route).await?;
+        }
+        if let Some(url) = &mut slack.fallback_url {
+            resolver.resolve_webhook(url).await?;
+        }
+        if let Some(token) = &mut slack.token {
+            resolver.resolve(token).await?;
+        }
+    }
+    for receiver in cfg.routing.receivers.values_mut() {
+        match receiver {
+            Receiver::Slack(route) => resolver.resolve_route(route).await?,
+            Receiver::Teams(url) | Receiver::Discord(url) | Receiver::GoogleChat(url) => {
+                resolver.resolve_webhook(url).await?
+            }
+            Receiver::Mattermost(webhook) => resolver.resolve_webhook(&mut webhook.url).await?,
+            Receiver::Telegram(chat) => resolver.resolve(&mut chat.bot_token).await?,
+            Receiver::Pushover(user) => resolver.resolve(&mut user.token).await?,
+            Receiver::PagerDuty(service) => resolver.resolve(&mut service.routing_key).await?,
+            Receiver::Opsgenie(service) => resolver.resolve(&mut service.api_key).await?,
+            Receiver::Jira(project) => resolver.resolve(&mut project.token).await?,
+            Receiver::ServiceNow(instance) => match &mut instance.auth {
+                ServiceNowAuth::Basic { password, .. } => resolver.resolve(password).await?,
+                ServiceNowAuth::Bearer(token) => resolver.resolve(token).await?,
+            },
+            Receiver::Email(_)
+            | Receiver::Matrix(_)
+            | Receiver::Webex(_)
+            | Receiver::Sms(_)
+            | Receiver::Sns(_)
+            | Receiver::Syslog(_) => {}
+            Receiver::Webhook(webhook) => {
+                resolver.resolve_webhook(&mut webhook.url).await?;
+                for value in webhook.headers.values_mut() {
+                    resolver.resolve(value).await?;
+                }
+                match &mut webhook.auth {
+                    Some(WebhookAuth::Bearer(token)) => resolver.resolve(token).await?,
+                    Some(WebhookAuth::Basic { password, .. }) => resolver.resolve(password).await?,
+                    None => {}
+                }
+            }
+        }
+    }
+    if let Some(password) = cfg.email.as_mut().and_then(|email| email.password.as_mut()) {
+        resolver.resolve(password).await?;
+    }
+    if let Some(matrix) = &mut cfg.matrix {
+        resolver.resolve(&mut matrix.access_token).await?;
+    }
+    if let Some(webex) = &mut cfg.webex {
+        resolver.resolve(&mut webex.bot_token).await?;
+    }
+    if let Some(twilio) = &mut cfg.twilio {
+        resolver.resolve(&mut twilio.auth_token).await?;
+    }
+    if let Some(pagerduty) = &mut cfg.pagerduty {
+        resolver.resolve(&mut pagerduty.routing_key).await?;
+        if let Some(token) = &mut pagerduty.api_token {
+            resolver.resolve(token).await?;
+        }
+    }
+    if let Some(interactions) = &mut cfg.interactions {
+        if let Some(secret) = &mut interactions.signing_secret {
+            resolver.resolve(secret).await?;
+        }
+        if let Some(token) = &mut interactions.app_token {
+            resolver.resolve(token).await?;
+        }
+    }
+    if let Some(url) = cfg
+        .heartbeat
+        .as_mut()
+        .and_then(|heartbeat| heartbeat.url.as_mut())
+    {
+        resolver.resolve(url).await?;
+    }
+    if let Some(sentry) = &mut cfg.sentry {
+        resolver.resolve(&mut sentry.dsn).await?;
+    }
+    if let Some(token) = cfg.admin.as_mut().and_then(|admin| admin.token.as_mut()) {
+        resolver.resolve(token).await?;
+    }
+    Ok(())
+}
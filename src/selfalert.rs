@@ -0,0 +1,90 @@
+//! Alerts about the bridge itself: reconnection storms, templates that fail
+//! to render and destinations whose circuits open. Each kind of trouble is
+//! reported to the ops receiver at most once an interval, however often it
+//! happens, so that it shows up somewhere other than the logs.
+
+use crate::config::SelfAlertsConfig;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Kinds of trouble.
+pub const RECONNECTS: &str = "reconnects";
+pub const RENDER: &str = "render";
+pub const CIRCUIT: &str = "circuit";
+
+/// Trouble of one kind since it was last reported.
+pub struct Trouble {
+    /// What happened first.
+    pub message: String,
+    /// How many times anything of the kind happened.
+    pub count: u32,
+}
+
+#[derive(Default)]
+pub struct SelfAlerts {
+    /// When the broker connection was recently lost.
+    reconnects: Mutex<VecDeque<Instant>>,
+    pending: Mutex<BTreeMap<&'static str, Trouble>>,
+    /// When each kind was last reported.
+    reported: Mutex<BTreeMap<&'static str, Instant>>,
+}
+
+impl SelfAlerts {
+    /// Note trouble of `kind`, described by `message`.
+    pub fn report(&self, kind: &'static str, message: String) {
+        self.pending
+            .lock()
+            .unwrap()
+            .entry(kind)
+            .or_insert(Trouble { message, count: 0 })
+            .count += 1;
+    }
+
+    /// Note a lost broker connection, reporting it once they come as often
+    /// as `cfg` says makes a storm.
+    pub fn reconnected(&self, cfg: &SelfAlertsConfig) {
+        let mut reconnects = self.reconnects.lock().unwrap();
+        let now = Instant::now();
+        reconnects.push_back(now);
+        while reconnects
+            .front()
+            .is_some_and(|at| now.duration_since(*at) > cfg.interval)
+        {
+            reconnects.pop_front();
+        }
+        if reconnects.len() >= cfg.reconnects as usize {
+            self.report(
+                RECONNECTS,
+                format!(
+                    "Lost the connection to rabbit {} times in {} minutes",
+                    reconnects.len(),
+                    cfg.interval.as_secs() / 60
+                ),
+            );
+        }
+    }
+
+    /// The trouble due to be reported under `cfg`: every kind not reported
+    /// within the interval.
+    pub fn take_due(&self, cfg: &SelfAlertsConfig) -> Vec<(&'static str, Trouble)> {
+        let mut pending = self.pending.lock().unwrap();
+        let mut reported = self.reported.lock().unwrap();
+        let now = Instant::now();
+        let due: Vec<&'static str> = pending
+            .keys()
+            .filter(|kind| {
+                reported
+                    .get(*kind)
+                    .is_none_or(|at| now.duration_since(*at) >= cfg.interval)
+            })
+            .copied()
+            .collect();
+        due.into_iter()
+            .filter_map(|kind| {
+                reported.insert(kind, now);
+                pending.remove(kind).map(|trouble| (kind, trouble))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,118 @@
+//! The `send-test` subcommand, which sends an alert through routing and
+//! templating to wherever it would go, so that new destinations and
+//! templates can be tried without waiting for something to fail.
+
+use crate::alert::Alert;
+use crate::config::{Config, ConfigSource};
+use crate::http;
+use crate::load_config;
+use crate::render::Templates;
+use crate::router::Dispatcher;
+use crate::silence::Silences;
+use crate::sink::pagerduty::PagerDuty;
+use crate::sink::Sinks;
+use crate::store::MessageStore;
+use serde_json::json;
+use std::fs;
+use std::sync::Arc;
+
+/// An alert like those 2Steps publishes, marked as a test.
+fn sample() -> Result<Alert, String> {
+    let host = hostname::get()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|_| "localhost".to_string());
+    let alert = json!({
+        "severity": "critical",
+        "status": "firing",
+        "title": "Test alert from 2steps-slack-alert",
+        "reason": "This is a test of the alert route, sent with send-test. No action is needed.",
+        "source": host,
+        "step": "send-test",
+        "run_id": hex::encode(rand::random::<[u8; 8]>()),
+        "fingerprint": "2steps-slack-alert/send-test",
+    });
+    Alert::from_slice(alert.to_string().as_bytes())
+}
+
+/// A dispatcher for delivering alerts straight away, outside the service,
+/// or logging them instead for a `dry_run`.
+pub fn dispatcher(cfg: &Arc<Config>, dry_run: bool) -> Result<Dispatcher, String> {
+    let templates = Templates::load(
+        &cfg.templates,
+        &cfg.routing,
+        &cfg.styles,
+        cfg.pagerduty.is_some(),
+        cfg.email.as_ref(),
+    )?;
+    let client = http::build_client(&cfg.http)?;
+    let pagerduty = cfg
+        .pagerduty
+        .as_ref()
+        .map(|pagerduty_cfg| PagerDuty::new(client.clone(), pagerduty_cfg));
+    let mut dispatcher = Dispatcher::new(
+        cfg.clone(),
+        Sinks::new(client, &cfg.http),
+        None,
+        templates,
+        MessageStore::open(&cfg.messages)?,
+        Silences::open(&cfg.silences)?,
+        pagerduty,
+    )?;
+    dispatcher.dry_run = dry_run;
+    Ok(dispatcher)
+}
+
+/// The position of the queue named `name`, or the first if none is.
+pub fn queue_index(cfg: &Config, name: Option<&str>) -> Result<usize, String> {
+    match name {
+        Some(name) => cfg
+            .queues
+            .iter()
+            .position(|queue| queue.name == name)
+            .ok_or_else(|| format!("No queue named {}", name)),
+        None => Ok(0),
+    }
+}
+
+/// Send the alert in the JSON file at `path`, or a sample one, as if it had
+/// arrived on the queue named `queue` or else the first, printing what is
+/// sent to each destination. Fails if any destination did not take it. A
+/// `dry_run` prints what would be sent without sending it.
+pub async fn send_test(
+    source: &ConfigSource,
+    path: Option<&str>,
+    queue: Option<&str>,
+    dry_run: bool,
+) -> Result<(), String> {
+    let cfg = Arc::new(load_config(source).await?);
+    let alert = match path {
+        Some(path) => Alert::from_slice(
+            &fs::read(path).map_err(|e| format!("Unable to read {}: {}", path, e))?,
+        )?,
+        None => sample()?,
+    };
+    let queue = queue_index(&cfg, queue)?;
+    let dispatcher = dispatcher(&cfg, dry_run)?;
+
+    let mut failed = Vec::new();
+    for (destination, body, result) in dispatcher.deliver_now(queue, &alert, &[]).await? {
+        println!("{}:", destination);
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&body).unwrap_or_else(|_| body.to_string())
+        );
+        match result {
+            Ok(_) if dry_run => println!("Not delivered to {}: dry run\n", destination),
+            Ok(_) => println!("Delivered to {}\n", destination),
+            Err(e) => {
+                println!("Unable to deliver to {}: {}\n", destination, e);
+                failed.push(destination);
+            }
+        }
+    }
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Not delivered to {}", failed.join(", ")))
+    }
+}
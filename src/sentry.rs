@@ -0,0 +1,187 @@
+//! Error reporting to Sentry: panics, and deliveries that fail, with the
+//! alerts they were about. Events are sent as envelopes to the project the
+//! DSN names, tagged with our release so that regressions show up as such.
+
+use crate::config::SentryConfig;
+use chrono::{SecondsFormat, Utc};
+use log::{debug, warn};
+use reqwest::{Client, Url};
+use serde_json::{json, Map, Value};
+use std::panic::{self, PanicHookInfo};
+use std::sync::Arc;
+use std::time::Duration;
+
+const CLIENT: &str = concat!("2steps-slack-alert/", env!("CARGO_PKG_VERSION"));
+const RELEASE: &str = concat!("2steps-slack-alert@", env!("CARGO_PKG_VERSION"));
+/// How long a panic waits for its event to be sent.
+const PANIC_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where a DSN says to send events, and the key to send them with.
+pub struct Dsn {
+    pub endpoint: String,
+    pub key: String,
+}
+
+/// The envelope endpoint and key of `dsn`, given as
+/// `https://<key>@<host>/<project>`.
+pub fn parse_dsn(dsn: &str) -> Result<Dsn, String> {
+    let url = Url::parse(dsn).map_err(|e| format!("invalid DSN: {}", e))?;
+    if !matches!(url.scheme(), "http" | "https") || !url.has_host() || url.username().is_empty() {
+        return Err("DSN must be an http or https URL with a key".to_string());
+    }
+    let path = url.path().trim_matches('/');
+    let (prefix, project) = match path.rsplit_once('/') {
+        Some((prefix, project)) => (format!("/{}", prefix), project),
+        None => (String::new(), path),
+    };
+    if project.is_empty() {
+        return Err("DSN must name a project".to_string());
+    }
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_string(),
+    };
+    Ok(Dsn {
+        endpoint: format!(
+            "{}://{}{}/api/{}/envelope/",
+            url.scheme(),
+            host,
+            prefix,
+            project
+        ),
+        key: url.username().to_string(),
+    })
+}
+
+/// `event` wrapped in an envelope.
+fn envelope(event: &Value) -> String {
+    let header = json!({
+        "event_id": event["event_id"],
+        "sent_at": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+    });
+    let item = json!({ "type": "event", "content_type": "application/json" });
+    format!("{}\n{}\n{}\n", header, item, event)
+}
+
+async fn send(client: Client, dsn: Arc<Dsn>, event: Value) {
+    let res = client
+        .post(&dsn.endpoint)
+        .header(
+            "X-Sentry-Auth",
+            format!(
+                "Sentry sentry_version=7, sentry_client={}, sentry_key={}",
+                CLIENT, dsn.key
+            ),
+        )
+        .header("Content-Type", "application/x-sentry-envelope")
+        .body(envelope(&event))
+        .send()
+        .await;
+    match res {
+        Ok(res) if res.status().is_success() => {
+            debug!("Event {} sent to Sentry", event["event_id"])
+        }
+        Ok(res) => warn!("Sentry returned {}", res.status()),
+        Err(e) => warn!("Unable to send event to Sentry: {}", e),
+    }
+}
+
+/// Sends events to a Sentry project.
+pub struct Sentry {
+    client: Client,
+    dsn: Arc<Dsn>,
+    environment: Option<String>,
+    server_name: Option<String>,
+}
+
+impl Sentry {
+    pub fn new(cfg: &SentryConfig, client: Client) -> Result<Sentry, String> {
+        Ok(Sentry {
+            client,
+            dsn: Arc::new(parse_dsn(&cfg.dsn).map_err(|e| format!("sentry: {}", e))?),
+            environment: cfg.environment.clone(),
+            server_name: hostname::get()
+                .ok()
+                .map(|name| name.to_string_lossy().to_string()),
+        })
+    }
+
+    /// An event at `level` with what every event carries.
+    fn event(&self, level: &str, message: &str, tags: Map<String, Value>) -> Value {
+        json!({
+            "event_id": hex::encode(rand::random::<[u8; 16]>()),
+            "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            "platform": "native",
+            "level": level,
+            "logger": "2steps-slack-alert",
+            "release": RELEASE,
+            "environment": self.environment,
+            "server_name": self.server_name,
+            "message": { "formatted": message },
+            "tags": tags,
+        })
+    }
+
+    /// Report a failed delivery to `destination`, with `context` describing
+    /// the alerts it was about.
+    pub fn delivery_failed(&self, destination: &str, outcome: &str, error: &str, context: Value) {
+        let mut tags = Map::new();
+        tags.insert("destination".to_string(), json!(destination));
+        tags.insert("outcome".to_string(), json!(outcome));
+        if let Some(severity) = context["severity"].as_str() {
+            tags.insert("severity".to_string(), json!(severity));
+        }
+        let mut event = self.event(
+            "error",
+            &format!("Delivery to {} {}: {}", destination, outcome, error),
+            tags,
+        );
+        // Grouped by where and how, rather than by the text of each error.
+        event["fingerprint"] = json!(["delivery", destination, outcome]);
+        event["extra"] = context;
+        tokio::spawn(send(self.client.clone(), self.dsn.clone(), event));
+    }
+
+    /// The event for a panic.
+    fn panic_event(&self, info: &PanicHookInfo<'_>) -> Value {
+        let message = match info.payload().downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => match info.payload().downcast_ref::<String>() {
+                Some(message) => message.clone(),
+                None => "Box<dyn Any>".to_string(),
+            },
+        };
+        let mut event = self.event("fatal", &message, Map::new());
+        event["exception"] = json!({
+            "values": [{ "type": "panic", "value": message, "mechanism": { "type": "panic", "handled": false } }],
+        });
+        event["extra"] = json!({
+            "location": info.location().map(|location| location.to_string()),
+            "thread": std::thread::current().name(),
+            "backtrace": std::backtrace::Backtrace::force_capture().to_string(),
+        });
+        event
+    }
+
+    /// Report panics, then carry on as before. The event is sent from a
+    /// thread of its own, as the runtime may be what is panicking.
+    pub fn install_panic_hook(self: Arc<Self>) {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let event = self.panic_event(info);
+            let dsn = self.dsn.clone();
+            let sent = std::thread::spawn(move || {
+                let runtime = tokio::runtime::Builder::new()
+                    .basic_scheduler()
+                    .enable_all()
+                    .build();
+                let client = Client::builder().timeout(PANIC_TIMEOUT).build();
+                if let (Ok(mut runtime), Ok(client)) = (runtime, client) {
+                    runtime.block_on(send(client, dsn, event));
+                }
+            });
+            let _ = sent.join();
+            previous(info);
+        }));
+    }
+}
@@ -0,0 +1,319 @@
+use crate::alert;
+use crate::config::{SilenceWindow, SilencesConfig};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Suppresses alerts whose attributes match `matchers` between `starts_at`
+/// and `ends_at`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Silence {
+    pub id: u64,
+    pub matchers: BTreeMap<String, String>,
+    pub starts_at: DateTime<Utc>,
+    pub ends_at: DateTime<Utc>,
+    /// Who asked for the silence, for the record.
+    pub created_by: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+}
+
+impl Silence {
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.starts_at <= now && now < self.ends_at
+    }
+
+    /// The matchers as `key=value` pairs separated by spaces.
+    pub fn describe(&self) -> String {
+        self.matchers
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Parse a duration such as `90s`, `30m`, `2h` or `1d`.
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let unit = match text.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+    let n: u64 = text[..text.len() - 1].parse().ok()?;
+    if n == 0 {
+        return None;
+    }
+    Some(Duration::from_secs(n.checked_mul(unit)?))
+}
+
+/// Who planned silences from the configuration are recorded as created by.
+pub const CONFIG_CREATOR: &str = "configuration";
+
+/// Silences for the windows that have not ended yet, numbered from
+/// `next_id`.
+fn planned(windows: &[SilenceWindow], next_id: &mut u64) -> Vec<Silence> {
+    let now = Utc::now();
+    windows
+        .iter()
+        .filter(|w| now < w.ends_at)
+        .map(|w| {
+            *next_id += 1;
+            Silence {
+                id: *next_id - 1,
+                matchers: w.matchers.clone(),
+                starts_at: w.starts_at,
+                ends_at: w.ends_at,
+                created_by: CONFIG_CREATOR.to_string(),
+                comment: w.comment.clone(),
+            }
+        })
+        .collect()
+}
+
+struct State {
+    /// Silences created at runtime.
+    silences: Vec<Silence>,
+    /// Silences from the configuration, which are never saved.
+    planned: Vec<Silence>,
+    next_id: u64,
+}
+
+/// Silences planned in the configuration and created at runtime. Runtime
+/// silences are optionally saved to a JSON file so that they outlive a
+/// restart. Expired silences are forgotten.
+pub struct Silences {
+    path: Option<PathBuf>,
+    state: Mutex<State>,
+}
+
+impl Silences {
+    pub fn open(cfg: &SilencesConfig) -> Result<Silences, String> {
+        let path = cfg.path.as_ref().map(PathBuf::from);
+        let silences: Vec<Silence> = match &path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(raw) => serde_json::from_str(&raw)
+                    .map_err(|e| format!("Unable to parse {}: {}", path.display(), e))?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+                Err(e) => return Err(format!("Unable to read {}: {}", path.display(), e)),
+            },
+            None => Vec::new(),
+        };
+        let now = Utc::now();
+        let silences: Vec<Silence> = silences.into_iter().filter(|s| now < s.ends_at).collect();
+        if !silences.is_empty() {
+            info!("Loaded {} silences", silences.len());
+        }
+
+        // Planned silences take the IDs after the saved ones, so their IDs
+        // may change from one run to the next.
+        let mut next_id = silences.iter().map(|s| s.id + 1).max().unwrap_or(1);
+        let planned = planned(&cfg.windows, &mut next_id);
+        if !planned.is_empty() {
+            info!("{} planned silences are current or upcoming", planned.len());
+        }
+
+        Ok(Silences {
+            path,
+            state: Mutex::new(State {
+                next_id,
+                silences,
+                planned,
+            }),
+        })
+    }
+
+    /// Replace the planned silences with those in `cfg`, as when the
+    /// configuration is reloaded. They are given new IDs.
+    pub fn plan(&self, cfg: &SilencesConfig) {
+        let mut state = self.state.lock().unwrap();
+        let mut next_id = state.next_id;
+        state.planned = planned(&cfg.windows, &mut next_id);
+        state.next_id = next_id;
+        info!(
+            "{} planned silences are current or upcoming",
+            state.planned.len()
+        );
+    }
+
+    /// Silence alerts matching `matchers` from now until `duration` has
+    /// passed.
+    pub fn add(
+        &self,
+        matchers: BTreeMap<String, String>,
+        duration: Duration,
+        created_by: &str,
+    ) -> Result<Silence, String> {
+        let starts_at = Utc::now();
+        let ends_at =
+            starts_at + chrono::Duration::from_std(duration).map_err(|_| "silence is too long")?;
+        self.add_window(matchers, starts_at, ends_at, created_by, None)
+    }
+
+    /// Silence alerts matching `matchers` between `starts_at` and
+    /// `ends_at`, as for a maintenance window planned ahead.
+    pub fn add_window(
+        &self,
+        matchers: BTreeMap<String, String>,
+        starts_at: DateTime<Utc>,
+        ends_at: DateTime<Utc>,
+        created_by: &str,
+        comment: Option<String>,
+    ) -> Result<Silence, String> {
+        if matchers.is_empty() {
+            return Err("a silence needs at least one matcher".to_string());
+        }
+        let now = Utc::now();
+        if ends_at <= starts_at || ends_at <= now {
+            return Err("a silence must end after it starts, and in the future".to_string());
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let silence = Silence {
+            id: state.next_id,
+            matchers,
+            starts_at,
+            ends_at,
+            created_by: created_by.to_string(),
+            comment,
+        };
+        state.next_id += 1;
+        state.silences.retain(|s| now < s.ends_at);
+        state.silences.push(silence.clone());
+        self.save(&state.silences);
+        info!(
+            "{} silenced {} from {} until {} (silence {})",
+            created_by,
+            silence.describe(),
+            starts_at.to_rfc3339(),
+            ends_at.to_rfc3339(),
+            silence.id
+        );
+        Ok(silence)
+    }
+
+    /// End the silence `id` early. Planned silences are left to the
+    /// configuration.
+    pub fn remove(&self, id: u64, removed_by: &str) -> Result<Silence, String> {
+        let mut state = self.state.lock().unwrap();
+        if state.planned.iter().any(|s| s.id == id) {
+            return Err(format!(
+                "silence {} is planned in the configuration, and ends when removed from it",
+                id
+            ));
+        }
+        let index = state
+            .silences
+            .iter()
+            .position(|s| s.id == id)
+            .ok_or_else(|| format!("no silence {}", id))?;
+        let silence = state.silences.remove(index);
+        self.save(&state.silences);
+        info!(
+            "{} removed silence {} of {}",
+            removed_by,
+            id,
+            silence.describe()
+        );
+        Ok(silence)
+    }
+
+    /// Silences in force now, oldest first.
+    pub fn active(&self) -> Vec<Silence> {
+        let now = Utc::now();
+        let state = self.state.lock().unwrap();
+        let mut active: Vec<Silence> = state
+            .planned
+            .iter()
+            .chain(&state.silences)
+            .filter(|s| s.is_active(now))
+            .cloned()
+            .collect();
+        active.sort_by_key(|s| s.starts_at);
+        active
+    }
+
+    /// Silences in force now or still to come, soonest first.
+    pub fn unexpired(&self) -> Vec<Silence> {
+        let now = Utc::now();
+        let state = self.state.lock().unwrap();
+        let mut unexpired: Vec<Silence> = state
+            .planned
+            .iter()
+            .chain(&state.silences)
+            .filter(|s| now < s.ends_at)
+            .cloned()
+            .collect();
+        unexpired.sort_by_key(|s| s.starts_at);
+        unexpired
+    }
+
+    /// The first active silence matching the alert, given in its serialized
+    /// form as for `alert::matches`.
+    pub fn silencing(&self, alert: &Value) -> Option<Silence> {
+        let now = Utc::now();
+        let state = self.state.lock().unwrap();
+        state
+            .planned
+            .iter()
+            .chain(&state.silences)
+            .find(|s| s.is_active(now) && alert::matches(alert, &s.matchers))
+            .cloned()
+    }
+
+    /// Best effort: losing the file only means silences end at a restart.
+    fn save(&self, silences: &[Silence]) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let tmp = path.with_extension("tmp");
+        let result = serde_json::to_string(silences)
+            .map_err(|e| e.to_string())
+            .and_then(|raw| fs::write(&tmp, raw).map_err(|e| e.to_string()))
+            .and_then(|_| fs::rename(&tmp, path).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            warn!("Unable to save silences to {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations_in_each_unit() {
+        let secs = |text| parse_duration(text).map(|d| d.as_secs());
+        assert_eq!(secs("90s"), Some(90));
+        assert_eq!(secs("30m"), Some(30 * 60));
+        assert_eq!(secs("2h"), Some(2 * 60 * 60));
+        assert_eq!(secs("1d"), Some(24 * 60 * 60));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        for text in &[
+            "",
+            "s",
+            "0m",
+            "5",
+            "5w",
+            "1.5h",
+            "-1h",
+            "h1",
+            "5 m",
+            "99999999999999999d",
+        ] {
+            assert_eq!(parse_duration(text), None, "{} parsed", text);
+        }
+    }
+}
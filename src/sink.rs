@@ -0,0 +1,154 @@
+//! Destinations alerts are delivered to. The router renders each alert once
+//! as a `Notification` and hands it to every `Sink` its route names.
+
+pub mod courier;
+pub mod discord;
+pub mod dryrun;
+pub mod email;
+pub mod google_chat;
+pub mod jira;
+pub mod matrix;
+pub mod mattermost;
+pub mod opsgenie;
+pub mod pagerduty;
+pub mod pushover;
+pub mod servicenow;
+pub mod slack;
+pub mod sns;
+pub mod syslog;
+pub mod teams;
+pub mod telegram;
+pub mod twilio;
+pub mod webex;
+pub mod webhook;
+
+use crate::alert::{Alert, Severity};
+use crate::config::HttpConfig;
+use discord::Discord;
+use email::Email;
+use futures::future::BoxFuture;
+use google_chat::GoogleChat;
+use jira::Jira;
+use matrix::Matrix;
+use mattermost::Mattermost;
+use opsgenie::Opsgenie;
+use pagerduty::Events;
+use pushover::Pushover;
+use reqwest::Client;
+use serde_json::Value;
+use servicenow::ServiceNow;
+use slack::Slack;
+use sns::Sns;
+use syslog::Syslog;
+use teams::Teams;
+use telegram::Telegram;
+use twilio::Twilio;
+use webex::Webex;
+use webhook::Webhook;
+
+pub enum SinkError {
+    /// Worth retrying as-is: network trouble or a server-side failure.
+    Transient(String),
+    /// The destination refused the notification itself; resending it
+    /// unchanged will fail again.
+    Rejected(String),
+}
+
+impl std::fmt::Display for SinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SinkError::Transient(e) | SinkError::Rejected(e) => f.write_str(e),
+        }
+    }
+}
+
+/// How a delivery came out, as metrics and the audit log name it.
+pub fn outcome(result: &Result<DeliveryReceipt, SinkError>) -> &'static str {
+    match result {
+        Ok(_) => "delivered",
+        Err(SinkError::Transient(_)) => "failed",
+        Err(SinkError::Rejected(_)) => "rejected",
+    }
+}
+
+/// An alert, or a digest or summary of several, rendered and ready to go
+/// out.
+#[derive(Clone)]
+pub struct Notification {
+    /// What it is about: one alert, several for a digest, or none for a
+    /// summary of suppressed notifications.
+    pub alerts: Vec<Alert>,
+    /// The most severe of them.
+    pub severity: Severity,
+    /// One line saying what it is about, for destinations that show a
+    /// title.
+    pub summary: String,
+    /// The message as the templates rendered it, in Slack's format.
+    pub body: Value,
+}
+
+/// What a sink reports about a notification it delivered.
+#[derive(Default)]
+pub struct DeliveryReceipt {
+    /// Where the notification ended up and what the destination calls it,
+    /// for destinations that say: a Slack channel and message timestamp.
+    pub channel: Option<String>,
+    pub id: Option<String>,
+}
+
+/// Somewhere notifications can be delivered.
+pub trait Sink: Send + Sync {
+    /// Names the destination in a form that is safe to log.
+    fn name(&self) -> &str;
+
+    /// Deliver `rendered`, retrying transient failures as the destination
+    /// allows before giving up on this attempt.
+    fn deliver(&self, rendered: Notification) -> BoxFuture<'_, Result<DeliveryReceipt, SinkError>>;
+}
+
+/// Clients for each kind of destination, shared by every delivery.
+pub struct Sinks {
+    pub slack: Slack,
+    pub teams: Teams,
+    pub discord: Discord,
+    pub google_chat: GoogleChat,
+    pub mattermost: Mattermost,
+    pub telegram: Telegram,
+    pub matrix: Matrix,
+    pub webex: Webex,
+    pub pushover: Pushover,
+    pub twilio: Twilio,
+    pub sns: Sns,
+    pub pagerduty: Events,
+    pub opsgenie: Opsgenie,
+    pub jira: Jira,
+    pub servicenow: ServiceNow,
+    pub syslog: Syslog,
+    pub email: Email,
+    pub webhook: Webhook,
+}
+
+impl Sinks {
+    pub fn new(client: Client, http: &HttpConfig) -> Sinks {
+        Sinks {
+            slack: Slack::new(client.clone(), http),
+            teams: Teams::new(client.clone(), http),
+            discord: Discord::new(client.clone(), http),
+            google_chat: GoogleChat::new(client.clone(), http),
+            mattermost: Mattermost::new(client.clone(), http),
+            telegram: Telegram::new(client.clone(), http),
+            matrix: Matrix::new(client.clone(), http),
+            webex: Webex::new(client.clone(), http),
+            pushover: Pushover::new(client.clone(), http),
+            twilio: Twilio::new(client.clone(), http),
+            sns: Sns::new(client.clone(), http),
+            pagerduty: Events::new(client.clone(), http),
+            opsgenie: Opsgenie::new(client.clone(), http),
+            jira: Jira::new(client.clone(), http),
+            servicenow: ServiceNow::new(client.clone(), http),
+            syslog: Syslog::new(http),
+            email: Email::new(http),
+            webhook: Webhook::new(client, http),
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! Spacing, rate limiting and retries for requests to chat services, shared
+//! by the sinks that post over HTTP.
+
+use crate::backoff::Backoff;
+use crate::config::HttpConfig;
+use crate::sink::SinkError;
+use log::warn;
+use reqwest::header::RETRY_AFTER;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::time::{delay_until, Duration, Instant};
+
+/// Used when a 429 arrives without a usable Retry-After header.
+pub const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(30);
+/// How many times to wait out a 429 before handing the message back.
+const MAX_RATE_LIMITED_RETRIES: u32 = 3;
+
+/// Why a single request did not go through.
+pub enum Failure {
+    /// The destination asked us to wait this long before trying again.
+    RateLimited(Duration),
+    Failed(SinkError),
+}
+
+impl From<SinkError> for Failure {
+    fn from(e: SinkError) -> Failure {
+        Failure::Failed(e)
+    }
+}
+
+/// The wait a 429 response asks for.
+pub fn retry_after(res: &reqwest::Response) -> Duration {
+    res.headers()
+        .get(RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_RETRY_AFTER)
+}
+
+/// Spaces requests to each destination a minimum interval apart, pauses a
+/// destination entirely while it is rate limiting us, and retries transient
+/// failures with backoff.
+pub struct Courier {
+    retries: u32,
+    retry_initial_delay: Duration,
+    retry_max_delay: Duration,
+    /// Earliest time the next request may go to each destination.
+    next_send: std::sync::Mutex<HashMap<String, Arc<Mutex<Instant>>>>,
+}
+
+impl Courier {
+    pub fn new(http: &HttpConfig) -> Courier {
+        Courier {
+            retries: http.retries,
+            retry_initial_delay: http.retry_initial_delay,
+            retry_max_delay: http.retry_max_delay,
+            next_send: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn next_send(&self, key: String) -> Arc<Mutex<Instant>> {
+        self.next_send
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_insert_with(|| Arc::new(Mutex::new(Instant::now())))
+            .clone()
+    }
+
+    /// Make requests with `request` until one goes through or fails for
+    /// good, at most one per `interval`. `key` identifies the destination
+    /// and is never logged, as it may be a webhook URL; `service` names it
+    /// in log messages.
+    pub async fn send<T, F, Fut>(
+        &self,
+        service: &str,
+        key: String,
+        interval: Duration,
+        mut request: F,
+    ) -> Result<T, SinkError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, Failure>>,
+    {
+        let next_send = self.next_send(key);
+        let mut backoff = Backoff::new(self.retry_initial_delay, self.retry_max_delay);
+        let mut retries = 0;
+        let mut rate_limited = 0;
+        loop {
+            {
+                // Holding the lock while we wait queues up other senders to
+                // the same destination behind us.
+                let mut next = next_send.lock().await;
+                delay_until(*next).await;
+                *next = Instant::now() + interval;
+            }
+
+            let error = match request().await {
+                Ok(sent) => return Ok(sent),
+                Err(Failure::RateLimited(wait)) if rate_limited < MAX_RATE_LIMITED_RETRIES => {
+                    rate_limited += 1;
+                    warn!(
+                        "Rate limited by {}, pausing destination for {:?}",
+                        service, wait
+                    );
+                    *next_send.lock().await = Instant::now() + wait;
+                    continue;
+                }
+                Err(Failure::RateLimited(_)) => {
+                    SinkError::Transient(format!("still rate limited by {}", service))
+                }
+                Err(Failure::Failed(e)) => e,
+            };
+            match error {
+                SinkError::Transient(e) if retries < self.retries => {
+                    retries += 1;
+                    rate_limited = 0;
+                    let delay = backoff.next_delay();
+                    warn!("{}; retrying in {:?}", e, delay);
+                    tokio::time::delay_for(delay).await;
+                }
+                e => return Err(e),
+            }
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! Discord, through channel webhooks taking embeds.
+
+use crate::alert::{Alert, Severity, Status};
+use crate::config::HttpConfig;
+use crate::sink::courier::{self, Courier, Failure};
+use crate::sink::{DeliveryReceipt, Notification, Sink, SinkError};
+use futures::future::BoxFuture;
+use log::debug;
+use reqwest::{Client, StatusCode};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// Discord allows five requests every two seconds per webhook.
+const MIN_INTERVAL: Duration = Duration::from_millis(400);
+/// Discord's limits on a message: embeds, fields per embed, and the
+/// lengths of a title, a description and a field value.
+const MAX_EMBEDS: usize = 10;
+const MAX_FIELDS: usize = 25;
+const MAX_TITLE: usize = 256;
+const MAX_DESCRIPTION: usize = 4096;
+const MAX_FIELD_VALUE: usize = 1024;
+
+/// The embed colour for an alert of `severity` in `status`.
+fn color(severity: Severity, status: Status) -> u32 {
+    match (status, severity) {
+        (Status::Resolved, _) => 0x2e_b6_7d,
+        (Status::Firing, Severity::Critical) => 0xe0_1e_5a,
+        (Status::Firing, Severity::Warning) => 0xec_b2_2e,
+        (Status::Firing, Severity::Info) => 0x36_c5_f0,
+    }
+}
+
+fn truncate(text: &str, max: usize) -> String {
+    text.chars().take(max).collect()
+}
+
+fn field(name: &str, value: impl ToString) -> Value {
+    json!({
+        "name": name,
+        "value": truncate(&value.to_string(), MAX_FIELD_VALUE),
+        "inline": true,
+    })
+}
+
+/// `alert` as an embed: its title, its reason as the description, and a
+/// field for each of its details.
+fn embed(alert: &Alert) -> Value {
+    let mut fields = vec![field("Severity", alert.severity)];
+    if alert.status == Status::Resolved {
+        fields.push(field("Status", "resolved"));
+    }
+    for (name, value) in [
+        ("Source", &alert.source),
+        ("Step", &alert.step),
+        ("Run", &alert.run_id),
+    ] {
+        if let Some(value) = value {
+            fields.push(field(name, value));
+        }
+    }
+    for (key, value) in &alert.fields {
+        let value = match value {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        fields.push(field(key, value));
+    }
+    fields.truncate(MAX_FIELDS);
+
+    let mut embed = json!({
+        "title": truncate(&alert.title, MAX_TITLE),
+        "color": color(alert.severity, alert.status),
+        "fields": fields,
+        "timestamp": alert.timestamp.to_rfc3339(),
+    });
+    if let Some(reason) = &alert.reason {
+        embed["description"] = json!(truncate(reason, MAX_DESCRIPTION));
+    }
+    embed
+}
+
+/// `rendered` as a webhook message: an embed for its alert, or its summary
+/// with an embed for each alert in a digest.
+pub fn message(rendered: &Notification) -> Value {
+    match rendered.alerts.as_slice() {
+        [alert] => json!({ "embeds": [embed(alert)] }),
+        alerts => {
+            let mut content = rendered.summary.clone();
+            if alerts.len() > MAX_EMBEDS {
+                content.push_str(&format!(" ({} not shown)", alerts.len() - MAX_EMBEDS));
+            }
+            let embeds: Vec<Value> = alerts.iter().take(MAX_EMBEDS).map(embed).collect();
+            json!({ "content": content, "embeds": embeds })
+        }
+    }
+}
+
+/// Posts messages to Discord webhooks.
+pub struct Discord {
+    client: Client,
+    courier: Courier,
+}
+
+impl Discord {
+    pub fn new(client: Client, http: &HttpConfig) -> Discord {
+        Discord {
+            client,
+            courier: Courier::new(http),
+        }
+    }
+
+    /// Post `message` to the webhook at `url`, retrying transient failures
+    /// a few times before giving up on this attempt.
+    pub async fn post(&self, url: &str, message: &Value) -> Result<(), SinkError> {
+        self.courier
+            .send("Discord", url.to_string(), MIN_INTERVAL, || {
+                self.post_once(url, message)
+            })
+            .await
+    }
+
+    async fn post_once(&self, url: &str, message: &Value) -> Result<(), Failure> {
+        let res = self
+            .client
+            .post(url)
+            .json(message)
+            .send()
+            .await
+            .map_err(|e| SinkError::Transient(format!("failed sending to Discord: {}", e)))?;
+
+        match res.status() {
+            StatusCode::TOO_MANY_REQUESTS => Err(Failure::RateLimited(courier::retry_after(&res))),
+            status if status.is_success() => {
+                debug!("Message acknowledged by Discord");
+                Ok(())
+            }
+            status if status.is_client_error() => {
+                Err(SinkError::Rejected(format!("Discord returned {}", status)).into())
+            }
+            status => Err(SinkError::Transient(format!("Discord returned {}", status)).into()),
+        }
+    }
+}
+
+/// A Discord webhook as a `Sink`.
+pub struct DiscordSink<'a> {
+    discord: &'a Discord,
+    url: &'a str,
+    /// See `QueueConfig::destination`.
+    name: String,
+}
+
+impl<'a> DiscordSink<'a> {
+    pub fn new(discord: &'a Discord, url: &'a str, name: String) -> DiscordSink<'a> {
+        DiscordSink { discord, url, name }
+    }
+}
+
+impl Sink for DiscordSink<'_> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn deliver(&self, rendered: Notification) -> BoxFuture<'_, Result<DeliveryReceipt, SinkError>> {
+        Box::pin(async move {
+            self.discord.post(self.url, &message(&rendered)).await?;
+            Ok(DeliveryReceipt::default())
+        })
+    }
+}
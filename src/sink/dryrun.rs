@@ -0,0 +1,35 @@
+//! Nowhere: what would have been delivered is logged instead, for trying a
+//! configuration against live traffic with `--dry-run`.
+
+use crate::sink::{DeliveryReceipt, Notification, Sink, SinkError};
+use futures::future::BoxFuture;
+use log::info;
+
+/// Logs each notification with the destination it was meant for.
+pub struct DryRunSink {
+    name: String,
+}
+
+impl DryRunSink {
+    pub fn new(name: String) -> DryRunSink {
+        DryRunSink { name }
+    }
+}
+
+impl Sink for DryRunSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn deliver(&self, rendered: Notification) -> BoxFuture<'_, Result<DeliveryReceipt, SinkError>> {
+        Box::pin(async move {
+            info!(
+                "Dry run: not delivering {} to {}: {}",
+                rendered.summary, self.name, rendered.body
+            );
+            // No receipt, so that nothing is threaded under or updates a
+            // message that was never posted.
+            Ok(DeliveryReceipt::default())
+        })
+    }
+}
@@ -0,0 +1,171 @@
+//! Email over SMTP, for teams that need a trail of alerts in their inboxes.
+
+use crate::config::{EmailConfig, EmailReceiver, HttpConfig, SmtpTls};
+use crate::render::Templates;
+use crate::sink::courier::{Courier, Failure};
+use crate::sink::{DeliveryReceipt, Notification, Sink, SinkError};
+use futures::future::BoxFuture;
+use lettre::message::{header, Mailbox, MultiPart, SinglePart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::client::{Tls, TlsParameters};
+use lettre::transport::smtp::Error as SmtpError;
+use lettre::{AsyncSmtpTransport, Message, Tokio02Connector, Tokio02Transport};
+use log::debug;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Keeps a burst of alerts from looking like spam to the mail server.
+const MIN_INTERVAL: Duration = Duration::from_millis(200);
+
+fn mailbox(address: &str) -> Result<Mailbox, SinkError> {
+    address
+        .parse()
+        .map_err(|e| SinkError::Rejected(format!("invalid email address {}: {}", address, e)))
+}
+
+/// 4xx replies and trouble reaching the server are worth another try;
+/// anything else, such as a refused recipient or bad credentials, is not.
+fn error(e: SmtpError) -> SinkError {
+    let message = format!("failed sending email: {}", e);
+    match e {
+        SmtpError::Transient(_) | SmtpError::Io(_) | SmtpError::Resolution => {
+            SinkError::Transient(message)
+        }
+        _ => SinkError::Rejected(message),
+    }
+}
+
+/// A client for the mail server in `server`. Connections are made afresh
+/// for each email, so a reloaded configuration applies at once.
+fn transport(server: &EmailConfig) -> Result<AsyncSmtpTransport<Tokio02Connector>, SinkError> {
+    let parameters = || {
+        TlsParameters::new(server.host.clone())
+            .map_err(|e| SinkError::Rejected(format!("unable to set up TLS: {}", e)))
+    };
+    let tls = match server.tls {
+        SmtpTls::Wrapper => Tls::Wrapper(parameters()?),
+        SmtpTls::Starttls => Tls::Required(parameters()?),
+        SmtpTls::None => Tls::None,
+    };
+    let mut builder = AsyncSmtpTransport::<Tokio02Connector>::builder_dangerous(&server.host)
+        .port(server.port)
+        .tls(tls);
+    if let (Some(username), Some(password)) = (&server.username, &server.password) {
+        builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+    }
+    Ok(builder.build())
+}
+
+/// The email for `rendered` to `recipients`, with plain text and HTML
+/// alternatives.
+fn message(
+    server: &EmailConfig,
+    recipients: &EmailReceiver,
+    templates: &Templates,
+    rendered: &Notification,
+) -> Result<Message, SinkError> {
+    let body = templates
+        .render_email(rendered)
+        .map_err(SinkError::Rejected)?;
+    let mut builder = Message::builder()
+        .from(mailbox(&server.from)?)
+        .subject(body.subject);
+    for address in &recipients.to {
+        builder = builder.to(mailbox(address)?);
+    }
+    for address in &recipients.cc {
+        builder = builder.cc(mailbox(address)?);
+    }
+    let part = |content_type: &str, body: String| {
+        SinglePart::builder()
+            .header(header::ContentType(
+                content_type.parse().expect("valid content type"),
+            ))
+            .body(body)
+    };
+    builder
+        .multipart(
+            MultiPart::alternative()
+                .singlepart(part("text/plain; charset=utf-8", body.text))
+                .singlepart(part("text/html; charset=utf-8", body.html)),
+        )
+        .map_err(|e| SinkError::Rejected(format!("unable to build email: {}", e)))
+}
+
+/// Sends emails, spaced and retried like requests to the chat services.
+pub struct Email {
+    courier: Courier,
+}
+
+impl Email {
+    pub fn new(http: &HttpConfig) -> Email {
+        Email {
+            courier: Courier::new(http),
+        }
+    }
+
+    /// Send `message` through `server`, retrying transient failures a few
+    /// times before giving up on this attempt.
+    pub async fn send(&self, server: &EmailConfig, message: Message) -> Result<(), SinkError> {
+        let transport = transport(server)?;
+        let envelope = message.envelope().clone();
+        let raw = message.formatted();
+        self.courier
+            .send(
+                "the mail server",
+                server.host.clone(),
+                MIN_INTERVAL,
+                || async {
+                    transport
+                        .send_raw(&envelope, &raw)
+                        .await
+                        .map(|_| ())
+                        .map_err(|e| Failure::Failed(error(e)))
+                },
+            )
+            .await
+    }
+}
+
+/// A list of email recipients as a `Sink`. Digests go out as one email.
+pub struct EmailSink<'a> {
+    email: &'a Email,
+    server: &'a EmailConfig,
+    recipients: &'a EmailReceiver,
+    templates: Arc<Templates>,
+    /// See `QueueConfig::destination`.
+    name: String,
+}
+
+impl<'a> EmailSink<'a> {
+    pub fn new(
+        email: &'a Email,
+        server: &'a EmailConfig,
+        recipients: &'a EmailReceiver,
+        templates: Arc<Templates>,
+        name: String,
+    ) -> EmailSink<'a> {
+        EmailSink {
+            email,
+            server,
+            recipients,
+            templates,
+            name,
+        }
+    }
+}
+
+impl Sink for EmailSink<'_> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn deliver(&self, rendered: Notification) -> BoxFuture<'_, Result<DeliveryReceipt, SinkError>> {
+        Box::pin(async move {
+            let message = message(self.server, self.recipients, &self.templates, &rendered)?;
+            self.email.send(self.server, message).await?;
+            debug!("Email accepted by {}", self.server.host);
+            Ok(DeliveryReceipt::default())
+        })
+    }
+}
@@ -0,0 +1,226 @@
+//! Google Chat, through space webhooks taking Cards v2.
+
+use crate::alert::{Alert, Severity, Status};
+use crate::config::HttpConfig;
+use crate::render::escape_html;
+use crate::sink::courier::{self, Courier, Failure};
+use crate::sink::{DeliveryReceipt, Notification, Sink, SinkError};
+use futures::future::BoxFuture;
+use log::debug;
+use reqwest::{Client, StatusCode};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// Google Chat allows one message a second to each space.
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+/// Alerts listed in a digest's card; the rest are counted in its header.
+const MAX_DIGEST_ALERTS: usize = 50;
+/// Longest reason shown in full; longer ones are cut short.
+const MAX_REASON: usize = 2000;
+
+/// The colour for an alert of `severity` in `status`.
+fn color(severity: Severity, status: Status) -> &'static str {
+    match (status, severity) {
+        (Status::Resolved, _) => "#2eb67d",
+        (Status::Firing, Severity::Critical) => "#e01e5a",
+        (Status::Firing, Severity::Warning) => "#ecb22e",
+        (Status::Firing, Severity::Info) => "#36c5f0",
+    }
+}
+
+fn truncate(text: &str, max: usize) -> String {
+    match text.char_indices().nth(max) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+/// A label and value, as card text.
+fn detail(label: &str, value: &str) -> Value {
+    json!({
+        "decoratedText": {
+            "topLabel": label,
+            "text": escape_html(value),
+            "wrapText": true,
+        }
+    })
+}
+
+/// `status` and `severity` as coloured card text.
+fn status_text(severity: Severity, status: Status) -> String {
+    let label = match status {
+        Status::Firing => severity.to_string().to_uppercase(),
+        Status::Resolved => "RESOLVED".to_string(),
+    };
+    format!(
+        "<font color=\"{}\"><b>{}</b></font>",
+        color(severity, status),
+        label
+    )
+}
+
+/// The card for `alert`: its title and status in the header, then its
+/// reason and a line for each of its details.
+fn alert_card(alert: &Alert) -> Value {
+    let mut sections = Vec::new();
+    if let Some(reason) = &alert.reason {
+        sections.push(json!({
+            "widgets": [{
+                "textParagraph": { "text": escape_html(&truncate(reason, MAX_REASON)) }
+            }]
+        }));
+    }
+    let mut details = vec![json!({
+        "decoratedText": {
+            "topLabel": "Status",
+            "text": status_text(alert.severity, alert.status),
+        }
+    })];
+    for (name, value) in [
+        ("Source", &alert.source),
+        ("Step", &alert.step),
+        ("Run", &alert.run_id),
+    ] {
+        if let Some(value) = value {
+            details.push(detail(name, value));
+        }
+    }
+    for (key, value) in &alert.fields {
+        let value = match value {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        details.push(detail(key, &value));
+    }
+    details.push(detail(
+        "Time",
+        &alert.timestamp.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+    ));
+    sections.push(json!({ "header": "Details", "widgets": details }));
+
+    json!({
+        "header": { "title": alert.title },
+        "sections": sections,
+    })
+}
+
+/// The card for a digest: its summary in the header and a line for each
+/// alert.
+fn digest_card(rendered: &Notification) -> Value {
+    let alerts = &rendered.alerts;
+    let widgets: Vec<Value> = alerts
+        .iter()
+        .take(MAX_DIGEST_ALERTS)
+        .map(|alert| {
+            let mut text = json!({
+                "topLabel": alert.severity.to_string(),
+                "text": format!("<b>{}</b>", escape_html(&alert.title)),
+                "wrapText": true,
+            });
+            if let Some(reason) = &alert.reason {
+                text["bottomLabel"] = json!(truncate(reason, 200));
+            }
+            json!({ "decoratedText": text })
+        })
+        .collect();
+    let mut header = json!({ "title": rendered.summary });
+    if alerts.len() > MAX_DIGEST_ALERTS {
+        header["subtitle"] = json!(format!("{} not shown", alerts.len() - MAX_DIGEST_ALERTS));
+    }
+    let mut card = json!({ "header": header });
+    if !widgets.is_empty() {
+        card["sections"] = json!([{ "widgets": widgets }]);
+    }
+    card
+}
+
+/// `rendered` as a message holding a card: the details of its alert, or a
+/// line for each alert in a digest.
+pub fn message(rendered: &Notification) -> Value {
+    let card = match rendered.alerts.as_slice() {
+        [alert] => alert_card(alert),
+        _ => digest_card(rendered),
+    };
+    json!({
+        "text": rendered.summary,
+        "cardsV2": [{ "cardId": "alert", "card": card }],
+    })
+}
+
+/// Posts cards to Google Chat webhooks.
+pub struct GoogleChat {
+    client: Client,
+    courier: Courier,
+}
+
+impl GoogleChat {
+    pub fn new(client: Client, http: &HttpConfig) -> GoogleChat {
+        GoogleChat {
+            client,
+            courier: Courier::new(http),
+        }
+    }
+
+    /// Post `message` to the webhook at `url`, retrying transient failures
+    /// a few times before giving up on this attempt.
+    pub async fn post(&self, url: &str, message: &Value) -> Result<(), SinkError> {
+        self.courier
+            .send("Google Chat", url.to_string(), MIN_INTERVAL, || {
+                self.post_once(url, message)
+            })
+            .await
+    }
+
+    async fn post_once(&self, url: &str, message: &Value) -> Result<(), Failure> {
+        let res = self
+            .client
+            .post(url)
+            .json(message)
+            .send()
+            .await
+            .map_err(|e| SinkError::Transient(format!("failed sending to Google Chat: {}", e)))?;
+
+        match res.status() {
+            StatusCode::TOO_MANY_REQUESTS => Err(Failure::RateLimited(courier::retry_after(&res))),
+            status if status.is_success() => {
+                debug!("Message accepted by Google Chat");
+                Ok(())
+            }
+            status if status.is_client_error() => {
+                Err(SinkError::Rejected(format!("Google Chat returned {}", status)).into())
+            }
+            status => Err(SinkError::Transient(format!("Google Chat returned {}", status)).into()),
+        }
+    }
+}
+
+/// A Google Chat space webhook as a `Sink`.
+pub struct GoogleChatSink<'a> {
+    google_chat: &'a GoogleChat,
+    url: &'a str,
+    /// See `QueueConfig::destination`.
+    name: String,
+}
+
+impl<'a> GoogleChatSink<'a> {
+    pub fn new(google_chat: &'a GoogleChat, url: &'a str, name: String) -> GoogleChatSink<'a> {
+        GoogleChatSink {
+            google_chat,
+            url,
+            name,
+        }
+    }
+}
+
+impl Sink for GoogleChatSink<'_> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn deliver(&self, rendered: Notification) -> BoxFuture<'_, Result<DeliveryReceipt, SinkError>> {
+        Box::pin(async move {
+            self.google_chat.post(self.url, &message(&rendered)).await?;
+            Ok(DeliveryReceipt::default())
+        })
+    }
+}
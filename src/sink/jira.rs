@@ -0,0 +1,329 @@
+//! Jira, through the REST API: an issue for each check, commented on and
+//! transitioned once the check recovers. Issues carry a label identifying
+//! their check, so the open issue for a check is found with a search
+//! rather than kept track of here.
+
+use crate::alert::{Alert, Status};
+use crate::config::{HttpConfig, JiraReceiver};
+use crate::render::Templates;
+use crate::sink::courier::{self, Courier, Failure};
+use crate::sink::{DeliveryReceipt, Notification, Sink, SinkError};
+use futures::future::BoxFuture;
+use log::{debug, warn};
+use reqwest::{Client, Method, StatusCode, Url};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Keeps well within Jira Cloud's limits on API requests.
+const MIN_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Deserialize)]
+struct SearchResponse {
+    issues: Vec<IssueRef>,
+}
+
+#[derive(Deserialize)]
+struct IssueRef {
+    key: String,
+}
+
+#[derive(Deserialize)]
+struct TransitionsResponse {
+    transitions: Vec<Transition>,
+}
+
+#[derive(Deserialize)]
+struct Transition {
+    id: String,
+    name: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct ErrorResponse {
+    #[serde(default)]
+    error_messages: Vec<String>,
+    #[serde(default)]
+    errors: serde_json::Map<String, Value>,
+}
+
+/// The label identifying the check with `fingerprint`, as labels cannot
+/// hold spaces or be very long.
+fn label(fingerprint: &str) -> String {
+    let hash = hex::encode(Sha256::digest(fingerprint.as_bytes()));
+    format!("2steps-{}", &hash[..16])
+}
+
+/// `project`'s REST API URL for the path made of `segments`.
+fn url(project: &JiraReceiver, segments: &[&str]) -> Result<Url, SinkError> {
+    let invalid = || SinkError::Rejected("invalid Jira URL".to_string());
+    let mut url = Url::parse(&project.url).map_err(|_| invalid())?;
+    url.path_segments_mut()
+        .map_err(|_| invalid())?
+        .pop_if_empty()
+        .extend(&["rest", "api", "2"])
+        .extend(segments);
+    Ok(url)
+}
+
+/// What Jira said was wrong with a request, or `status` if it did not say.
+fn describe(status: StatusCode, error: ErrorResponse) -> String {
+    let mut messages = error.error_messages;
+    for (field, message) in error.errors {
+        match message {
+            Value::String(message) => messages.push(format!("{}: {}", field, message)),
+            other => messages.push(format!("{}: {}", field, other)),
+        }
+    }
+    if messages.is_empty() {
+        status.to_string()
+    } else {
+        messages.join("; ")
+    }
+}
+
+/// Opens, comments on and transitions Jira issues.
+pub struct Jira {
+    client: Client,
+    courier: Courier,
+}
+
+impl Jira {
+    pub fn new(client: Client, http: &HttpConfig) -> Jira {
+        Jira {
+            client,
+            courier: Courier::new(http),
+        }
+    }
+
+    /// Send a request to `url`, retrying transient failures a few times
+    /// before giving up on this attempt, and return the response body.
+    async fn request(
+        &self,
+        project: &JiraReceiver,
+        method: Method,
+        url: Url,
+        body: Option<&Value>,
+    ) -> Result<Value, SinkError> {
+        self.courier
+            .send("Jira", project.url.clone(), MIN_INTERVAL, || {
+                self.request_once(project, method.clone(), url.clone(), body)
+            })
+            .await
+    }
+
+    async fn request_once(
+        &self,
+        project: &JiraReceiver,
+        method: Method,
+        url: Url,
+        body: Option<&Value>,
+    ) -> Result<Value, Failure> {
+        let mut req = self.client.request(method, url);
+        req = match &project.username {
+            Some(username) => req.basic_auth(username, Some(&project.token)),
+            None => req.bearer_auth(&project.token),
+        };
+        if let Some(body) = body {
+            req = req.json(body);
+        }
+        let res = req
+            .send()
+            .await
+            .map_err(|e| SinkError::Transient(format!("failed sending to Jira: {}", e)))?;
+
+        let status = res.status();
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            return Err(Failure::RateLimited(courier::retry_after(&res)));
+        }
+        let text = res
+            .text()
+            .await
+            .map_err(|e| SinkError::Transient(format!("unreadable response from Jira: {}", e)))?;
+        if status.is_success() {
+            if text.trim().is_empty() {
+                return Ok(Value::Null);
+            }
+            return serde_json::from_str(&text).map_err(|e| {
+                SinkError::Transient(format!("unreadable response from Jira: {}", e)).into()
+            });
+        }
+        let error = describe(status, serde_json::from_str(&text).unwrap_or_default());
+        if status.is_client_error() {
+            Err(SinkError::Rejected(format!("Jira returned {}", error)).into())
+        } else {
+            Err(SinkError::Transient(format!("Jira returned {}", error)).into())
+        }
+    }
+
+    /// The key of the unresolved issue in `project` labelled `label`, if
+    /// there is one.
+    async fn open_issue(
+        &self,
+        project: &JiraReceiver,
+        label: &str,
+    ) -> Result<Option<String>, SinkError> {
+        let mut url = url(project, &["search"])?;
+        url.query_pairs_mut()
+            .append_pair(
+                "jql",
+                &format!(
+                    "project = \"{}\" AND labels = \"{}\" AND statusCategory != Done \
+                     ORDER BY created DESC",
+                    project.project, label
+                ),
+            )
+            .append_pair("fields", "key")
+            .append_pair("maxResults", "1");
+        let body = self.request(project, Method::GET, url, None).await?;
+        let search: SearchResponse = serde_json::from_value(body).map_err(|e| {
+            SinkError::Transient(format!("unreadable search results from Jira: {}", e))
+        })?;
+        Ok(search.issues.into_iter().next().map(|issue| issue.key))
+    }
+
+    /// Apply the transition named `name` to the issue `key`, if it is one
+    /// the issue can take.
+    async fn transition(
+        &self,
+        project: &JiraReceiver,
+        key: &str,
+        name: &str,
+    ) -> Result<(), SinkError> {
+        let url = url(project, &["issue", key, "transitions"])?;
+        let body = self
+            .request(project, Method::GET, url.clone(), None)
+            .await?;
+        let transitions: TransitionsResponse = serde_json::from_value(body).map_err(|e| {
+            SinkError::Transient(format!("unreadable transitions from Jira: {}", e))
+        })?;
+        match transitions
+            .transitions
+            .iter()
+            .find(|transition| transition.name.eq_ignore_ascii_case(name))
+        {
+            Some(transition) => {
+                let body = json!({ "transition": { "id": transition.id } });
+                self.request(project, Method::POST, url, Some(&body))
+                    .await?;
+                debug!("Moved Jira issue {} on with {}", key, transition.name);
+            }
+            None => warn!("Jira issue {} has no transition named {}", key, name),
+        }
+        Ok(())
+    }
+
+    /// Open an issue for `alert` if it is firing and has none open, or
+    /// comment on and transition its open issue if it has recovered.
+    pub async fn update(
+        &self,
+        project: &JiraReceiver,
+        templates: &Templates,
+        alert: &Alert,
+    ) -> Result<(), SinkError> {
+        let label = label(&alert.key());
+        let open = self.open_issue(project, &label).await?;
+        match (alert.status, open) {
+            (Status::Firing, Some(key)) => {
+                debug!("Jira issue {} is already open for {}", key, alert.key());
+            }
+            (Status::Firing, None) => {
+                let issue = templates
+                    .render_jira(project, alert)
+                    .map_err(SinkError::Rejected)?;
+                let mut labels = project.labels.clone();
+                labels.push(label);
+                let body = json!({
+                    "fields": {
+                        "project": { "key": project.project },
+                        "issuetype": { "name": project.issue_type },
+                        "summary": issue.summary,
+                        "description": issue.description,
+                        "labels": labels,
+                    }
+                });
+                let created = self
+                    .request(
+                        project,
+                        Method::POST,
+                        url(project, &["issue"])?,
+                        Some(&body),
+                    )
+                    .await?;
+                debug!(
+                    "Opened Jira issue {} for {}",
+                    created["key"].as_str().unwrap_or("?"),
+                    alert.key()
+                );
+            }
+            (Status::Resolved, None) => {
+                debug!("No open Jira issue to resolve for {}", alert.key());
+            }
+            (Status::Resolved, Some(key)) => {
+                let at = alert.resolved_at.unwrap_or(alert.timestamp);
+                let body = json!({
+                    "body": format!(
+                        "The check recovered at {}.",
+                        at.format("%Y-%m-%d %H:%M:%S UTC")
+                    )
+                });
+                let comments = url(project, &["issue", &key, "comment"])?;
+                self.request(project, Method::POST, comments, Some(&body))
+                    .await?;
+                if let Some(name) = &project.resolve_transition {
+                    // The comment is in; trying again would repeat it.
+                    if let Err(e) = self.transition(project, &key, name).await {
+                        warn!("Unable to move Jira issue {} on: {}", key, e);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A Jira project as a `Sink`. Each alert in a digest is handled on its
+/// own, and summaries about no alert in particular are left out.
+pub struct JiraSink<'a> {
+    jira: &'a Jira,
+    project: &'a JiraReceiver,
+    templates: Arc<Templates>,
+    /// See `QueueConfig::destination`.
+    name: String,
+}
+
+impl<'a> JiraSink<'a> {
+    pub fn new(
+        jira: &'a Jira,
+        project: &'a JiraReceiver,
+        templates: Arc<Templates>,
+        name: String,
+    ) -> JiraSink<'a> {
+        JiraSink {
+            jira,
+            project,
+            templates,
+            name,
+        }
+    }
+}
+
+impl Sink for JiraSink<'_> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn deliver(&self, rendered: Notification) -> BoxFuture<'_, Result<DeliveryReceipt, SinkError>> {
+        Box::pin(async move {
+            for alert in &rendered.alerts {
+                self.jira
+                    .update(self.project, &self.templates, alert)
+                    .await?;
+            }
+            Ok(DeliveryReceipt::default())
+        })
+    }
+}
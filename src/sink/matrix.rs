@@ -0,0 +1,312 @@
+//! Matrix, through the client-server API of a homeserver.
+
+use crate::alert::{Alert, Severity, Status};
+use crate::config::{HttpConfig, MatrixConfig};
+use crate::render::escape_html;
+use crate::sink::courier::{Courier, Failure, DEFAULT_RETRY_AFTER};
+use crate::sink::{DeliveryReceipt, Notification, Sink, SinkError};
+use chrono::Utc;
+use futures::future::BoxFuture;
+use log::debug;
+use reqwest::{Client, StatusCode, Url};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Spaces messages to each room; homeservers rate limit senders.
+const MIN_INTERVAL: Duration = Duration::from_millis(200);
+const HTML_FORMAT: &str = "org.matrix.custom.html";
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    #[serde(default)]
+    errcode: Option<String>,
+    #[serde(default)]
+    retry_after_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct SendResponse {
+    event_id: String,
+}
+
+#[derive(Deserialize)]
+struct DirectoryResponse {
+    room_id: String,
+}
+
+/// The colour for an alert of `severity` in `status`, for clients that
+/// honour `data-mx-color`.
+fn color(severity: Severity, status: Status) -> &'static str {
+    match (status, severity) {
+        (Status::Resolved, _) => "#2eb67d",
+        (Status::Firing, Severity::Critical) => "#e01e5a",
+        (Status::Firing, Severity::Warning) => "#ecb22e",
+        (Status::Firing, Severity::Info) => "#36c5f0",
+    }
+}
+
+/// The details of `alert` as name and value pairs.
+fn details(alert: &Alert) -> Vec<(String, String)> {
+    let mut details = vec![("Severity".to_string(), alert.severity.to_string())];
+    for (name, value) in [
+        ("Source", &alert.source),
+        ("Step", &alert.step),
+        ("Run", &alert.run_id),
+    ] {
+        if let Some(value) = value {
+            details.push((name.to_string(), value.clone()));
+        }
+    }
+    for (key, value) in &alert.fields {
+        let value = match value {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        details.push((key.clone(), value));
+    }
+    details
+}
+
+/// `alert` as plain text and as HTML.
+fn alert_message(alert: &Alert) -> (String, String) {
+    let mut text = alert.title.clone();
+    let title = escape_html(&alert.title);
+    let title = match alert.status {
+        Status::Firing => format!("<strong>{}</strong>", title),
+        Status::Resolved => format!("<strong><del>{}</del></strong> (resolved)", title),
+    };
+    let mut html = format!(
+        "<font data-mx-color=\"{}\">●</font> {}",
+        color(alert.severity, alert.status),
+        title
+    );
+    if let Some(reason) = &alert.reason {
+        text.push_str(&format!("\n{}", reason));
+        html.push_str(&format!("<br>{}", escape_html(reason)));
+    }
+    html.push_str("<ul>");
+    for (name, value) in details(alert) {
+        text.push_str(&format!("\n{}: {}", name, value));
+        html.push_str(&format!(
+            "<li><em>{}:</em> {}</li>",
+            escape_html(&name),
+            escape_html(&value)
+        ));
+    }
+    html.push_str("</ul>");
+    (text, html)
+}
+
+/// `rendered` as an `m.room.message` event content: its alert in full, or
+/// its summary and a line for each alert in a digest.
+pub fn content(rendered: &Notification) -> Value {
+    let (text, html) = match rendered.alerts.as_slice() {
+        [alert] => alert_message(alert),
+        alerts => {
+            let mut text = rendered.summary.clone();
+            let mut html = format!("<strong>{}</strong><ul>", escape_html(&rendered.summary));
+            for alert in alerts {
+                match &alert.reason {
+                    Some(reason) => {
+                        text.push_str(&format!("\n- {}: {}", alert.title, reason));
+                        html.push_str(&format!(
+                            "<li><strong>{}</strong>: {}</li>",
+                            escape_html(&alert.title),
+                            escape_html(reason)
+                        ));
+                    }
+                    None => {
+                        text.push_str(&format!("\n- {}", alert.title));
+                        html.push_str(&format!(
+                            "<li><strong>{}</strong></li>",
+                            escape_html(&alert.title)
+                        ));
+                    }
+                }
+            }
+            html.push_str("</ul>");
+            (text, html)
+        }
+    };
+    json!({
+        "msgtype": "m.notice",
+        "body": text,
+        "format": HTML_FORMAT,
+        "formatted_body": html,
+    })
+}
+
+/// Posts messages to Matrix rooms through a homeserver.
+pub struct Matrix {
+    client: Client,
+    courier: Courier,
+    /// Room IDs already looked up by alias.
+    rooms: std::sync::Mutex<HashMap<String, String>>,
+}
+
+impl Matrix {
+    pub fn new(client: Client, http: &HttpConfig) -> Matrix {
+        Matrix {
+            client,
+            courier: Courier::new(http),
+            rooms: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// `server`'s client API URL for the path made of `segments`.
+    fn url(server: &MatrixConfig, segments: &[&str]) -> Result<Url, SinkError> {
+        let invalid = || SinkError::Rejected("invalid homeserver URL".to_string());
+        let mut url = Url::parse(&server.homeserver).map_err(|_| invalid())?;
+        url.path_segments_mut()
+            .map_err(|_| invalid())?
+            .pop_if_empty()
+            .extend(&["_matrix", "client", "v3"])
+            .extend(segments);
+        Ok(url)
+    }
+
+    /// The ID of `room`, looking it up if it is an alias.
+    async fn room_id(&self, server: &MatrixConfig, room: &str) -> Result<String, SinkError> {
+        if !room.starts_with('#') {
+            return Ok(room.to_string());
+        }
+        if let Some(id) = self.rooms.lock().unwrap().get(room) {
+            return Ok(id.clone());
+        }
+
+        let res = self
+            .client
+            .get(Matrix::url(server, &["directory", "room", room])?)
+            .bearer_auth(&server.access_token)
+            .send()
+            .await
+            .map_err(|e| SinkError::Transient(format!("failed looking up Matrix room: {}", e)))?;
+        match res.status() {
+            status if status.is_success() => {}
+            status if status.is_client_error() => {
+                return Err(SinkError::Rejected(format!(
+                    "Matrix returned {} for room {}",
+                    status, room
+                )))
+            }
+            status => return Err(SinkError::Transient(format!("Matrix returned {}", status))),
+        }
+        let directory: DirectoryResponse = res
+            .json()
+            .await
+            .map_err(|e| SinkError::Transient(format!("unreadable response from Matrix: {}", e)))?;
+        self.rooms
+            .lock()
+            .unwrap()
+            .insert(room.to_string(), directory.room_id.clone());
+        Ok(directory.room_id)
+    }
+
+    /// Send `content` to `room`, retrying transient failures a few times
+    /// before giving up on this attempt.
+    pub async fn send(
+        &self,
+        server: &MatrixConfig,
+        room: &str,
+        content: &Value,
+    ) -> Result<(), SinkError> {
+        let room_id = self.room_id(server, room).await?;
+        // The same transaction ID for every attempt, so that the homeserver
+        // drops repeats of a request that did go through.
+        let txn = format!(
+            "2steps-{}-{}",
+            Utc::now().timestamp_millis(),
+            rand::random::<u32>()
+        );
+        let url = Matrix::url(server, &["rooms", &room_id, "send", "m.room.message", &txn])?;
+        self.courier
+            .send("Matrix", room_id.clone(), MIN_INTERVAL, || {
+                self.send_once(server, url.clone(), content)
+            })
+            .await
+    }
+
+    async fn send_once(
+        &self,
+        server: &MatrixConfig,
+        url: Url,
+        content: &Value,
+    ) -> Result<(), Failure> {
+        let res = self
+            .client
+            .put(url)
+            .bearer_auth(&server.access_token)
+            .json(content)
+            .send()
+            .await
+            .map_err(|e| SinkError::Transient(format!("failed sending to Matrix: {}", e)))?;
+
+        let status = res.status();
+        if status.is_success() {
+            let sent: SendResponse = res.json().await.map_err(|e| {
+                SinkError::Transient(format!("unreadable response from Matrix: {}", e))
+            })?;
+            debug!("Message acknowledged by Matrix as {}", sent.event_id);
+            return Ok(());
+        }
+        let error: Option<ErrorResponse> = res.json().await.ok();
+        let errcode = error
+            .as_ref()
+            .and_then(|error| error.errcode.clone())
+            .unwrap_or_else(|| status.to_string());
+        match status {
+            StatusCode::TOO_MANY_REQUESTS => Err(Failure::RateLimited(
+                error
+                    .and_then(|error| error.retry_after_ms)
+                    .map(Duration::from_millis)
+                    .unwrap_or(DEFAULT_RETRY_AFTER),
+            )),
+            status if status.is_client_error() => {
+                Err(SinkError::Rejected(format!("Matrix returned {}", errcode)).into())
+            }
+            _ => Err(SinkError::Transient(format!("Matrix returned {}", errcode)).into()),
+        }
+    }
+}
+
+/// A Matrix room as a `Sink`.
+pub struct MatrixSink<'a> {
+    matrix: &'a Matrix,
+    server: &'a MatrixConfig,
+    room: &'a str,
+    /// See `QueueConfig::destination`.
+    name: String,
+}
+
+impl<'a> MatrixSink<'a> {
+    pub fn new(
+        matrix: &'a Matrix,
+        server: &'a MatrixConfig,
+        room: &'a str,
+        name: String,
+    ) -> MatrixSink<'a> {
+        MatrixSink {
+            matrix,
+            server,
+            room,
+            name,
+        }
+    }
+}
+
+impl Sink for MatrixSink<'_> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn deliver(&self, rendered: Notification) -> BoxFuture<'_, Result<DeliveryReceipt, SinkError>> {
+        Box::pin(async move {
+            self.matrix
+                .send(self.server, self.room, &content(&rendered))
+                .await?;
+            Ok(DeliveryReceipt::default())
+        })
+    }
+}
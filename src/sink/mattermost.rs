@@ -0,0 +1,185 @@
+//! Mattermost, through incoming webhooks taking Slack-style attachments.
+//! Mattermost does not understand Block Kit, so messages are built from
+//! the alerts rather than from the rendered Slack body.
+
+use crate::alert::{Alert, Severity, Status};
+use crate::config::{HttpConfig, MattermostReceiver};
+use crate::sink::courier::{self, Courier, Failure};
+use crate::sink::{DeliveryReceipt, Notification, Sink, SinkError};
+use futures::future::BoxFuture;
+use log::debug;
+use reqwest::{Client, StatusCode};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// Mattermost servers rate limit each webhook, by default to ten requests
+/// a second.
+const MIN_INTERVAL: Duration = Duration::from_millis(100);
+/// Attachments shown for a digest; the rest are counted in its text.
+const MAX_ATTACHMENTS: usize = 20;
+
+/// The attachment colour for an alert of `severity` in `status`.
+fn color(severity: Severity, status: Status) -> &'static str {
+    match (status, severity) {
+        (Status::Resolved, _) => "#2eb67d",
+        (Status::Firing, Severity::Critical) => "#e01e5a",
+        (Status::Firing, Severity::Warning) => "#ecb22e",
+        (Status::Firing, Severity::Info) => "#36c5f0",
+    }
+}
+
+fn field(title: &str, value: impl ToString) -> Value {
+    json!({ "title": title, "value": value.to_string(), "short": true })
+}
+
+/// `alert` as an attachment: its title, its reason as the text, and a
+/// field for each of its details.
+fn attachment(alert: &Alert) -> Value {
+    let mut fields = vec![field("Severity", alert.severity)];
+    if alert.status == Status::Resolved {
+        fields.push(field("Status", "resolved"));
+    }
+    for (name, value) in [
+        ("Source", &alert.source),
+        ("Step", &alert.step),
+        ("Run", &alert.run_id),
+    ] {
+        if let Some(value) = value {
+            fields.push(field(name, value));
+        }
+    }
+    for (key, value) in &alert.fields {
+        let value = match value {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        fields.push(field(key, value));
+    }
+
+    let title = match alert.status {
+        Status::Firing => alert.title.clone(),
+        Status::Resolved => format!("~~{}~~", alert.title),
+    };
+    json!({
+        "fallback": alert.title,
+        "color": color(alert.severity, alert.status),
+        "title": title,
+        "text": alert.reason.clone().unwrap_or_default(),
+        "fields": fields,
+        "ts": alert.timestamp.timestamp(),
+    })
+}
+
+/// `rendered` as a webhook payload for `receiver`: an attachment for its
+/// alert, or its summary with an attachment for each alert in a digest.
+pub fn payload(receiver: &MattermostReceiver, rendered: &Notification) -> Value {
+    let mut payload = match rendered.alerts.as_slice() {
+        [alert] => json!({ "attachments": [attachment(alert)] }),
+        alerts => {
+            let mut text = format!("**{}**", rendered.summary);
+            if alerts.len() > MAX_ATTACHMENTS {
+                text.push_str(&format!(" ({} not shown)", alerts.len() - MAX_ATTACHMENTS));
+            }
+            let attachments: Vec<Value> = alerts
+                .iter()
+                .take(MAX_ATTACHMENTS)
+                .map(attachment)
+                .collect();
+            json!({ "text": text, "attachments": attachments })
+        }
+    };
+    for (key, value) in [
+        ("channel", &receiver.channel),
+        ("username", &receiver.username),
+        ("icon_url", &receiver.icon_url),
+    ] {
+        if let Some(value) = value {
+            payload[key] = json!(value);
+        }
+    }
+    payload
+}
+
+/// Posts messages to Mattermost webhooks.
+pub struct Mattermost {
+    client: Client,
+    courier: Courier,
+}
+
+impl Mattermost {
+    pub fn new(client: Client, http: &HttpConfig) -> Mattermost {
+        Mattermost {
+            client,
+            courier: Courier::new(http),
+        }
+    }
+
+    /// Post `payload` to the webhook at `url`, retrying transient failures
+    /// a few times before giving up on this attempt.
+    pub async fn post(&self, url: &str, payload: &Value) -> Result<(), SinkError> {
+        self.courier
+            .send("Mattermost", url.to_string(), MIN_INTERVAL, || {
+                self.post_once(url, payload)
+            })
+            .await
+    }
+
+    async fn post_once(&self, url: &str, payload: &Value) -> Result<(), Failure> {
+        let res = self
+            .client
+            .post(url)
+            .json(payload)
+            .send()
+            .await
+            .map_err(|e| SinkError::Transient(format!("failed sending to Mattermost: {}", e)))?;
+
+        match res.status() {
+            StatusCode::TOO_MANY_REQUESTS => Err(Failure::RateLimited(courier::retry_after(&res))),
+            status if status.is_success() => {
+                debug!("Message accepted by Mattermost");
+                Ok(())
+            }
+            status if status.is_client_error() => {
+                Err(SinkError::Rejected(format!("Mattermost returned {}", status)).into())
+            }
+            status => Err(SinkError::Transient(format!("Mattermost returned {}", status)).into()),
+        }
+    }
+}
+
+/// A Mattermost webhook as a `Sink`.
+pub struct MattermostSink<'a> {
+    mattermost: &'a Mattermost,
+    receiver: &'a MattermostReceiver,
+    /// See `QueueConfig::destination`.
+    name: String,
+}
+
+impl<'a> MattermostSink<'a> {
+    pub fn new(
+        mattermost: &'a Mattermost,
+        receiver: &'a MattermostReceiver,
+        name: String,
+    ) -> MattermostSink<'a> {
+        MattermostSink {
+            mattermost,
+            receiver,
+            name,
+        }
+    }
+}
+
+impl Sink for MattermostSink<'_> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn deliver(&self, rendered: Notification) -> BoxFuture<'_, Result<DeliveryReceipt, SinkError>> {
+        Box::pin(async move {
+            self.mattermost
+                .post(&self.receiver.url, &payload(self.receiver, &rendered))
+                .await?;
+            Ok(DeliveryReceipt::default())
+        })
+    }
+}
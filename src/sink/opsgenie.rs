@@ -0,0 +1,211 @@
+//! Opsgenie, through the Alert API of a team's API integration.
+
+use crate::alert::{Alert, Severity, Status};
+use crate::config::{HttpConfig, OpsgenieReceiver};
+use crate::sink::courier::{self, Courier, Failure};
+use crate::sink::{DeliveryReceipt, Notification, Sink, SinkError};
+use futures::future::BoxFuture;
+use log::debug;
+use reqwest::{Client, StatusCode, Url};
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+/// Keeps well within Opsgenie's per-minute limits on API requests.
+const MIN_INTERVAL: Duration = Duration::from_millis(200);
+/// Opsgenie's limits on the lengths of an alias and a message.
+const MAX_ALIAS: usize = 512;
+const MAX_MESSAGE: usize = 130;
+const SOURCE: &str = "2steps-slack-alert";
+
+/// The alias identifying the check with `fingerprint` in Opsgenie: the
+/// fingerprint itself, or a hash of it if it is too long.
+fn alias(fingerprint: &str) -> String {
+    if fingerprint.len() <= MAX_ALIAS {
+        fingerprint.to_string()
+    } else {
+        hex::encode(Sha256::digest(fingerprint.as_bytes()))
+    }
+}
+
+fn priority(severity: Severity) -> &'static str {
+    match severity {
+        Severity::Critical => "P1",
+        Severity::Warning => "P3",
+        Severity::Info => "P5",
+    }
+}
+
+/// Tags for `alert`: those configured for the receiver, then `key:value`
+/// for each of the alert's extra fields with a plain value.
+fn tags(alert: &Alert, service: &OpsgenieReceiver) -> Vec<String> {
+    let mut tags = service.tags.clone();
+    for (key, value) in &alert.fields {
+        match value {
+            Value::String(value) => tags.push(format!("{}:{}", key, value)),
+            Value::Number(_) | Value::Bool(_) => tags.push(format!("{}:{}", key, value)),
+            _ => {}
+        }
+    }
+    tags
+}
+
+/// The body of a request creating an alert for `alert`.
+fn create(alert: &Alert, service: &OpsgenieReceiver) -> Value {
+    let mut details = Map::new();
+    for (key, value) in [
+        ("source", &alert.source),
+        ("step", &alert.step),
+        ("run_id", &alert.run_id),
+    ] {
+        if let Some(value) = value {
+            details.insert(key.to_string(), json!(value));
+        }
+    }
+    let mut body = json!({
+        "message": alert.title.chars().take(MAX_MESSAGE).collect::<String>(),
+        "alias": alias(&alert.key()),
+        "priority": priority(alert.severity),
+        "tags": tags(alert, service),
+        "details": details,
+        "source": SOURCE,
+    });
+    if let Some(reason) = &alert.reason {
+        body["description"] = json!(reason);
+    }
+    if let Some(source) = &alert.source {
+        body["entity"] = json!(source);
+    }
+    body
+}
+
+/// The Alert API URL for creating alerts, or for `action` on the alert
+/// with the given alias.
+fn url(service: &OpsgenieReceiver, action: Option<(&str, &str)>) -> Result<Url, SinkError> {
+    let invalid = || SinkError::Rejected("invalid Opsgenie URL".to_string());
+    let mut url = Url::parse(&service.url).map_err(|_| invalid())?;
+    {
+        let mut path = url.path_segments_mut().map_err(|_| invalid())?;
+        path.pop_if_empty().extend(&["v2", "alerts"]);
+        if let Some((alias, action)) = action {
+            path.extend(&[alias, action]);
+        }
+    }
+    if action.is_some() {
+        url.query_pairs_mut().append_pair("identifierType", "alias");
+    }
+    Ok(url)
+}
+
+/// Creates, acknowledges and closes alerts in Opsgenie.
+pub struct Opsgenie {
+    client: Client,
+    courier: Courier,
+}
+
+impl Opsgenie {
+    pub fn new(client: Client, http: &HttpConfig) -> Opsgenie {
+        Opsgenie {
+            client,
+            courier: Courier::new(http),
+        }
+    }
+
+    /// Send `body` to `url`, retrying transient failures a few times before
+    /// giving up on this attempt.
+    async fn request(
+        &self,
+        service: &OpsgenieReceiver,
+        url: Url,
+        body: &Value,
+    ) -> Result<(), SinkError> {
+        self.courier
+            .send("Opsgenie", service.api_key.clone(), MIN_INTERVAL, || {
+                self.request_once(&service.api_key, url.clone(), body)
+            })
+            .await
+    }
+
+    async fn request_once(&self, api_key: &str, url: Url, body: &Value) -> Result<(), Failure> {
+        let res = self
+            .client
+            .post(url)
+            .header("Authorization", format!("GenieKey {}", api_key))
+            .json(body)
+            .send()
+            .await
+            .map_err(|e| SinkError::Transient(format!("failed sending to Opsgenie: {}", e)))?;
+        match res.status() {
+            StatusCode::TOO_MANY_REQUESTS => Err(Failure::RateLimited(courier::retry_after(&res))),
+            status if status.is_success() => Ok(()),
+            status if status.is_client_error() => {
+                Err(SinkError::Rejected(format!("Opsgenie returned {}", status)).into())
+            }
+            status => Err(SinkError::Transient(format!("Opsgenie returned {}", status)).into()),
+        }
+    }
+
+    /// Open, acknowledge or close the Opsgenie alert for `alert`, as it is
+    /// firing, has been taken on by someone, or has recovered.
+    pub async fn update(&self, service: &OpsgenieReceiver, alert: &Alert) -> Result<(), SinkError> {
+        let alias = alias(&alert.key());
+        match (alert.status, &alert.acknowledged_by) {
+            (Status::Resolved, _) => {
+                let body = json!({ "source": SOURCE, "note": "The check recovered" });
+                let url = url(service, Some((&alias, "close")))?;
+                self.request(service, url, &body).await
+            }
+            (Status::Firing, Some(user)) => {
+                let body = json!({
+                    "source": SOURCE,
+                    "note": format!("Acknowledged in Slack by {}", user),
+                });
+                let url = url(service, Some((&alias, "acknowledge")))?;
+                self.request(service, url, &body).await
+            }
+            (Status::Firing, None) => {
+                let url = url(service, None)?;
+                self.request(service, url, &create(alert, service)).await
+            }
+        }
+    }
+}
+
+/// An Opsgenie integration as a `Sink`. Each alert in a digest is sent on
+/// its own, and summaries about no alert in particular are left out.
+pub struct OpsgenieSink<'a> {
+    opsgenie: &'a Opsgenie,
+    service: &'a OpsgenieReceiver,
+    /// See `QueueConfig::destination`.
+    name: String,
+}
+
+impl<'a> OpsgenieSink<'a> {
+    pub fn new(
+        opsgenie: &'a Opsgenie,
+        service: &'a OpsgenieReceiver,
+        name: String,
+    ) -> OpsgenieSink<'a> {
+        OpsgenieSink {
+            opsgenie,
+            service,
+            name,
+        }
+    }
+}
+
+impl Sink for OpsgenieSink<'_> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn deliver(&self, rendered: Notification) -> BoxFuture<'_, Result<DeliveryReceipt, SinkError>> {
+        Box::pin(async move {
+            for alert in &rendered.alerts {
+                self.opsgenie.update(self.service, alert).await?;
+                debug!("Opsgenie accepted request for {}", alert.key());
+            }
+            Ok(DeliveryReceipt::default())
+        })
+    }
+}
@@ -0,0 +1,297 @@
+use crate::alert::{Alert, Status};
+use crate::config::{HttpConfig, PagerDutyConfig, PagerDutyReceiver};
+use crate::sink::courier::{self, Courier, Failure};
+use crate::sink::{DeliveryReceipt, Notification, Sink, SinkError};
+use futures::future::BoxFuture;
+use log::{debug, warn};
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+const INCIDENTS_URL: &str = "https://api.pagerduty.com/incidents";
+/// PagerDuty accepts about 120 events a minute for each integration key.
+const MIN_INTERVAL: Duration = Duration::from_millis(500);
+/// The longest dedup key PagerDuty accepts.
+const MAX_DEDUP_KEY: usize = 255;
+/// The longest summary PagerDuty accepts.
+const MAX_SUMMARY: usize = 1024;
+/// PagerDuty creates incidents from events asynchronously, so the lookup
+/// may need a few tries before the incident shows up.
+const LOOKUP_ATTEMPTS: u32 = 3;
+const LOOKUP_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Deserialize)]
+struct Incidents {
+    incidents: Vec<Incident>,
+}
+
+#[derive(Deserialize)]
+struct Incident {
+    html_url: String,
+}
+
+/// The dedup key for the check with `fingerprint`: the fingerprint itself,
+/// or a hash of it if it is too long for PagerDuty.
+fn dedup_key(fingerprint: &str) -> String {
+    if fingerprint.len() <= MAX_DEDUP_KEY {
+        fingerprint.to_string()
+    } else {
+        hex::encode(Sha256::digest(fingerprint.as_bytes()))
+    }
+}
+
+/// The event payload describing `alert`, with `details` added to its own.
+fn payload(alert: &Alert, severity: &str, mut details: Map<String, Value>) -> Value {
+    if let Some(reason) = &alert.reason {
+        details.insert("reason".to_string(), json!(reason));
+    }
+    if let Some(run_id) = &alert.run_id {
+        details.insert("run_id".to_string(), json!(run_id));
+    }
+    for (k, v) in &alert.fields {
+        details.insert(k.clone(), v.clone());
+    }
+    let mut payload = json!({
+        "summary": alert.title.chars().take(MAX_SUMMARY).collect::<String>(),
+        "source": alert.source.as_deref().unwrap_or("2steps"),
+        "severity": severity,
+        "timestamp": alert.timestamp.to_rfc3339(),
+        "custom_details": details,
+    });
+    if let Some(step) = &alert.step {
+        payload["component"] = json!(step);
+    }
+    payload
+}
+
+/// Raises incidents through the PagerDuty Events API v2.
+pub struct PagerDuty {
+    client: Client,
+    url: String,
+    routing_key: String,
+    api_token: Option<String>,
+}
+
+impl PagerDuty {
+    pub fn new(client: Client, cfg: &PagerDutyConfig) -> PagerDuty {
+        PagerDuty {
+            client,
+            url: cfg.url.clone(),
+            routing_key: cfg.routing_key.clone(),
+            api_token: cfg.api_token.clone(),
+        }
+    }
+
+    /// Trigger an incident for the check with `fingerprint`, described by
+    /// `alert` when we still have it, on behalf of the Slack user `user`.
+    /// Returns a link to the incident if it can be found.
+    pub async fn trigger(
+        &self,
+        fingerprint: &str,
+        alert: Option<&Alert>,
+        user: &str,
+    ) -> Result<Option<String>, String> {
+        let mut details = Map::new();
+        details.insert("escalated_by".to_string(), json!(user));
+        let payload = match alert {
+            Some(alert) => payload(alert, "critical", details),
+            None => json!({
+                "summary": fingerprint,
+                "source": "2steps",
+                "severity": "critical",
+                "custom_details": details,
+            }),
+        };
+        let event = json!({
+            "routing_key": self.routing_key,
+            "event_action": "trigger",
+            "dedup_key": dedup_key(fingerprint),
+            "payload": payload,
+        });
+
+        let res = self
+            .client
+            .post(&self.url)
+            .json(&event)
+            .send()
+            .await
+            .map_err(|e| format!("failed sending to PagerDuty: {}", e))?;
+        if res.status() != StatusCode::ACCEPTED {
+            return Err(format!("PagerDuty returned {}", res.status()));
+        }
+        debug!("PagerDuty accepted event for {}", fingerprint);
+
+        Ok(match &self.api_token {
+            Some(token) => self.incident_url(token, &dedup_key(fingerprint)).await,
+            None => None,
+        })
+    }
+
+    /// Resolve the incident raised for the check with `fingerprint`, as
+    /// when the check recovers.
+    pub async fn resolve(&self, fingerprint: &str) -> Result<(), String> {
+        let event = json!({
+            "routing_key": self.routing_key,
+            "event_action": "resolve",
+            "dedup_key": dedup_key(fingerprint),
+        });
+        let res = self
+            .client
+            .post(&self.url)
+            .json(&event)
+            .send()
+            .await
+            .map_err(|e| format!("failed sending to PagerDuty: {}", e))?;
+        if res.status() != StatusCode::ACCEPTED {
+            return Err(format!("PagerDuty returned {}", res.status()));
+        }
+        debug!("PagerDuty accepted resolution of {}", fingerprint);
+        Ok(())
+    }
+
+    /// Look up the web page of the incident raised with `dedup_key`.
+    async fn incident_url(&self, token: &str, dedup_key: &str) -> Option<String> {
+        for _ in 0..LOOKUP_ATTEMPTS {
+            tokio::time::delay_for(LOOKUP_DELAY).await;
+            let res = self
+                .client
+                .get(INCIDENTS_URL)
+                .header("Authorization", format!("Token token={}", token))
+                .header("Accept", "application/vnd.pagerduty+json;version=2")
+                .query(&[("incident_key", dedup_key)])
+                .send()
+                .await;
+            let found = match res {
+                Ok(res) if res.status().is_success() => res.json::<Incidents>().await,
+                Ok(res) => {
+                    warn!("PagerDuty incident lookup returned {}", res.status());
+                    return None;
+                }
+                Err(e) => {
+                    warn!("PagerDuty incident lookup failed: {}", e);
+                    return None;
+                }
+            };
+            match found {
+                Ok(found) => {
+                    if let Some(incident) = found.incidents.into_iter().next() {
+                        return Some(incident.html_url);
+                    }
+                }
+                Err(e) => {
+                    warn!("Unreadable PagerDuty incident lookup: {}", e);
+                    return None;
+                }
+            }
+        }
+        None
+    }
+}
+
+/// The event telling PagerDuty about `alert`: a trigger while it is firing,
+/// an acknowledgement once someone has taken responsibility for it, and a
+/// resolution once it recovers.
+fn event(routing_key: &str, alert: &Alert) -> Value {
+    let action = match alert.status {
+        Status::Resolved => "resolve",
+        Status::Firing if alert.acknowledged_by.is_some() => "acknowledge",
+        Status::Firing => "trigger",
+    };
+    let mut event = json!({
+        "routing_key": routing_key,
+        "event_action": action,
+        "dedup_key": dedup_key(&alert.key()),
+    });
+    if action == "trigger" {
+        event["payload"] = payload(alert, &alert.severity.to_string(), Map::new());
+    }
+    event
+}
+
+/// Sends events about the alerts routed to PagerDuty receivers.
+pub struct Events {
+    client: Client,
+    courier: Courier,
+}
+
+impl Events {
+    pub fn new(client: Client, http: &HttpConfig) -> Events {
+        Events {
+            client,
+            courier: Courier::new(http),
+        }
+    }
+
+    /// Send `event` to the service, retrying transient failures a few times
+    /// before giving up on this attempt.
+    pub async fn send(&self, service: &PagerDutyReceiver, event: &Value) -> Result<(), SinkError> {
+        self.courier
+            .send(
+                "PagerDuty",
+                service.routing_key.clone(),
+                MIN_INTERVAL,
+                || self.send_once(&service.url, event),
+            )
+            .await
+    }
+
+    async fn send_once(&self, url: &str, event: &Value) -> Result<(), Failure> {
+        let res = self
+            .client
+            .post(url)
+            .json(event)
+            .send()
+            .await
+            .map_err(|e| SinkError::Transient(format!("failed sending to PagerDuty: {}", e)))?;
+        match res.status() {
+            StatusCode::TOO_MANY_REQUESTS => Err(Failure::RateLimited(courier::retry_after(&res))),
+            status if status.is_success() => Ok(()),
+            status if status.is_client_error() => {
+                Err(SinkError::Rejected(format!("PagerDuty returned {}", status)).into())
+            }
+            status => Err(SinkError::Transient(format!("PagerDuty returned {}", status)).into()),
+        }
+    }
+}
+
+/// A PagerDuty service as a `Sink`. Each alert in a digest gets an event of
+/// its own, and summaries about no alert in particular are left out.
+pub struct PagerDutySink<'a> {
+    events: &'a Events,
+    service: &'a PagerDutyReceiver,
+    /// See `QueueConfig::destination`.
+    name: String,
+}
+
+impl<'a> PagerDutySink<'a> {
+    pub fn new(
+        events: &'a Events,
+        service: &'a PagerDutyReceiver,
+        name: String,
+    ) -> PagerDutySink<'a> {
+        PagerDutySink {
+            events,
+            service,
+            name,
+        }
+    }
+}
+
+impl Sink for PagerDutySink<'_> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn deliver(&self, rendered: Notification) -> BoxFuture<'_, Result<DeliveryReceipt, SinkError>> {
+        Box::pin(async move {
+            for alert in &rendered.alerts {
+                let event = event(&self.service.routing_key, alert);
+                self.events.send(self.service, &event).await?;
+                debug!("PagerDuty accepted event for {}", alert.key());
+            }
+            Ok(DeliveryReceipt::default())
+        })
+    }
+}
@@ -0,0 +1,276 @@
+//! Pushover, through the Message API. Emergencies carry a tag identifying
+//! their check, so that the repeats of one still going when its check
+//! recovers are cancelled without keeping track of receipts here.
+
+use crate::alert::{Alert, Severity, Status};
+use crate::config::{HttpConfig, PushoverReceiver};
+use crate::sink::courier::{self, Courier, Failure};
+use crate::sink::{DeliveryReceipt, Notification, Sink, SinkError};
+use futures::future::BoxFuture;
+use log::debug;
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+/// Pushover asks for no more than a couple of messages a second.
+const MIN_INTERVAL: Duration = Duration::from_millis(500);
+/// Pushover's limits on the length of a title and of a message.
+const MAX_TITLE: usize = 250;
+const MAX_MESSAGE: usize = 1024;
+const EMERGENCY: i8 = 2;
+/// Priority of recoveries: no sound or vibration.
+const RESOLVED: i8 = -1;
+
+#[derive(Deserialize, Default)]
+struct ErrorResponse {
+    #[serde(default)]
+    errors: Vec<String>,
+}
+
+/// The tag identifying emergencies about the check with `fingerprint`.
+fn tag(fingerprint: &str) -> String {
+    let hash = hex::encode(Sha256::digest(fingerprint.as_bytes()));
+    format!("2steps-{}", &hash[..16])
+}
+
+fn truncate(text: &str, max: usize) -> String {
+    match text.char_indices().nth(max - 1) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+/// The priority `user` gives `alert`.
+fn priority(user: &PushoverReceiver, severity: Severity, status: Status) -> i8 {
+    match status {
+        Status::Firing => user.priority.get(&severity).copied().unwrap_or(0),
+        Status::Resolved => RESOLVED,
+    }
+}
+
+/// A message for Pushover.
+pub struct Message {
+    pub title: String,
+    pub message: String,
+    pub priority: i8,
+    /// Tags of the checks an emergency is about.
+    pub tags: Vec<String>,
+    pub url: Option<String>,
+    pub timestamp: Option<i64>,
+}
+
+/// `alert` as a message: its title, and its reason followed by its
+/// details.
+fn alert_message(user: &PushoverReceiver, alert: &Alert) -> Message {
+    let title = match alert.status {
+        Status::Firing => alert.title.clone(),
+        Status::Resolved => format!("Resolved: {}", alert.title),
+    };
+    let mut lines = Vec::new();
+    if let Some(reason) = &alert.reason {
+        lines.push(reason.clone());
+    }
+    lines.push(format!("Severity: {}", alert.severity));
+    for (name, value) in [
+        ("Source", &alert.source),
+        ("Step", &alert.step),
+        ("Run", &alert.run_id),
+    ] {
+        if let Some(value) = value {
+            lines.push(format!("{}: {}", name, value));
+        }
+    }
+    Message {
+        title,
+        message: lines.join("\n"),
+        priority: priority(user, alert.severity, alert.status),
+        tags: vec![tag(&alert.key())],
+        url: alert.incident_url.clone(),
+        timestamp: Some(alert.timestamp.timestamp()),
+    }
+}
+
+/// `rendered` as a message for `user`: its alert in full, or its summary
+/// and a line for each alert in a digest, at the priority of the most
+/// pressing of them.
+pub fn message(user: &PushoverReceiver, rendered: &Notification) -> Message {
+    if let [alert] = rendered.alerts.as_slice() {
+        return alert_message(user, alert);
+    }
+    let lines: Vec<String> = rendered
+        .alerts
+        .iter()
+        .map(|alert| match alert.status {
+            Status::Firing => format!("• {}", alert.title),
+            Status::Resolved => format!("• Resolved: {}", alert.title),
+        })
+        .collect();
+    let priority = rendered
+        .alerts
+        .iter()
+        .map(|alert| priority(user, alert.severity, alert.status))
+        .max()
+        .unwrap_or_else(|| priority(user, Severity::Info, Status::Firing));
+    Message {
+        title: rendered.summary.clone(),
+        message: lines.join("\n"),
+        priority,
+        tags: rendered
+            .alerts
+            .iter()
+            .map(|alert| tag(&alert.key()))
+            .collect(),
+        url: None,
+        timestamp: None,
+    }
+}
+
+/// Sends messages to Pushover users.
+pub struct Pushover {
+    client: Client,
+    courier: Courier,
+}
+
+impl Pushover {
+    pub fn new(client: Client, http: &HttpConfig) -> Pushover {
+        Pushover {
+            client,
+            courier: Courier::new(http),
+        }
+    }
+
+    /// Send a form to `path` on `user`'s API host, retrying transient
+    /// failures a few times before giving up on this attempt.
+    async fn request(
+        &self,
+        user: &PushoverReceiver,
+        path: &str,
+        form: &[(&str, String)],
+    ) -> Result<(), SinkError> {
+        self.courier
+            .send("Pushover", user.user.clone(), MIN_INTERVAL, || {
+                self.request_once(user, path, form)
+            })
+            .await
+    }
+
+    async fn request_once(
+        &self,
+        user: &PushoverReceiver,
+        path: &str,
+        form: &[(&str, String)],
+    ) -> Result<(), Failure> {
+        let res = self
+            .client
+            .post(&format!("{}{}", user.url.trim_end_matches('/'), path))
+            .form(form)
+            .send()
+            .await
+            .map_err(|e| SinkError::Transient(format!("failed sending to Pushover: {}", e)))?;
+
+        let status = res.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            return Err(Failure::RateLimited(courier::retry_after(&res)));
+        }
+        let errors = res.json::<ErrorResponse>().await.unwrap_or_default().errors;
+        let description = if errors.is_empty() {
+            status.to_string()
+        } else {
+            errors.join("; ")
+        };
+        if status.is_client_error() {
+            Err(SinkError::Rejected(format!("Pushover returned {}", description)).into())
+        } else {
+            Err(SinkError::Transient(format!("Pushover returned {}", description)).into())
+        }
+    }
+
+    /// Send `message` to `user`.
+    pub async fn send(&self, user: &PushoverReceiver, message: &Message) -> Result<(), SinkError> {
+        let mut form = vec![
+            ("token", user.token.clone()),
+            ("user", user.user.clone()),
+            ("title", truncate(&message.title, MAX_TITLE)),
+            ("message", truncate(&message.message, MAX_MESSAGE)),
+            ("priority", message.priority.to_string()),
+        ];
+        if message.priority == EMERGENCY {
+            form.push(("retry", user.retry.as_secs().to_string()));
+            form.push(("expire", user.expire.as_secs().to_string()));
+            form.push(("tags", message.tags.join(",")));
+        }
+        if let Some(device) = &user.device {
+            form.push(("device", device.clone()));
+        }
+        if let Some(sound) = &user.sound {
+            form.push(("sound", sound.clone()));
+        }
+        if let Some(url) = &message.url {
+            form.push(("url", url.clone()));
+        }
+        if let Some(timestamp) = message.timestamp {
+            form.push(("timestamp", timestamp.to_string()));
+        }
+        self.request(user, "/1/messages.json", &form).await?;
+        debug!("Message accepted by Pushover");
+        Ok(())
+    }
+
+    /// Stop repeating the emergencies about the check with `fingerprint`.
+    pub async fn cancel(
+        &self,
+        user: &PushoverReceiver,
+        fingerprint: &str,
+    ) -> Result<(), SinkError> {
+        let path = format!("/1/receipts/cancel_by_tag/{}.json", tag(fingerprint));
+        self.request(user, &path, &[("token", user.token.clone())])
+            .await
+    }
+}
+
+/// A Pushover user or group as a `Sink`.
+pub struct PushoverSink<'a> {
+    pushover: &'a Pushover,
+    user: &'a PushoverReceiver,
+    /// See `QueueConfig::destination`.
+    name: String,
+}
+
+impl<'a> PushoverSink<'a> {
+    pub fn new(
+        pushover: &'a Pushover,
+        user: &'a PushoverReceiver,
+        name: String,
+    ) -> PushoverSink<'a> {
+        PushoverSink {
+            pushover,
+            user,
+            name,
+        }
+    }
+}
+
+impl Sink for PushoverSink<'_> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn deliver(&self, rendered: Notification) -> BoxFuture<'_, Result<DeliveryReceipt, SinkError>> {
+        Box::pin(async move {
+            let emergencies = self.user.priority.values().any(|&p| p == EMERGENCY);
+            for alert in &rendered.alerts {
+                if emergencies && alert.status == Status::Resolved {
+                    self.pushover.cancel(self.user, &alert.key()).await?;
+                }
+            }
+            self.pushover
+                .send(self.user, &message(self.user, &rendered))
+                .await?;
+            Ok(DeliveryReceipt::default())
+        })
+    }
+}
@@ -0,0 +1,342 @@
+//! ServiceNow, through the Table API: an incident for each check, kept up
+//! to date while it fires and resolved once it recovers. Incidents carry a
+//! correlation ID identifying their check, so the open incident for a check
+//! is found with a query rather than kept track of here.
+
+use crate::alert::{Alert, Severity, Status};
+use crate::config::{HttpConfig, ServiceNowAuth, ServiceNowReceiver};
+use crate::render::alert_details;
+use crate::sink::courier::{self, Courier, Failure};
+use crate::sink::{DeliveryReceipt, Notification, Sink, SinkError};
+use futures::future::BoxFuture;
+use log::debug;
+use reqwest::{Client, Method, StatusCode, Url};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+/// Keeps well within the rate limits instances usually set on the API.
+const MIN_INTERVAL: Duration = Duration::from_millis(200);
+/// ServiceNow's limit on the length of a short description.
+const MAX_SHORT_DESCRIPTION: usize = 160;
+const CORRELATION_DISPLAY: &str = "2steps-slack-alert";
+/// Incident states that are no longer open: resolved, closed and
+/// cancelled.
+const CLOSED_STATES: &str = "6,7,8";
+
+#[derive(Deserialize)]
+struct Record {
+    sys_id: String,
+}
+
+#[derive(Deserialize, Default)]
+struct ErrorResponse {
+    #[serde(default)]
+    error: Option<ErrorDetail>,
+}
+
+#[derive(Deserialize)]
+struct ErrorDetail {
+    #[serde(default)]
+    message: Option<String>,
+    #[serde(default)]
+    detail: Option<String>,
+}
+
+/// The correlation ID identifying the check with `fingerprint`.
+fn correlation_id(fingerprint: &str) -> String {
+    hex::encode(Sha256::digest(fingerprint.as_bytes()))
+}
+
+/// Urgency and impact for an alert of `severity`: high, medium or low.
+fn level(severity: Severity) -> &'static str {
+    match severity {
+        Severity::Critical => "1",
+        Severity::Warning => "2",
+        Severity::Info => "3",
+    }
+}
+
+/// The incident fields that follow `alert` while it fires: its urgency
+/// and impact, then those set from its fields as `instance` maps them.
+fn mapped_fields(alert: &Alert, instance: &ServiceNowReceiver) -> Map<String, Value> {
+    let mut fields = Map::new();
+    fields.insert("urgency".to_string(), json!(level(alert.severity)));
+    fields.insert("impact".to_string(), json!(level(alert.severity)));
+    let values = serde_json::to_value(alert).unwrap_or_default();
+    for (field, name) in &instance.fields {
+        match &values[name] {
+            Value::Null => {}
+            Value::String(value) => {
+                fields.insert(field.clone(), json!(value));
+            }
+            other => {
+                fields.insert(field.clone(), json!(other.to_string()));
+            }
+        }
+    }
+    fields
+}
+
+/// The fields of a new incident for `alert`.
+fn create(alert: &Alert, instance: &ServiceNowReceiver) -> Value {
+    let mut description = String::new();
+    if let Some(reason) = &alert.reason {
+        description.push_str(&format!("{}\n\n", reason));
+    }
+    for (name, value) in alert_details(alert) {
+        description.push_str(&format!("{}: {}\n", name, value));
+    }
+    let short_description = format!(
+        "[{}] {}",
+        alert.severity.to_string().to_uppercase(),
+        alert.title
+    );
+
+    let mut fields: Map<String, Value> = instance
+        .defaults
+        .iter()
+        .map(|(field, value)| (field.clone(), json!(value)))
+        .collect();
+    fields.insert(
+        "short_description".to_string(),
+        json!(short_description
+            .chars()
+            .take(MAX_SHORT_DESCRIPTION)
+            .collect::<String>()),
+    );
+    fields.insert("description".to_string(), json!(description));
+    fields.insert(
+        "correlation_id".to_string(),
+        json!(correlation_id(&alert.key())),
+    );
+    fields.insert(
+        "correlation_display".to_string(),
+        json!(CORRELATION_DISPLAY),
+    );
+    fields.extend(mapped_fields(alert, instance));
+    Value::Object(fields)
+}
+
+/// The fields that resolve the incident for `alert`: the built-in state,
+/// close code and close notes, under any `instance` sets itself.
+fn resolve(alert: &Alert, instance: &ServiceNowReceiver) -> Value {
+    let at = alert.resolved_at.unwrap_or(alert.timestamp);
+    let mut fields = Map::new();
+    fields.insert("state".to_string(), json!("6"));
+    fields.insert("close_code".to_string(), json!("Solution provided"));
+    fields.insert(
+        "close_notes".to_string(),
+        json!(format!(
+            "The check recovered at {}.",
+            at.format("%Y-%m-%d %H:%M:%S UTC")
+        )),
+    );
+    for (field, value) in &instance.resolve {
+        fields.insert(field.clone(), json!(value));
+    }
+    Value::Object(fields)
+}
+
+/// `instance`'s Table API URL for incidents, or for the incident `sys_id`.
+fn url(instance: &ServiceNowReceiver, sys_id: Option<&str>) -> Result<Url, SinkError> {
+    let invalid = || SinkError::Rejected("invalid ServiceNow URL".to_string());
+    let mut url = Url::parse(&instance.instance).map_err(|_| invalid())?;
+    {
+        let mut path = url.path_segments_mut().map_err(|_| invalid())?;
+        path.pop_if_empty()
+            .extend(&["api", "now", "table", "incident"]);
+        if let Some(sys_id) = sys_id {
+            path.push(sys_id);
+        }
+    }
+    Ok(url)
+}
+
+/// Creates, updates and resolves incidents in ServiceNow.
+pub struct ServiceNow {
+    client: Client,
+    courier: Courier,
+}
+
+impl ServiceNow {
+    pub fn new(client: Client, http: &HttpConfig) -> ServiceNow {
+        ServiceNow {
+            client,
+            courier: Courier::new(http),
+        }
+    }
+
+    /// Send a request to `url`, retrying transient failures a few times
+    /// before giving up on this attempt, and return the `result` of the
+    /// response.
+    async fn request(
+        &self,
+        instance: &ServiceNowReceiver,
+        method: Method,
+        url: Url,
+        body: Option<&Value>,
+    ) -> Result<Value, SinkError> {
+        self.courier
+            .send(
+                "ServiceNow",
+                instance.instance.clone(),
+                MIN_INTERVAL,
+                || self.request_once(instance, method.clone(), url.clone(), body),
+            )
+            .await
+    }
+
+    async fn request_once(
+        &self,
+        instance: &ServiceNowReceiver,
+        method: Method,
+        url: Url,
+        body: Option<&Value>,
+    ) -> Result<Value, Failure> {
+        let mut req = self
+            .client
+            .request(method, url)
+            .header("Accept", "application/json");
+        req = match &instance.auth {
+            ServiceNowAuth::Basic { username, password } => {
+                req.basic_auth(username, Some(password))
+            }
+            ServiceNowAuth::Bearer(token) => req.bearer_auth(token),
+        };
+        if let Some(body) = body {
+            req = req.json(body);
+        }
+        let res = req
+            .send()
+            .await
+            .map_err(|e| SinkError::Transient(format!("failed sending to ServiceNow: {}", e)))?;
+
+        let status = res.status();
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            return Err(Failure::RateLimited(courier::retry_after(&res)));
+        }
+        if status.is_success() {
+            let mut body: Value = res.json().await.map_err(|e| {
+                SinkError::Transient(format!("unreadable response from ServiceNow: {}", e))
+            })?;
+            return Ok(body["result"].take());
+        }
+        let error = res.json::<ErrorResponse>().await.unwrap_or_default().error;
+        let description = match error {
+            Some(ErrorDetail {
+                message: Some(message),
+                detail: Some(detail),
+            }) => format!("{}: {}", message, detail),
+            Some(ErrorDetail {
+                message: Some(message),
+                ..
+            }) => message,
+            _ => status.to_string(),
+        };
+        if status.is_client_error() {
+            Err(SinkError::Rejected(format!("ServiceNow returned {}", description)).into())
+        } else {
+            Err(SinkError::Transient(format!("ServiceNow returned {}", description)).into())
+        }
+    }
+
+    /// The open incident for the check with `fingerprint`, if there is
+    /// one.
+    async fn open_incident(
+        &self,
+        instance: &ServiceNowReceiver,
+        fingerprint: &str,
+    ) -> Result<Option<Record>, SinkError> {
+        let mut url = url(instance, None)?;
+        url.query_pairs_mut()
+            .append_pair(
+                "sysparm_query",
+                &format!(
+                    "correlation_id={}^stateNOT IN{}",
+                    correlation_id(fingerprint),
+                    CLOSED_STATES
+                ),
+            )
+            .append_pair("sysparm_fields", "sys_id")
+            .append_pair("sysparm_limit", "1");
+        let result = self.request(instance, Method::GET, url, None).await?;
+        let records: Vec<Record> = serde_json::from_value(result).map_err(|e| {
+            SinkError::Transient(format!("unreadable query results from ServiceNow: {}", e))
+        })?;
+        Ok(records.into_iter().next())
+    }
+
+    /// Open an incident for `alert`, update its open incident while it
+    /// fires, or resolve that incident once it has recovered.
+    pub async fn update(
+        &self,
+        instance: &ServiceNowReceiver,
+        alert: &Alert,
+    ) -> Result<(), SinkError> {
+        let open = self.open_incident(instance, &alert.key()).await?;
+        let (method, url, body) = match (alert.status, &open) {
+            (Status::Firing, None) => (Method::POST, url(instance, None)?, create(alert, instance)),
+            (Status::Firing, Some(incident)) => (
+                Method::PATCH,
+                url(instance, Some(&incident.sys_id))?,
+                Value::Object(mapped_fields(alert, instance)),
+            ),
+            (Status::Resolved, Some(incident)) => (
+                Method::PATCH,
+                url(instance, Some(&incident.sys_id))?,
+                resolve(alert, instance),
+            ),
+            (Status::Resolved, None) => {
+                debug!("No open ServiceNow incident to resolve for {}", alert.key());
+                return Ok(());
+            }
+        };
+        let result = self.request(instance, method, url, Some(&body)).await?;
+        debug!(
+            "ServiceNow accepted incident {} for {}",
+            result["number"].as_str().unwrap_or("?"),
+            alert.key()
+        );
+        Ok(())
+    }
+}
+
+/// A ServiceNow instance as a `Sink`. Each alert in a digest is handled on
+/// its own, and summaries about no alert in particular are left out.
+pub struct ServiceNowSink<'a> {
+    servicenow: &'a ServiceNow,
+    instance: &'a ServiceNowReceiver,
+    /// See `QueueConfig::destination`.
+    name: String,
+}
+
+impl<'a> ServiceNowSink<'a> {
+    pub fn new(
+        servicenow: &'a ServiceNow,
+        instance: &'a ServiceNowReceiver,
+        name: String,
+    ) -> ServiceNowSink<'a> {
+        ServiceNowSink {
+            servicenow,
+            instance,
+            name,
+        }
+    }
+}
+
+impl Sink for ServiceNowSink<'_> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn deliver(&self, rendered: Notification) -> BoxFuture<'_, Result<DeliveryReceipt, SinkError>> {
+        Box::pin(async move {
+            for alert in &rendered.alerts {
+                self.servicenow.update(self.instance, alert).await?;
+            }
+            Ok(DeliveryReceipt::default())
+        })
+    }
+}
@@ -0,0 +1,329 @@
+pub mod blocks;
+
+use crate::alert::Severity;
+use crate::config::{HttpConfig, Route, SlackConfig};
+use crate::sink::courier::{self, Courier, Failure};
+use crate::sink::{DeliveryReceipt, Notification, Sink, SinkError};
+use crate::trace;
+use futures::future::BoxFuture;
+use log::debug;
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Slack allows roughly one message per second per webhook.
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+const POST_MESSAGE_URL: &str = "https://slack.com/api/chat.postMessage";
+const UPDATE_URL: &str = "https://slack.com/api/chat.update";
+const LOOKUP_BY_EMAIL_URL: &str = "https://slack.com/api/users.lookupByEmail";
+const CONNECTIONS_OPEN_URL: &str = "https://slack.com/api/apps.connections.open";
+
+/// Where a message is sent.
+#[derive(Clone, Copy)]
+pub enum Target<'a> {
+    Webhook(&'a str),
+    /// `chat.postMessage` to a channel, authenticated with a bot token.
+    Channel {
+        token: &'a str,
+        channel: &'a str,
+    },
+    /// `chat.update` of the message posted to `channel` at `ts`.
+    Update {
+        token: &'a str,
+        channel: &'a str,
+        ts: &'a str,
+    },
+}
+
+impl<'a> Target<'a> {
+    /// Where an alert of `severity` goes under `cfg`, unless the routing
+    /// tree picked a `receiver`. An alert's own `channel` is honoured only
+    /// when there is a token to post with, and takes precedence.
+    pub fn resolve(
+        cfg: &'a SlackConfig,
+        severity: Severity,
+        channel: Option<&'a str>,
+        receiver: Option<&'a Route>,
+    ) -> Target<'a> {
+        let token = cfg.token.as_deref();
+        if let (Some(channel), Some(token)) = (channel, token) {
+            return Target::Channel { token, channel };
+        }
+        match receiver.unwrap_or_else(|| cfg.route_for(severity)) {
+            Route::Webhook(url) => Target::Webhook(url),
+            Route::Channel(channel) => Target::Channel {
+                token: token.expect("config requires a token for channel routes"),
+                channel,
+            },
+        }
+    }
+
+    /// Identifies the destination for throttling; never logged, as it may
+    /// be a webhook URL.
+    fn key(&self) -> String {
+        match self {
+            Target::Webhook(url) => url.to_string(),
+            Target::Channel { channel, .. } | Target::Update { channel, .. } => {
+                format!("chat.postMessage:{}", channel)
+            }
+        }
+    }
+}
+
+/// Where Slack says a message ended up. Only the Web API reports this.
+pub struct Posted {
+    pub channel: String,
+    pub ts: String,
+}
+
+#[derive(Deserialize)]
+struct ApiResponse {
+    ok: bool,
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    channel: Option<String>,
+    #[serde(default)]
+    ts: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct LookupResponse {
+    ok: bool,
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    user: Option<User>,
+}
+
+#[derive(Deserialize)]
+struct User {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct ConnectionsOpenResponse {
+    ok: bool,
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    url: Option<String>,
+}
+
+/// A copy of a message body with extra top-level string keys.
+fn with_keys(body: &Value, keys: &[(&str, &str)]) -> Value {
+    let mut body = body.clone();
+    if let Some(body) = body.as_object_mut() {
+        for (k, v) in keys {
+            body.insert(k.to_string(), Value::String(v.to_string()));
+        }
+    }
+    body
+}
+
+/// Web API errors that may succeed if the same request is tried again.
+fn is_transient(error: &str) -> bool {
+    matches!(
+        error,
+        "internal_error" | "fatal_error" | "service_unavailable" | "request_timeout"
+    )
+}
+
+/// Posts to Slack, spacing messages to each webhook or channel at least
+/// `MIN_INTERVAL` apart and pausing a destination entirely while Slack is
+/// rate limiting it.
+pub struct Slack {
+    client: Client,
+    courier: Courier,
+    /// User IDs already looked up by email address.
+    users: std::sync::Mutex<HashMap<String, String>>,
+}
+
+impl Slack {
+    pub fn new(client: Client, http: &HttpConfig) -> Slack {
+        Slack {
+            client,
+            courier: Courier::new(http),
+            users: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Find the ID of the user with the given email address.
+    pub async fn lookup_user(&self, token: &str, email: &str) -> Result<String, SinkError> {
+        if let Some(id) = self.users.lock().unwrap().get(email) {
+            return Ok(id.clone());
+        }
+
+        let res = self
+            .client
+            .get(LOOKUP_BY_EMAIL_URL)
+            .bearer_auth(token)
+            .query(&[("email", email)])
+            .send()
+            .await
+            .map_err(|e| SinkError::Transient(format!("failed looking up user: {}", e)))?;
+        let lookup: LookupResponse = res
+            .json()
+            .await
+            .map_err(|e| SinkError::Transient(format!("unreadable response from Slack: {}", e)))?;
+
+        match (lookup.ok, lookup.user) {
+            (true, Some(user)) => {
+                self.users
+                    .lock()
+                    .unwrap()
+                    .insert(email.to_string(), user.id.clone());
+                Ok(user.id)
+            }
+            _ => Err(SinkError::Rejected(format!(
+                "Slack returned {}",
+                lookup.error.as_deref().unwrap_or("no user")
+            ))),
+        }
+    }
+
+    /// Get a websocket URL for a new Socket Mode connection.
+    pub async fn open_socket(&self, app_token: &str) -> Result<String, SinkError> {
+        let res = self
+            .client
+            .post(CONNECTIONS_OPEN_URL)
+            .bearer_auth(app_token)
+            .send()
+            .await
+            .map_err(|e| SinkError::Transient(format!("failed opening connection: {}", e)))?;
+        let open: ConnectionsOpenResponse = res
+            .json()
+            .await
+            .map_err(|e| SinkError::Transient(format!("unreadable response from Slack: {}", e)))?;
+
+        match (open.ok, open.url, open.error.as_deref()) {
+            (true, Some(url), _) => Ok(url),
+            (_, _, Some(error)) if is_transient(error) || error == "ratelimited" => {
+                Err(SinkError::Transient(format!("Slack returned {}", error)))
+            }
+            (_, _, error) => Err(SinkError::Rejected(format!(
+                "Slack returned {}",
+                error.unwrap_or("no URL")
+            ))),
+        }
+    }
+
+    /// Post `body` to the target, retrying transient failures a few times
+    /// before giving up on this attempt.
+    pub async fn post(
+        &self,
+        target: Target<'_>,
+        body: &Value,
+    ) -> Result<Option<Posted>, SinkError> {
+        self.courier
+            .send("Slack", target.key(), MIN_INTERVAL, || {
+                self.post_once(target, body)
+            })
+            .await
+    }
+
+    async fn post_once(&self, target: Target<'_>, body: &Value) -> Result<Option<Posted>, Failure> {
+        let request = match target {
+            Target::Webhook(url) => self.client.post(url).json(body),
+            Target::Channel { token, channel } => {
+                let body = with_keys(body, &[("channel", channel)]);
+                self.client
+                    .post(POST_MESSAGE_URL)
+                    .bearer_auth(token)
+                    .json(&body)
+            }
+            Target::Update { token, channel, ts } => {
+                let body = with_keys(body, &[("channel", channel), ("ts", ts)]);
+                self.client.post(UPDATE_URL).bearer_auth(token).json(&body)
+            }
+        };
+        let request = match trace::traceparent() {
+            Some(traceparent) => request.header("traceparent", traceparent),
+            None => request,
+        };
+        let res = request
+            .send()
+            .await
+            .map_err(|e| SinkError::Transient(format!("failed sending to slack: {:?}", e)))?;
+
+        match res.status() {
+            StatusCode::OK => {}
+            StatusCode::TOO_MANY_REQUESTS => {
+                return Err(Failure::RateLimited(courier::retry_after(&res)))
+            }
+            status if status.is_client_error() => {
+                return Err(SinkError::Rejected(format!("Slack returned {}", status)).into())
+            }
+            status => return Err(SinkError::Transient(format!("Slack returned {}", status)).into()),
+        }
+
+        if let Target::Webhook(_) = target {
+            debug!("Message acknowledged by Slack");
+            return Ok(None);
+        }
+
+        // The Web API reports failures in the body of a 200 response.
+        let api: ApiResponse = res
+            .json()
+            .await
+            .map_err(|e| SinkError::Transient(format!("unreadable response from Slack: {}", e)))?;
+        match (api.ok, api.error.as_deref()) {
+            (true, _) => {
+                debug!("Message acknowledged by Slack");
+                Ok(match (api.channel, api.ts) {
+                    (Some(channel), Some(ts)) => Some(Posted { channel, ts }),
+                    _ => None,
+                })
+            }
+            (false, Some("ratelimited")) => Err(Failure::RateLimited(courier::DEFAULT_RETRY_AFTER)),
+            (false, Some(error)) if is_transient(error) => {
+                Err(SinkError::Transient(format!("Slack returned {}", error)).into())
+            }
+            (false, error) => Err(SinkError::Rejected(format!(
+                "Slack returned {}",
+                error.unwrap_or("an unspecified error")
+            ))
+            .into()),
+        }
+    }
+}
+
+/// A Slack destination as a `Sink`. Messages are edited in place with
+/// `Slack::post` and `Target::Update`, which has no counterpart elsewhere.
+pub struct SlackSink<'a> {
+    slack: &'a Slack,
+    target: Target<'a>,
+    /// See `QueueConfig::destination`.
+    name: String,
+}
+
+impl<'a> SlackSink<'a> {
+    pub fn new(slack: &'a Slack, target: Target<'a>, name: String) -> SlackSink<'a> {
+        SlackSink {
+            slack,
+            target,
+            name,
+        }
+    }
+}
+
+impl Sink for SlackSink<'_> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn deliver(&self, rendered: Notification) -> BoxFuture<'_, Result<DeliveryReceipt, SinkError>> {
+        Box::pin(async move {
+            let posted = self.slack.post(self.target, &rendered.body).await?;
+            Ok(match posted {
+                Some(Posted { channel, ts }) => DeliveryReceipt {
+                    channel: Some(channel),
+                    id: Some(ts),
+                },
+                None => DeliveryReceipt::default(),
+            })
+        })
+    }
+}
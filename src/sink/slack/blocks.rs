@@ -0,0 +1,305 @@
+//! Typed Block Kit structures. Serializing these produces the JSON Slack
+//! expects, so a message built from them has the right shape by
+//! construction; only Slack's length limits are left to check at runtime.
+
+use serde::Serialize;
+use serde_json::Value;
+
+/// Slack rejects any text object longer than this.
+pub const MAX_TEXT_LEN: usize = 3000;
+/// Slack rejects section fields longer than this.
+pub const MAX_FIELD_LEN: usize = 2000;
+/// Slack rejects sections with more fields than this.
+pub const MAX_FIELDS: usize = 10;
+/// Slack rejects messages with more blocks than this.
+pub const MAX_BLOCKS: usize = 50;
+/// Slack rejects block IDs longer than this.
+pub const MAX_BLOCK_ID_LEN: usize = 255;
+
+/// Cut `text` down to at most `max` characters, marking the cut.
+fn truncate(text: String, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text;
+    }
+    let mut cut: String = text.chars().take(max - 1).collect();
+    cut.push('…');
+    cut
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename = "plain_text")]
+pub struct PlainText {
+    pub text: String,
+    pub emoji: bool,
+}
+
+impl PlainText {
+    pub fn new(text: impl Into<String>) -> PlainText {
+        PlainText {
+            text: truncate(text.into(), MAX_TEXT_LEN),
+            emoji: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename = "mrkdwn")]
+pub struct Mrkdwn {
+    pub text: String,
+}
+
+impl Mrkdwn {
+    pub fn new(text: impl Into<String>) -> Mrkdwn {
+        Mrkdwn {
+            text: truncate(text.into(), MAX_TEXT_LEN),
+        }
+    }
+}
+
+/// A text object where Slack accepts either kind.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum Text {
+    Plain(PlainText),
+    Mrkdwn(Mrkdwn),
+}
+
+impl From<PlainText> for Text {
+    fn from(text: PlainText) -> Text {
+        Text::Plain(text)
+    }
+}
+
+impl From<Mrkdwn> for Text {
+    fn from(text: Mrkdwn) -> Text {
+        Text::Mrkdwn(text)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename = "button")]
+pub struct Button {
+    pub text: PlainText,
+    /// Identifies the button in the interaction payload sent when clicked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub action_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+}
+
+impl Button {
+    pub fn new(text: impl Into<String>) -> Button {
+        Button {
+            text: PlainText::new(text),
+            action_id: None,
+            value: None,
+        }
+    }
+
+    pub fn action_id(mut self, action_id: impl Into<String>) -> Button {
+        self.action_id = Some(action_id.into());
+        self
+    }
+
+    pub fn value(mut self, value: impl Into<String>) -> Button {
+        self.value = Some(value.into());
+        self
+    }
+}
+
+/// One choice in a menu.
+#[derive(Debug, Clone, Serialize)]
+pub struct Choice {
+    pub text: PlainText,
+    pub value: String,
+}
+
+/// A menu of choices behind a "more" icon.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename = "overflow")]
+pub struct Overflow {
+    pub action_id: String,
+    pub options: Vec<Choice>,
+}
+
+impl Overflow {
+    pub fn new(action_id: impl Into<String>) -> Overflow {
+        Overflow {
+            action_id: action_id.into(),
+            options: Vec::new(),
+        }
+    }
+
+    pub fn option(mut self, text: impl Into<String>, value: impl Into<String>) -> Overflow {
+        self.options.push(Choice {
+            text: PlainText::new(text),
+            value: value.into(),
+        });
+        self
+    }
+}
+
+/// Interactive element placed beside a section's text.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum Accessory {
+    Button(Button),
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Section {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<Text>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<Text>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accessory: Option<Accessory>,
+}
+
+impl Section {
+    pub fn new(text: impl Into<Text>) -> Section {
+        Section {
+            text: Some(text.into()),
+            ..Section::default()
+        }
+    }
+
+    /// Add a field, silently dropping any beyond Slack's limit.
+    pub fn field(mut self, field: Mrkdwn) -> Section {
+        if self.fields.len() < MAX_FIELDS {
+            self.fields.push(Text::Mrkdwn(Mrkdwn {
+                text: truncate(field.text, MAX_FIELD_LEN),
+            }));
+        }
+        self
+    }
+
+    pub fn accessory(mut self, accessory: Accessory) -> Section {
+        self.accessory = Some(accessory);
+        self
+    }
+}
+
+/// Interactive element in an actions block.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum Element {
+    Button(Button),
+    Overflow(Overflow),
+}
+
+/// A row of interactive elements. Slack reports the `block_id` with any
+/// action taken on them.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Actions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block_id: Option<String>,
+    pub elements: Vec<Element>,
+}
+
+impl Actions {
+    pub fn element(mut self, element: Element) -> Actions {
+        self.elements.push(element);
+        self
+    }
+
+    /// Set the block ID, which must be no longer than `MAX_BLOCK_ID_LEN`.
+    pub fn block_id(mut self, block_id: impl Into<String>) -> Actions {
+        self.block_id = Some(block_id.into());
+        self
+    }
+}
+
+/// Small print shown beneath a message.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Context {
+    pub elements: Vec<Text>,
+}
+
+impl Context {
+    pub fn new(text: impl Into<Text>) -> Context {
+        Context {
+            elements: vec![text.into()],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Block {
+    Section(Section),
+    Divider,
+    Actions(Actions),
+    Context(Context),
+}
+
+impl From<Actions> for Block {
+    fn from(actions: Actions) -> Block {
+        Block::Actions(actions)
+    }
+}
+
+impl From<Section> for Block {
+    fn from(section: Section) -> Block {
+        Block::Section(section)
+    }
+}
+
+impl From<Context> for Block {
+    fn from(context: Context) -> Block {
+        Block::Context(context)
+    }
+}
+
+/// Blocks shown beside a coloured bar.
+#[derive(Debug, Clone, Serialize)]
+pub struct Attachment {
+    pub color: String,
+    pub blocks: Vec<Block>,
+}
+
+/// A complete message body.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Message {
+    /// Plain fallback shown in notifications and by clients that cannot
+    /// display blocks.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub blocks: Vec<Block>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+}
+
+impl Message {
+    pub fn new() -> Message {
+        Message::default()
+    }
+
+    pub fn text(mut self, text: impl Into<String>) -> Message {
+        self.text = Some(text.into());
+        self
+    }
+
+    /// Append a block, silently dropping any beyond Slack's limit.
+    pub fn block(mut self, block: impl Into<Block>) -> Message {
+        if self.blocks.len() < MAX_BLOCKS {
+            self.blocks.push(block.into());
+        }
+        self
+    }
+
+    /// Move the blocks added so far into an attachment with a `color` bar.
+    pub fn color(mut self, color: impl Into<String>) -> Message {
+        let blocks = std::mem::take(&mut self.blocks);
+        self.attachments.push(Attachment {
+            color: color.into(),
+            blocks,
+        });
+        self
+    }
+
+    pub fn to_value(&self) -> Value {
+        serde_json::to_value(self).expect("Block Kit messages always serialize")
+    }
+}
@@ -0,0 +1,227 @@
+//! Amazon SNS topics, through the Publish action of the query API, for
+//! consumers in AWS that would rather subscribe than read the broker.
+
+use crate::alert::Alert;
+use crate::aws;
+use crate::config::HttpConfig;
+use crate::sink::courier::{Courier, Failure, DEFAULT_RETRY_AFTER};
+use crate::sink::{DeliveryReceipt, Notification, Sink, SinkError};
+use futures::future::BoxFuture;
+use log::debug;
+use reqwest::Client;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// SNS takes thousands of publishes a second; this only smooths bursts.
+const MIN_INTERVAL: Duration = Duration::from_millis(20);
+const CONTENT_TYPE: &str = "application/x-www-form-urlencoded; charset=utf-8";
+/// SNS's limits on a message: attributes, and the length of a subject.
+const MAX_ATTRIBUTES: usize = 10;
+const MAX_SUBJECT: usize = 100;
+
+/// Whether SNS accepts `name` as the name of a message attribute.
+fn attribute_name(name: &str) -> bool {
+    let lower = name.to_ascii_lowercase();
+    !name.is_empty()
+        && name.len() <= 256
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-.".contains(c))
+        && !name.starts_with('.')
+        && !name.ends_with('.')
+        && !name.contains("..")
+        && !lower.starts_with("aws.")
+        && !lower.starts_with("amazon.")
+}
+
+/// The labels of `alert` that can go out as message attributes: its source
+/// and step, and its additional fields holding strings, numbers or
+/// booleans.
+fn labels(alert: &Alert) -> Vec<(String, &'static str, String)> {
+    let mut labels = Vec::new();
+    for (name, value) in [("source", &alert.source), ("step", &alert.step)] {
+        if let Some(value) = value {
+            labels.push((name.to_string(), "String", value.clone()));
+        }
+    }
+    for (key, value) in &alert.fields {
+        let (data_type, value) = match value {
+            Value::String(s) => ("String", s.clone()),
+            Value::Number(n) => ("Number", n.to_string()),
+            Value::Bool(b) => ("String", b.to_string()),
+            _ => continue,
+        };
+        if attribute_name(key) {
+            labels.push((key.clone(), data_type, value));
+        }
+    }
+    labels
+}
+
+/// Message attributes for `rendered`: its severity, then the labels its
+/// alerts all share, as many as SNS allows.
+pub fn attributes(rendered: &Notification) -> Vec<(String, &'static str, String)> {
+    let mut attributes = vec![(
+        "severity".to_string(),
+        "String",
+        rendered.severity.to_string(),
+    )];
+    if let Some((first, rest)) = rendered.alerts.split_first() {
+        let others: Vec<_> = rest.iter().map(labels).collect();
+        for label in labels(first) {
+            if others.iter().all(|labels| labels.contains(&label)) {
+                attributes.push(label);
+            }
+        }
+    }
+    if attributes.len() > MAX_ATTRIBUTES {
+        debug!(
+            "Leaving {} labels out of the SNS message attributes",
+            attributes.len() - MAX_ATTRIBUTES
+        );
+        attributes.truncate(MAX_ATTRIBUTES);
+    }
+    attributes
+}
+
+/// `summary` as an SNS subject: printable ASCII on one line, cut short.
+pub fn subject(summary: &str) -> String {
+    let subject: String = summary
+        .chars()
+        .map(|c| if c.is_ascii_graphic() { c } else { ' ' })
+        .take(MAX_SUBJECT)
+        .collect();
+    subject.trim().to_string()
+}
+
+/// The text of the first `tag` element in `xml`, such as the error code in
+/// one of SNS's error responses.
+fn element<'x>(xml: &'x str, tag: &str) -> Option<&'x str> {
+    let start = xml.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", tag))?;
+    Some(&xml[start..end])
+}
+
+/// Publishes messages to SNS topics.
+pub struct Sns {
+    client: Client,
+    courier: Courier,
+}
+
+impl Sns {
+    pub fn new(client: Client, http: &HttpConfig) -> Sns {
+        Sns {
+            client,
+            courier: Courier::new(http),
+        }
+    }
+
+    /// Publish `message` to `topic` with `subject` and `attributes`,
+    /// retrying transient failures a few times before giving up on this
+    /// attempt.
+    pub async fn publish(
+        &self,
+        topic: &str,
+        subject: &str,
+        message: &str,
+        attributes: &[(String, &'static str, String)],
+    ) -> Result<(), SinkError> {
+        let mut form = vec![
+            ("Action".to_string(), "Publish".to_string()),
+            ("Version".to_string(), "2010-03-31".to_string()),
+            ("TopicArn".to_string(), topic.to_string()),
+            ("Message".to_string(), message.to_string()),
+        ];
+        if !subject.is_empty() {
+            form.push(("Subject".to_string(), subject.to_string()));
+        }
+        for (n, (name, data_type, value)) in attributes.iter().enumerate() {
+            let entry = format!("MessageAttributes.entry.{}", n + 1);
+            form.push((format!("{}.Name", entry), name.clone()));
+            form.push((format!("{}.Value.DataType", entry), data_type.to_string()));
+            form.push((format!("{}.Value.StringValue", entry), value.clone()));
+        }
+        let body = serde_urlencoded::to_string(&form)
+            .map_err(|e| SinkError::Rejected(format!("unable to encode SNS message: {}", e)))?;
+
+        self.courier
+            .send("SNS", topic.to_string(), MIN_INTERVAL, || {
+                self.publish_once(topic, body.clone())
+            })
+            .await
+    }
+
+    async fn publish_once(&self, topic: &str, body: String) -> Result<(), Failure> {
+        // The topic's ARN names its region: arn:aws:sns:<region>:...
+        let region = topic.split(':').nth(3).unwrap_or_default();
+        let res = aws::post(
+            &self.client,
+            "sns",
+            region,
+            &[("content-type", CONTENT_TYPE)],
+            body,
+        )
+        .map_err(SinkError::Rejected)?
+        .send()
+        .await
+        .map_err(|e| SinkError::Transient(format!("failed sending to SNS: {}", e)))?;
+
+        let status = res.status();
+        if status.is_success() {
+            debug!("Message published to SNS");
+            return Ok(());
+        }
+        let xml = res.text().await.unwrap_or_default();
+        let code = element(&xml, "Code").unwrap_or_else(|| status.as_str());
+        match code {
+            "Throttling" | "ThrottlingException" | "ThrottledException" => {
+                Err(Failure::RateLimited(DEFAULT_RETRY_AFTER))
+            }
+            _ if status.is_client_error() => {
+                Err(SinkError::Rejected(format!("SNS returned {}", code)).into())
+            }
+            _ => Err(SinkError::Transient(format!("SNS returned {}", code)).into()),
+        }
+    }
+}
+
+/// An SNS topic as a `Sink`. The message is the JSON a webhook receiver
+/// gets by default.
+pub struct SnsSink<'a> {
+    sns: &'a Sns,
+    topic: &'a str,
+    /// See `QueueConfig::destination`.
+    name: String,
+}
+
+impl<'a> SnsSink<'a> {
+    pub fn new(sns: &'a Sns, topic: &'a str, name: String) -> SnsSink<'a> {
+        SnsSink { sns, topic, name }
+    }
+}
+
+impl Sink for SnsSink<'_> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn deliver(&self, rendered: Notification) -> BoxFuture<'_, Result<DeliveryReceipt, SinkError>> {
+        Box::pin(async move {
+            let message = json!({
+                "summary": rendered.summary,
+                "severity": rendered.severity.to_string(),
+                "alerts": rendered.alerts,
+            })
+            .to_string();
+            self.sns
+                .publish(
+                    self.topic,
+                    &subject(&rendered.summary),
+                    &message,
+                    &attributes(&rendered),
+                )
+                .await?;
+            Ok(DeliveryReceipt::default())
+        })
+    }
+}
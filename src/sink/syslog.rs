@@ -0,0 +1,284 @@
+//! Syslog, as RFC 5424 messages over UDP, TCP or TLS, so that a SIEM keeps
+//! a record of every notification whatever happens to it elsewhere.
+
+use crate::alert::{Alert, Severity, Status};
+use crate::config::{HttpConfig, SyslogReceiver, SyslogTransport};
+use crate::sink::courier::{Courier, Failure};
+use crate::sink::{DeliveryReceipt, Notification, Sink, SinkError};
+use crate::source::tls_connector;
+use chrono::{SecondsFormat, Utc};
+use futures::future::BoxFuture;
+use log::debug;
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket};
+
+/// Collectors take messages as fast as we can send them.
+const MIN_INTERVAL: Duration = Duration::from_millis(0);
+const NIL: &str = "-";
+/// Enterprise number for our structured data IDs: the one RFC 5612 sets
+/// aside for documentation, as we have none of our own.
+const ENTERPRISE: u32 = 32473;
+/// RFC 5424's limit on the length of a parameter name.
+const MAX_PARAM_NAME: usize = 32;
+
+/// The syslog severity for an alert of `severity` in `status`: critical,
+/// warning or informational, or notice once it has recovered.
+fn severity_code(severity: Severity, status: Status) -> u8 {
+    match (status, severity) {
+        (Status::Resolved, _) => 5,
+        (Status::Firing, Severity::Critical) => 2,
+        (Status::Firing, Severity::Warning) => 4,
+        (Status::Firing, Severity::Info) => 6,
+    }
+}
+
+/// `value` with the characters special in a parameter value escaped.
+fn escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Whether `name` can be a parameter name.
+fn param_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_PARAM_NAME
+        && name
+            .chars()
+            .all(|c| c.is_ascii_graphic() && !matches!(c, '=' | ']' | '"'))
+}
+
+/// A structured data element with `id` and `params`.
+fn element(id: &str, params: &[(String, String)]) -> String {
+    let mut element = format!("[{}@{}", id, ENTERPRISE);
+    for (name, value) in params {
+        element.push_str(&format!(" {}=\"{}\"", name, escape(value)));
+    }
+    element.push(']');
+    element
+}
+
+/// Structured data for `alert`: its details, then its additional fields.
+fn structured_data(alert: &Alert) -> String {
+    let status = match alert.status {
+        Status::Firing => "firing",
+        Status::Resolved => "resolved",
+    };
+    let mut details = vec![
+        ("key".to_string(), alert.key()),
+        ("severity".to_string(), alert.severity.to_string()),
+        ("status".to_string(), status.to_string()),
+    ];
+    for (name, value) in [
+        ("source", &alert.source),
+        ("step", &alert.step),
+        ("run_id", &alert.run_id),
+    ] {
+        if let Some(value) = value {
+            details.push((name.to_string(), value.clone()));
+        }
+    }
+    let mut data = element("alert", &details);
+
+    let fields: Vec<(String, String)> = alert
+        .fields
+        .iter()
+        .filter(|(key, _)| param_name(key))
+        .map(|(key, value)| {
+            let value = match value {
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            (key.clone(), value)
+        })
+        .collect();
+    if !fields.is_empty() {
+        data.push_str(&element("fields", &fields));
+    }
+    data
+}
+
+/// An RFC 5424 message for `server` about `alert`, or about no alert in
+/// particular with `summary` as its text.
+pub fn message(server: &SyslogReceiver, alert: Option<&Alert>, summary: &str) -> String {
+    let (severity, msgid, data, text) = match alert {
+        Some(alert) => {
+            let msgid = match alert.status {
+                Status::Firing => "firing",
+                Status::Resolved => "resolved",
+            };
+            let text = match &alert.reason {
+                Some(reason) => format!("{}: {}", alert.title, reason),
+                None => alert.title.clone(),
+            };
+            (
+                severity_code(alert.severity, alert.status),
+                msgid,
+                structured_data(alert),
+                text,
+            )
+        }
+        None => (
+            severity_code(Severity::Info, Status::Firing),
+            "summary",
+            NIL.to_string(),
+            summary.to_string(),
+        ),
+    };
+    // The byte order mark says the text is UTF-8.
+    format!(
+        "<{}>1 {} {} {} {} {} {} \u{feff}{}",
+        server.facility as u32 * 8 + severity as u32,
+        Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+        server.hostname.as_deref().unwrap_or(NIL),
+        server.app_name,
+        std::process::id(),
+        msgid,
+        data,
+        text
+    )
+}
+
+fn transient(e: impl std::fmt::Display) -> Failure {
+    SinkError::Transient(format!("failed sending to syslog: {}", e)).into()
+}
+
+/// Sends messages to syslog servers. Connections are made for each
+/// message, so a reloaded configuration applies at once; alerts are too
+/// few for the handshakes to matter.
+pub struct Syslog {
+    courier: Courier,
+    connect_timeout: Duration,
+}
+
+impl Syslog {
+    pub fn new(http: &HttpConfig) -> Syslog {
+        Syslog {
+            courier: Courier::new(http),
+            connect_timeout: http.connect_timeout,
+        }
+    }
+
+    /// Send `message` to `server`, retrying transient failures a few times
+    /// before giving up on this attempt.
+    pub async fn send(&self, server: &SyslogReceiver, message: &str) -> Result<(), SinkError> {
+        let key = format!("{}:{}", server.host, server.port);
+        self.courier
+            .send("syslog", key, MIN_INTERVAL, || {
+                self.send_once(server, message)
+            })
+            .await
+    }
+
+    async fn send_once(&self, server: &SyslogReceiver, message: &str) -> Result<(), Failure> {
+        let address = tokio::net::lookup_host((server.host.as_str(), server.port))
+            .await
+            .map_err(transient)?
+            .next()
+            .ok_or_else(|| transient(format!("{} has no addresses", server.host)))?;
+
+        if server.transport == SyslogTransport::Udp {
+            let local: SocketAddr = if address.is_ipv4() {
+                ([0, 0, 0, 0], 0).into()
+            } else {
+                ([0u16; 8], 0).into()
+            };
+            let mut socket = UdpSocket::bind(local).await.map_err(transient)?;
+            socket
+                .send_to(message.as_bytes(), &address)
+                .await
+                .map_err(transient)?;
+            debug!("Sent syslog message to {}", address);
+            return Ok(());
+        }
+
+        let stream = tokio::time::timeout(self.connect_timeout, TcpStream::connect(&address))
+            .await
+            .map_err(|_| transient(format!("timed out connecting to {}", address)))?
+            .map_err(transient)?;
+        // Octet counting, so that messages may hold line breaks.
+        let frame = format!("{} {}", message.len(), message);
+        if server.transport == SyslogTransport::Tls {
+            let connector = match &server.tls {
+                Some(tls) => tls_connector(tls),
+                None => native_tls::TlsConnector::new()
+                    .map_err(|e| format!("Unable to set up TLS: {}", e)),
+            }
+            .map_err(SinkError::Rejected)?;
+            let domain = server
+                .tls
+                .as_ref()
+                .and_then(|tls| tls.server_name.as_deref())
+                .unwrap_or(&server.host);
+            let mut stream = tokio_tls::TlsConnector::from(connector)
+                .connect(domain, stream)
+                .await
+                .map_err(transient)?;
+            stream
+                .write_all(frame.as_bytes())
+                .await
+                .map_err(transient)?;
+            AsyncWriteExt::shutdown(&mut stream)
+                .await
+                .map_err(transient)?;
+        } else {
+            let mut stream = stream;
+            stream
+                .write_all(frame.as_bytes())
+                .await
+                .map_err(transient)?;
+            AsyncWriteExt::shutdown(&mut stream)
+                .await
+                .map_err(transient)?;
+        }
+        debug!("Sent syslog message to {}", address);
+        Ok(())
+    }
+}
+
+/// A syslog server as a `Sink`. Each alert in a digest gets a message of
+/// its own, and summaries about no alert in particular get one too.
+pub struct SyslogSink<'a> {
+    syslog: &'a Syslog,
+    server: &'a SyslogReceiver,
+    /// See `QueueConfig::destination`.
+    name: String,
+}
+
+impl<'a> SyslogSink<'a> {
+    pub fn new(syslog: &'a Syslog, server: &'a SyslogReceiver, name: String) -> SyslogSink<'a> {
+        SyslogSink {
+            syslog,
+            server,
+            name,
+        }
+    }
+}
+
+impl Sink for SyslogSink<'_> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn deliver(&self, rendered: Notification) -> BoxFuture<'_, Result<DeliveryReceipt, SinkError>> {
+        Box::pin(async move {
+            if rendered.alerts.is_empty() {
+                let message = message(self.server, None, &rendered.summary);
+                self.syslog.send(self.server, &message).await?;
+            }
+            for alert in &rendered.alerts {
+                let message = message(self.server, Some(alert), &rendered.summary);
+                self.syslog.send(self.server, &message).await?;
+            }
+            Ok(DeliveryReceipt::default())
+        })
+    }
+}
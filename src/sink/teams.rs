@@ -0,0 +1,191 @@
+//! Microsoft Teams, through incoming webhooks taking Adaptive Cards.
+
+use crate::alert::{Alert, Severity, Status};
+use crate::config::HttpConfig;
+use crate::sink::courier::{self, Courier, Failure};
+use crate::sink::{DeliveryReceipt, Notification, Sink, SinkError};
+use futures::future::BoxFuture;
+use log::debug;
+use reqwest::{Client, StatusCode};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// Teams accepts about four messages a second per webhook.
+const MIN_INTERVAL: Duration = Duration::from_millis(250);
+const CARD_CONTENT_TYPE: &str = "application/vnd.microsoft.card.adaptive";
+const CARD_SCHEMA: &str = "http://adaptivecards.io/schemas/adaptive-card.json";
+
+/// The Adaptive Card colour for an alert of `severity` in `status`.
+fn color(severity: Severity, status: Status) -> &'static str {
+    match (status, severity) {
+        (Status::Resolved, _) => "good",
+        (Status::Firing, Severity::Critical) => "attention",
+        (Status::Firing, Severity::Warning) => "warning",
+        (Status::Firing, Severity::Info) => "accent",
+    }
+}
+
+fn text(text: &str) -> Value {
+    json!({ "type": "TextBlock", "text": text, "wrap": true })
+}
+
+fn fact(title: &str, value: impl ToString) -> Value {
+    json!({ "title": title, "value": value.to_string() })
+}
+
+/// The details of a single alert as a fact set.
+fn facts(alert: &Alert) -> Value {
+    let mut facts = vec![
+        fact("Severity", alert.severity),
+        fact(
+            "Status",
+            match alert.status {
+                Status::Firing => "firing",
+                Status::Resolved => "resolved",
+            },
+        ),
+    ];
+    if let Some(source) = &alert.source {
+        facts.push(fact("Source", source));
+    }
+    if let Some(step) = &alert.step {
+        facts.push(fact("Step", step));
+    }
+    if let Some(run_id) = &alert.run_id {
+        facts.push(fact("Run", run_id));
+    }
+    facts.push(fact(
+        "Time",
+        alert.timestamp.format("%Y-%m-%d %H:%M:%S UTC"),
+    ));
+    json!({ "type": "FactSet", "facts": facts })
+}
+
+/// `rendered` as a message holding an Adaptive Card: headed by its summary,
+/// then the details of its alert, or a line for each alert in a digest.
+pub fn card(rendered: &Notification) -> Value {
+    let status = match rendered.alerts.as_slice() {
+        [alert] => alert.status,
+        _ => Status::Firing,
+    };
+    let mut body = vec![json!({
+        "type": "TextBlock",
+        "text": rendered.summary,
+        "size": "Medium",
+        "weight": "Bolder",
+        "color": color(rendered.severity, status),
+        "wrap": true,
+    })];
+    match rendered.alerts.as_slice() {
+        [alert] => {
+            if let Some(reason) = &alert.reason {
+                body.push(text(reason));
+            }
+            body.push(facts(alert));
+        }
+        alerts => {
+            for alert in alerts {
+                body.push(text(&match &alert.reason {
+                    Some(reason) => format!("- **{}**: {}", alert.title, reason),
+                    None => format!("- **{}**", alert.title),
+                }));
+            }
+        }
+    }
+
+    json!({
+        "type": "message",
+        "attachments": [{
+            "contentType": CARD_CONTENT_TYPE,
+            "contentUrl": null,
+            "content": {
+                "$schema": CARD_SCHEMA,
+                "type": "AdaptiveCard",
+                "version": "1.4",
+                "body": body,
+                "msteams": { "width": "Full" },
+            },
+        }],
+    })
+}
+
+/// Posts cards to Teams webhooks, with the same spacing, rate limiting and
+/// retries as messages to Slack.
+pub struct Teams {
+    client: Client,
+    courier: Courier,
+}
+
+impl Teams {
+    pub fn new(client: Client, http: &HttpConfig) -> Teams {
+        Teams {
+            client,
+            courier: Courier::new(http),
+        }
+    }
+
+    /// Post `card` to the webhook at `url`, retrying transient failures a
+    /// few times before giving up on this attempt.
+    pub async fn post(&self, url: &str, card: &Value) -> Result<(), SinkError> {
+        self.courier
+            .send("Teams", url.to_string(), MIN_INTERVAL, || {
+                self.post_once(url, card)
+            })
+            .await
+    }
+
+    async fn post_once(&self, url: &str, card: &Value) -> Result<(), Failure> {
+        let res = self
+            .client
+            .post(url)
+            .json(card)
+            .send()
+            .await
+            .map_err(|e| SinkError::Transient(format!("failed sending to Teams: {:?}", e)))?;
+
+        match res.status() {
+            StatusCode::TOO_MANY_REQUESTS => Err(Failure::RateLimited(courier::retry_after(&res))),
+            status if status.is_success() => {
+                // Older connector webhooks report throttling in the body of
+                // a 200 response.
+                let body = res.text().await.unwrap_or_default();
+                if body.contains("HTTP error 429") {
+                    return Err(Failure::RateLimited(courier::DEFAULT_RETRY_AFTER));
+                }
+                debug!("Message acknowledged by Teams");
+                Ok(())
+            }
+            status if status.is_client_error() => {
+                Err(SinkError::Rejected(format!("Teams returned {}", status)).into())
+            }
+            status => Err(SinkError::Transient(format!("Teams returned {}", status)).into()),
+        }
+    }
+}
+
+/// A Teams webhook as a `Sink`.
+pub struct TeamsSink<'a> {
+    teams: &'a Teams,
+    url: &'a str,
+    /// See `QueueConfig::destination`.
+    name: String,
+}
+
+impl<'a> TeamsSink<'a> {
+    pub fn new(teams: &'a Teams, url: &'a str, name: String) -> TeamsSink<'a> {
+        TeamsSink { teams, url, name }
+    }
+}
+
+impl Sink for TeamsSink<'_> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn deliver(&self, rendered: Notification) -> BoxFuture<'_, Result<DeliveryReceipt, SinkError>> {
+        Box::pin(async move {
+            self.teams.post(self.url, &card(&rendered)).await?;
+            Ok(DeliveryReceipt::default())
+        })
+    }
+}
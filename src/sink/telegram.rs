@@ -0,0 +1,246 @@
+//! Telegram, through the Bot API's sendMessage with MarkdownV2 text.
+
+use crate::alert::{Alert, Severity, Status};
+use crate::config::{HttpConfig, TelegramReceiver};
+use crate::sink::courier::{Courier, Failure, DEFAULT_RETRY_AFTER};
+use crate::sink::{DeliveryReceipt, Notification, Sink, SinkError};
+use futures::future::BoxFuture;
+use log::debug;
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// Telegram allows about one message a second to each chat.
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+/// Telegram's limit on the length of a message, less room for the note of
+/// alerts left out of a digest.
+const MAX_TEXT: usize = 4000;
+/// Longest reason shown in full; longer ones are cut short.
+const MAX_REASON: usize = 1000;
+/// Characters that must be escaped everywhere in MarkdownV2 text.
+const SPECIAL: &str = "_*[]()~`>#+-=|{}.!\\";
+
+#[derive(Deserialize)]
+struct ApiResponse {
+    ok: bool,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    parameters: Option<ResponseParameters>,
+}
+
+#[derive(Deserialize)]
+struct ResponseParameters {
+    #[serde(default)]
+    retry_after: Option<u64>,
+}
+
+/// `text` with the characters MarkdownV2 gives meaning to escaped.
+pub fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        if SPECIAL.contains(c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+fn truncate(text: &str, max: usize) -> String {
+    match text.char_indices().nth(max) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+/// What went wrong with a request, leaving out the URL as it holds the bot
+/// token.
+fn cause(e: &reqwest::Error) -> String {
+    match std::error::Error::source(e) {
+        Some(source) => source.to_string(),
+        None => "request failed".to_string(),
+    }
+}
+
+fn icon(severity: Severity, status: Status) -> &'static str {
+    match (status, severity) {
+        (Status::Resolved, _) => "✅",
+        (Status::Firing, Severity::Critical) => "🔴",
+        (Status::Firing, Severity::Warning) => "🟠",
+        (Status::Firing, Severity::Info) => "🔵",
+    }
+}
+
+/// `alert` in full: its title, its reason and a line for each detail.
+fn alert_text(alert: &Alert) -> String {
+    let title = match alert.status {
+        Status::Firing => format!("*{}*", escape(&alert.title)),
+        Status::Resolved => format!("*~{}~*", escape(&alert.title)),
+    };
+    let mut lines = vec![format!("{} {}", icon(alert.severity, alert.status), title)];
+    if let Some(reason) = &alert.reason {
+        lines.push(escape(&truncate(reason, MAX_REASON)));
+    }
+    lines.push(String::new());
+    let mut details = vec![("Severity".to_string(), alert.severity.to_string())];
+    for (name, value) in [
+        ("Source", &alert.source),
+        ("Step", &alert.step),
+        ("Run", &alert.run_id),
+    ] {
+        if let Some(value) = value {
+            details.push((name.to_string(), value.clone()));
+        }
+    }
+    for (key, value) in &alert.fields {
+        let value = match value {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        details.push((key.clone(), value));
+    }
+    for (name, value) in details {
+        lines.push(format!("_{}:_ {}", escape(&name), escape(&value)));
+    }
+    lines.join("\n")
+}
+
+/// `rendered` as MarkdownV2: its alert in full, or its summary and a line
+/// for each alert in a digest, as many as fit.
+pub fn text(rendered: &Notification) -> String {
+    if let [alert] = rendered.alerts.as_slice() {
+        let text = alert_text(alert);
+        if text.chars().count() <= MAX_TEXT {
+            return text;
+        }
+    }
+
+    let mut text = format!(
+        "{} *{}*",
+        icon(rendered.severity, Status::Firing),
+        escape(&rendered.summary)
+    );
+    for (shown, alert) in rendered.alerts.iter().enumerate() {
+        let line = match &alert.reason {
+            Some(reason) => format!(
+                "\n• *{}*: {}",
+                escape(&alert.title),
+                escape(&truncate(reason, 200))
+            ),
+            None => format!("\n• *{}*", escape(&alert.title)),
+        };
+        if text.chars().count() + line.chars().count() > MAX_TEXT {
+            let more = format!("…and {} more", rendered.alerts.len() - shown);
+            text.push_str(&format!("\n{}", escape(&more)));
+            break;
+        }
+        text.push_str(&line);
+    }
+    text
+}
+
+/// Sends messages to Telegram chats through their bots.
+pub struct Telegram {
+    client: Client,
+    courier: Courier,
+}
+
+impl Telegram {
+    pub fn new(client: Client, http: &HttpConfig) -> Telegram {
+        Telegram {
+            client,
+            courier: Courier::new(http),
+        }
+    }
+
+    /// Send `text` to `chat`, retrying transient failures a few times
+    /// before giving up on this attempt.
+    pub async fn send(&self, chat: &TelegramReceiver, text: &str) -> Result<(), SinkError> {
+        let key = format!("{}:{}", chat.bot_token, chat.chat_id);
+        self.courier
+            .send("Telegram", key, MIN_INTERVAL, || self.send_once(chat, text))
+            .await
+    }
+
+    async fn send_once(&self, chat: &TelegramReceiver, text: &str) -> Result<(), Failure> {
+        let url = format!(
+            "{}/bot{}/sendMessage",
+            chat.url.trim_end_matches('/'),
+            chat.bot_token
+        );
+        let res = self
+            .client
+            .post(&url)
+            .json(&json!({
+                "chat_id": chat.chat_id,
+                "text": text,
+                "parse_mode": "MarkdownV2",
+                "disable_web_page_preview": true,
+            }))
+            .send()
+            .await
+            .map_err(|e| {
+                SinkError::Transient(format!("failed sending to Telegram: {}", cause(&e)))
+            })?;
+
+        let status = res.status();
+        let api: Option<ApiResponse> = res.json().await.ok();
+        let description = api
+            .as_ref()
+            .and_then(|api| api.description.clone())
+            .unwrap_or_else(|| status.to_string());
+        match (status, api) {
+            (_, Some(ApiResponse { ok: true, .. })) => {
+                debug!("Message acknowledged by Telegram");
+                Ok(())
+            }
+            (StatusCode::TOO_MANY_REQUESTS, api) => Err(Failure::RateLimited(
+                api.and_then(|api| api.parameters)
+                    .and_then(|parameters| parameters.retry_after)
+                    .map(Duration::from_secs)
+                    .unwrap_or(DEFAULT_RETRY_AFTER),
+            )),
+            (status, _) if status.is_client_error() => {
+                Err(SinkError::Rejected(format!("Telegram returned {}", description)).into())
+            }
+            _ => Err(SinkError::Transient(format!("Telegram returned {}", description)).into()),
+        }
+    }
+}
+
+/// A Telegram chat as a `Sink`.
+pub struct TelegramSink<'a> {
+    telegram: &'a Telegram,
+    chat: &'a TelegramReceiver,
+    /// See `QueueConfig::destination`.
+    name: String,
+}
+
+impl<'a> TelegramSink<'a> {
+    pub fn new(
+        telegram: &'a Telegram,
+        chat: &'a TelegramReceiver,
+        name: String,
+    ) -> TelegramSink<'a> {
+        TelegramSink {
+            telegram,
+            chat,
+            name,
+        }
+    }
+}
+
+impl Sink for TelegramSink<'_> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn deliver(&self, rendered: Notification) -> BoxFuture<'_, Result<DeliveryReceipt, SinkError>> {
+        Box::pin(async move {
+            self.telegram.send(self.chat, &text(&rendered)).await?;
+            Ok(DeliveryReceipt::default())
+        })
+    }
+}
@@ -0,0 +1,200 @@
+//! Text messages through Twilio's Messages API, for alerts that must reach
+//! someone when chat does not.
+
+use crate::alert::Status;
+use crate::config::{HttpConfig, TwilioConfig};
+use crate::ratelimit::RateLimiter;
+use crate::sink::courier::{self, Courier, Failure};
+use crate::sink::{DeliveryReceipt, Notification, Sink, SinkError};
+use futures::future::BoxFuture;
+use log::{debug, warn};
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use std::time::Duration;
+
+/// Twilio queues texts from a long code at about one a second.
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+/// Longest text sent, in characters: two segments, keeping the cost of
+/// each message down.
+const MAX_TEXT: usize = 300;
+/// Names the account-wide bucket in the rate limiter.
+const BUDGET: &str = "sms";
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    #[serde(default)]
+    code: Option<u32>,
+    #[serde(default)]
+    message: Option<String>,
+}
+
+/// `rendered` as a short text: its alert's title and reason, or the
+/// summary of a digest, marked with the severity or as resolved.
+pub fn text(rendered: &Notification) -> String {
+    let text = match rendered.alerts.as_slice() {
+        [alert] => {
+            let label = match alert.status {
+                Status::Resolved => "RESOLVED".to_string(),
+                Status::Firing => alert.severity.to_string().to_uppercase(),
+            };
+            match &alert.reason {
+                Some(reason) => format!("[{}] {}: {}", label, alert.title, reason),
+                None => format!("[{}] {}", label, alert.title),
+            }
+        }
+        _ => format!(
+            "[{}] {}",
+            rendered.severity.to_string().to_uppercase(),
+            rendered.summary
+        ),
+    };
+    match text.char_indices().nth(MAX_TEXT) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text,
+    }
+}
+
+/// Sends texts through a Twilio account, within its budget.
+pub struct Twilio {
+    client: Client,
+    courier: Courier,
+    budget: RateLimiter,
+}
+
+impl Twilio {
+    pub fn new(client: Client, http: &HttpConfig) -> Twilio {
+        Twilio {
+            client,
+            courier: Courier::new(http),
+            budget: RateLimiter::default(),
+        }
+    }
+
+    /// Text `body` to `to`, retrying transient failures a few times before
+    /// giving up on this attempt. Returns false without sending anything
+    /// if the account's budget is spent.
+    pub async fn send(
+        &self,
+        account: &TwilioConfig,
+        to: &str,
+        body: &str,
+    ) -> Result<bool, SinkError> {
+        if !self.budget.admit(&[(BUDGET.to_string(), account.limit)]) {
+            return Ok(false);
+        }
+        self.courier
+            .send("Twilio", account.account_sid.clone(), MIN_INTERVAL, || {
+                self.send_once(account, to, body)
+            })
+            .await?;
+        Ok(true)
+    }
+
+    async fn send_once(&self, account: &TwilioConfig, to: &str, body: &str) -> Result<(), Failure> {
+        let url = format!(
+            "{}/2010-04-01/Accounts/{}/Messages.json",
+            account.url.trim_end_matches('/'),
+            account.account_sid
+        );
+        let mut form = vec![("To", to), ("Body", body)];
+        match (&account.from, &account.messaging_service_sid) {
+            (Some(from), _) => form.push(("From", from)),
+            (None, Some(service)) => form.push(("MessagingServiceSid", service)),
+            (None, None) => {}
+        }
+        let res = self
+            .client
+            .post(&url)
+            .basic_auth(&account.account_sid, Some(&account.auth_token))
+            .form(&form)
+            .send()
+            .await
+            .map_err(|e| SinkError::Transient(format!("failed sending to Twilio: {}", e)))?;
+
+        let status = res.status();
+        if status.is_success() {
+            debug!("Text accepted by Twilio");
+            return Ok(());
+        }
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            return Err(Failure::RateLimited(courier::retry_after(&res)));
+        }
+        let error = res.json::<ErrorResponse>().await.ok();
+        let description = match error {
+            Some(ErrorResponse {
+                code: Some(code),
+                message: Some(message),
+            }) => format!("error {}: {}", code, message),
+            _ => status.to_string(),
+        };
+        if status.is_client_error() {
+            Err(SinkError::Rejected(format!("Twilio returned {}", description)).into())
+        } else {
+            Err(SinkError::Transient(format!("Twilio returned {}", description)).into())
+        }
+    }
+}
+
+/// A list of phone numbers as a `Sink`. Each number gets its own text,
+/// counted against the account's budget.
+pub struct SmsSink<'a> {
+    twilio: &'a Twilio,
+    account: &'a TwilioConfig,
+    numbers: &'a [String],
+    /// See `QueueConfig::destination`.
+    name: String,
+}
+
+impl<'a> SmsSink<'a> {
+    pub fn new(
+        twilio: &'a Twilio,
+        account: &'a TwilioConfig,
+        numbers: &'a [String],
+        name: String,
+    ) -> SmsSink<'a> {
+        SmsSink {
+            twilio,
+            account,
+            numbers,
+            name,
+        }
+    }
+}
+
+impl Sink for SmsSink<'_> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn deliver(&self, rendered: Notification) -> BoxFuture<'_, Result<DeliveryReceipt, SinkError>> {
+        Box::pin(async move {
+            let body = text(&rendered);
+            // Once any number has been texted the delivery counts as done,
+            // as trying again would text that number twice.
+            let mut delivered = false;
+            let mut failure = None;
+            for (sent, number) in self.numbers.iter().enumerate() {
+                match self.twilio.send(self.account, number, &body).await {
+                    Ok(true) => delivered = true,
+                    Ok(false) => {
+                        warn!(
+                            "SMS budget spent; not texting {} of {} numbers for {}",
+                            self.numbers.len() - sent,
+                            self.numbers.len(),
+                            self.name
+                        );
+                        return Ok(DeliveryReceipt::default());
+                    }
+                    Err(e) => {
+                        warn!("Unable to text a number for {}: {}", self.name, e);
+                        failure.get_or_insert(e);
+                    }
+                }
+            }
+            match failure {
+                Some(e) if !delivered => Err(e),
+                _ => Ok(DeliveryReceipt::default()),
+            }
+        })
+    }
+}
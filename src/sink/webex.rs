@@ -0,0 +1,221 @@
+//! Cisco Webex, through a bot posting markdown messages to rooms.
+
+use crate::alert::{Alert, Severity, Status};
+use crate::config::{HttpConfig, WebexConfig};
+use crate::sink::courier::{self, Courier, Failure};
+use crate::sink::{DeliveryReceipt, Notification, Sink, SinkError};
+use futures::future::BoxFuture;
+use log::debug;
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// Webex allows bots a few hundred messages a minute; this keeps a burst
+/// to one room well within that.
+const MIN_INTERVAL: Duration = Duration::from_millis(250);
+/// Webex's limit on the length of a message in bytes, less room for the
+/// note of alerts left out of a digest.
+const MAX_MARKDOWN: usize = 7000;
+/// Characters that markdown gives meaning to.
+const SPECIAL: &str = "\\`*_[]~<>#";
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    #[serde(default)]
+    message: Option<String>,
+}
+
+/// `text` with the characters markdown gives meaning to escaped.
+pub fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        if SPECIAL.contains(c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+fn icon(severity: Severity, status: Status) -> &'static str {
+    match (status, severity) {
+        (Status::Resolved, _) => "✅",
+        (Status::Firing, Severity::Critical) => "🔴",
+        (Status::Firing, Severity::Warning) => "🟠",
+        (Status::Firing, Severity::Info) => "🔵",
+    }
+}
+
+/// `alert` in full: its title, its reason and a line for each detail.
+fn alert_markdown(alert: &Alert) -> String {
+    let title = match alert.status {
+        Status::Firing => format!("**{}**", escape(&alert.title)),
+        Status::Resolved => format!("**{}** (resolved)", escape(&alert.title)),
+    };
+    let mut lines = vec![format!("{} {}", icon(alert.severity, alert.status), title)];
+    if let Some(reason) = &alert.reason {
+        lines.push(escape(reason));
+    }
+    lines.push(String::new());
+    lines.push(format!("- _Severity:_ {}", alert.severity));
+    for (name, value) in [
+        ("Source", &alert.source),
+        ("Step", &alert.step),
+        ("Run", &alert.run_id),
+    ] {
+        if let Some(value) = value {
+            lines.push(format!("- _{}:_ {}", name, escape(value)));
+        }
+    }
+    for (key, value) in &alert.fields {
+        let value = match value {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        lines.push(format!("- _{}:_ {}", escape(key), escape(&value)));
+    }
+    lines.join("\n")
+}
+
+/// `rendered` as markdown: its alert in full, or its summary and a line
+/// for each alert in a digest, as many as fit.
+pub fn markdown(rendered: &Notification) -> String {
+    if let [alert] = rendered.alerts.as_slice() {
+        let markdown = alert_markdown(alert);
+        if markdown.len() <= MAX_MARKDOWN {
+            return markdown;
+        }
+    }
+
+    let mut markdown = format!(
+        "{} **{}**",
+        icon(rendered.severity, Status::Firing),
+        escape(&rendered.summary)
+    );
+    for (shown, alert) in rendered.alerts.iter().enumerate() {
+        let line = match &alert.reason {
+            Some(reason) => format!(
+                "\n- **{}**: {}",
+                escape(&alert.title),
+                escape(&reason.chars().take(200).collect::<String>())
+            ),
+            None => format!("\n- **{}**", escape(&alert.title)),
+        };
+        if markdown.len() + line.len() > MAX_MARKDOWN {
+            markdown.push_str(&format!("\n\n…and {} more", rendered.alerts.len() - shown));
+            break;
+        }
+        markdown.push_str(&line);
+    }
+    markdown
+}
+
+/// Posts messages to Webex rooms as a bot.
+pub struct Webex {
+    client: Client,
+    courier: Courier,
+}
+
+impl Webex {
+    pub fn new(client: Client, http: &HttpConfig) -> Webex {
+        Webex {
+            client,
+            courier: Courier::new(http),
+        }
+    }
+
+    /// Post `markdown` to `room`, with `text` for clients that cannot show
+    /// it, retrying transient failures a few times before giving up on this
+    /// attempt.
+    pub async fn post(
+        &self,
+        bot: &WebexConfig,
+        room: &str,
+        markdown: &str,
+        text: &str,
+    ) -> Result<(), SinkError> {
+        self.courier
+            .send("Webex", room.to_string(), MIN_INTERVAL, || {
+                self.post_once(bot, room, markdown, text)
+            })
+            .await
+    }
+
+    async fn post_once(
+        &self,
+        bot: &WebexConfig,
+        room: &str,
+        markdown: &str,
+        text: &str,
+    ) -> Result<(), Failure> {
+        let res = self
+            .client
+            .post(&format!("{}/v1/messages", bot.url.trim_end_matches('/')))
+            .bearer_auth(&bot.bot_token)
+            .json(&json!({ "roomId": room, "markdown": markdown, "text": text }))
+            .send()
+            .await
+            .map_err(|e| SinkError::Transient(format!("failed sending to Webex: {}", e)))?;
+
+        let status = res.status();
+        if status.is_success() {
+            debug!("Message accepted by Webex");
+            return Ok(());
+        }
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            return Err(Failure::RateLimited(courier::retry_after(&res)));
+        }
+        let description = res
+            .json::<ErrorResponse>()
+            .await
+            .ok()
+            .and_then(|error| error.message)
+            .unwrap_or_else(|| status.to_string());
+        if status.is_client_error() {
+            Err(SinkError::Rejected(format!("Webex returned {}", description)).into())
+        } else {
+            Err(SinkError::Transient(format!("Webex returned {}", description)).into())
+        }
+    }
+}
+
+/// A Webex room as a `Sink`.
+pub struct WebexSink<'a> {
+    webex: &'a Webex,
+    bot: &'a WebexConfig,
+    room: &'a str,
+    /// See `QueueConfig::destination`.
+    name: String,
+}
+
+impl<'a> WebexSink<'a> {
+    pub fn new(
+        webex: &'a Webex,
+        bot: &'a WebexConfig,
+        room: &'a str,
+        name: String,
+    ) -> WebexSink<'a> {
+        WebexSink {
+            webex,
+            bot,
+            room,
+            name,
+        }
+    }
+}
+
+impl Sink for WebexSink<'_> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn deliver(&self, rendered: Notification) -> BoxFuture<'_, Result<DeliveryReceipt, SinkError>> {
+        Box::pin(async move {
+            self.webex
+                .post(self.bot, self.room, &markdown(&rendered), &rendered.summary)
+                .await?;
+            Ok(DeliveryReceipt::default())
+        })
+    }
+}
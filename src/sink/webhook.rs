@@ -0,0 +1,119 @@
+//! Arbitrary HTTP endpoints, for tooling without an integration of its own.
+
+use crate::config::{HttpConfig, WebhookAuth, WebhookReceiver};
+use crate::render::Templates;
+use crate::sink::courier::{self, Courier, Failure};
+use crate::sink::{DeliveryReceipt, Notification, Sink, SinkError};
+use futures::future::BoxFuture;
+use log::debug;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, StatusCode};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Spaces requests lightly; endpoints that need more can say so with 429.
+const MIN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// POSTs rendered bodies to webhook receivers.
+pub struct Webhook {
+    client: Client,
+    courier: Courier,
+}
+
+impl Webhook {
+    pub fn new(client: Client, http: &HttpConfig) -> Webhook {
+        Webhook {
+            client,
+            courier: Courier::new(http),
+        }
+    }
+
+    /// Post `body` to `receiver`, retrying transient failures a few times
+    /// before giving up on this attempt.
+    pub async fn post(&self, receiver: &WebhookReceiver, body: &str) -> Result<(), SinkError> {
+        self.courier
+            .send("webhook", receiver.url.clone(), MIN_INTERVAL, || {
+                self.post_once(receiver, body)
+            })
+            .await
+    }
+
+    async fn post_once(&self, receiver: &WebhookReceiver, body: &str) -> Result<(), Failure> {
+        let mut request = self
+            .client
+            .post(&receiver.url)
+            .header(CONTENT_TYPE, receiver.content_type.as_str())
+            .body(body.to_string());
+        for (name, value) in &receiver.headers {
+            request = request.header(name.as_str(), value.as_str());
+        }
+        request = match &receiver.auth {
+            Some(WebhookAuth::Bearer(token)) => request.bearer_auth(token),
+            Some(WebhookAuth::Basic { username, password }) => {
+                request.basic_auth(username, Some(password))
+            }
+            None => request,
+        };
+        let res = request
+            .send()
+            .await
+            .map_err(|e| SinkError::Transient(format!("failed sending to webhook: {}", e)))?;
+
+        match res.status() {
+            StatusCode::TOO_MANY_REQUESTS => Err(Failure::RateLimited(courier::retry_after(&res))),
+            status if status.is_success() => {
+                debug!("Request accepted by webhook");
+                Ok(())
+            }
+            StatusCode::REQUEST_TIMEOUT => {
+                Err(SinkError::Transient("webhook returned 408".to_string()).into())
+            }
+            status if status.is_client_error() => {
+                Err(SinkError::Rejected(format!("webhook returned {}", status)).into())
+            }
+            status => Err(SinkError::Transient(format!("webhook returned {}", status)).into()),
+        }
+    }
+}
+
+/// A webhook receiver as a `Sink`. Digests go out as one request.
+pub struct WebhookSink<'a> {
+    webhook: &'a Webhook,
+    receiver: &'a WebhookReceiver,
+    templates: Arc<Templates>,
+    /// See `QueueConfig::destination`.
+    name: String,
+}
+
+impl<'a> WebhookSink<'a> {
+    pub fn new(
+        webhook: &'a Webhook,
+        receiver: &'a WebhookReceiver,
+        templates: Arc<Templates>,
+        name: String,
+    ) -> WebhookSink<'a> {
+        WebhookSink {
+            webhook,
+            receiver,
+            templates,
+            name,
+        }
+    }
+}
+
+impl Sink for WebhookSink<'_> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn deliver(&self, rendered: Notification) -> BoxFuture<'_, Result<DeliveryReceipt, SinkError>> {
+        Box::pin(async move {
+            let body = self
+                .templates
+                .render_webhook(self.receiver.template.as_deref(), &rendered)
+                .map_err(SinkError::Rejected)?;
+            self.webhook.post(self.receiver, &body).await?;
+            Ok(DeliveryReceipt::default())
+        })
+    }
+}
@@ -0,0 +1,1294 @@
+use crate::alert::Alert;
+use crate::backoff::Backoff;
+use crate::config::{BindingConfig, Config, FieldValue, QueueConfig, RabbitConfig, TlsConfig};
+use crate::router::Dispatcher;
+use crate::trace::TraceContext;
+use crate::ShutdownSignal;
+use futures::StreamExt;
+use lapin::{
+    message::Delivery,
+    options::*,
+    tcp::TcpStream,
+    types::{AMQPValue, FieldTable},
+    uri::{AMQPScheme, AMQPUri},
+    Connection, ConnectionPromise, ConnectionProperties, ExchangeKind,
+};
+use log::{debug, error, info, warn};
+use mio::{Events, Poll, PollOpt, Ready, Token};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tcp_stream::HandshakeError;
+use tokio::sync::{mpsc, watch, Mutex};
+use tracing::{info_span, Instrument};
+
+/// Header carrying the number of failed processing attempts so far.
+pub const ATTEMPTS_HEADER: &str = "x-attempts";
+/// Header describing why a message was dead-lettered.
+pub const FAILURE_REASON_HEADER: &str = "x-failure-reason";
+/// Header listing the destinations that have already taken a message, so
+/// that retries skip them.
+pub const DELIVERED_HEADER: &str = "x-delivered";
+/// How long to wait for the broker to confirm a republished delivery
+/// before giving it back to the queue instead.
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct Rabbit {
+    pub conn: Connection,
+    pub chan: lapin::Channel,
+    /// In confirm mode, for republishing deliveries before acking them.
+    confirms: Arc<Mutex<lapin::Channel>>,
+    /// One per configured queue, in configuration order.
+    pub subscriptions: Vec<Subscription>,
+    dlx: String,
+}
+
+pub struct Subscription {
+    pub q: lapin::Queue,
+    pub consumer: lapin::Consumer,
+    /// Delay queues for successive retries, shortest delay first.
+    retry_queues: Vec<String>,
+}
+
+/// Cloneable handle for settling deliveries from worker tasks.
+#[derive(Clone)]
+pub struct Settler {
+    chan: lapin::Channel,
+    /// Held for the length of each publish and its confirm, so that the
+    /// confirm waited for is that publish's own.
+    confirms: Arc<Mutex<lapin::Channel>>,
+    queue: String,
+    retry_queues: Vec<String>,
+    dlx: String,
+}
+
+/// Cloneable handle for publishing events of our own, as opposed to
+/// settling deliveries.
+#[derive(Clone)]
+pub struct Publisher {
+    chan: lapin::Channel,
+}
+
+impl Drop for Rabbit {
+    /// Best effort only: `shutdown` is the way to close cleanly. If we are
+    /// dropped with the connection still open, close it in the background
+    /// rather than blocking inside the runtime.
+    fn drop(&mut self) {
+        if !self.conn.status().connected() {
+            return;
+        }
+        warn!("Rabbit dropped without being shut down");
+
+        let (chan, conn) = (self.chan.clone(), self.conn.clone());
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                let _ = chan.close(200, "client shut down").await;
+                let _ = conn.close(200, "client shut down").await;
+            });
+        }
+    }
+}
+
+fn field_value(value: &FieldValue) -> AMQPValue {
+    match value {
+        FieldValue::Str(s) => AMQPValue::LongString(s.as_str().into()),
+        FieldValue::Int(i) => AMQPValue::LongLongInt(*i),
+        FieldValue::Bool(b) => AMQPValue::Boolean(*b),
+    }
+}
+
+fn field_table(fields: &BTreeMap<String, FieldValue>) -> FieldTable {
+    let mut table = FieldTable::default();
+    for (key, value) in fields {
+        table.insert(key.as_str().into(), field_value(value));
+    }
+    table
+}
+
+fn binding_arguments(binding: &BindingConfig) -> FieldTable {
+    let mut args = field_table(&binding.headers);
+    args.insert(
+        "x-match".into(),
+        AMQPValue::LongString(binding.x_match.as_str().into()),
+    );
+    args
+}
+
+fn io_error<E: std::fmt::Display>(e: E) -> lapin::Error {
+    lapin::Error::IOError(Arc::new(io::Error::other(e.to_string())))
+}
+
+/// Split a PEM bundle into the individual certificates it holds.
+fn read_certificates(path: &str) -> Result<Vec<native_tls::Certificate>, String> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+
+    let pem = fs::read_to_string(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+    pem.match_indices(BEGIN)
+        .map(|(start, _)| {
+            let rest = &pem[start + BEGIN.len()..];
+            let end = start + BEGIN.len() + rest.find(BEGIN).unwrap_or(rest.len());
+            native_tls::Certificate::from_pem(&pem.as_bytes()[start..end])
+                .map_err(|e| format!("Invalid certificate in {}: {}", path, e))
+        })
+        .collect()
+}
+
+/// A connector trusting the CAs and presenting the client identity that
+/// `tls` names.
+pub fn tls_connector(tls: &TlsConfig) -> Result<native_tls::TlsConnector, String> {
+    let mut builder = native_tls::TlsConnector::builder();
+
+    if let Some(path) = &tls.ca_file {
+        for cert in read_certificates(path)? {
+            builder.add_root_certificate(cert);
+        }
+    }
+
+    if let (Some(cert_path), Some(key_path)) = (&tls.cert_file, &tls.key_file) {
+        let cert =
+            fs::read(cert_path).map_err(|e| format!("Unable to read {}: {}", cert_path, e))?;
+        let key = fs::read(key_path).map_err(|e| format!("Unable to read {}: {}", key_path, e))?;
+        let identity = native_tls::Identity::from_pkcs8(&cert, &key)
+            .map_err(|e| format!("Invalid client certificate or key: {}", e))?;
+        builder.identity(identity);
+    } else if let Some(path) = &tls.pkcs12_file {
+        let der = fs::read(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+        let identity = native_tls::Identity::from_pkcs12(&der, &tls.pkcs12_password)
+            .map_err(|e| format!("Invalid PKCS#12 archive {}: {}", path, e))?;
+        builder.identity(identity);
+    }
+
+    builder
+        .build()
+        .map_err(|e| format!("Unable to set up TLS: {}", e))
+}
+
+/// Open an `amqps://` connection using our own TLS connector, so that the CA
+/// bundle, client identity and SNI name from the config are honoured.
+async fn connect_tls(
+    uri: AMQPUri,
+    tls: &TlsConfig,
+    timeout: Duration,
+) -> lapin::Result<Connection> {
+    // Matches the token lapin's IO loop registers its socket under.
+    const SOCKET: Token = Token(1);
+
+    let connector = tls_connector(tls).map_err(io_error)?;
+    let domain = tls
+        .server_name
+        .clone()
+        .unwrap_or_else(|| uri.authority.host.clone());
+
+    let stream = TcpStream::connect(format!("{}:{}", uri.authority.host, uri.authority.port))
+        .map_err(io_error)?;
+    let poll = Poll::new().map_err(io_error)?;
+    poll.register(&stream, SOCKET, Ready::all(), PollOpt::edge())
+        .map_err(io_error)?;
+
+    let deadline = Instant::now() + timeout;
+    let mut events = Events::with_capacity(16);
+    let mut handshake = stream.into_native_tls(connector, &domain);
+    let stream = loop {
+        match handshake {
+            Ok(stream) => break stream,
+            Err(HandshakeError::Failure(e)) => return Err(io_error(e)),
+            Err(HandshakeError::WouldBlock(mid)) => {
+                let remaining = deadline
+                    .checked_duration_since(Instant::now())
+                    .ok_or_else(|| io_error("timed out during TLS handshake"))?;
+                poll.poll(&mut events, Some(remaining)).map_err(io_error)?;
+                handshake = mid.handshake();
+            }
+        }
+    };
+
+    let promise: ConnectionPromise =
+        Connection::connector(ConnectionProperties::default())(stream, uri, Some((poll, SOCKET)))?;
+    promise.await
+}
+
+/// The brokers we know about and where to start trying them next time.
+pub struct Brokers {
+    uris: Vec<AMQPUri>,
+    round_robin: bool,
+    next: usize,
+}
+
+impl Brokers {
+    pub fn new(rabbit: &RabbitConfig) -> Result<Brokers, String> {
+        Ok(Brokers {
+            uris: rabbit.uris()?,
+            round_robin: rabbit.round_robin,
+            next: 0,
+        })
+    }
+}
+
+async fn connect_uri(
+    uri: AMQPUri,
+    rabbit: &RabbitConfig,
+    timeout: Duration,
+) -> lapin::Result<Connection> {
+    let connecting = async {
+        match (&uri.scheme, &rabbit.tls) {
+            (AMQPScheme::AMQPS, Some(tls)) => connect_tls(uri, tls, timeout).await,
+            (AMQPScheme::AMQP, Some(_)) => {
+                warn!("rabbit.tls is configured but the broker address is not amqps://");
+                Connection::connect_uri(uri, ConnectionProperties::default()).await
+            }
+            _ => Connection::connect_uri(uri, ConnectionProperties::default()).await,
+        }
+    };
+    tokio::time::timeout(timeout, connecting)
+        .await
+        .map_err(|_| io_error(format!("timed out after {:?}", timeout)))?
+}
+
+/// Try each broker in turn, returning the first connection established or
+/// the last error seen.
+async fn connect(rabbit: &RabbitConfig, brokers: &mut Brokers) -> lapin::Result<Connection> {
+    let count = brokers.uris.len();
+    let mut last_error = io_error("no brokers configured");
+
+    for offset in 0..count {
+        let index = (brokers.next + offset) % count;
+        let uri = brokers.uris[index].clone();
+        let (host, port) = (uri.authority.host.clone(), uri.authority.port);
+        info!(
+            "Connecting to {}:{} as {}",
+            host, port, uri.authority.userinfo.username
+        );
+
+        match connect_uri(uri, rabbit, rabbit.connection_timeout).await {
+            Ok(conn) => {
+                if brokers.round_robin {
+                    brokers.next = (index + 1) % count;
+                }
+                return Ok(conn);
+            }
+            Err(e) => {
+                warn!("Unable to connect to {}:{}: {:?}", host, port, e);
+                last_error = e;
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Declare the exchange alerts are published to, its dead-letter exchange
+/// and any we publish interactions to, or only check that they exist if
+/// `passive`. Returns the name of the dead-letter exchange.
+async fn declare_exchanges(
+    chan: &lapin::Channel,
+    cfg: &Config,
+    passive: bool,
+) -> lapin::Result<String> {
+    let exchange = &cfg.exchange;
+    let ex = &exchange.name;
+    let exchange_options = ExchangeDeclareOptions {
+        passive,
+        durable: exchange.durable,
+        auto_delete: exchange.auto_delete,
+        ..ExchangeDeclareOptions::default()
+    };
+    chan.exchange_declare(
+        ex,
+        exchange.kind.clone(),
+        exchange_options,
+        FieldTable::default(),
+    )
+    .await?;
+
+    // Messages we give up on are parked in <queue>.dlq via <exchange>.dlx so
+    // that operators can inspect them; the broker uses the same route for
+    // anything it dead-letters itself.
+    let dlx = format!("{}.dlx", ex);
+    chan.exchange_declare(
+        &dlx,
+        ExchangeKind::Direct,
+        ExchangeDeclareOptions {
+            passive,
+            durable: exchange.durable,
+            ..ExchangeDeclareOptions::default()
+        },
+        FieldTable::default(),
+    )
+    .await?;
+
+    if let Some(interactions) = &cfg.interactions {
+        chan.exchange_declare(
+            &interactions.exchange,
+            ExchangeKind::Topic,
+            ExchangeDeclareOptions {
+                passive,
+                durable: true,
+                ..ExchangeDeclareOptions::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+    }
+    Ok(dlx)
+}
+
+/// Connect and declare what we consume from, or for a `dry_run` only what
+/// takes a copy of it. Without `rabbit.declare` what we consume from is
+/// only checked.
+pub async fn rabbit_connect(
+    cfg: &Config,
+    brokers: &mut Brokers,
+    dry_run: bool,
+) -> lapin::Result<Rabbit> {
+    let conn = connect(&cfg.rabbit, brokers).await?;
+    let chan = conn.create_channel().await?;
+
+    let passive = !cfg.rabbit.declare;
+    let ex = &cfg.exchange.name;
+    let dlx = declare_exchanges(&chan, cfg, passive).await?;
+
+    chan.basic_qos(cfg.consumer.prefetch, BasicQosOptions::default())
+        .await?;
+    let confirms = conn.create_channel().await?;
+    confirms
+        .confirm_select(ConfirmSelectOptions::default())
+        .await?;
+
+    let mut subscriptions = Vec::with_capacity(cfg.queues.len());
+    for queue in &cfg.queues {
+        let subscription = if dry_run {
+            subscribe_copy(&chan, ex, queue).await?
+        } else {
+            subscribe(&chan, ex, &dlx, queue, &cfg.consumer.retry_delays, passive).await?
+        };
+        subscriptions.push(subscription);
+    }
+
+    info!("Completed rabbit bus initialization");
+
+    Ok(Rabbit {
+        conn,
+        chan,
+        confirms: Arc::new(Mutex::new(confirms)),
+        subscriptions,
+        dlx,
+    })
+}
+
+/// Declare everything `cfg` consumes from and publishes to, then
+/// disconnect: the `setup` subcommand, for provisioning the broker with
+/// credentials that may configure it ahead of a service whose may not.
+pub async fn setup(cfg: &Config) -> Result<(), String> {
+    let mut brokers = Brokers::new(&cfg.rabbit)?;
+    let declared = async {
+        let conn = connect(&cfg.rabbit, &mut brokers).await?;
+        let chan = conn.create_channel().await?;
+        let dlx = declare_exchanges(&chan, cfg, false).await?;
+        for queue in &cfg.queues {
+            declare_queue(
+                &chan,
+                &cfg.exchange.name,
+                &dlx,
+                queue,
+                &cfg.consumer.retry_delays,
+                false,
+            )
+            .await?;
+            info!("Declared {}", queue.name);
+        }
+        let _ = chan.close(200, "setup complete").await;
+        conn.close(200, "setup complete").await
+    };
+    declared
+        .await
+        .map_err(|e| format!("Unable to set up rabbit: {:?}", e))
+}
+
+/// Declare a delay queue for each retry interval, or check they exist if
+/// `passive`. Messages published to one sit there until their TTL expires
+/// and are then dead-lettered straight back onto `q` via the default
+/// exchange. Each is named after its delay in seconds, which the
+/// configuration keeps whole and distinct.
+async fn declare_retry_queues(
+    chan: &lapin::Channel,
+    q: &str,
+    durable: bool,
+    delays: &[Duration],
+    passive: bool,
+) -> lapin::Result<Vec<String>> {
+    let mut names = Vec::with_capacity(delays.len());
+    for delay in delays {
+        let name = format!("{}.retry.{}s", q, delay.as_secs());
+
+        let mut args = FieldTable::default();
+        args.insert(
+            "x-message-ttl".into(),
+            AMQPValue::LongLongInt(delay.as_millis() as i64),
+        );
+        args.insert(
+            "x-dead-letter-exchange".into(),
+            AMQPValue::LongString("".into()),
+        );
+        args.insert(
+            "x-dead-letter-routing-key".into(),
+            AMQPValue::LongString(q.into()),
+        );
+        chan.queue_declare(
+            &name,
+            QueueDeclareOptions {
+                passive,
+                durable,
+                ..QueueDeclareOptions::default()
+            },
+            args,
+        )
+        .await?;
+
+        names.push(name);
+    }
+    Ok(names)
+}
+
+/// Declare a queue with its dead-letter queue, retry queues and bindings,
+/// or if `passive` check that the queues exist and leave the bindings be.
+/// Returns the queue and its retry queues.
+async fn declare_queue(
+    chan: &lapin::Channel,
+    ex: &str,
+    dlx: &str,
+    queue: &QueueConfig,
+    retry_delays: &[Duration],
+    passive: bool,
+) -> lapin::Result<(lapin::Queue, Vec<String>)> {
+    let q = queue.name.as_str();
+    let options = QueueDeclareOptions {
+        passive,
+        durable: queue.durable,
+        auto_delete: queue.auto_delete,
+        ..QueueDeclareOptions::default()
+    };
+
+    // The DLQ shares the queue's durability and type so that a quorum queue
+    // doesn't dead-letter into a transient classic one.
+    let dlq = format!("{}.dlq", q);
+    let mut dlq_args = FieldTable::default();
+    if let Some(queue_type) = queue.arguments.get("x-queue-type") {
+        dlq_args.insert("x-queue-type".into(), field_value(queue_type));
+    }
+    chan.queue_declare(
+        &dlq,
+        QueueDeclareOptions {
+            auto_delete: false,
+            ..options
+        },
+        dlq_args,
+    )
+    .await?;
+    if !passive {
+        chan.queue_bind(
+            &dlq,
+            dlx,
+            q,
+            QueueBindOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+    }
+
+    let mut args = field_table(&queue.arguments);
+    args.insert(
+        "x-dead-letter-exchange".into(),
+        AMQPValue::LongString(dlx.into()),
+    );
+    args.insert(
+        "x-dead-letter-routing-key".into(),
+        AMQPValue::LongString(q.into()),
+    );
+    let declared = chan.queue_declare(q, options, args).await?;
+    let retry_queues = declare_retry_queues(chan, q, queue.durable, retry_delays, passive).await?;
+
+    if !passive {
+        for binding in &queue.bindings {
+            chan.queue_bind(
+                q,
+                ex,
+                &binding.routing_key,
+                QueueBindOptions::default(),
+                binding_arguments(binding),
+            )
+            .await?;
+        }
+    }
+    Ok((declared, retry_queues))
+}
+
+/// Declare a queue, or check it if `passive`, as `declare_queue` does, and
+/// start consuming from it.
+async fn subscribe(
+    chan: &lapin::Channel,
+    ex: &str,
+    dlx: &str,
+    queue: &QueueConfig,
+    retry_delays: &[Duration],
+    passive: bool,
+) -> lapin::Result<Subscription> {
+    let q = queue.name.as_str();
+    let (declared, retry_queues) =
+        declare_queue(chan, ex, dlx, queue, retry_delays, passive).await?;
+
+    let consumer = chan
+        .clone()
+        .basic_consume(
+            q,
+            &format!("2steps-slack-alert.{}", q),
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    info!("Subscribed to {}", q);
+
+    Ok(Subscription {
+        q: declared,
+        consumer,
+        retry_queues,
+    })
+}
+
+/// Bind `name` to the exchange as `queue` is bound.
+async fn bind_as(
+    chan: &lapin::Channel,
+    name: &str,
+    ex: &str,
+    queue: &QueueConfig,
+) -> lapin::Result<()> {
+    for binding in &queue.bindings {
+        chan.queue_bind(
+            name,
+            ex,
+            &binding.routing_key,
+            QueueBindOptions::default(),
+            binding_arguments(binding),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Bind a queue of our own, which goes when we do, to the exchange as
+/// `queue` is bound, and start consuming from it: a copy of the queue's
+/// traffic for a dry run, leaving the queue itself to whatever consumes it
+/// for real.
+async fn subscribe_copy(
+    chan: &lapin::Channel,
+    ex: &str,
+    queue: &QueueConfig,
+) -> lapin::Result<Subscription> {
+    let declared = chan
+        .queue_declare(
+            "",
+            QueueDeclareOptions {
+                exclusive: true,
+                auto_delete: true,
+                ..QueueDeclareOptions::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+    let copy = declared.name().to_string();
+    bind_as(chan, &copy, ex, queue).await?;
+
+    let consumer = chan
+        .clone()
+        .basic_consume(
+            &copy,
+            &format!("2steps-slack-alert.{}.dry-run", queue.name),
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    info!("Copying {} into {} for a dry run", queue.name, copy);
+
+    Ok(Subscription {
+        q: declared,
+        consumer,
+        retry_queues: Vec::new(),
+    })
+}
+
+/// Bind a queue of our own, which goes when we do, to the exchange as each
+/// of `queues` is bound, and consume from it without acknowledging: what
+/// `tail` watches, leaving the queues to whatever consumes them. The
+/// connection is returned to be held for as long as the consumer is read.
+pub async fn tap(
+    cfg: &Config,
+    queues: &[&QueueConfig],
+) -> Result<(Connection, lapin::Consumer), String> {
+    let mut brokers = Brokers::new(&cfg.rabbit)?;
+    let tapped = async {
+        let conn = connect(&cfg.rabbit, &mut brokers).await?;
+        let chan = conn.create_channel().await?;
+        let declared = chan
+            .queue_declare(
+                "",
+                QueueDeclareOptions {
+                    exclusive: true,
+                    auto_delete: true,
+                    ..QueueDeclareOptions::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+        let name = declared.name().to_string();
+        for queue in queues {
+            bind_as(&chan, &name, &cfg.exchange.name, queue).await?;
+        }
+        let consumer = chan
+            .basic_consume(
+                &name,
+                "2steps-slack-alert.tail",
+                BasicConsumeOptions {
+                    no_ack: true,
+                    ..BasicConsumeOptions::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+        Ok((conn, consumer))
+    };
+    tapped
+        .await
+        .map_err(|e: lapin::Error| format!("Unable to tap the exchange: {:?}", e))
+}
+
+/// A header value as JSON, for showing to people.
+fn header_json(value: &AMQPValue) -> serde_json::Value {
+    use serde_json::{json, Value};
+    match value {
+        AMQPValue::Boolean(b) => json!(b),
+        AMQPValue::ShortShortInt(n) => json!(n),
+        AMQPValue::ShortShortUInt(n) => json!(n),
+        AMQPValue::ShortInt(n) => json!(n),
+        AMQPValue::ShortUInt(n) => json!(n),
+        AMQPValue::LongInt(n) => json!(n),
+        AMQPValue::LongUInt(n) => json!(n),
+        AMQPValue::LongLongInt(n) => json!(n),
+        AMQPValue::Timestamp(n) => json!(n),
+        AMQPValue::Float(n) => json!(n),
+        AMQPValue::Double(n) => json!(n),
+        AMQPValue::ShortString(s) => json!(s.as_str()),
+        AMQPValue::LongString(s) => json!(s.as_str()),
+        AMQPValue::FieldArray(items) => {
+            Value::Array(items.as_slice().iter().map(header_json).collect())
+        }
+        AMQPValue::FieldTable(table) => Value::Object(
+            table
+                .inner()
+                .iter()
+                .map(|(name, value)| (name.as_str().to_string(), header_json(value)))
+                .collect(),
+        ),
+        AMQPValue::Void => Value::Null,
+        other => json!(format!("{:?}", other)),
+    }
+}
+
+/// The headers of a delivery as a JSON object.
+pub fn headers(delivery: &Delivery) -> serde_json::Value {
+    let headers = delivery.properties.headers().as_ref();
+    serde_json::Value::Object(
+        headers
+            .into_iter()
+            .flat_map(|table| table.inner())
+            .map(|(name, value)| (name.as_str().to_string(), header_json(value)))
+            .collect(),
+    )
+}
+
+/// The number of messages waiting in, and consumers of, each of `queues`,
+/// asked of the broker on a channel of its own so that a queue gone missing
+/// cannot close the one we consume on.
+pub async fn queue_depths(conn: &Connection, queues: &[String]) -> lapin::Result<Vec<(u32, u32)>> {
+    let chan = conn.create_channel().await?;
+    let mut depths = Vec::with_capacity(queues.len());
+    for queue in queues {
+        let declared = chan
+            .queue_declare(
+                queue,
+                QueueDeclareOptions {
+                    passive: true,
+                    ..QueueDeclareOptions::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+        depths.push((declared.message_count(), declared.consumer_count()));
+    }
+    let _ = chan.close(200, "queue depths read").await;
+    Ok(depths)
+}
+
+/// The messages parked in dead-letter queues, taken one at a time on a
+/// connection of their own. Those not removed go back to their queue when
+/// it is closed.
+pub struct DeadLetters {
+    conn: Connection,
+    chan: lapin::Channel,
+}
+
+impl DeadLetters {
+    pub async fn open(cfg: &Config) -> Result<DeadLetters, String> {
+        let mut brokers = Brokers::new(&cfg.rabbit)?;
+        let conn = connect(&cfg.rabbit, &mut brokers)
+            .await
+            .map_err(|e| format!("Unable to connect to rabbit: {:?}", e))?;
+        let chan = conn
+            .create_channel()
+            .await
+            .map_err(|e| format!("Unable to open a channel: {:?}", e))?;
+        Ok(DeadLetters { conn, chan })
+    }
+
+    /// The next message parked for `queue`, if any are left that have not
+    /// been taken already.
+    pub async fn next(&self, queue: &str) -> Result<Option<Delivery>, String> {
+        let dlq = format!("{}.dlq", queue);
+        let message = self
+            .chan
+            .basic_get(&dlq, BasicGetOptions { no_ack: false })
+            .await
+            .map_err(|e| format!("Unable to read {}: {:?}", dlq, e))?;
+        Ok(message.map(|message| message.delivery))
+    }
+
+    /// Remove a message taken with `next` from its dead-letter queue.
+    pub async fn remove(&self, delivery: &Delivery) -> Result<(), String> {
+        self.chan
+            .basic_ack(delivery.delivery_tag, BasicAckOptions::default())
+            .await
+            .map_err(|e| format!("Unable to remove a dead letter: {:?}", e))
+    }
+
+    pub async fn close(self) {
+        let _ = self.chan.close(200, "dead letters read").await;
+        let _ = self.conn.close(200, "dead letters read").await;
+    }
+}
+
+/// Number of failed attempts already recorded against a delivery.
+pub fn attempts(delivery: &Delivery) -> u32 {
+    let value = delivery
+        .properties
+        .headers()
+        .as_ref()
+        .and_then(|h| h.inner().get(ATTEMPTS_HEADER).cloned());
+
+    match value {
+        Some(AMQPValue::LongUInt(n)) => n,
+        Some(AMQPValue::LongInt(n)) => n.max(0) as u32,
+        Some(AMQPValue::LongLongInt(n)) => n.max(0) as u32,
+        _ => 0,
+    }
+}
+
+/// The trace `delivery` continues, from its `header` or else its
+/// correlation ID.
+fn trace_header(delivery: &Delivery, header: &str) -> Option<String> {
+    let value = delivery
+        .properties
+        .headers()
+        .as_ref()
+        .and_then(|h| h.inner().get(header).cloned());
+
+    match value {
+        Some(AMQPValue::LongString(value)) => Some(value.as_str().to_string()),
+        Some(AMQPValue::ShortString(value)) => Some(value.as_str().to_string()),
+        _ => delivery
+            .properties
+            .correlation_id()
+            .as_ref()
+            .map(|id| id.as_str().to_string()),
+    }
+}
+
+/// Destinations that took a delivery on earlier attempts; see
+/// `DELIVERED_HEADER`.
+pub fn delivered(delivery: &Delivery) -> Vec<String> {
+    let value = delivery
+        .properties
+        .headers()
+        .as_ref()
+        .and_then(|h| h.inner().get(DELIVERED_HEADER).cloned());
+
+    match value {
+        Some(AMQPValue::FieldArray(names)) => names
+            .as_slice()
+            .iter()
+            .filter_map(|name| match name {
+                AMQPValue::LongString(name) => Some(name.as_str().to_string()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn with_headers(delivery: &Delivery, extra: Vec<(&str, AMQPValue)>) -> lapin::BasicProperties {
+    let mut headers = delivery.properties.headers().clone().unwrap_or_default();
+    for (key, value) in extra {
+        headers.insert(key.into(), value);
+    }
+    delivery.properties.clone().with_headers(headers)
+}
+
+impl Rabbit {
+    /// Close the channel and connection, leaving the broker to redeliver
+    /// anything we hadn't settled. Both are attempted even if the first
+    /// fails; the first error is returned.
+    pub async fn shutdown(self) -> lapin::Result<()> {
+        let chan = self.chan.close(200, "client shut down").await;
+        let conn = self.conn.close(200, "client shut down").await;
+        info!("Closed connection to rabbit");
+        chan.and(conn)
+    }
+
+    pub fn publisher(&self) -> Publisher {
+        Publisher {
+            chan: self.chan.clone(),
+        }
+    }
+
+    /// A settler for deliveries from the given subscription.
+    pub fn settler(&self, subscription: &Subscription) -> Settler {
+        Settler {
+            chan: self.chan.clone(),
+            confirms: self.confirms.clone(),
+            queue: subscription.q.name().to_string(),
+            retry_queues: subscription.retry_queues.clone(),
+            dlx: self.dlx.clone(),
+        }
+    }
+}
+
+impl Settler {
+    /// Whether deliveries can still be settled, which they cannot once the
+    /// channel they came on is gone.
+    pub fn is_open(&self) -> bool {
+        self.chan.status().is_connected()
+    }
+
+    pub async fn ack(&self, delivery: &Delivery) -> lapin::Result<()> {
+        self.chan
+            .basic_ack(delivery.delivery_tag, BasicAckOptions::default())
+            .await
+    }
+
+    /// Publish on the confirm channel and wait for the broker to take the
+    /// message. Fails if the broker nacks it, returns it as unroutable or
+    /// does not confirm it in time.
+    async fn publish_confirmed(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        data: Vec<u8>,
+        props: lapin::BasicProperties,
+    ) -> lapin::Result<()> {
+        let confirms = self.confirms.lock().await;
+        confirms
+            .basic_publish(
+                exchange,
+                routing_key,
+                BasicPublishOptions {
+                    mandatory: true,
+                    ..BasicPublishOptions::default()
+                },
+                data,
+                props,
+            )
+            .await?;
+        match tokio::time::timeout(CONFIRM_TIMEOUT, confirms.wait_for_confirms()).await {
+            Ok(Ok(returned)) if returned.is_empty() => Ok(()),
+            Ok(Ok(_)) => Err(io_error(format!("{} is unroutable", routing_key))),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(io_error("the broker did not confirm the publish")),
+        }
+    }
+
+    /// Ack the delivery once `republished` has been confirmed, or give it
+    /// back to its queue if it was not, so that it is never lost.
+    async fn settle_republished(
+        &self,
+        delivery: &Delivery,
+        republished: lapin::Result<()>,
+    ) -> lapin::Result<()> {
+        match republished {
+            Ok(()) => self.ack(delivery).await,
+            Err(e) => {
+                warn!(
+                    "Unable to republish delivery {}, requeueing it: {:?}",
+                    delivery.delivery_tag, e
+                );
+                self.chan
+                    .basic_nack(
+                        delivery.delivery_tag,
+                        BasicNackOptions {
+                            requeue: true,
+                            ..BasicNackOptions::default()
+                        },
+                    )
+                    .await?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Republish the delivery with an updated attempt count and the
+    /// destinations that have `delivered` it to the delay queue for that
+    /// attempt, then ack the original once the broker confirms it. The last delay is reused for any
+    /// attempts beyond the configured intervals; with no intervals
+    /// configured the delivery goes straight to the back of its queue.
+    pub async fn retry(
+        &self,
+        delivery: &Delivery,
+        attempts: u32,
+        delivered: &[String],
+    ) -> lapin::Result<()> {
+        let target = if self.retry_queues.is_empty() {
+            &self.queue
+        } else {
+            let index = (attempts.max(1) as usize - 1).min(self.retry_queues.len() - 1);
+            &self.retry_queues[index]
+        };
+        debug!(
+            "Retrying delivery {} via {} (attempt {})",
+            delivery.delivery_tag, target, attempts
+        );
+
+        let delivered: Vec<AMQPValue> = delivered
+            .iter()
+            .map(|name| AMQPValue::LongString(name.as_str().into()))
+            .collect();
+        let props = with_headers(
+            delivery,
+            vec![
+                (ATTEMPTS_HEADER, AMQPValue::LongUInt(attempts)),
+                (DELIVERED_HEADER, AMQPValue::FieldArray(delivered.into())),
+            ],
+        );
+        let republished = self
+            .publish_confirmed("", target, delivery.data.clone(), props)
+            .await;
+        self.settle_republished(delivery, republished).await
+    }
+
+    /// Park the delivery in the dead-letter queue, recording why and after
+    /// how many attempts, then ack the original once the broker confirms it.
+    pub async fn dead_letter(
+        &self,
+        delivery: &Delivery,
+        attempts: u32,
+        reason: &str,
+    ) -> lapin::Result<()> {
+        warn!(
+            "Dead-lettering delivery {} after {} attempt(s): {}",
+            delivery.delivery_tag, attempts, reason
+        );
+        let props = with_headers(
+            delivery,
+            vec![
+                (ATTEMPTS_HEADER, AMQPValue::LongUInt(attempts)),
+                (FAILURE_REASON_HEADER, AMQPValue::LongString(reason.into())),
+            ],
+        );
+        let republished = self
+            .publish_confirmed(&self.dlx, &self.queue, delivery.data.clone(), props)
+            .await;
+        self.settle_republished(delivery, republished).await
+    }
+}
+
+impl Publisher {
+    /// Publish a persistent JSON message.
+    pub async fn publish(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        data: Vec<u8>,
+    ) -> lapin::Result<()> {
+        let props = lapin::BasicProperties::default()
+            .with_content_type("application/json".into())
+            .with_delivery_mode(2);
+        self.chan
+            .basic_publish(
+                exchange,
+                routing_key,
+                BasicPublishOptions::default(),
+                data,
+                props,
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+type Lane = Arc<Mutex<mpsc::Receiver<(Delivery, Alert)>>>;
+
+/// Whether consumption is paused, for the consumers of every queue. While
+/// it is, nothing more is taken from the queues; what the broker already
+/// sent, up to `consumer.prefetch` a queue, is processed and the rest waits
+/// on the broker.
+pub struct Pause {
+    paused: watch::Sender<bool>,
+    changed: watch::Receiver<bool>,
+}
+
+impl Default for Pause {
+    fn default() -> Pause {
+        let (paused, changed) = watch::channel(false);
+        Pause { paused, changed }
+    }
+}
+
+impl Pause {
+    pub fn paused(&self) -> bool {
+        *self.changed.borrow()
+    }
+
+    /// Pause or resume consumption, returning whether that changed anything.
+    pub fn set(&self, paused: bool) -> bool {
+        if self.paused() == paused {
+            return false;
+        }
+        info!(
+            "{} consumption",
+            if paused { "Pausing" } else { "Resuming" }
+        );
+        let _ = self.paused.broadcast(paused);
+        true
+    }
+
+    /// Resolves once consumption is not paused.
+    async fn resumed(&self) {
+        let mut changed = self.changed.clone();
+        while *changed.borrow() {
+            if changed.recv().await.is_none() {
+                return;
+            }
+        }
+    }
+}
+
+/// Process deliveries from one queue until its consumer stream ends, which
+/// happens when the broker cancels us or the connection is lost, or until
+/// shutdown is requested.
+///
+/// Deliveries are handed to `consumer.concurrency` workers. When ordering is
+/// enabled each worker has its own lane and alerts are pinned to a lane by
+/// their key; otherwise all workers pull from one shared lane.
+async fn consume_queue(
+    subscription: &mut Subscription,
+    settler: Settler,
+    cfg: &Arc<Config>,
+    queue: usize,
+    dispatcher: &Arc<Dispatcher>,
+    shutdown: ShutdownSignal,
+) {
+    info!("Consuming alerts from {}", subscription.q.name());
+
+    let workers = cfg.consumer.concurrency;
+    let lanes = if cfg.consumer.ordered { workers } else { 1 };
+
+    let mut senders = Vec::with_capacity(lanes);
+    let mut receivers: Vec<Lane> = Vec::with_capacity(lanes);
+    for _ in 0..lanes {
+        let (tx, rx) = mpsc::channel(1);
+        senders.push(tx);
+        receivers.push(Arc::new(Mutex::new(rx)));
+    }
+
+    let handles: Vec<_> = (0..workers)
+        .map(|i| {
+            let lane = receivers[i % lanes].clone();
+            let settler = settler.clone();
+            let dispatcher = dispatcher.clone();
+            tokio::spawn(async move {
+                loop {
+                    let next = lane.lock().await.recv().await;
+                    match next {
+                        Some((delivery, mut alert)) => {
+                            let trace = dispatcher.cfg().tracing.as_ref().map(|tracing| {
+                                TraceContext::new(trace_header(&delivery, &tracing.header).as_deref())
+                            });
+                            let span = info_span!(
+                                "alert",
+                                fingerprint = %alert.key(),
+                                delivery = delivery.delivery_tag,
+                                trace_id = trace.as_ref().map(|trace| trace.trace_id.as_str()),
+                                remote_parent = trace.as_ref().and_then(|trace| trace.parent.as_deref()),
+                            );
+                            alert.trace_id = trace.map(|trace| trace.trace_id);
+                            dispatcher
+                                .process(&settler, queue, delivery, alert)
+                                .instrument(span)
+                                .await
+                        }
+                        None => break,
+                    }
+                }
+            })
+        })
+        .collect();
+
+    loop {
+        if dispatcher.pause.paused() {
+            info!("Paused consuming from {}", subscription.q.name());
+            tokio::select! {
+                _ = dispatcher.pause.resumed() => {
+                    info!("Resumed consuming from {}", subscription.q.name());
+                }
+                _ = shutdown.clone() => {
+                    info!("Stopped consuming from {}", subscription.q.name());
+                    break;
+                }
+            }
+        }
+        let delivery = tokio::select! {
+            delivery = subscription.consumer.next() => match delivery {
+                Some(delivery) => delivery,
+                None => break,
+            },
+            _ = shutdown.clone() => {
+                info!("Stopped consuming from {}", subscription.q.name());
+                break;
+            }
+        };
+        let delivery = match delivery {
+            Ok(delivery) => delivery,
+            Err(e) => {
+                error!("Failed to receive delivery: {:?}", e);
+                continue;
+            }
+        };
+
+        let tag = delivery.delivery_tag;
+        let alert = Alert::from_slice(&delivery.data);
+        dispatcher.metrics.consumed(alert.is_ok());
+        if let Some(audit) = &dispatcher.audit {
+            audit.received(subscription.q.name().as_str(), tag, &delivery.data, &alert);
+        }
+        let alert = match alert {
+            Ok(alert) => alert,
+            Err(e) => {
+                dispatcher.metrics.dead_lettered();
+                error!(
+                    "Rejecting delivery {}: {} (payload: {})",
+                    tag,
+                    e,
+                    String::from_utf8_lossy(&delivery.data)
+                );
+                // Redelivering a payload we cannot parse would only fail again.
+                let attempts = attempts(&delivery) + 1;
+                if let Err(e) = settler.dead_letter(&delivery, attempts, &e).await {
+                    error!("Failed to dead-letter delivery {}: {:?}", tag, e);
+                }
+                continue;
+            }
+        };
+
+        let lane = if lanes > 1 {
+            let mut hasher = DefaultHasher::new();
+            alert.key().hash(&mut hasher);
+            (hasher.finish() % lanes as u64) as usize
+        } else {
+            0
+        };
+        if senders[lane].send((delivery, alert)).await.is_err() {
+            error!("Worker for delivery {} has gone away", tag);
+        }
+    }
+
+    // Let the workers finish what they already hold before we reconnect or
+    // exit. Anything still unsettled after the timeout is redelivered by the
+    // broker once the channel closes.
+    drop(senders);
+    let drain = futures::future::join_all(handles);
+    match tokio::time::timeout(cfg.consumer.shutdown_timeout, drain).await {
+        Ok(results) => {
+            for result in results {
+                if let Err(e) = result {
+                    error!("Worker failed: {}", e);
+                }
+            }
+        }
+        Err(_) => warn!(
+            "Gave up waiting for in-flight deliveries from {} after {:?}",
+            subscription.q.name(),
+            cfg.consumer.shutdown_timeout
+        ),
+    }
+}
+
+/// Consume from every subscribed queue until all of them have stopped.
+pub async fn consume(
+    rabbit: &mut Rabbit,
+    cfg: &Arc<Config>,
+    dispatcher: &Arc<Dispatcher>,
+    shutdown: &ShutdownSignal,
+) {
+    let settlers: Vec<Settler> = rabbit
+        .subscriptions
+        .iter()
+        .map(|s| rabbit.settler(s))
+        .collect();
+
+    let consumers = rabbit
+        .subscriptions
+        .iter_mut()
+        .zip(settlers)
+        .enumerate()
+        .map(|(queue, (subscription, settler))| {
+            consume_queue(
+                subscription,
+                settler,
+                cfg,
+                queue,
+                dispatcher,
+                shutdown.clone(),
+            )
+        });
+    futures::future::join_all(consumers).await;
+}
+
+/// Connect to the broker, backing off between failed attempts. Gives up
+/// after `max_attempts` if given, otherwise keeps trying.
+pub async fn connect_with_retry(
+    cfg: &Config,
+    brokers: &mut Brokers,
+    backoff: &mut Backoff,
+    max_attempts: Option<u32>,
+    dry_run: bool,
+) -> Result<Rabbit, String> {
+    let mut attempt = 1;
+    loop {
+        match rabbit_connect(cfg, brokers, dry_run).await {
+            Ok(rabbit) => {
+                backoff.reset();
+                return Ok(rabbit);
+            }
+            Err(e) if max_attempts.is_some_and(|max| attempt >= max) => {
+                return Err(format!("Failed to initialize rabbit: {:?}", e));
+            }
+            Err(e) => error!("Failed to connect to rabbit: {:?}", e),
+        }
+
+        let delay = backoff.next_delay();
+        info!("Retrying connection in {:?}", delay);
+        tokio::time::delay_for(delay).await;
+        attempt += 1;
+    }
+}
@@ -0,0 +1,246 @@
+use crate::alert::{Alert, Severity};
+use crate::config::SpoolConfig;
+use crate::router::Dispatcher;
+use crate::sink::{Notification, SinkError};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// A rendered alert waiting for its destination to recover. Entries hold
+/// the queue and severity the webhook is looked up by, rather than the
+/// webhook URL, so that secrets are not written to disk.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Entry {
+    /// See `QueueConfig::destination`.
+    pub destination: String,
+    pub queue: String,
+    pub severity: Severity,
+    /// The alert's own choice of channel, if it was honoured.
+    #[serde(default)]
+    pub channel: Option<String>,
+    /// The receiver chosen by the routing tree, if any.
+    #[serde(default)]
+    pub receiver: Option<String>,
+    pub body: Value,
+    /// What it is about, for destinations other than Slack; see
+    /// `Notification`.
+    #[serde(default)]
+    pub alerts: Vec<Alert>,
+    #[serde(default)]
+    pub summary: String,
+}
+
+impl Entry {
+    fn notification(&self) -> Notification {
+        Notification {
+            alerts: self.alerts.clone(),
+            severity: self.severity,
+            summary: self.summary.clone(),
+            body: self.body.clone(),
+        }
+    }
+}
+
+struct State {
+    /// Spooled entries in arrival order, each with an in-memory sequence
+    /// number used to remove it once delivered.
+    entries: VecDeque<(u64, Entry)>,
+    next_seq: u64,
+    bytes: u64,
+    /// When each destination started failing; cleared on success.
+    failing_since: HashMap<String, Instant>,
+}
+
+/// Bounded append-only spool file. New entries are appended as JSON lines;
+/// the file is rewritten without an entry once it has been delivered.
+pub struct Spool {
+    path: PathBuf,
+    max_bytes: u64,
+    after: Duration,
+    state: Mutex<State>,
+}
+
+fn encode(entry: &Entry) -> Result<String, String> {
+    serde_json::to_string(entry)
+        .map(|line| line + "\n")
+        .map_err(|e| format!("Unable to encode spool entry: {}", e))
+}
+
+impl Spool {
+    /// Open the spool, loading anything left over from a previous run.
+    pub fn open(cfg: &SpoolConfig) -> Result<Spool, String> {
+        let path = PathBuf::from(&cfg.path);
+        let mut entries = VecDeque::new();
+        let mut bytes = 0;
+
+        match File::open(&path) {
+            Ok(file) => {
+                for (n, line) in BufReader::new(file).lines().enumerate() {
+                    let line = line.map_err(|e| format!("Unable to read spool: {}", e))?;
+                    match serde_json::from_str::<Entry>(&line) {
+                        Ok(entry) => {
+                            bytes += line.len() as u64 + 1;
+                            entries.push_back((entries.len() as u64, entry));
+                        }
+                        Err(e) => warn!("Skipping corrupt spool entry on line {}: {}", n + 1, e),
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("Unable to open spool {}: {}", cfg.path, e)),
+        }
+        if !entries.is_empty() {
+            info!("Loaded {} spooled alerts from {}", entries.len(), cfg.path);
+        }
+
+        Ok(Spool {
+            path,
+            max_bytes: cfg.max_bytes,
+            after: cfg.after,
+            state: Mutex::new(State {
+                next_seq: entries.len() as u64,
+                entries,
+                bytes,
+                failing_since: HashMap::new(),
+            }),
+        })
+    }
+
+    /// Whether alerts for `destination` are already waiting in the spool, in
+    /// which case new ones must join them to stay in order.
+    pub async fn is_backlogged(&self, destination: &str) -> bool {
+        let state = self.state.lock().await;
+        state
+            .entries
+            .iter()
+            .any(|(_, e)| e.destination == destination)
+    }
+
+    pub async fn record_success(&self, destination: &str) {
+        self.state.lock().await.failing_since.remove(destination);
+    }
+
+    /// Note a failed delivery to `destination` and report whether it has now
+    /// been failing for long enough to start spooling.
+    pub async fn record_failure(&self, destination: &str) -> bool {
+        let mut state = self.state.lock().await;
+        let since = state
+            .failing_since
+            .entry(destination.to_string())
+            .or_insert_with(Instant::now);
+        since.elapsed() >= self.after
+    }
+
+    pub async fn push(&self, entry: Entry) -> Result<(), String> {
+        let line = encode(&entry)?;
+
+        let mut state = self.state.lock().await;
+        if state.bytes + line.len() as u64 > self.max_bytes {
+            return Err("spool is full".to_string());
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| {
+                file.write_all(line.as_bytes())?;
+                file.sync_data()
+            })
+            .map_err(|e| format!("Unable to write to spool: {}", e))?;
+
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.bytes += line.len() as u64;
+        state.entries.push_back((seq, entry));
+        Ok(())
+    }
+
+    /// How many entries the spool holds, and how many bytes they take.
+    pub async fn depth(&self) -> (usize, u64) {
+        let state = self.state.lock().await;
+        (state.entries.len(), state.bytes)
+    }
+
+    pub async fn pending(&self) -> Vec<(u64, Entry)> {
+        self.state.lock().await.entries.iter().cloned().collect()
+    }
+
+    /// Drop a delivered entry and rewrite the spool file without it.
+    pub async fn remove(&self, seq: u64) -> Result<(), String> {
+        let mut state = self.state.lock().await;
+        state.entries.retain(|(s, _)| *s != seq);
+
+        let mut contents = String::new();
+        for (_, entry) in &state.entries {
+            contents.push_str(&encode(entry)?);
+        }
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, &contents)
+            .and_then(|_| fs::rename(&tmp, &self.path))
+            .map_err(|e| format!("Unable to rewrite spool: {}", e))?;
+        state.bytes = contents.len() as u64;
+        Ok(())
+    }
+}
+
+/// Periodically try to deliver spooled alerts in order. A destination that
+/// is still failing is skipped until the next pass, so its later entries are
+/// not sent ahead of the one that failed. Destinations are looked up in the
+/// configuration in force at the time of each pass.
+pub async fn replay(spool: Arc<Spool>, dispatcher: Arc<Dispatcher>, interval: Duration) {
+    loop {
+        tokio::time::delay_for(interval).await;
+
+        let cfg = dispatcher.cfg();
+
+        let mut blocked = HashSet::new();
+        for (seq, entry) in spool.pending().await {
+            if blocked.contains(&entry.destination) {
+                continue;
+            }
+            let queue = match cfg.queues.iter().position(|q| q.name == entry.queue) {
+                Some(queue) => queue,
+                None => {
+                    warn!("Dropping spooled alert for unknown queue {}", entry.queue);
+                    if let Err(e) = spool.remove(seq).await {
+                        error!("{}", e);
+                    }
+                    continue;
+                }
+            };
+
+            let sink = dispatcher.sink(
+                &cfg,
+                queue,
+                entry.severity,
+                entry.channel.as_deref(),
+                entry.receiver.as_deref(),
+                entry.destination.clone(),
+            );
+            match sink.deliver(entry.notification()).await {
+                Ok(_) => {
+                    debug!("Replayed spooled alert for {}", entry.destination);
+                    spool.record_success(&entry.destination).await;
+                }
+                Err(SinkError::Rejected(e)) => {
+                    error!("Discarding spooled alert for {}: {}", entry.destination, e);
+                }
+                Err(SinkError::Transient(e)) => {
+                    debug!("Still unable to deliver to {}: {}", entry.destination, e);
+                    blocked.insert(entry.destination);
+                    continue;
+                }
+            }
+            if let Err(e) = spool.remove(seq).await {
+                error!("{}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,190 @@
+//! The metrics pushed to a StatsD or DogStatsD agent over UDP, for
+//! monitoring that cannot scrape `/metrics`. Counters are sent as the
+//! increase since the last push, each delivery's duration as a timing, and
+//! the depth of the queues and the spool as gauges.
+
+use crate::config::{StatsdConfig, StatsdFormat};
+use crate::metrics::Sample;
+use crate::router::Dispatcher;
+use log::warn;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+
+/// The most sent in one datagram, to stay within a typical MTU.
+const MAX_DATAGRAM: usize = 1432;
+
+/// `value` with the characters that delimit StatsD lines replaced.
+fn clean(value: &str, delimiters: &[char]) -> String {
+    value
+        .chars()
+        .map(|c| {
+            if delimiters.contains(&c) || c.is_whitespace() {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+/// The line for a metric `name` with `labels`, given `value` and `kind`
+/// in the StatsD sense, as `cfg` says to write it.
+fn line(
+    cfg: &StatsdConfig,
+    name: &str,
+    labels: &[(&str, String)],
+    value: &str,
+    kind: &str,
+) -> String {
+    match cfg.format {
+        StatsdFormat::Statsd => {
+            let mut name = format!("{}.{}", cfg.prefix, name);
+            for (_, label) in labels {
+                name.push('.');
+                name.push_str(&clean(label, &['.', ':', '|', '@']));
+            }
+            format!("{}:{}|{}", name, value, kind)
+        }
+        StatsdFormat::Dogstatsd => {
+            let tags: Vec<String> = labels
+                .iter()
+                .map(|(key, label)| (key.to_string(), label.as_str()))
+                .chain(
+                    cfg.tags
+                        .iter()
+                        .map(|(key, tag)| (key.clone(), tag.as_str())),
+                )
+                .map(|(key, tag)| {
+                    format!(
+                        "{}:{}",
+                        clean(&key, &[',', '|', '#', ':']),
+                        clean(tag, &[',', '|', '#'])
+                    )
+                })
+                .collect();
+            let mut line = format!("{}.{}:{}|{}", cfg.prefix, name, value, kind);
+            if !tags.is_empty() {
+                line.push_str("|#");
+                line.push_str(&tags.join(","));
+            }
+            line
+        }
+    }
+}
+
+/// `lines` packed into as few datagrams as fit.
+fn datagrams(lines: Vec<String>) -> Vec<String> {
+    let mut datagrams: Vec<String> = Vec::new();
+    for line in lines {
+        match datagrams.last_mut() {
+            Some(datagram) if datagram.len() + 1 + line.len() <= MAX_DATAGRAM => {
+                datagram.push('\n');
+                datagram.push_str(&line);
+            }
+            _ => datagrams.push(line),
+        }
+    }
+    datagrams
+}
+
+/// Counters already pushed, by name and labels.
+type Pushed = HashMap<(&'static str, Vec<(&'static str, String)>), u64>;
+
+/// What has changed since `pushed`, as lines, noting what is now pushed.
+async fn lines(dispatcher: &Dispatcher, cfg: &StatsdConfig, pushed: &mut Pushed) -> Vec<String> {
+    let metrics = &dispatcher.metrics;
+    let dropped = dispatcher.dropped.lock().unwrap().clone();
+    let mut lines = Vec::new();
+    for Sample {
+        name,
+        labels,
+        value,
+    } in metrics.samples(&dropped)
+    {
+        let delta = value.saturating_sub(pushed.get(&(name, labels.clone())).copied().unwrap_or(0));
+        if delta > 0 {
+            lines.push(line(cfg, name, &labels, &delta.to_string(), "c"));
+        }
+        pushed.insert((name, labels), value);
+    }
+    for (destination, elapsed) in metrics.take_timings() {
+        lines.push(line(
+            cfg,
+            "delivery.duration",
+            &[("destination", destination)],
+            &format!("{:.3}", elapsed.as_secs_f64() * 1000.0),
+            "ms",
+        ));
+    }
+    for (queue, (messages, consumers)) in metrics.queue_depths() {
+        let labels = [("queue", queue)];
+        lines.push(line(
+            cfg,
+            "queue.messages",
+            &labels,
+            &messages.to_string(),
+            "g",
+        ));
+        lines.push(line(
+            cfg,
+            "queue.consumers",
+            &labels,
+            &consumers.to_string(),
+            "g",
+        ));
+    }
+    if let Some(spool) = &dispatcher.spool {
+        let (entries, bytes) = spool.depth().await;
+        lines.push(line(cfg, "spool.entries", &[], &entries.to_string(), "g"));
+        lines.push(line(cfg, "spool.bytes", &[], &bytes.to_string(), "g"));
+    }
+    lines
+}
+
+/// Send `datagrams` to the agent at `address`.
+async fn send(address: &str, datagrams: Vec<String>) -> Result<(), String> {
+    let target: SocketAddr = tokio::net::lookup_host(address)
+        .await
+        .map_err(|e| format!("Unable to resolve {}: {}", address, e))?
+        .next()
+        .ok_or_else(|| format!("Unable to resolve {}", address))?;
+    let local = if target.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let mut socket = UdpSocket::bind(local)
+        .await
+        .map_err(|e| format!("Unable to open a UDP socket: {}", e))?;
+    for datagram in datagrams {
+        socket
+            .send_to(datagram.as_bytes(), &target)
+            .await
+            .map_err(|e| format!("Unable to send to {}: {}", address, e))?;
+    }
+    Ok(())
+}
+
+/// Push the metrics as the configuration in force says. Runs until the
+/// process exits.
+pub async fn push(dispatcher: Arc<Dispatcher>) {
+    dispatcher.metrics.collect_timings();
+    let mut pushed = Pushed::new();
+    loop {
+        let cfg = dispatcher.cfg();
+        let statsd = match &cfg.statsd {
+            Some(statsd) => statsd,
+            None => return,
+        };
+        tokio::time::delay_for(statsd.interval).await;
+        let lines = lines(&dispatcher, statsd, &mut pushed).await;
+        if lines.is_empty() {
+            continue;
+        }
+        if let Err(e) = send(&statsd.address, datagrams(lines)).await {
+            warn!("Unable to push metrics to statsd: {}", e);
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use crate::alert::Alert;
+use crate::config::MessagesConfig;
+use chrono::{DateTime, Duration, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// A message posted through the Web API, with the alert it was rendered
+/// from so that it can be rendered again when the alert changes state.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StoredMessage {
+    /// The queue the alert arrived on, whose token can edit the message.
+    #[serde(default)]
+    pub queue: String,
+    pub channel: String,
+    pub ts: String,
+    pub posted_at: DateTime<Utc>,
+    pub alert: Alert,
+    /// Reminders sent so far about an unacknowledged alert.
+    #[serde(default)]
+    pub reminders: u32,
+}
+
+/// The Slack message last posted for each alert fingerprint, optionally
+/// saved to a JSON file so that threads survive a restart.
+pub struct MessageStore {
+    path: Option<PathBuf>,
+    ttl: Duration,
+    messages: Mutex<HashMap<String, StoredMessage>>,
+}
+
+impl MessageStore {
+    pub fn open(cfg: &MessagesConfig) -> Result<MessageStore, String> {
+        let path = cfg.path.as_ref().map(PathBuf::from);
+        let messages = match &path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(raw) => serde_json::from_str(&raw)
+                    .map_err(|e| format!("Unable to parse {}: {}", path.display(), e))?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+                Err(e) => return Err(format!("Unable to read {}: {}", path.display(), e)),
+            },
+            None => HashMap::new(),
+        };
+        if !messages.is_empty() {
+            info!("Loaded {} posted messages", messages.len());
+        }
+
+        Ok(MessageStore {
+            path,
+            ttl: Duration::from_std(cfg.ttl).map_err(|_| "messages.ttl is too long")?,
+            messages: Mutex::new(messages),
+        })
+    }
+
+    pub fn get(&self, fingerprint: &str) -> Option<StoredMessage> {
+        let messages = self.messages.lock().unwrap();
+        messages
+            .get(fingerprint)
+            .filter(|m| Utc::now() - m.posted_at < self.ttl)
+            .cloned()
+    }
+
+    /// Every message still within its ttl, with its fingerprint.
+    pub fn messages(&self) -> Vec<(String, StoredMessage)> {
+        let messages = self.messages.lock().unwrap();
+        let now = Utc::now();
+        messages
+            .iter()
+            .filter(|(_, m)| now - m.posted_at < self.ttl)
+            .map(|(fingerprint, m)| (fingerprint.clone(), m.clone()))
+            .collect()
+    }
+
+    /// How many messages are remembered, including any past their ttl.
+    pub fn count(&self) -> usize {
+        self.messages.lock().unwrap().len()
+    }
+
+    pub fn insert(&self, fingerprint: &str, message: StoredMessage) {
+        let mut messages = self.messages.lock().unwrap();
+        let now = Utc::now();
+        messages.retain(|_, m| now - m.posted_at < self.ttl);
+        messages.insert(fingerprint.to_string(), message);
+        self.save(&messages);
+    }
+
+    pub fn remove(&self, fingerprint: &str) {
+        let mut messages = self.messages.lock().unwrap();
+        if messages.remove(fingerprint).is_some() {
+            self.save(&messages);
+        }
+    }
+
+    /// Best effort: losing the file only means later alerts start new
+    /// threads.
+    fn save(&self, messages: &HashMap<String, StoredMessage>) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let tmp = path.with_extension("tmp");
+        let result = serde_json::to_string(messages)
+            .map_err(|e| e.to_string())
+            .and_then(|raw| fs::write(&tmp, raw).map_err(|e| e.to_string()))
+            .and_then(|_| fs::rename(&tmp, path).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            warn!(
+                "Unable to save posted messages to {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+}
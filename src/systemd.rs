@@ -0,0 +1,212 @@
+//! Running as a systemd service: readiness, status and watchdog keepalives
+//! sent to `$NOTIFY_SOCKET`, and listeners handed over by socket
+//! activation. Outside systemd, and off Unix, none of this does anything.
+//!
+//! An example unit, with the metrics listener activated by a socket unit:
+//!
+//! ```text
+//! # /etc/systemd/system/2steps-slack-alert.service
+//! [Unit]
+//! Description=2Steps alerts to Slack
+//! After=network-online.target rabbitmq-server.service
+//! Wants=network-online.target
+//!
+//! [Service]
+//! Type=notify
+//! ExecStart=/opt/remasys/2steps/bin/2steps-slack-alert
+//! ExecReload=/bin/kill -HUP $MAINPID
+//! WatchdogSec=30
+//! Restart=on-failure
+//! User=twosteps
+//!
+//! [Install]
+//! WantedBy=multi-user.target
+//!
+//! # /etc/systemd/system/2steps-slack-alert-metrics.socket
+//! [Socket]
+//! ListenStream=9090
+//! FileDescriptorName=metrics
+//! Service=2steps-slack-alert.service
+//!
+//! [Install]
+//! WantedBy=sockets.target
+//! ```
+//!
+//! The metrics are then served on the socket's port whatever
+//! `metrics.listen` says, though `metrics` must still be configured. The
+//! health probes are taken over the same way by a socket named `health`.
+
+use crate::health::Health;
+use crate::router::Dispatcher;
+use futures::Stream;
+use log::{debug, info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::io;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+/// How often the status is brought up to date.
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+/// The period the status counts alerts over.
+const STATUS_WINDOW: usize = 6;
+
+/// Tell systemd `state`, such as `READY=1`, if it is listening.
+pub fn notify(state: &str) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::net::UnixDatagram;
+
+        let path = match env::var("NOTIFY_SOCKET") {
+            Ok(path) => path,
+            Err(_) => return,
+        };
+        let sent = UnixDatagram::unbound().and_then(|socket| match path.strip_prefix('@') {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                socket.send_to_addr(state.as_bytes(), &addr)
+            }
+            _ => socket.send_to(state.as_bytes(), &path),
+        });
+        if let Err(e) = sent {
+            debug!("Unable to notify systemd of {}: {}", state, e);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = state;
+}
+
+/// The interval systemd expects keepalives within, if it is watching us.
+fn watchdog_interval() -> Option<Duration> {
+    let pid = env::var("WATCHDOG_PID").ok();
+    if pid.is_some_and(|pid| pid != std::process::id().to_string()) {
+        return None;
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec)).filter(|interval| !interval.is_zero())
+}
+
+/// Send keepalives at half the interval systemd expects them, if it is
+/// watching us. They come from the runtime the alerts are processed on, so
+/// stop, and systemd restarts us, should it hang.
+pub async fn watchdog() {
+    let interval = match watchdog_interval() {
+        Some(interval) => interval,
+        None => return,
+    };
+    info!(
+        "Sending systemd watchdog keepalives every {:?}",
+        interval / 2
+    );
+    loop {
+        notify("WATCHDOG=1");
+        tokio::time::delay_for(interval / 2).await;
+    }
+}
+
+/// Keep the status systemd shows up to date with whether we are connected
+/// and how many alerts came and went in the last minute. Runs until the
+/// process exits.
+pub async fn report_status(dispatcher: Arc<Dispatcher>, health: Arc<Health>) {
+    if env::var_os("NOTIFY_SOCKET").is_none() {
+        return;
+    }
+    let mut history = VecDeque::with_capacity(STATUS_WINDOW + 1);
+    history.push_back(dispatcher.metrics.totals());
+    loop {
+        tokio::time::delay_for(STATUS_INTERVAL).await;
+        let totals = dispatcher.metrics.totals();
+        history.push_back(totals);
+        if history.len() > STATUS_WINDOW + 1 {
+            history.pop_front();
+        }
+        let (consumed, delivered, failed) = history[0];
+        let state = if dispatcher.pause.paused() {
+            "Paused".to_string()
+        } else if health.connected() {
+            format!("Consuming from {} queue(s)", dispatcher.cfg().queues.len())
+        } else {
+            "Reconnecting to rabbit".to_string()
+        };
+        notify(&format!(
+            "STATUS={}: {} alerts received, {} delivered, {} not delivered in the last minute",
+            state,
+            totals.0 - consumed,
+            totals.1 - delivered,
+            totals.2 - failed
+        ));
+    }
+}
+
+/// The sockets systemd passed us, by the `FileDescriptorName=` of each.
+#[cfg(unix)]
+fn activated() -> HashMap<String, TcpListener> {
+    use std::os::unix::io::FromRawFd;
+
+    /// Where the passed descriptors start.
+    const FIRST: i32 = 3;
+
+    let mut listeners = HashMap::new();
+    if env::var("LISTEN_PID").ok() != Some(std::process::id().to_string()) {
+        return listeners;
+    }
+    let count: i32 = match env::var("LISTEN_FDS").ok().and_then(|n| n.parse().ok()) {
+        Some(count) => count,
+        None => return listeners,
+    };
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':');
+    for fd in FIRST..FIRST + count {
+        let name = names.next().unwrap_or("unknown").to_string();
+        // systemd hands these over for us to own, one each.
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        listeners.insert(name, listener);
+    }
+    // Not for any process we might start.
+    for var in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(var);
+    }
+    listeners
+}
+
+#[cfg(not(unix))]
+fn activated() -> HashMap<String, TcpListener> {
+    HashMap::new()
+}
+
+static ACTIVATED: OnceLock<Mutex<HashMap<String, TcpListener>>> = OnceLock::new();
+
+/// The socket systemd passed us named `name`, if there is one. Each is
+/// given out once.
+pub fn listener(name: &str) -> Option<TcpListener> {
+    let listener = ACTIVATED
+        .get_or_init(|| Mutex::new(activated()))
+        .lock()
+        .unwrap()
+        .remove(name);
+    if let Some(listener) = &listener {
+        match listener.local_addr() {
+            Ok(addr) => info!("Using the {} socket systemd passed, on {}", name, addr),
+            Err(e) => warn!("The {} socket systemd passed is not usable: {}", name, e),
+        }
+    }
+    listener
+}
+
+/// The connections made to `listener`, for serving with warp.
+pub fn incoming(
+    listener: TcpListener,
+) -> io::Result<impl Stream<Item = io::Result<tokio::net::TcpStream>>> {
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::TcpListener::from_std(listener)?;
+    Ok(futures::stream::unfold(
+        listener,
+        |mut listener| async move {
+            let accepted = listener.accept().await.map(|(stream, _)| stream);
+            Some((accepted, listener))
+        },
+    ))
+}
@@ -0,0 +1,227 @@
+//! The system's own log: syslog or the journal on Unix, the Event Log on
+//! Windows. Each line is sent with a priority matching its level, so that
+//! the system's tools can pick out errors and warnings.
+
+use crate::config::SystemLogKind;
+use std::io::{self, Write};
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// What lines are logged as.
+const IDENT: &str = "2steps-slack-alert";
+#[cfg(unix)]
+const JOURNAL: &str = "/run/systemd/journal/socket";
+
+/// The syslog severity for `level`, as journald takes it too.
+#[cfg(unix)]
+fn priority(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        _ => 7,
+    }
+}
+
+/// Send `message` over `socket`, connecting it to `path` again should the
+/// daemon have been restarted since.
+#[cfg(unix)]
+fn send_unix(
+    socket: &std::os::unix::net::UnixDatagram,
+    path: &str,
+    message: &[u8],
+) -> io::Result<()> {
+    if socket.send(message).is_err() {
+        socket.connect(path)?;
+        socket.send(message)?;
+    }
+    Ok(())
+}
+
+/// A source registered with the Event Log.
+#[cfg(windows)]
+pub struct EventSource(winapi::um::winnt::HANDLE);
+
+// The Event Log takes reports from any thread through one handle.
+#[cfg(windows)]
+unsafe impl Send for EventSource {}
+#[cfg(windows)]
+unsafe impl Sync for EventSource {}
+
+#[cfg(windows)]
+impl Drop for EventSource {
+    fn drop(&mut self) {
+        unsafe { winapi::um::winbase::DeregisterEventSource(self.0) };
+    }
+}
+
+#[cfg(windows)]
+fn wide(value: &str) -> Vec<u16> {
+    value.encode_utf16().chain(std::iter::once(0)).collect()
+}
+
+/// Where lines are sent.
+pub enum SystemLog {
+    /// The local syslog daemon, as RFC 3164 messages from the daemon
+    /// facility.
+    #[cfg(unix)]
+    Syslog(std::os::unix::net::UnixDatagram, &'static str),
+    /// The journal, through its native protocol.
+    #[cfg(unix)]
+    Journald(std::os::unix::net::UnixDatagram),
+    #[cfg(windows)]
+    EventLog(EventSource),
+}
+
+impl SystemLog {
+    pub fn open(kind: SystemLogKind) -> Result<SystemLog, String> {
+        match kind {
+            #[cfg(unix)]
+            SystemLogKind::Syslog => {
+                let socket = std::os::unix::net::UnixDatagram::unbound()
+                    .map_err(|e| format!("Unable to open a socket for syslog: {}", e))?;
+                let path = ["/dev/log", "/var/run/syslog"]
+                    .iter()
+                    .find(|path| socket.connect(path).is_ok())
+                    .ok_or_else(|| "Unable to connect to syslog at /dev/log".to_string())?;
+                Ok(SystemLog::Syslog(socket, path))
+            }
+            #[cfg(unix)]
+            SystemLogKind::Journald => {
+                let socket = std::os::unix::net::UnixDatagram::unbound()
+                    .map_err(|e| format!("Unable to open a socket for the journal: {}", e))?;
+                socket
+                    .connect(JOURNAL)
+                    .map_err(|e| format!("Unable to connect to the journal: {}", e))?;
+                Ok(SystemLog::Journald(socket))
+            }
+            #[cfg(windows)]
+            SystemLogKind::Eventlog => {
+                let name = wide(IDENT);
+                let handle = unsafe {
+                    winapi::um::winbase::RegisterEventSourceW(std::ptr::null(), name.as_ptr())
+                };
+                if handle.is_null() {
+                    return Err(format!(
+                        "Unable to register with the Event Log: {}",
+                        io::Error::last_os_error()
+                    ));
+                }
+                Ok(SystemLog::EventLog(EventSource(handle)))
+            }
+            _ => Err(format!("{:?} logging is not available here", kind)),
+        }
+    }
+
+    fn send(&self, level: &Level, line: &str) -> io::Result<()> {
+        match self {
+            #[cfg(unix)]
+            SystemLog::Syslog(socket, path) => {
+                let message = format!(
+                    "<{}>{}[{}]: {}",
+                    3 * 8 + priority(level),
+                    IDENT,
+                    std::process::id(),
+                    line
+                );
+                send_unix(socket, path, message.as_bytes())
+            }
+            #[cfg(unix)]
+            SystemLog::Journald(socket) => {
+                let mut message = format!(
+                    "PRIORITY={}\nSYSLOG_IDENTIFIER={}\nSYSLOG_PID={}\nMESSAGE\n",
+                    priority(level),
+                    IDENT,
+                    std::process::id()
+                )
+                .into_bytes();
+                // Sized rather than ended by a newline, as it may hold some.
+                message.extend_from_slice(&(line.len() as u64).to_le_bytes());
+                message.extend_from_slice(line.as_bytes());
+                message.push(b'\n');
+                send_unix(socket, JOURNAL, &message)
+            }
+            #[cfg(windows)]
+            SystemLog::EventLog(source) => {
+                use winapi::um::winnt::{
+                    EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE,
+                };
+                let kind = match *level {
+                    Level::ERROR => EVENTLOG_ERROR_TYPE,
+                    Level::WARN => EVENTLOG_WARNING_TYPE,
+                    _ => EVENTLOG_INFORMATION_TYPE,
+                };
+                let line = wide(line);
+                let mut strings = [line.as_ptr()];
+                let reported = unsafe {
+                    winapi::um::winbase::ReportEventW(
+                        source.0,
+                        kind,
+                        0,
+                        0,
+                        std::ptr::null_mut(),
+                        1,
+                        0,
+                        strings.as_mut_ptr(),
+                        std::ptr::null_mut(),
+                    )
+                };
+                if reported == 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Gathers a line, and sends it once it is written.
+pub struct SystemLogWriter<'a> {
+    log: &'a SystemLog,
+    level: Level,
+    line: Vec<u8>,
+}
+
+impl Write for SystemLogWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SystemLogWriter<'_> {
+    fn drop(&mut self) {
+        let line = String::from_utf8_lossy(&self.line);
+        let line = line.trim_end();
+        if line.is_empty() {
+            return;
+        }
+        if let Err(e) = self.log.send(&self.level, line) {
+            eprintln!("Unable to write to the system log: {}", e);
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for SystemLog {
+    type Writer = SystemLogWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        SystemLogWriter {
+            log: self,
+            level: Level::INFO,
+            line: Vec::new(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        SystemLogWriter {
+            log: self,
+            level: *meta.level(),
+            line: Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! The `tail` subcommand, which prints alerts as they are published instead
+//! of delivering them, for seeing what 2Steps sends without disturbing the
+//! queues the service consumes from.
+
+use crate::alert::Alert;
+use crate::config::{ConfigSource, QueueConfig};
+use crate::expr::Expr;
+use crate::load_config;
+use crate::source;
+use chrono::{SecondsFormat, Utc};
+use futures::StreamExt;
+use lapin::message::Delivery;
+use serde_json::Value;
+use std::future::Future;
+
+/// Print `delivery` if it passes all of `filters`, which see the alert as
+/// drop rules do, or the payload as published if it is not one.
+fn show(delivery: &Delivery, filters: &[Expr]) {
+    let payload: Option<Value> = serde_json::from_slice(&delivery.data).ok();
+    let alert = Alert::from_slice(&delivery.data);
+    let value = match &alert {
+        Ok(alert) => serde_json::to_value(alert).ok(),
+        Err(_) => payload.clone(),
+    };
+    let passes = match &value {
+        Some(value) => filters.iter().all(|filter| filter.matches(value)),
+        None => filters.is_empty(),
+    };
+    if !passes {
+        return;
+    }
+
+    println!(
+        "--- {} routing key {:?}",
+        Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        delivery.routing_key.as_str()
+    );
+    let headers = source::headers(delivery);
+    if headers
+        .as_object()
+        .is_some_and(|headers| !headers.is_empty())
+    {
+        println!("headers: {}", headers);
+    }
+    match &payload {
+        Some(payload) => println!(
+            "{}",
+            serde_json::to_string_pretty(payload).unwrap_or_else(|_| payload.to_string())
+        ),
+        None => println!("{}", String::from_utf8_lossy(&delivery.data)),
+    }
+    if let Err(e) = alert {
+        println!("(not an alert: {})", e);
+    }
+}
+
+/// Print each alert published to the exchange as the queue named `queue`
+/// is bound, or any of the queues, that passes every one of `filters`,
+/// until `shutdown` resolves.
+pub async fn tail(
+    source: &ConfigSource,
+    queue: Option<&str>,
+    filters: &[String],
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), String> {
+    let cfg = load_config(source).await?;
+    let filters = filters
+        .iter()
+        .map(|filter| Expr::parse(filter).map_err(|e| format!("Invalid filter {}: {}", filter, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let queues: Vec<&QueueConfig> = match queue {
+        Some(name) => vec![cfg
+            .queues
+            .iter()
+            .find(|queue| queue.name == name)
+            .ok_or_else(|| format!("No queue named {}", name))?],
+        None => cfg.queues.iter().collect(),
+    };
+
+    let (_conn, mut consumer) = source::tap(&cfg, &queues).await?;
+    // On stderr, so that stdout holds only what was published.
+    eprintln!(
+        "Tailing {} as {} is bound; press Ctrl-C to stop",
+        cfg.exchange.name,
+        queues
+            .iter()
+            .map(|queue| queue.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    futures::pin_mut!(shutdown);
+    loop {
+        let delivery = tokio::select! {
+            delivery = consumer.next() => delivery,
+            _ = &mut shutdown => return Ok(()),
+        };
+        match delivery {
+            Some(Ok(delivery)) => show(&delivery, &filters),
+            Some(Err(e)) => return Err(format!("Lost the connection to rabbit: {:?}", e)),
+            None => return Err("The broker stopped the tail".to_string()),
+        }
+    }
+}
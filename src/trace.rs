@@ -0,0 +1,283 @@
+//! Traces of each alert from the queue through rendering to delivery.
+//!
+//! The trace an alert belongs to comes from a header on its message, in W3C
+//! `traceparent` form or as any other correlation ID, or is started afresh.
+//! Spans within it are kept track of by `TraceLayer`, which hands them to
+//! an OTLP collector over HTTP once they close, if there is one, and gives
+//! the span in progress to outgoing requests as their `traceparent`.
+
+use crate::config::TracingConfig;
+use log::warn;
+use reqwest::Client;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{Layer, Registry};
+
+/// The span fields that start a trace: its ID, and the span in another
+/// service it continues from.
+pub const TRACE_ID: &str = "trace_id";
+pub const REMOTE_PARENT: &str = "remote_parent";
+/// How often finished spans are sent to the collector.
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+/// Finished spans kept while the collector cannot be reached; later ones
+/// are dropped.
+const MAX_PENDING: usize = 2048;
+
+/// Where an alert's trace continues from.
+pub struct TraceContext {
+    pub trace_id: String,
+    /// The span that published the alert, if the header said.
+    pub parent: Option<String>,
+}
+
+fn is_hex(value: &str, len: usize) -> bool {
+    value.len() == len && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+impl TraceContext {
+    /// The context `value` names: the trace and parent of a `traceparent`,
+    /// a trace ID or UUID as it is, or a trace ID derived from any other
+    /// correlation ID. A new trace is started if there is none.
+    pub fn new(value: Option<&str>) -> TraceContext {
+        let value = match value.map(str::trim).filter(|value| !value.is_empty()) {
+            Some(value) => value,
+            None => {
+                return TraceContext {
+                    trace_id: hex::encode(rand::random::<[u8; 16]>()),
+                    parent: None,
+                }
+            }
+        };
+        let parts: Vec<&str> = value.split('-').collect();
+        if let [_, trace_id, parent, _] = parts.as_slice() {
+            if is_hex(trace_id, 32) && is_hex(parent, 16) {
+                return TraceContext {
+                    trace_id: trace_id.to_ascii_lowercase(),
+                    parent: Some(parent.to_ascii_lowercase()),
+                };
+            }
+        }
+        let compact = value.replace('-', "");
+        let trace_id = if is_hex(&compact, 32) {
+            compact.to_ascii_lowercase()
+        } else {
+            hex::encode(&Sha256::digest(value.as_bytes())[..16])
+        };
+        TraceContext {
+            trace_id,
+            parent: None,
+        }
+    }
+}
+
+/// What is known of a span in a trace.
+struct SpanData {
+    trace_id: String,
+    span_id: String,
+    parent: Option<String>,
+    start: SystemTime,
+    attributes: Vec<(String, Value)>,
+}
+
+/// Collects the fields of a span as attributes.
+struct Fields<'a>(&'a mut Vec<(String, Value)>);
+
+impl Fields<'_> {
+    fn set(&mut self, field: &Field, value: Value) {
+        let name = field.name();
+        match self.0.iter_mut().find(|(key, _)| key == name) {
+            Some((_, old)) => *old = value,
+            None => self.0.push((name.to_string(), value)),
+        }
+    }
+}
+
+impl Visit for Fields<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.set(field, json!({ "stringValue": format!("{:?}", value) }));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field, json!({ "stringValue": value }));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.set(field, json!({ "intValue": value.to_string() }));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.set(field, json!({ "intValue": value.to_string() }));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.set(field, json!({ "boolValue": value }));
+    }
+}
+
+fn string_attribute(attributes: &[(String, Value)], name: &str) -> Option<String> {
+    attributes
+        .iter()
+        .find(|(key, _)| key == name)
+        .and_then(|(_, value)| value["stringValue"].as_str())
+        .map(String::from)
+}
+
+fn nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+/// Keeps track of the spans of traces started by a span with a `TRACE_ID`
+/// field, and queues them for export once they close.
+pub struct TraceLayer {
+    /// Finished spans in OTLP's JSON form; `None` if nothing exports them.
+    pending: Option<Arc<Mutex<Vec<Value>>>>,
+}
+
+impl<S> Layer<S> for TraceLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let mut attributes = Vec::new();
+        attrs.record(&mut Fields(&mut attributes));
+        let inherited = span.parent().and_then(|parent| {
+            parent
+                .extensions()
+                .get::<SpanData>()
+                .map(|data| (data.trace_id.clone(), Some(data.span_id.clone())))
+        });
+        let (trace_id, parent) = match inherited {
+            Some(inherited) => inherited,
+            None => match string_attribute(&attributes, TRACE_ID) {
+                Some(trace_id) => (trace_id, string_attribute(&attributes, REMOTE_PARENT)),
+                // Not part of an alert's trace.
+                None => return,
+            },
+        };
+        span.extensions_mut().insert(SpanData {
+            trace_id,
+            span_id: hex::encode(rand::random::<[u8; 8]>()),
+            parent,
+            start: SystemTime::now(),
+            attributes,
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                values.record(&mut Fields(&mut data.attributes));
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let (pending, span) = match (&self.pending, ctx.span(&id)) {
+            (Some(pending), Some(span)) => (pending, span),
+            _ => return,
+        };
+        let data = match span.extensions_mut().remove::<SpanData>() {
+            Some(data) => data,
+            None => return,
+        };
+        let failed = data.attributes.iter().any(|(key, _)| key == "error");
+        let mut otlp = json!({
+            "traceId": data.trace_id,
+            "spanId": data.span_id,
+            "name": span.name(),
+            "kind": 1,
+            "startTimeUnixNano": nanos(data.start),
+            "endTimeUnixNano": nanos(SystemTime::now()),
+            "attributes": data
+                .attributes
+                .iter()
+                .map(|(key, value)| json!({ "key": key, "value": value }))
+                .collect::<Vec<Value>>(),
+            "status": { "code": if failed { 2 } else { 1 } },
+        });
+        if let Some(parent) = data.parent {
+            otlp["parentSpanId"] = json!(parent);
+        }
+        let mut pending = pending.lock().unwrap();
+        if pending.len() < MAX_PENDING {
+            pending.push(otlp);
+        }
+    }
+}
+
+/// The `traceparent` of the span in progress, if it is part of an alert's
+/// trace.
+pub fn traceparent() -> Option<String> {
+    tracing::Span::current()
+        .with_subscriber(|(id, dispatch)| {
+            let registry = dispatch.downcast_ref::<Registry>()?;
+            let span = registry.span(id)?;
+            let extensions = span.extensions();
+            let data = extensions.get::<SpanData>()?;
+            Some(format!("00-{}-{}-01", data.trace_id, data.span_id))
+        })
+        .flatten()
+}
+
+/// Send the spans gathered in `pending` to the collector at `endpoint`
+/// every so often.
+async fn export(pending: Arc<Mutex<Vec<Value>>>, endpoint: String, service_name: String) {
+    let client = Client::new();
+    let url = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+    loop {
+        tokio::time::delay_for(EXPORT_INTERVAL).await;
+        let spans = mem::take(&mut *pending.lock().unwrap());
+        if spans.is_empty() {
+            continue;
+        }
+        let body = json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [
+                        { "key": "service.name", "value": { "stringValue": service_name } },
+                    ],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+                    "spans": spans,
+                }],
+            }],
+        });
+        match client.post(&url).json(&body).send().await {
+            Ok(res) if res.status().is_success() => {}
+            Ok(res) => warn!("Trace collector returned {}", res.status()),
+            Err(e) => warn!("Unable to send traces to the collector: {}", e),
+        }
+    }
+}
+
+/// A layer following the traces `cfg` describes, exporting them if it
+/// names a collector. Must be called within the runtime.
+pub fn layer(cfg: &TracingConfig) -> TraceLayer {
+    let pending = cfg.otlp_endpoint.as_ref().map(|endpoint| {
+        let pending = Arc::new(Mutex::new(Vec::new()));
+        tokio::spawn(export(
+            pending.clone(),
+            endpoint.clone(),
+            cfg.service_name.clone(),
+        ));
+        pending
+    });
+    TraceLayer { pending }
+}
@@ -0,0 +1,207 @@
+//! Running unattended as a Windows service. `service install` registers the
+//! service to start with Windows and run `service run` with the same
+//! configuration; the Service Control Manager's stop and shutdown requests
+//! shut the bridge down as Ctrl-C would. Nothing is written to a console,
+//! so `logging.system: eventlog` or `logging.file` is wanted.
+
+use crate::config::{ConfigSource, Format};
+use crate::run;
+use std::ffi::OsString;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use windows_service::service::{
+    ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+    ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+use windows_service::{define_windows_service, service_dispatcher};
+
+/// What the service is registered as.
+const NAME: &str = "2steps-slack-alert";
+const DISPLAY_NAME: &str = "2Steps Slack Alert";
+const DESCRIPTION: &str = "Publishes 2Steps alerts from RabbitMQ to Slack and elsewhere.";
+/// How long to wait for the service to stop before removing it.
+const STOP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The configuration the service runs with, from its command line.
+static SOURCE: OnceLock<ConfigSource> = OnceLock::new();
+
+/// The arguments that give the service `source` and `verbosity`, with the
+/// configuration's path made absolute, as services start in the system
+/// directory.
+fn launch_arguments(source: &ConfigSource, verbosity: i32) -> Result<Vec<OsString>, String> {
+    let path = Path::new(&source.path)
+        .canonicalize()
+        .map_err(|e| format!("Unable to find {}: {}", source.path, e))?;
+    let mut arguments: Vec<OsString> = vec![
+        "service".into(),
+        "run".into(),
+        "--config".into(),
+        path.into_os_string(),
+    ];
+    if let Some(format) = source.format {
+        let format = match format {
+            Format::Yaml => "yaml",
+            Format::Toml => "toml",
+            Format::Json => "json",
+        };
+        arguments.extend(["--format".into(), format.into()]);
+    }
+    if let Some(profile) = &source.profile {
+        arguments.extend(["--profile".into(), profile.into()]);
+    }
+    for setting in &source.overrides {
+        arguments.extend(["--set".into(), setting.into()]);
+    }
+    match verbosity {
+        i32::MIN..=-1 => arguments.push("--quiet".into()),
+        0 => {}
+        n => arguments.push(format!("-{}", "v".repeat(n as usize)).into()),
+    }
+    Ok(arguments)
+}
+
+/// Register the service to start with Windows, running with `source` at
+/// `verbosity`.
+pub fn install(source: &ConfigSource, verbosity: i32) -> Result<(), String> {
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )
+    .map_err(|e| format!("Unable to open the service manager: {}", e))?;
+    let info = ServiceInfo {
+        name: NAME.into(),
+        display_name: DISPLAY_NAME.into(),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe()
+            .map_err(|e| format!("Unable to find this program: {}", e))?,
+        launch_arguments: launch_arguments(source, verbosity)?,
+        dependencies: Vec::new(),
+        account_name: None,
+        account_password: None,
+    };
+    let service = manager
+        .create_service(&info, ServiceAccess::CHANGE_CONFIG)
+        .map_err(|e| format!("Unable to install the {} service: {}", NAME, e))?;
+    service
+        .set_description(DESCRIPTION)
+        .map_err(|e| format!("Unable to describe the {} service: {}", NAME, e))?;
+    Ok(())
+}
+
+/// Stop the service if it is running, and remove it.
+pub fn uninstall() -> Result<(), String> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .map_err(|e| format!("Unable to open the service manager: {}", e))?;
+    let service = manager
+        .open_service(
+            NAME,
+            ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+        )
+        .map_err(|e| format!("Unable to open the {} service: {}", NAME, e))?;
+    let query = || {
+        service
+            .query_status()
+            .map(|status| status.current_state)
+            .map_err(|e| format!("Unable to query the {} service: {}", NAME, e))
+    };
+    if query()? != ServiceState::Stopped {
+        service
+            .stop()
+            .map_err(|e| format!("Unable to stop the {} service: {}", NAME, e))?;
+        let started = Instant::now();
+        while query()? != ServiceState::Stopped {
+            if started.elapsed() > STOP_TIMEOUT {
+                return Err(format!("The {} service did not stop", NAME));
+            }
+            std::thread::sleep(Duration::from_millis(500));
+        }
+    }
+    service
+        .delete()
+        .map_err(|e| format!("Unable to remove the {} service: {}", NAME, e))
+}
+
+/// Run as the service with `source`, until the Service Control Manager
+/// stops it. Fails if not started by the Service Control Manager.
+pub fn dispatch(source: ConfigSource) -> Result<(), String> {
+    let _ = SOURCE.set(source);
+    service_dispatcher::start(NAME, ffi_service_main)
+        .map_err(|e| format!("Unable to run as a service: {}", e))
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(e) = serve() {
+        log::error!("{}", e);
+    }
+}
+
+fn status(state: ServiceState, exit_code: ServiceExitCode) -> ServiceStatus {
+    ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted: match state {
+            ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            _ => ServiceControlAccept::empty(),
+        },
+        exit_code,
+        checkpoint: 0,
+        wait_hint: match state {
+            ServiceState::StopPending => STOP_TIMEOUT,
+            _ => Duration::default(),
+        },
+        process_id: None,
+    }
+}
+
+/// Run the bridge, with a stop or shutdown request from the Service
+/// Control Manager taken as a request to shut down.
+fn serve() -> Result<(), String> {
+    let source = SOURCE
+        .get()
+        .cloned()
+        .ok_or_else(|| "No configuration to run the service with".to_string())?;
+    let (stop, stopped) = futures::channel::oneshot::channel::<()>();
+    let stop = Mutex::new(Some(stop));
+    let handler = move |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            log::info!("Stop requested by the service manager");
+            if let Some(stop) = stop.lock().unwrap().take() {
+                let _ = stop.send(());
+            }
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    };
+    let status_handle = service_control_handler::register(NAME, handler)
+        .map_err(|e| format!("Unable to register with the service manager: {}", e))?;
+    let set_status = |state, exit_code| {
+        status_handle
+            .set_service_status(status(state, exit_code))
+            .map_err(|e| format!("Unable to report the service's status: {}", e))
+    };
+    set_status(ServiceState::Running, ServiceExitCode::Win32(0))?;
+
+    let mut runtime = tokio::runtime::Runtime::new()
+        .map_err(|e| format!("Unable to start the runtime: {}", e))?;
+    let shutdown = async move {
+        let _ = stopped.await;
+    };
+    let ran = runtime.block_on(run(source, false, shutdown));
+    set_status(ServiceState::StopPending, ServiceExitCode::Win32(0))?;
+    drop(runtime);
+
+    let exit_code = match &ran {
+        Ok(()) => ServiceExitCode::Win32(0),
+        Err(_) => ServiceExitCode::ServiceSpecific(1),
+    };
+    set_status(ServiceState::Stopped, exit_code)?;
+    ran
+}